@@ -140,16 +140,15 @@ impl From<&Arguments> for Options<'_> {
 	}
 }
 
+/// Builds the client ID to use when none is provided.
+///
+/// With a clean session the ID is left empty, and a random ID is generated
+/// by the client.
 fn build_client_id(clean_session: bool) -> String {
 	if !clean_session {
 		format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"),)
 	} else {
-		format!(
-			"{}/{}:{}",
-			env!("CARGO_PKG_NAME"),
-			env!("CARGO_PKG_VERSION"),
-			process::id()
-		)
+		String::new()
 	}
 }
 
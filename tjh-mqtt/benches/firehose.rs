@@ -0,0 +1,109 @@
+//! Counts the allocations made while routing a stream of incoming Publish
+//! packets to their subscriptions.
+//!
+//! Run with `cargo bench --bench firehose`.
+use bytes::BytesMut;
+use std::{
+	alloc::{GlobalAlloc, Layout, System},
+	hint::black_box,
+	io::Cursor,
+	sync::atomic::{AtomicUsize, Ordering},
+	time::Instant,
+};
+use tjh_mqtt::{
+	clients::{ClientState, Message},
+	packets::{Connect, Frame, Publish, SubAck},
+	FilterBuf, Packet, PacketId, QoS, Topic,
+};
+
+const MESSAGES: usize = 100_000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+		unsafe { System.alloc(layout) }
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		unsafe { System.dealloc(ptr, layout) }
+	}
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() {
+	// Serialize the stream of Publish packets up front.
+	let mut stream = BytesMut::new();
+	for n in 0..MESSAGES {
+		let topic = format!("sensors/{}/temperature", n % 100);
+		Publish::AtMostOnce {
+			retain: false,
+			topic: Topic::new(&topic).unwrap(),
+			payload: format!("{n}").into(),
+		}
+		.serialize_to_bytes(&mut stream)
+		.unwrap();
+	}
+	let stream = stream.freeze();
+
+	for filter_trie in [false, true] {
+		let mut state = ClientState::<usize, (), (), ()>::new(&Connect::default());
+		state.use_filter_trie(filter_trie);
+		for (index, filter) in ["sensors/#", "sensors/+/temperature", "devices/+/status"]
+			.into_iter()
+			.enumerate()
+		{
+			let filter = FilterBuf::new(filter).unwrap();
+			state.subscribe(vec![(filter, QoS::AtMostOnce)], index, ());
+			state
+				.suback(SubAck {
+					id: PacketId::new(index as u16 + 1).unwrap(),
+					result: vec![Ok(QoS::AtMostOnce)],
+				})
+				.unwrap();
+		}
+
+		let mut buffer = stream.clone();
+		let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+		let start = Instant::now();
+		while !buffer.is_empty() {
+			let extent = Frame::check(&mut Cursor::new(&buffer[..])).unwrap();
+			let frame = Frame::parse(buffer.split_to(extent)).unwrap();
+			let Packet::Publish(publish) = Packet::parse(&frame).unwrap() else {
+				unreachable!();
+			};
+			let Publish::AtMostOnce {
+				retain,
+				topic,
+				payload,
+			} = *publish
+			else {
+				unreachable!();
+			};
+
+			let channel = state.find_publish_channel(topic).unwrap();
+			black_box((
+				channel,
+				Message {
+					topic: topic.to_topic_buf(),
+					qos: QoS::AtMostOnce,
+					retain,
+					payload,
+				},
+			));
+		}
+		let elapsed = start.elapsed();
+		let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+		println!(
+			"filter trie {filter_trie:>5}: {:.2} allocations/message, {:>8.2?}/message",
+			allocations as f64 / MESSAGES as f64,
+			elapsed / MESSAGES as u32,
+		);
+	}
+}
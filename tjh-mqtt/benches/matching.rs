@@ -0,0 +1,60 @@
+//! Compares `Filter::matches_topic` for literal filters against matching them
+//! level by level.
+//!
+//! Run with `cargo bench --bench matching`.
+use std::{hint::black_box, time::Instant};
+use tjh_mqtt::{Filter, FilterBuf, Topic, TopicBuf};
+
+const ITERATIONS: usize = 1_000;
+
+/// Matches every level of a literal filter, as `matches_topic` did for all
+/// filters.
+fn match_levels(filter: &Filter, topic: &Topic) -> Option<usize> {
+	let mut topic_levels = topic.levels();
+	let mut exact = 0;
+	for level in filter.levels() {
+		if topic_levels.next().is_none_or(|t| t != level) {
+			return None;
+		}
+		exact += 1;
+	}
+	(topic_levels.count() == 0).then_some(exact)
+}
+
+fn main() {
+	for subscriptions in [10, 100, 1_000, 10_000] {
+		let filters: Vec<_> = (0..subscriptions)
+			.map(|n| FilterBuf::new(format!("sensors/{n}/temperature")).unwrap())
+			.collect();
+
+		let topics: Vec<_> = (0..ITERATIONS)
+			.map(|n| TopicBuf::new(format!("sensors/{}/temperature", n % subscriptions)).unwrap())
+			.collect();
+
+		let start = Instant::now();
+		for topic in &topics {
+			let matched = filters
+				.iter()
+				.filter_map(|filter| match_levels(filter, topic))
+				.count();
+			black_box(matched);
+		}
+		let levels = start.elapsed();
+
+		let start = Instant::now();
+		for topic in &topics {
+			let matched = filters
+				.iter()
+				.filter_map(|filter| filter.matches_topic(topic))
+				.count();
+			black_box(matched);
+		}
+		let literal = start.elapsed();
+
+		println!(
+			"{subscriptions:>6} literal filters: levels {:>10.2?}/topic, matches_topic {:>10.2?}/topic",
+			levels / ITERATIONS as u32,
+			literal / ITERATIONS as u32,
+		);
+	}
+}
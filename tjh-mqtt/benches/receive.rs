@@ -0,0 +1,73 @@
+//! Compares splitting incoming frames with `Frame::check` and `Frame::parse`
+//! against `Frame::split_from`, which decodes the fixed header once.
+//!
+//! Run with `cargo bench --bench receive`.
+use bytes::{Bytes, BytesMut};
+use std::{hint::black_box, io::Cursor, time::Instant};
+use tjh_mqtt::{
+	packets::{Frame, Publish},
+	Packet, Topic,
+};
+
+const MESSAGES: usize = 100_000;
+const ROUNDS: usize = 10;
+
+/// Splits and parses every packet in `stream`, checking each frame and then
+/// parsing its header again.
+fn check_and_parse(stream: &Bytes) -> usize {
+	let mut buffer = BytesMut::from(&stream[..]);
+	let mut parsed = 0;
+	while let Ok(extent) = Frame::check(&mut Cursor::new(&buffer[..])) {
+		let frame = Frame::parse(buffer.split_to(extent).freeze()).unwrap();
+		black_box(Packet::parse(&frame).unwrap());
+		parsed += 1;
+	}
+	parsed
+}
+
+/// Splits and parses every packet in `stream` with `Frame::split_from`.
+fn split_and_parse(stream: &Bytes) -> usize {
+	let mut buffer = BytesMut::from(&stream[..]);
+	let mut parsed = 0;
+	while let Some(frame) = Frame::split_from(&mut buffer).unwrap() {
+		black_box(Packet::parse(&frame).unwrap());
+		parsed += 1;
+	}
+	parsed
+}
+
+fn main() {
+	for payload_len in [8, 256, 4096] {
+		let mut stream = BytesMut::new();
+		for n in 0..MESSAGES {
+			let topic = format!("sensors/{}/temperature", n % 100);
+			Publish::AtMostOnce {
+				retain: false,
+				topic: Topic::new(&topic).unwrap(),
+				payload: vec![0; payload_len].into(),
+			}
+			.serialize_to_bytes(&mut stream)
+			.unwrap();
+		}
+		let stream = stream.freeze();
+
+		let start = Instant::now();
+		for _ in 0..ROUNDS {
+			assert_eq!(check_and_parse(&stream), MESSAGES);
+		}
+		let two_pass = start.elapsed();
+
+		let start = Instant::now();
+		for _ in 0..ROUNDS {
+			assert_eq!(split_and_parse(&stream), MESSAGES);
+		}
+		let single_pass = start.elapsed();
+
+		let total = (stream.len() * ROUNDS) as f64 / (1024.0 * 1024.0);
+		println!(
+			"{payload_len:>5} byte payloads: check + parse {:>8.1} MiB/s, split_from {:>8.1} MiB/s",
+			total / two_pass.as_secs_f64(),
+			total / single_pass.as_secs_f64(),
+		);
+	}
+}
@@ -0,0 +1,56 @@
+//! Compares routing topics with a `FilterTrie` against checking every filter.
+//!
+//! Run with `cargo bench --bench routing`.
+use std::{hint::black_box, time::Instant};
+use tjh_mqtt::{routing::FilterTrie, FilterBuf, TopicBuf};
+
+const ITERATIONS: usize = 10_000;
+
+fn main() {
+	for subscriptions in [10, 100, 1_000, 10_000] {
+		let filters: Vec<_> = (0..subscriptions)
+			.map(|n| match n % 4 {
+				0 => FilterBuf::new(format!("sensors/{n}/temperature")).unwrap(),
+				1 => FilterBuf::new(format!("sensors/{n}/+")).unwrap(),
+				2 => FilterBuf::new(format!("devices/{n}/#")).unwrap(),
+				_ => FilterBuf::new(format!("+/{n}/status")).unwrap(),
+			})
+			.collect();
+
+		let mut trie = FilterTrie::new();
+		for (index, filter) in filters.iter().enumerate() {
+			trie.insert(filter, index);
+		}
+
+		let topics: Vec<_> = (0..ITERATIONS)
+			.map(|n| TopicBuf::new(format!("sensors/{}/temperature", n % subscriptions)).unwrap())
+			.collect();
+
+		let start = Instant::now();
+		for topic in &topics {
+			let best = filters
+				.iter()
+				.enumerate()
+				.filter_map(|(index, filter)| filter.matches_topic(topic).map(|m| (m, index)))
+				.max();
+			black_box(best);
+		}
+		let linear = start.elapsed();
+
+		let start = Instant::now();
+		for topic in &topics {
+			let best = trie
+				.matching_filters(topic)
+				.filter_map(|(filter, index)| filter.matches_topic(topic).map(|m| (m, *index)))
+				.max();
+			black_box(best);
+		}
+		let routed = start.elapsed();
+
+		println!(
+			"{subscriptions:>6} subscriptions: linear {:>10.2?}/topic, trie {:>10.2?}/topic",
+			linear / ITERATIONS as u32,
+			routed / ITERATIONS as u32,
+		);
+	}
+}
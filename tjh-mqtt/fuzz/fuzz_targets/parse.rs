@@ -0,0 +1,19 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use mqtt::{packets::Frame, Packet, ParseMode};
+
+fuzz_target!(|data: &[u8]| {
+	// Feed the input through the same path as the client's read loop: split
+	// complete frames from the front of the buffer, then parse each packet.
+	let mut buffer = BytesMut::from(data);
+	while let Ok(Some(frame)) = Frame::split_from(&mut buffer) {
+		for mode in [ParseMode::Strict, ParseMode::Lenient] {
+			let _ = Packet::parse_with(&frame, mode);
+			#[cfg(feature = "v5")]
+			let _ = Packet::parse_v5(&frame, mode);
+		}
+		let _ = mqtt::packets::validate(&frame);
+	}
+});
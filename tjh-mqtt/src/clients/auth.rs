@@ -0,0 +1,25 @@
+use core::fmt;
+
+/// Participates in MQTT v5 enhanced authentication, such as SCRAM or Kerberos.
+///
+/// The Client sends the [`method`] and [`initial_data`] in the Connect
+/// packet. Until the Server accepts or refuses the connection with a ConnAck
+/// packet, it may send any number of Auth packets with a challenge; each is
+/// answered with the data returned by [`continue_auth`].
+///
+/// [`method`]: Authenticator::method
+/// [`initial_data`]: Authenticator::initial_data
+/// [`continue_auth`]: Authenticator::continue_auth
+pub trait Authenticator: fmt::Debug + Send + Sync {
+	/// Returns the name of the authentication method, e.g. `"SCRAM-SHA-1"`.
+	fn method(&self) -> &str;
+
+	/// Returns the authentication data to send in the Connect packet.
+	///
+	/// This is called for every connection attempt, so a new exchange is started
+	/// each time.
+	fn initial_data(&mut self) -> Option<Vec<u8>>;
+
+	/// Returns the response to a `challenge` from the Server.
+	fn continue_auth(&mut self, challenge: &[u8]) -> Vec<u8>;
+}
@@ -1,20 +1,28 @@
-use crate::{FilterBuf, QoS, TopicBuf};
-use bytes::Bytes;
+use crate::{misc::BytesVec, FilterBuf, QoS, TopicBuf};
 
 #[derive(Debug)]
-pub enum Command<T, PubResp, SubResp, UnSubResp> {
+pub enum Command<T, PubResp, SubResp, UnSubResp, ShutdownResp, FlushResp, CountResp> {
 	Publish(PublishCommand<PubResp>),
 	Subscribe(SubscribeCommand<T, SubResp>),
 	Unsubscribe(UnsubscribeCommand<UnSubResp>),
-	Shutdown,
+	Shutdown(ShutdownCommand<ShutdownResp>),
+	Flush(FlushCommand<FlushResp>),
+	KeepAlive(KeepAliveCommand),
+	PendingCount(PendingCountCommand<CountResp>),
 }
 
 #[derive(Debug)]
 pub struct PublishCommand<R> {
 	pub topic: TopicBuf,
-	pub payload: Bytes,
+	pub payload: BytesVec,
 	pub qos: QoS,
 	pub retain: bool,
+	#[cfg(feature = "v5")]
+	pub properties: crate::packets::PublishProperties,
+	/// When the message was published, from which its message expiry interval
+	/// is counted.
+	#[cfg(feature = "v5")]
+	pub created_at: std::time::Instant,
 	pub response: R,
 }
 
@@ -30,3 +38,25 @@ pub struct UnsubscribeCommand<R> {
 	pub filters: Vec<FilterBuf>,
 	pub response: R,
 }
+
+#[derive(Debug)]
+pub struct ShutdownCommand<R> {
+	pub response: R,
+}
+
+#[derive(Debug)]
+pub struct FlushCommand<R> {
+	pub response: R,
+}
+
+/// Requests the number of requests waiting for a response from the Server.
+#[derive(Debug)]
+pub struct PendingCountCommand<R> {
+	pub response: R,
+}
+
+/// Changes the keep alive advertised in the Connect packet, in seconds.
+#[derive(Debug)]
+pub struct KeepAliveCommand {
+	pub keep_alive: u16,
+}
@@ -1,4 +1,4 @@
-use crate::{Filter, FilterBuf, InvalidFilter, QoS};
+use crate::{routing::FilterSet, Filter, FilterBuf, InvalidFilter, QoS, Topic, TopicBuf};
 
 /// A collection of FilterBuf.
 pub struct Filters(pub(crate) Vec<FilterBuf>);
@@ -6,6 +6,29 @@ pub struct Filters(pub(crate) Vec<FilterBuf>);
 /// A collection of (FilterBuf, QoS).
 pub struct FiltersWithQoS(pub(crate) Vec<(FilterBuf, QoS)>);
 
+impl FiltersWithQoS {
+	/// Removes filters that are made redundant by another filter in the
+	/// collection. See [`FilterSet`] for details.
+	///
+	/// Compaction is opt-in, as the Server tracks each subscribed filter
+	/// separately.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::clients::FiltersWithQoS;
+	/// // Only "a/#" will be subscribed to.
+	/// let filters = FiltersWithQoS::try_from(["a/#", "a/b"]).unwrap().compact();
+	/// ```
+	pub fn compact(self) -> Self {
+		let Self(filters) = self;
+		let mut set = FilterSet::new();
+		for (filter, qos) in filters {
+			set.insert(&filter, qos);
+		}
+		Self(set.into_vec())
+	}
+}
+
 impl<T: AsRef<str>> TryFrom<&[T]> for Filters {
 	type Error = InvalidFilter;
 	fn try_from(value: &[T]) -> Result<Self, Self::Error> {
@@ -51,15 +74,32 @@ impl TryFrom<&Filter> for FiltersWithQoS {
 	type Error = InvalidFilter;
 	#[inline]
 	fn try_from(value: &Filter) -> Result<Self, Self::Error> {
-		Self::try_from(value.to_owned())
+		Ok(Self::from(value.to_owned()))
 	}
 }
 
-impl TryFrom<FilterBuf> for FiltersWithQoS {
-	type Error = InvalidFilter;
+// A FilterBuf has already been validated, so the conversion cannot fail.
+impl From<FilterBuf> for FiltersWithQoS {
+	#[inline]
+	fn from(value: FilterBuf) -> Self {
+		Self(vec![(value, QoS::default())])
+	}
+}
+
+// Every topic is a valid filter, so topics are not validated again. A
+// `(TopicBuf, QoS)` pair is converted by the `TryFrom<(T, QoS)>` impl below,
+// which cannot fail for the same reason.
+impl From<TopicBuf> for FiltersWithQoS {
+	#[inline]
+	fn from(value: TopicBuf) -> Self {
+		Self::from(FilterBuf::from(value))
+	}
+}
+
+impl From<&Topic> for FiltersWithQoS {
 	#[inline]
-	fn try_from(value: FilterBuf) -> Result<Self, Self::Error> {
-		Ok(Self(vec![(value, QoS::default())]))
+	fn from(value: &Topic) -> Self {
+		Self::from(value.to_topic_buf())
 	}
 }
 
@@ -96,6 +136,9 @@ where
 	}
 }
 
+// For `T = FilterBuf` the conversion of each filter is the identity, so
+// already validated filters are moved into the collection without being
+// validated again.
 impl<E, T: TryInto<FilterBuf, Error = E>> TryFrom<Vec<(T, QoS)>> for FiltersWithQoS
 where
 	InvalidFilter: From<E>,
@@ -124,3 +167,56 @@ where
 		Ok(Self(filters))
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::FiltersWithQoS;
+	use crate::{Filter, QoS, Topic};
+
+	#[test]
+	fn filter_bufs_are_not_revalidated() {
+		// `from_static` skips validation, so this is an invalid filter that would
+		// be rejected if it were validated again.
+		let unchecked = Filter::from_static("a/#/b").to_owned();
+
+		let FiltersWithQoS(filters) = FiltersWithQoS::from(unchecked.clone());
+		assert_eq!(filters, [(unchecked.clone(), QoS::AtMostOnce)]);
+
+		let FiltersWithQoS(filters) =
+			FiltersWithQoS::try_from(vec![(unchecked.clone(), QoS::AtLeastOnce)]).unwrap();
+		assert_eq!(filters, [(unchecked, QoS::AtLeastOnce)]);
+	}
+
+	#[test]
+	fn topics_are_not_revalidated() {
+		// As with filters, `from_static` skips validation.
+		let unchecked = Topic::from_static("a/\0");
+
+		let FiltersWithQoS(filters) = FiltersWithQoS::from(unchecked);
+		assert_eq!(
+			filters,
+			[(Filter::from_static("a/\0").to_owned(), QoS::AtMostOnce)]
+		);
+
+		let FiltersWithQoS(filters) =
+			FiltersWithQoS::try_from((unchecked.to_topic_buf(), QoS::ExactlyOnce)).unwrap();
+		assert_eq!(
+			filters,
+			[(Filter::from_static("a/\0").to_owned(), QoS::ExactlyOnce)]
+		);
+	}
+
+	#[test]
+	fn compact() {
+		let FiltersWithQoS(filters) = FiltersWithQoS::try_from(["a/b", "a/#", "a/b/c", "b"])
+			.unwrap()
+			.compact();
+		assert_eq!(
+			filters,
+			[
+				(Filter::from_static("a/#").to_owned(), QoS::AtMostOnce),
+				(Filter::from_static("b").to_owned(), QoS::AtMostOnce),
+			]
+		);
+	}
+}
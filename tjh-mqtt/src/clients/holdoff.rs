@@ -7,6 +7,7 @@ pub struct HoldOff {
 	min: Duration,
 	max: Duration,
 	cur: Option<Duration>,
+	attempts: u32,
 }
 
 #[allow(unused)]
@@ -16,18 +17,33 @@ impl HoldOff {
 			min: r.start,
 			max: r.end,
 			cur: None,
+			attempts: 0,
 		}
 	}
 
+	/// Returns the current hold-off period, or `None` before the first call to
+	/// `increase_with()`.
+	pub fn current(&self) -> Option<Duration> {
+		self.cur
+	}
+
+	/// Returns the number of calls to `increase_with()` since the hold-off was
+	/// created or last reset.
+	pub fn attempts(&self) -> u32 {
+		self.attempts
+	}
+
 	/// Reset the hold-off period to `min`.
 	pub fn reset(&mut self) {
 		self.cur = Some(self.min);
+		self.attempts = 0;
 	}
 
 	/// Increase the hold-off period.
 	///
 	/// If the new hold-off period is more then `max` then `max` is used.
 	pub fn increase_with(&mut self, f: impl FnOnce(Duration) -> Duration) {
+		self.attempts = self.attempts.saturating_add(1);
 		self.cur = Some(match self.cur {
 			None => self.min,
 			Some(cur) => cmp::min(cmp::max(cur, f(cur)), self.max),
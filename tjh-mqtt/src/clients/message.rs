@@ -1,14 +1,142 @@
-use crate::TopicBuf;
+use crate::{QoS, TopicBuf};
 use bytes::Bytes;
 
 /// A published message received from the Server.
-#[derive(Debug)]
+///
+/// Messages can also be built to be published, for example with
+/// `Client::publish_message` in the tokio client.
+#[derive(Clone, Debug)]
 pub struct Message {
 	/// The topic the published message.
 	pub topic: TopicBuf,
 
+	/// The quality of service the message was delivered with.
+	pub qos: QoS,
+
 	pub retain: bool,
 
 	/// The payload of the published message.
 	pub payload: Bytes,
 }
+
+impl Message {
+	/// Creates a new message, with a QoS of [`AtMostOnce`] and the retain flag
+	/// cleared.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::{clients::Message, QoS, Topic};
+	/// let message = Message::new(Topic::from_static("a/b"), "hello")
+	/// 	.with_qos(QoS::AtLeastOnce)
+	/// 	.with_retain(true);
+	/// assert_eq!(message.qos, QoS::AtLeastOnce);
+	/// assert!(message.retain);
+	/// ```
+	///
+	/// [`AtMostOnce`]: crate::QoS#variant.AtMostOnce
+	#[inline]
+	pub fn new(topic: impl Into<TopicBuf>, payload: impl Into<Bytes>) -> Self {
+		Self {
+			topic: topic.into(),
+			qos: QoS::AtMostOnce,
+			retain: false,
+			payload: payload.into(),
+		}
+	}
+
+	/// Sets the quality of service of the message.
+	#[inline]
+	pub fn with_qos(mut self, qos: QoS) -> Self {
+		self.qos = qos;
+		self
+	}
+
+	/// Sets the retain flag of the message.
+	#[inline]
+	pub fn with_retain(mut self, retain: bool) -> Self {
+		self.retain = retain;
+		self
+	}
+
+	/// Returns a copy of the message with a different topic, keeping the
+	/// payload, QoS and retain flag.
+	///
+	/// The payload is not copied.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::{clients::Message, QoS, Topic};
+	/// let received = Message::new(Topic::from_static("sensors/1"), "20.5");
+	/// let message = received.republish_to(Topic::from_static("archive/sensors/1"));
+	/// assert_eq!(message.topic.as_str(), "archive/sensors/1");
+	/// assert_eq!(message.payload.as_ptr(), received.payload.as_ptr());
+	/// ```
+	#[inline]
+	pub fn republish_to(&self, topic: impl Into<TopicBuf>) -> Self {
+		Self {
+			topic: topic.into(),
+			..self.clone()
+		}
+	}
+
+	/// Returns a reference to the payload of the message.
+	#[inline]
+	pub fn payload_bytes(&self) -> &Bytes {
+		&self.payload
+	}
+
+	/// Consumes the message, returning the topic, payload, QoS and retain flag.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::{clients::Message, QoS, TopicBuf};
+	/// let message = Message {
+	/// 	topic: TopicBuf::new("a/b").unwrap(),
+	/// 	qos: QoS::AtLeastOnce,
+	/// 	retain: false,
+	/// 	payload: "hello".into(),
+	/// };
+	///
+	/// let (topic, payload, qos, retain) = message.into_parts();
+	/// assert_eq!(topic.as_str(), "a/b");
+	/// assert_eq!(&payload[..], b"hello");
+	/// ```
+	#[inline]
+	pub fn into_parts(self) -> (TopicBuf, Bytes, QoS, bool) {
+		let Self {
+			topic,
+			qos,
+			retain,
+			payload,
+		} = self;
+		(topic, payload, qos, retain)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Message;
+	use crate::{QoS, TopicBuf};
+	use bytes::Bytes;
+
+	#[test]
+	fn into_parts() {
+		let payload = Bytes::from_static(b"payload");
+		let message = Message {
+			topic: TopicBuf::new("a/b/c").unwrap(),
+			qos: QoS::ExactlyOnce,
+			retain: true,
+			payload: payload.clone(),
+		};
+		assert_eq!(message.payload_bytes(), &payload);
+
+		let (topic, received_payload, qos, retain) = message.into_parts();
+		assert_eq!(topic, TopicBuf::new("a/b/c").unwrap());
+		assert_eq!(received_payload, payload);
+		assert_eq!(qos, QoS::ExactlyOnce);
+		assert!(retain);
+
+		// The payload should not have been copied.
+		assert_eq!(received_payload.as_ptr(), payload.as_ptr());
+	}
+}
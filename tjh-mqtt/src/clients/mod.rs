@@ -1,9 +1,12 @@
 #[cfg(feature = "tokio-client")]
 pub(crate) mod command;
 
+#[cfg(feature = "v5")]
+mod auth;
 mod conv;
 mod holdoff;
 mod message;
+mod session;
 
 #[cfg(feature = "tokio-client")]
 mod state;
@@ -14,7 +17,11 @@ pub mod tokio;
 pub use self::{
 	conv::{Filters, FiltersWithQoS},
 	message::Message,
+	session::{FileSessionStore, SessionStore},
 };
 
+#[cfg(feature = "v5")]
+pub use self::auth::Authenticator;
+
 #[cfg(feature = "tokio-client")]
 pub use self::state::{ClientState, StateError};
@@ -0,0 +1,85 @@
+use core::fmt;
+use std::{
+	fs::{File, OpenOptions},
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::Path,
+};
+
+/// Storage for the in-flight Publish packets of a client session, so they
+/// survive process restarts.
+///
+/// The session is saved as a log of serialized packets: each outgoing Publish
+/// packet with a QoS of AtLeastOnce or ExactlyOnce when it is first sent, a
+/// PubRel packet when the Server receives an ExactlyOnce packet, and a PubAck
+/// or PubComp packet when the handshake is complete. Replaying the log
+/// reconstructs the Publish packets which have not been acknowledged.
+///
+/// The methods are called from the client task, which cannot make progress
+/// until they return, so they should complete quickly.
+pub trait SessionStore: fmt::Debug + Send + Sync {
+	/// Appends a serialized `packet` to the log.
+	fn save(&mut self, packet: &[u8]) -> io::Result<()>;
+
+	/// Returns the contents of the log, in the order they were saved.
+	fn load(&mut self) -> io::Result<Vec<u8>>;
+
+	/// Empties the log. This is called when there are no Publish packets in
+	/// flight.
+	fn clear(&mut self) -> io::Result<()>;
+
+	/// Discards everything after the first `len` bytes of the log. This is
+	/// called to remove a partially saved packet when the log is loaded.
+	///
+	/// The default implementation clears the log and saves the first `len` bytes
+	/// again.
+	fn truncate(&mut self, len: usize) -> io::Result<()> {
+		let log = self.load()?;
+		self.clear()?;
+		self.save(&log[..len.min(log.len())])
+	}
+}
+
+/// A [`SessionStore`] which appends the log to a file.
+///
+/// Each packet is synced to disk before the Client sends it. This blocks the
+/// thread running the client task, and so any other tasks on it, for the
+/// duration of the write.
+#[derive(Debug)]
+pub struct FileSessionStore {
+	file: File,
+}
+
+impl FileSessionStore {
+	/// Opens the log at `path`, creating it if it does not exist.
+	pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+		let file = OpenOptions::new()
+			.read(true)
+			.append(true)
+			.create(true)
+			.open(path)?;
+		Ok(Self { file })
+	}
+}
+
+impl SessionStore for FileSessionStore {
+	fn save(&mut self, packet: &[u8]) -> io::Result<()> {
+		self.file.write_all(packet)?;
+		self.file.sync_data()
+	}
+
+	fn load(&mut self) -> io::Result<Vec<u8>> {
+		let mut log = Vec::new();
+		self.file.seek(SeekFrom::Start(0))?;
+		self.file.read_to_end(&mut log)?;
+		Ok(log)
+	}
+
+	fn clear(&mut self) -> io::Result<()> {
+		self.truncate(0)
+	}
+
+	fn truncate(&mut self, len: usize) -> io::Result<()> {
+		self.file.set_len(len as u64)?;
+		self.file.sync_data()
+	}
+}
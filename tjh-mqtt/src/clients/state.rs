@@ -1,24 +1,50 @@
-use super::Message;
+#[cfg(feature = "v5")]
+use super::Authenticator;
+use super::{Message, SessionStore};
 use crate::{
-	misc::WrappingNonZeroU16,
-	packets::{self, Publish, SerializePacket, SubAck, Subscribe, UnsubAck, Unsubscribe},
-	FilterBuf, PacketId, PacketType, QoS, Topic,
+	misc::{BytesVec, WrappingNonZeroU16},
+	packets::{
+		self, DeserializeError, Frame, OwnedPublish, Publish, SerializePacket, SubAck, Subscribe,
+		UnsubAck, Unsubscribe,
+	},
+	routing::FilterTrie,
+	FilterBuf, Packet, PacketId, PacketType, ParseMode, QoS, Topic,
 };
 use bytes::{Bytes, BytesMut};
 use core::fmt;
+#[cfg(feature = "v5")]
+use std::borrow::Cow;
 use std::{
-	collections::HashMap,
+	collections::{
+		hash_map::{DefaultHasher, RandomState},
+		HashMap, HashSet, VecDeque,
+	},
+	hash::{BuildHasher, Hash, Hasher},
+	io, mem,
 	num::NonZeroU16,
 	time::{Duration, Instant},
 };
 
+/// Publish payloads up to this length are copied into the outgoing buffer,
+/// rather than queued as separate chunks.
+const INLINE_PAYLOAD_LEN: usize = 256;
+
 #[derive(Debug)]
-pub enum StateError<'a> {
+pub enum StateError {
 	Unsolicited(PacketType),
 	/// The Client received a packet that the Server should not send.
 	InvalidPacket,
 	ProtocolError(&'static str),
-	DeliveryFailure(Publish<'a>),
+	/// The Server returned a different number of results in a SubAck packet
+	/// than there were filters in the Subscribe packet.
+	SubAckLengthMismatch {
+		requested: usize,
+		returned: usize,
+	},
+	/// An incoming message could not be delivered to a subscription, usually
+	/// because it was unsubscribed while the message was in flight. The QoS
+	/// handshake for the message is still completed.
+	DeliveryFailure(Message),
 	HardDeliveryFailure,
 }
 
@@ -28,15 +54,45 @@ pub struct ClientState<PubTx, PubResp, SubResp, UnSubResp> {
 	/// filters.
 	active_subscriptions: Vec<Subscription<PubTx>>,
 
+	/// Optional index of the active subscriptions, keyed by filter level. When
+	/// present, this is used to find candidate subscriptions for incoming
+	/// packets rather than checking every active subscription.
+	routing: Option<FilterTrie<usize>>,
+
 	pub outgoing: BytesMut,
 
-	/// Incoming Publish packets.
-	pub incoming: HashMap<PacketId, Message>,
+	/// Outgoing data queued behind the outgoing buffer, without being copied
+	/// into it.
+	queued: BytesVec,
+
+	/// The IDs of incoming ExactlyOnce Publish packets which have been
+	/// delivered, but not yet released by the Server.
+	pub incoming: HashSet<PacketId>,
+
+	/// Recently delivered AtLeastOnce Publish packets, used to suppress
+	/// redeliveries from the Server.
+	recent_publishes: Option<RecentPublishes>,
 
 	publish_state: HashMap<PacketId, PublishState<PubResp>>,
+
+	/// When each outgoing Publish packet may be dropped, if nothing is waiting
+	/// for its response.
+	publish_expires: HashMap<PacketId, Instant>,
+
+	/// The IDs of outgoing Publish packets which were dropped, so a late
+	/// acknowledgement can be ignored.
+	expired_publishes: HashSet<PacketId>,
 	subscribe_state: HashMap<PacketId, SubscribeState<PubTx, SubResp>>,
+
+	/// The IDs of subscribe requests which timed out, so a late SubAck can be
+	/// ignored.
+	expired_subscribes: HashSet<PacketId>,
 	unsubscribe_state: HashMap<PacketId, UnsubscribeState<UnSubResp>>,
 
+	/// The IDs of unsubscribe requests which timed out, so a late UnsubAck can
+	/// be ignored.
+	expired_unsubscribes: HashSet<PacketId>,
+
 	publish_packet_id: WrappingNonZeroU16,
 	subscribe_packet_id: WrappingNonZeroU16,
 	unsubscribe_packet_id: WrappingNonZeroU16,
@@ -47,8 +103,54 @@ pub struct ClientState<PubTx, PubResp, SubResp, UnSubResp> {
 
 	pub keep_alive: Duration,
 
+	/// The fraction by which the interval between PingReq packets is randomly
+	/// shortened, so many clients with the same keep alive do not send them in
+	/// step.
+	pub keep_alive_jitter: f64,
+
+	/// Period of inactivity after which the connection is closed, if any.
+	pub idle_timeout: Option<Duration>,
+
+	/// How long to wait for the Server to accept the connection with a ConnAck
+	/// packet, after sending the Connect packet.
+	pub handshake_timeout: Duration,
+
+	/// How long to wait for a SubAck or UnsubAck packet before failing the
+	/// subscribe or unsubscribe request.
+	pub subscribe_timeout: Duration,
+
+	/// How long to keep an outgoing Publish packet for re-sending once nothing
+	/// is waiting for its response.
+	pub abandoned_publish_timeout: Duration,
+
+	/// Send a Disconnect packet when the Server violates the protocol.
+	pub disconnect_on_protocol_error: bool,
+
+	/// How strictly packets from the Server are parsed.
+	pub parse_mode: ParseMode,
+
+	/// The protocol level of the Connect packet.
+	protocol_level: u8,
+
 	// This is Some if there is a active PingReq request.
 	pub pingreq_state: Option<Instant>,
+
+	/// Persists the in-flight Publish packets, if set.
+	session_store: Option<Box<dyn SessionStore>>,
+
+	/// Takes part in enhanced authentication when connecting, if set.
+	#[cfg(feature = "v5")]
+	pub authenticator: Option<Box<dyn Authenticator>>,
+
+	/// The number of incoming ExactlyOnce messages the client will hold
+	/// unreleased, sent in the Connect packet with MQTT v5.
+	#[cfg(feature = "v5")]
+	pub receive_maximum: Option<u16>,
+
+	/// The Connect packet without its properties, so it can be sent with new
+	/// authentication data.
+	#[cfg(feature = "v5")]
+	connect_parts: (Bytes, Bytes),
 }
 
 #[derive(Debug)]
@@ -58,17 +160,68 @@ struct Subscription<T> {
 	channel: T,
 }
 
+/// The state of an outgoing Publish packet.
+///
+/// The response is `None` if the packet was restored from a [`SessionStore`].
+/// The packet is kept, with the duplicate flag set, until the Server has
+/// received it so it can be re-sent.
 #[derive(Debug)]
 enum PublishState<R> {
-	Ack { response: R },
-	Rec { response: R },
-	Comp { response: R },
+	Ack {
+		response: Option<R>,
+		publish: OutgoingPublish,
+	},
+	Rec {
+		response: Option<R>,
+		publish: OutgoingPublish,
+	},
+	Comp {
+		response: Option<R>,
+	},
+}
+
+/// An outgoing Publish packet, kept with the duplicate flag set to be re-sent.
+#[derive(Debug)]
+struct OutgoingPublish {
+	publish: OwnedPublish,
+
+	/// Sent when the protocol level is 5. Properties are not persisted in the
+	/// session store, so they are empty for restored packets.
+	#[cfg(feature = "v5")]
+	properties: packets::PublishProperties,
+
+	/// When the packet was first queued, from which its message expiry
+	/// interval is counted.
+	#[cfg(feature = "v5")]
+	created_at: Instant,
+}
+
+impl OutgoingPublish {
+	fn new(publish: OwnedPublish) -> Self {
+		Self {
+			publish,
+			#[cfg(feature = "v5")]
+			properties: Default::default(),
+			#[cfg(feature = "v5")]
+			created_at: Instant::now(),
+		}
+	}
+
+	/// Returns the remaining lifetime of the message in seconds, or `None` if
+	/// it does not expire.
+	#[cfg(feature = "v5")]
+	fn remaining_lifetime(&self) -> Option<u32> {
+		self.properties
+			.message_expiry_interval
+			.map(|interval| remaining_lifetime(interval, self.created_at).unwrap_or(0))
+	}
 }
 
 #[derive(Debug)]
 struct SubscribeState<T, R> {
 	filters: Vec<Subscription<T>>,
 	response: R,
+	/// When the request fails if no SubAck has been received.
 	expires: Instant,
 }
 
@@ -76,6 +229,7 @@ struct SubscribeState<T, R> {
 struct UnsubscribeState<T> {
 	filters: Vec<FilterBuf>,
 	response: T,
+	/// When the request fails if no UnsubAck has been received.
 	expires: Instant,
 }
 
@@ -85,17 +239,39 @@ impl<PubTx, PubResp, SubResp, UnSubResp> Default
 	fn default() -> Self {
 		Self {
 			active_subscriptions: Vec::new(),
+			routing: None,
 			outgoing: BytesMut::new(),
+			queued: BytesVec::new(),
 			incoming: Default::default(),
+			recent_publishes: None,
 			publish_state: Default::default(),
+			publish_expires: Default::default(),
+			expired_publishes: Default::default(),
 			subscribe_state: Default::default(),
+			expired_subscribes: Default::default(),
 			unsubscribe_state: Default::default(),
+			expired_unsubscribes: Default::default(),
 			publish_packet_id: WrappingNonZeroU16::MAX,
 			subscribe_packet_id: WrappingNonZeroU16::MAX,
 			unsubscribe_packet_id: WrappingNonZeroU16::MAX,
 			connect: Default::default(),
 			keep_alive: Duration::default(),
+			keep_alive_jitter: 0.0,
+			idle_timeout: None,
+			handshake_timeout: Duration::from_secs(30),
+			subscribe_timeout: Duration::from_secs(30),
+			abandoned_publish_timeout: Duration::from_secs(30),
+			disconnect_on_protocol_error: false,
+			parse_mode: ParseMode::Strict,
+			protocol_level: 4,
 			pingreq_state: Default::default(),
+			session_store: None,
+			#[cfg(feature = "v5")]
+			authenticator: None,
+			#[cfg(feature = "v5")]
+			receive_maximum: None,
+			#[cfg(feature = "v5")]
+			connect_parts: Default::default(),
 		}
 	}
 }
@@ -109,28 +285,373 @@ impl<PubTx: fmt::Debug, PubResp, SubResp, UnSubResp>
 
 		Self {
 			connect: buffer.freeze(),
+			protocol_level: connect.protocol_level,
+			#[cfg(feature = "v5")]
+			connect_parts: connect.serialize_parts().unwrap(),
 			..Default::default()
 		}
 	}
 
+	/// Changes the keep alive in the Connect packet sent on the next
+	/// connection.
+	pub fn set_keep_alive(&mut self, seconds: u16) {
+		let frame = Frame::parse(self.connect.clone()).expect("Connect packet should be valid");
+		let mut connect =
+			packets::Connect::parse(&frame.payload).expect("Connect packet should be valid");
+		connect.keep_alive = seconds;
+
+		let mut buffer = BytesMut::new();
+		connect.serialize_to_bytes(&mut buffer).unwrap();
+		#[cfg(feature = "v5")]
+		{
+			self.connect_parts = connect.serialize_parts().unwrap();
+		}
+		self.connect = buffer.freeze();
+		self.keep_alive = Duration::from_secs(seconds.into());
+	}
+
 	pub fn enqueue_packet(&mut self, packet: &impl SerializePacket) {
 		packet
 			.serialize_to_bytes(&mut self.outgoing)
 			.expect("serializing to BytesMut should not failed");
 	}
 
-	pub fn buffer(&mut self) -> Option<Bytes> {
-		(!self.outgoing.is_empty()).then(|| self.outgoing.split().freeze())
+	/// Queues a Subscribe packet, encoded for the protocol level of the Connect
+	/// packet.
+	fn enqueue_subscribe(&mut self, subscribe: &Subscribe) {
+		#[cfg(feature = "v5")]
+		if self.protocol_level >= packets::ProtocolVersion::V5.level() {
+			subscribe
+				.serialize_to_bytes_v5(&mut self.outgoing)
+				.expect("serializing to BytesMut should not failed");
+			return;
+		}
+		self.enqueue_packet(subscribe);
+	}
+
+	/// Queues an Unsubscribe packet, encoded for the protocol level of the
+	/// Connect packet.
+	fn enqueue_unsubscribe(&mut self, unsubscribe: &Unsubscribe) {
+		#[cfg(feature = "v5")]
+		if self.protocol_level >= packets::ProtocolVersion::V5.level() {
+			unsubscribe
+				.serialize_to_bytes_v5(&mut self.outgoing)
+				.expect("serializing to BytesMut should not failed");
+			return;
+		}
+		self.enqueue_packet(unsubscribe);
+	}
+
+	/// Parses a packet from the Server, decoded for the protocol level of the
+	/// Connect packet.
+	pub fn parse_packet<'a>(&self, frame: &'a Frame) -> Result<Packet<'a>, DeserializeError> {
+		#[cfg(feature = "v5")]
+		if self.protocol_level >= packets::ProtocolVersion::V5.level() {
+			return Packet::parse_v5(frame, self.parse_mode);
+		}
+		Packet::parse_with(frame, self.parse_mode)
+	}
+
+	/// Queues a Disconnect packet. With MQTT v5, the packet has `reason_code`.
+	pub fn enqueue_disconnect(&mut self, reason_code: u8) {
+		if self.protocol_level >= packets::ProtocolVersion::V5.level() {
+			self.outgoing.extend_from_slice(&[0xe0, 0x01, reason_code]);
+		} else {
+			self.enqueue_packet(&packets::Disconnect);
+		}
+	}
+
+	/// Queues `publish` with `payload` in place of its own payload. With MQTT
+	/// v5, `properties` are included.
+	///
+	/// Unless the payload is short, it is not copied into the outgoing buffer.
+	fn enqueue_publish(
+		&mut self,
+		publish: &Publish,
+		#[cfg(feature = "v5")] properties: &packets::PublishProperties,
+		mut payload: BytesVec,
+	) {
+		#[cfg(feature = "v5")]
+		let result = if self.protocol_level >= packets::ProtocolVersion::V5.level() {
+			publish.serialize_header_v5(payload.len(), properties, &mut self.outgoing)
+		} else {
+			publish.serialize_header(payload.len(), &mut self.outgoing)
+		};
+		#[cfg(not(feature = "v5"))]
+		let result = publish.serialize_header(payload.len(), &mut self.outgoing);
+		result.expect("serializing to BytesMut should not failed");
+
+		if payload.len() <= INLINE_PAYLOAD_LEN {
+			for chunk in payload.iter() {
+				self.outgoing.extend_from_slice(chunk);
+			}
+		} else {
+			self.queued.push(self.outgoing.split().freeze());
+			self.queued.append(&mut payload);
+		}
+	}
+
+	/// Returns `true` if there is outgoing data to write.
+	#[inline]
+	pub fn has_outgoing(&self) -> bool {
+		!self.outgoing.is_empty() || !self.queued.is_empty()
+	}
+
+	/// Returns the length in bytes of the outgoing data not yet taken by
+	/// [`buffer`](Self::buffer).
+	#[inline]
+	pub fn pending_outgoing(&self) -> usize {
+		self.outgoing.len() + self.queued.len()
+	}
+
+	/// Returns the number of publish, subscribe and unsubscribe requests
+	/// waiting for a response from the Server.
+	///
+	/// These are re-sent when reconnecting, so would have to be handed over
+	/// along with the session.
+	pub fn pending_requests(&self) -> usize {
+		self.publish_state.len() + self.subscribe_state.len() + self.unsubscribe_state.len()
+	}
+
+	/// Takes all of the outgoing data to write.
+	pub fn buffer(&mut self) -> Option<BytesVec> {
+		self.queued.push(self.outgoing.split().freeze());
+		(!self.queued.is_empty()).then(|| mem::take(&mut self.queued))
 	}
 
 	pub fn reconnect(&mut self) {
+		self.enqueue_connect();
+		self.resend_publishes();
+		self.resend_subscribes();
+	}
+
+	/// Queues the Connect packet, starting a new authentication exchange if an
+	/// authenticator is set.
+	fn enqueue_connect(&mut self) {
+		#[cfg(feature = "v5")]
+		if self.protocol_level >= packets::ProtocolVersion::V5.level() {
+			let mut properties = packets::ConnectProperties {
+				receive_maximum: self.receive_maximum,
+				..Default::default()
+			};
+			let data;
+			if let Some(authenticator) = self.authenticator.as_mut() {
+				data = authenticator.initial_data();
+				properties.authentication_method = Some(authenticator.method());
+				properties.authentication_data = data.as_deref();
+			}
+			packets::Connect::serialize_from_parts(
+				&self.connect_parts,
+				&properties,
+				&mut self.outgoing,
+			)
+			.expect("serializing to BytesMut should not failed");
+			return;
+		}
+
 		self.outgoing.extend_from_slice(&self.connect[..]);
 	}
 
+	/// Re-sends the Publish and PubRel packets which have not been acknowledged,
+	/// with their original packet IDs.
+	fn resend_publishes(&mut self) {
+		let mut in_flight: Vec<_> = self.publish_state.iter().collect();
+		in_flight.sort_unstable_by_key(|(id, _)| **id);
+
+		for (&id, state) in in_flight {
+			let result = match state {
+				PublishState::Ack { publish, .. } | PublishState::Rec { publish, .. } => {
+					let packet = publish.publish.as_publish();
+					#[cfg(feature = "v5")]
+					if self.protocol_level >= packets::ProtocolVersion::V5.level() {
+						// The Server is sent the remaining lifetime of the message.
+						let properties = match publish.remaining_lifetime() {
+							Some(remaining) => Cow::Owned(packets::PublishProperties {
+								message_expiry_interval: Some(remaining),
+								..publish.properties.clone()
+							}),
+							None => Cow::Borrowed(&publish.properties),
+						};
+						let payload = packet.payload();
+						packet
+							.serialize_header_v5(payload.len(), &properties, &mut self.outgoing)
+							.map(|()| self.outgoing.extend_from_slice(payload))
+					} else {
+						packet.serialize_to_bytes(&mut self.outgoing)
+					}
+					#[cfg(not(feature = "v5"))]
+					packet.serialize_to_bytes(&mut self.outgoing)
+				}
+				PublishState::Comp { .. } => {
+					packets::PubRel::new(id).serialize_to_bytes(&mut self.outgoing)
+				}
+			};
+			result.expect("serializing to BytesMut should not failed");
+		}
+	}
+
+	/// Re-sends the Subscribe packets which have not been acknowledged, with
+	/// their original packet IDs.
+	///
+	/// The Server may not have received them before the connection was lost,
+	/// so without this the requests, including those re-subscribing the active
+	/// subscriptions, would only time out.
+	fn resend_subscribes(&mut self) {
+		let mut in_flight: Vec<_> = self.subscribe_state.iter().collect();
+		in_flight.sort_unstable_by_key(|(id, _)| **id);
+
+		for (&id, SubscribeState { filters, .. }) in in_flight {
+			let packet = packets::Subscribe {
+				id,
+				filters: filters
+					.iter()
+					.map(|Subscription { filter, qos, .. }| (filter.as_ref(), *qos))
+					.collect(),
+			};
+			#[cfg(feature = "v5")]
+			let result = match self.protocol_level >= packets::ProtocolVersion::V5.level() {
+				true => packet.serialize_to_bytes_v5(&mut self.outgoing),
+				false => packet.serialize_to_bytes(&mut self.outgoing),
+			};
+			#[cfg(not(feature = "v5"))]
+			let result = packet.serialize_to_bytes(&mut self.outgoing);
+			result.expect("serializing to BytesMut should not failed");
+		}
+	}
+
+	/// Restores the in-flight Publish packets from `store`, and saves changes to
+	/// them to the store from now on.
+	///
+	/// The restored packets are re-sent when the client next connects.
+	pub fn use_session_store(&mut self, mut store: Box<dyn SessionStore>) -> io::Result<()> {
+		let log = Bytes::from(store.load()?);
+		let mut cursor = io::Cursor::new(&log[..]);
+		let mut start = 0;
+		loop {
+			let end = match Frame::check(&mut cursor) {
+				Ok(end) => end,
+				// The last packet may have been partially written.
+				Err(DeserializeError::Incomplete) => break,
+				Err(error) => return Err(io::Error::new(io::ErrorKind::InvalidData, error)),
+			};
+
+			let frame = Frame::parse(log.slice(start..end))
+				.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+			self.restore(&frame)
+				.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+			start = end;
+		}
+
+		if self.publish_state.is_empty() {
+			store.clear()?;
+		} else if start < log.len() {
+			// Packets saved from now on would be read as part of the partial one.
+			store.truncate(start)?;
+		}
+		self.session_store = Some(store);
+		Ok(())
+	}
+
+	/// Applies a packet from the session log to the in-flight Publish packets.
+	fn restore(&mut self, frame: &Frame) -> Result<(), DeserializeError> {
+		match Packet::parse(frame)? {
+			Packet::Publish(publish) => {
+				let Some((id, publish)) = resend_form(*publish) else {
+					return Err(DeserializeError::MalformedPacket(
+						"session log contains an AtMostOnce Publish packet",
+					));
+				};
+				let state = match publish {
+					OwnedPublish::AtLeastOnce { .. } => PublishState::Ack {
+						response: None,
+						publish: OutgoingPublish::new(publish),
+					},
+					_ => PublishState::Rec {
+						response: None,
+						publish: OutgoingPublish::new(publish),
+					},
+				};
+				self.publish_state.insert(id, state);
+			}
+			Packet::PubRel(packets::PubRel { id, .. }) => {
+				self.publish_state
+					.insert(id, PublishState::Comp { response: None });
+			}
+			Packet::PubAck(packets::PubAck { id, .. })
+			| Packet::PubComp(packets::PubComp { id, .. }) => {
+				self.publish_state.remove(&id);
+			}
+			_ => {
+				return Err(DeserializeError::MalformedPacket(
+					"unexpected packet in session log",
+				))
+			}
+		}
+		Ok(())
+	}
+
+	/// Saves `packet` to the session store, if any. If there are no Publish
+	/// packets in flight, the store is cleared instead.
+	fn save(&mut self, packet: &impl SerializePacket) {
+		let Some(store) = self.session_store.as_mut() else {
+			return;
+		};
+
+		let result = if self.publish_state.is_empty() {
+			store.clear()
+		} else {
+			let mut buffer = BytesMut::new();
+			packet
+				.serialize_to_bytes(&mut buffer)
+				.expect("serializing to BytesMut should not failed");
+			store.save(&buffer)
+		};
+		if let Err(error) = result {
+			tracing::error!(?error, "failed to save session state");
+		}
+	}
+
+	/// Responds to an Auth packet received from the Server while connecting.
+	#[cfg(feature = "v5")]
+	pub fn authenticate(&mut self, auth: &packets::Auth) -> Result<(), StateError> {
+		use packets::{Auth, AuthProperties, AuthReasonCode};
+
+		let Some(authenticator) = self.authenticator.as_mut() else {
+			return Err(StateError::Unsolicited(PacketType::Auth));
+		};
+
+		if auth.reason_code != AuthReasonCode::ContinueAuthentication {
+			return Err(StateError::ProtocolError(
+				"expected Auth packet to continue authentication",
+			));
+		}
+
+		if auth.properties.authentication_method != Some(authenticator.method()) {
+			return Err(StateError::ProtocolError(
+				"Auth packet has a different authentication method",
+			));
+		}
+
+		let challenge = auth.properties.authentication_data.unwrap_or_default();
+		let data = authenticator.continue_auth(challenge);
+		let response = Auth {
+			reason_code: AuthReasonCode::ContinueAuthentication,
+			properties: AuthProperties {
+				authentication_method: Some(authenticator.method()),
+				authentication_data: Some(&data),
+				..Default::default()
+			},
+		};
+		response
+			.serialize_to_bytes(&mut self.outgoing)
+			.expect("serializing to BytesMut should not failed");
+		Ok(())
+	}
+
 	pub fn unsubscribe(&mut self, filters: Vec<FilterBuf>, response: UnSubResp) {
 		// Generate and serialize an UnSub packet.
 		let id = self.generate_unsubscribe_id();
-		self.enqueue_packet(&Unsubscribe {
+		self.enqueue_unsubscribe(&Unsubscribe {
 			id,
 			filters: filters.iter().map(|filter| filter.as_ref()).collect(),
 		});
@@ -140,14 +661,21 @@ impl<PubTx: fmt::Debug, PubResp, SubResp, UnSubResp>
 			UnsubscribeState {
 				filters,
 				response,
-				expires: Instant::now(),
+				expires: Instant::now() + self.subscribe_timeout,
 			},
 		);
 	}
 
-	pub fn unsuback(&mut self, unsuback: UnsubAck) -> Result<UnSubResp, StateError> {
+	/// Handles an incoming UnsubAck packet.
+	///
+	/// Returns `None` if the unsubscribe request has already timed out.
+	pub fn unsuback(&mut self, unsuback: UnsubAck) -> Result<Option<UnSubResp>, StateError> {
 		let UnsubAck { id } = unsuback;
 
+		if self.expired_unsubscribes.remove(&id) {
+			return Ok(None);
+		}
+
 		let Some(unsubscribe_state) = self.unsubscribe_state.remove(&id) else {
 			return Err(StateError::Unsolicited(PacketType::UnsubAck));
 		};
@@ -159,8 +687,9 @@ impl<PubTx: fmt::Debug, PubResp, SubResp, UnSubResp>
 		// Remove the filters from the active subscriptions.
 		self.active_subscriptions
 			.retain(|sub| !filters.contains(&sub.filter));
+		self.rebuild_routing();
 
-		Ok(response)
+		Ok(Some(response))
 	}
 
 	fn generate_publish_id(&mut self) -> PacketId {
@@ -173,7 +702,9 @@ impl<PubTx: fmt::Debug, PubResp, SubResp, UnSubResp>
 				break;
 			}
 		}
-		self.publish_packet_id.get()
+		let id = self.publish_packet_id.get();
+		self.expired_publishes.remove(&id);
+		id
 	}
 
 	fn generate_subscribe_id(&mut self) -> PacketId {
@@ -186,7 +717,12 @@ impl<PubTx: fmt::Debug, PubResp, SubResp, UnSubResp>
 				break;
 			}
 		}
-		self.subscribe_packet_id.get()
+
+		// A SubAck for an expired request with the same ID can no longer be
+		// distinguished, so stop ignoring it.
+		let id = self.subscribe_packet_id.get();
+		self.expired_subscribes.remove(&id);
+		id
 	}
 
 	fn generate_unsubscribe_id(&mut self) -> PacketId {
@@ -199,7 +735,71 @@ impl<PubTx: fmt::Debug, PubResp, SubResp, UnSubResp>
 				break;
 			}
 		}
-		self.unsubscribe_packet_id.get()
+
+		let id = self.unsubscribe_packet_id.get();
+		self.expired_unsubscribes.remove(&id);
+		id
+	}
+
+	/// Enables or disables the use of a [`FilterTrie`] to find the channel for
+	/// incoming Publish packets.
+	///
+	/// The trie avoids checking every active subscription for each incoming
+	/// packet, at the cost of rebuilding it whenever the active subscriptions
+	/// change.
+	pub fn use_filter_trie(&mut self, enabled: bool) {
+		self.routing = enabled.then(FilterTrie::new);
+		self.rebuild_routing();
+	}
+
+	/// Enables or disables suppression of redelivered AtLeastOnce Publish
+	/// packets.
+	///
+	/// If `capacity` is `Some`, that many of the most recently delivered packets
+	/// are remembered by packet identifier, topic and a hash of the payload.
+	/// Redeliveries are only recognised while they remain in the cache, so this
+	/// does not make AtLeastOnce delivery exactly once.
+	pub fn use_duplicate_cache(&mut self, capacity: Option<usize>) {
+		self.recent_publishes = capacity
+			.filter(|&capacity| capacity > 0)
+			.map(RecentPublishes::new);
+	}
+
+	/// Records an incoming AtLeastOnce Publish packet, returning `true` if it
+	/// is a redelivery of a packet which has already been delivered.
+	///
+	/// This always returns `false` if the duplicate cache is disabled.
+	pub fn is_redelivery(&mut self, publish: &Publish) -> bool {
+		let Publish::AtLeastOnce {
+			id,
+			duplicate,
+			topic,
+			payload,
+			..
+		} = publish
+		else {
+			return false;
+		};
+		let Some(recent) = &mut self.recent_publishes else {
+			return false;
+		};
+
+		let mut hasher = DefaultHasher::new();
+		(id, topic.as_str(), &payload[..]).hash(&mut hasher);
+		let seen = recent.insert(hasher.finish());
+
+		// The Server sets the duplicate flag on every redelivery.
+		*duplicate && seen
+	}
+
+	fn rebuild_routing(&mut self) {
+		if let Some(routing) = &mut self.routing {
+			routing.clear();
+			for (index, Subscription { filter, .. }) in self.active_subscriptions.iter().enumerate()
+			{
+				routing.insert(filter, index);
+			}
+		}
 	}
 
 	#[inline]
@@ -210,6 +810,7 @@ impl<PubTx: fmt::Debug, PubResp, SubResp, UnSubResp>
 	pub fn generate_resubscribe(&mut self, response: SubResp) -> bool {
 		if !self.active_subscriptions.is_empty() {
 			let filters: Vec<_> = self.active_subscriptions.drain(..).collect();
+			self.rebuild_routing();
 
 			let id = self.generate_subscribe_id();
 			let packet = packets::Subscribe {
@@ -220,14 +821,14 @@ impl<PubTx: fmt::Debug, PubResp, SubResp, UnSubResp>
 					.collect(),
 			};
 
-			self.enqueue_packet(&packet);
+			self.enqueue_subscribe(&packet);
 
 			self.subscribe_state.insert(
 				id,
 				SubscribeState {
 					filters,
 					response,
-					expires: Instant::now(),
+					expires: Instant::now() + self.subscribe_timeout,
 				},
 			);
 
@@ -237,72 +838,325 @@ impl<PubTx: fmt::Debug, PubResp, SubResp, UnSubResp>
 		}
 	}
 
-	pub fn expired(&self) -> bool {
-		let now = Instant::now();
+	/// Returns the interval at which to send PingReq packets for `keep_alive`,
+	/// randomly shortened by up to `keep_alive_jitter`.
+	pub fn ping_interval(&self, keep_alive: Duration) -> Duration {
+		let random = RandomState::new().build_hasher().finish();
+		jittered(keep_alive, self.keep_alive_jitter, random)
+	}
 
-		let expired_pingreq = self.pingreq_state.map_or(false, |v| v > now);
+	/// Returns `true` if the Server has not responded to a PingReq packet
+	/// within `keep_alive`.
+	pub fn expired(&self, keep_alive: Duration) -> bool {
+		self.pingreq_state
+			.is_some_and(|sent| sent.elapsed() >= keep_alive)
+	}
 
-		let expired_subscribes = self
+	/// Removes the subscribe requests which have not been acknowledged before
+	/// their deadline, returning their responses and filters.
+	///
+	/// A SubAck for one of these requests is ignored if it arrives later.
+	pub fn expire_subscribes(&mut self) -> Vec<(SubResp, Vec<FilterBuf>)> {
+		let now = Instant::now();
+		let expired: Vec<_> = self
 			.subscribe_state
 			.iter()
-			.any(|(_, SubscribeState { expires, .. })| expires > &now);
+			.filter(|(_, SubscribeState { expires, .. })| *expires <= now)
+			.map(|(id, _)| *id)
+			.collect();
+
+		expired
+			.into_iter()
+			.filter_map(|id| {
+				let state = self.subscribe_state.remove(&id)?;
+				self.expired_subscribes.insert(id);
+				let filters = state.filters.into_iter().map(|sub| sub.filter).collect();
+				Some((state.response, filters))
+			})
+			.collect()
+	}
+
+	/// Removes the outgoing Publish packets whose responses are `abandoned`,
+	/// once they have been kept for the abandoned publish timeout. Returns the
+	/// number of packets removed.
+	///
+	/// The removed packets are not re-sent. A late acknowledgement for one of
+	/// them is ignored, or with a PubRec, answered with a PubRel.
+	pub fn expire_publishes(&mut self, abandoned: impl Fn(&PubResp) -> bool) -> usize {
+		let now = Instant::now();
+		let expired: Vec<_> = self
+			.publish_expires
+			.iter()
+			.filter(|(_, expires)| **expires <= now)
+			.map(|(id, _)| *id)
+			.filter(|id| match self.publish_state.get(id) {
+				Some(
+					PublishState::Ack { response, .. }
+					| PublishState::Rec { response, .. }
+					| PublishState::Comp { response },
+				) => response.as_ref().is_some_and(&abandoned),
+				None => true,
+			})
+			.collect();
+
+		let mut removed = 0;
+		for id in expired {
+			self.publish_expires.remove(&id);
+			if self.publish_state.remove(&id).is_some() {
+				self.expired_publishes.insert(id);
+				self.save(&packets::PubComp::new(id));
+				removed += 1;
+			}
+		}
+		removed
+	}
+
+	/// Removes the outgoing Publish packets whose message expiry interval has
+	/// elapsed, returning their responses.
+	///
+	/// This is called before reconnecting, so expired messages are not re-sent.
+	/// A late acknowledgement for one of them is ignored, or with a PubRec,
+	/// answered with a PubRel.
+	#[cfg(feature = "v5")]
+	pub fn remove_expired_messages(&mut self) -> Vec<PubResp> {
+		let expired: Vec<_> = self
+			.publish_state
+			.iter()
+			.filter(|(_, state)| match state {
+				PublishState::Ack { publish, .. } | PublishState::Rec { publish, .. } => {
+					publish.remaining_lifetime() == Some(0)
+				}
+				// The Server already has the message.
+				PublishState::Comp { .. } => false,
+			})
+			.map(|(id, _)| *id)
+			.collect();
+
+		let mut responses = Vec::new();
+		for id in expired {
+			self.publish_expires.remove(&id);
+			if let Some(PublishState::Ack { response, .. } | PublishState::Rec { response, .. }) =
+				self.publish_state.remove(&id)
+			{
+				self.expired_publishes.insert(id);
+				self.save(&packets::PubComp::new(id));
+				responses.extend(response);
+			}
+		}
+		responses
+	}
+
+	/// Removes every pending publish, subscribe and unsubscribe request,
+	/// returning their responses.
+	pub fn take_responses(&mut self) -> (Vec<PubResp>, Vec<SubResp>, Vec<UnSubResp>) {
+		self.publish_expires.clear();
+		let publishes = self
+			.publish_state
+			.drain()
+			.filter_map(|(_, state)| match state {
+				PublishState::Ack { response, .. }
+				| PublishState::Rec { response, .. }
+				| PublishState::Comp { response } => response,
+			})
+			.collect();
+		let subscribes = self
+			.subscribe_state
+			.drain()
+			.map(|(_, state)| state.response)
+			.collect();
+		let unsubscribes = self
+			.unsubscribe_state
+			.drain()
+			.map(|(_, state)| state.response)
+			.collect();
+		(publishes, subscribes, unsubscribes)
+	}
 
-		let expired_unsubscribes = self
+	/// Removes the unsubscribe requests which have not been acknowledged before
+	/// their deadline, returning their responses.
+	///
+	/// The filters are removed from the active subscriptions regardless, and an
+	/// UnsubAck for one of these requests is ignored if it arrives later.
+	pub fn expire_unsubscribes(&mut self) -> Vec<UnSubResp> {
+		let now = Instant::now();
+		let expired: Vec<_> = self
 			.unsubscribe_state
 			.iter()
-			.any(|(_, UnsubscribeState { expires, .. })| expires > &now);
+			.filter(|(_, UnsubscribeState { expires, .. })| *expires <= now)
+			.map(|(id, _)| *id)
+			.collect();
+
+		let responses = expired
+			.into_iter()
+			.filter_map(|id| {
+				let UnsubscribeState {
+					filters, response, ..
+				} = self.unsubscribe_state.remove(&id)?;
+				self.expired_unsubscribes.insert(id);
+				self.active_subscriptions
+					.retain(|sub| !filters.contains(&sub.filter));
+				Some(response)
+			})
+			.collect::<Vec<_>>();
 
-		expired_pingreq || expired_subscribes || expired_unsubscribes
+		if !responses.is_empty() {
+			self.rebuild_routing();
+		}
+		responses
 	}
 
 	/// Generates an outgoing Publish packet.
+	///
+	/// Publish packets with a QoS of [`AtLeastOnce`] or [`ExactlyOnce`] are kept
+	/// until they are acknowledged, so their payload is made contiguous.
+	///
+	/// [`AtLeastOnce`]: QoS#variant.AtLeastOnce
+	/// [`ExactlyOnce`]: QoS#variant.ExactlyOnce
 	pub fn publish(
 		&mut self,
 		topic: &Topic,
-		payload: Bytes,
+		payload: BytesVec,
+		qos: QoS,
+		retain: bool,
+		response: PubResp,
+	) -> Option<PubResp> {
+		self.publish_impl(
+			topic,
+			payload,
+			qos,
+			retain,
+			#[cfg(feature = "v5")]
+			Default::default(),
+			response,
+		)
+	}
+
+	/// Generates an outgoing Publish packet with MQTT v5 `properties`.
+	///
+	/// The properties are only sent if the protocol level is 5.
+	#[cfg(feature = "v5")]
+	pub fn publish_with_properties(
+		&mut self,
+		topic: &Topic,
+		payload: BytesVec,
+		qos: QoS,
+		retain: bool,
+		properties: packets::PublishProperties,
+		response: PubResp,
+	) -> Option<PubResp> {
+		self.publish_impl(topic, payload, qos, retain, properties, response)
+	}
+
+	fn publish_impl(
+		&mut self,
+		topic: &Topic,
+		payload: BytesVec,
 		qos: QoS,
 		retain: bool,
+		#[cfg(feature = "v5")] properties: packets::PublishProperties,
 		response: PubResp,
 	) -> Option<PubResp> {
 		match qos {
 			QoS::AtMostOnce => {
-				self.enqueue_packet(&Publish::AtMostOnce {
+				let publish = Publish::AtMostOnce {
 					retain,
 					topic,
+					payload: Bytes::new(),
+				};
+				self.enqueue_publish(
+					&publish,
+					#[cfg(feature = "v5")]
+					&properties,
 					payload,
-				});
+				);
 
 				Some(response)
 			}
 			QoS::AtLeastOnce => {
 				let id = self.generate_publish_id();
-				self.publish_state
-					.insert(id, PublishState::Ack { response });
+				let payload = payload.into_bytes();
 
 				// Generate the first attempt.
-				self.enqueue_packet(&Publish::AtLeastOnce {
+				let publish = Publish::AtLeastOnce {
 					id,
 					retain,
 					duplicate: false,
 					topic,
-					payload,
-				});
+					payload: payload.clone(),
+				};
+				self.enqueue_publish(
+					&publish,
+					#[cfg(feature = "v5")]
+					&properties,
+					payload.clone().into(),
+				);
+
+				self.publish_expires
+					.insert(id, Instant::now() + self.abandoned_publish_timeout);
+				self.publish_state.insert(
+					id,
+					PublishState::Ack {
+						response: Some(response),
+						publish: OutgoingPublish {
+							publish: OwnedPublish::AtLeastOnce {
+								id,
+								retain,
+								duplicate: true,
+								topic: topic.to_topic_buf(),
+								payload,
+							},
+							#[cfg(feature = "v5")]
+							properties,
+							#[cfg(feature = "v5")]
+							created_at: Instant::now(),
+						},
+					},
+				);
+				self.save(&publish);
 
 				None
 			}
 			QoS::ExactlyOnce => {
 				let id = self.generate_publish_id();
-				self.publish_state
-					.insert(id, PublishState::Rec { response });
+				let payload = payload.into_bytes();
 
 				// Generate the first attempt.
-				self.enqueue_packet(&Publish::ExactlyOnce {
+				let publish = Publish::ExactlyOnce {
 					id,
 					retain,
 					duplicate: false,
 					topic,
-					payload,
-				});
+					payload: payload.clone(),
+				};
+				self.enqueue_publish(
+					&publish,
+					#[cfg(feature = "v5")]
+					&properties,
+					payload.clone().into(),
+				);
+
+				self.publish_expires
+					.insert(id, Instant::now() + self.abandoned_publish_timeout);
+				self.publish_state.insert(
+					id,
+					PublishState::Rec {
+						response: Some(response),
+						publish: OutgoingPublish {
+							publish: OwnedPublish::ExactlyOnce {
+								id,
+								retain,
+								duplicate: true,
+								topic: topic.to_topic_buf(),
+								payload,
+							},
+							#[cfg(feature = "v5")]
+							properties,
+							#[cfg(feature = "v5")]
+							created_at: Instant::now(),
+						},
+					},
+				);
+				self.save(&publish);
 
 				None
 			}
@@ -310,71 +1164,164 @@ impl<PubTx: fmt::Debug, PubResp, SubResp, UnSubResp>
 	}
 
 	/// Handles an incoming PubAck packet.
-	pub fn puback(&mut self, id: NonZeroU16) -> Result<PubResp, StateError> {
+	///
+	/// Returns the response for the Publish packet, unless it was restored from
+	/// a [`SessionStore`].
+	pub fn puback(&mut self, id: NonZeroU16) -> Result<Option<PubResp>, StateError> {
 		let Some(PublishState::Ack { response, .. }) = self.publish_state.remove(&id) else {
+			if self.expired_publishes.remove(&id) {
+				return Ok(None);
+			}
 			return Err(StateError::Unsolicited(PacketType::PubAck));
 		};
 
+		self.publish_expires.remove(&id);
+		self.save(&packets::PubAck::new(id));
 		Ok(response)
 	}
 
 	/// Handles an incoming PubRec packet.
 	pub fn pubrec(&mut self, id: NonZeroU16) -> Result<(), StateError> {
-		let Some(PublishState::Rec { response, .. }) = self.publish_state.remove(&id) else {
-			return Err(StateError::Unsolicited(PacketType::PubRec));
+		let response = match self.publish_state.remove(&id) {
+			Some(PublishState::Rec { response, .. }) => response,
+			// The Server has the message, so complete the handshake.
+			None if self.expired_publishes.remove(&id) => None,
+			_ => return Err(StateError::Unsolicited(PacketType::PubRec)),
 		};
 
 		self.publish_state
 			.insert(id, PublishState::Comp { response });
 
 		// Queue an incoming PubRel packet.
-		self.enqueue_packet(&packets::PubRel { id });
+		self.enqueue_packet(&packets::PubRel::new(id));
+		self.save(&packets::PubRel::new(id));
 		Ok(())
 	}
 
+	/// Handles an incoming PubRec packet with a failure reason code.
+	///
+	/// The Server has discarded the message, so the handshake ends without a
+	/// PubRel packet. Returns the response for the Publish packet, unless it
+	/// was restored from a [`SessionStore`].
+	#[cfg(feature = "v5")]
+	pub fn pubrec_failed(&mut self, id: NonZeroU16) -> Result<Option<PubResp>, StateError> {
+		let response = match self.publish_state.remove(&id) {
+			Some(PublishState::Rec { response, .. }) => response,
+			None if self.expired_publishes.remove(&id) => None,
+			_ => return Err(StateError::Unsolicited(PacketType::PubRec)),
+		};
+
+		self.publish_expires.remove(&id);
+		self.save(&packets::PubComp::new(id));
+		Ok(response)
+	}
+
 	/// Handles an incoming PubComp packet.
-	pub fn pubcomp(&mut self, id: NonZeroU16) -> Result<PubResp, StateError> {
+	///
+	/// Returns the response for the Publish packet, unless it was restored from
+	/// a [`SessionStore`].
+	pub fn pubcomp(&mut self, id: NonZeroU16) -> Result<Option<PubResp>, StateError> {
 		let Some(PublishState::Comp { response }) = self.publish_state.remove(&id) else {
+			if self.expired_publishes.remove(&id) {
+				return Ok(None);
+			}
 			return Err(StateError::Unsolicited(PacketType::PubComp));
 		};
 
+		self.publish_expires.remove(&id);
+		self.save(&packets::PubComp::new(id));
 		Ok(response)
 	}
 
-	pub fn pubrel(&mut self, id: PacketId) -> Result<Message, StateError> {
-		let Some(message) = self.incoming.remove(&id) else {
+	/// Records an incoming ExactlyOnce Publish packet, returning `true` if a
+	/// packet with the same ID has already been received and not yet released
+	/// by the Server.
+	///
+	/// The Server re-sends the Publish packet if the PubRec from the client is
+	/// lost. Until the Server sends PubRel, any Publish packet with the same ID
+	/// is the same message, so it must be acknowledged again but not delivered.
+	///
+	/// With MQTT v5, the Server must not send more new messages than the
+	/// receive maximum sent in the Connect packet.
+	pub fn is_unreleased(&mut self, id: PacketId) -> Result<bool, StateError> {
+		if self.incoming.contains(&id) {
+			return Ok(true);
+		}
+
+		#[cfg(feature = "v5")]
+		if let Some(maximum) = self.receive_maximum {
+			if self.protocol_level >= packets::ProtocolVersion::V5.level()
+				&& self.incoming.len() >= usize::from(maximum)
+			{
+				return Err(StateError::ProtocolError("receive maximum exceeded"));
+			}
+		}
+
+		self.incoming.insert(id);
+		Ok(false)
+	}
+
+	/// Handles an incoming PubRel packet.
+	///
+	/// The message was delivered when the Publish packet arrived, so only the
+	/// packet ID is released. Returns [`StateError::Unsolicited`] if the ID is
+	/// not held, which happens when the PubComp from the client is lost and
+	/// the Server re-sends PubRel.
+	pub fn pubrel(&mut self, id: PacketId) -> Result<(), StateError> {
+		if !self.incoming.remove(&id) {
 			return Err(StateError::Unsolicited(PacketType::PubRel));
-		};
+		}
 
-		Ok(message)
+		Ok(())
 	}
 
 	/// Finds a channel to publish messages for `topic` to.
+	///
+	/// If more than one active subscription matches the topic, the channel for
+	/// the most specific filter is returned (see [`Matches`]). Ties between
+	/// equally specific filters are resolved in favour of the subscription added
+	/// most recently.
+	///
+	/// [`Matches`]: crate::Matches
 	pub fn find_publish_channel(&self, topic: &Topic) -> Option<&PubTx> {
 		let start = Instant::now();
 
-		let Some((filter, score, channel)) = self
-			.active_subscriptions
-			.iter()
-			.filter_map(
-				|Subscription {
-				     filter, channel, ..
-				 }| {
-					filter
-						.matches_topic(topic)
-						.map(|score| (filter, score.score(), channel))
-				},
-			)
-			.max_by_key(|(_, score, _)| *score)
-		else {
+		// Ties are broken by the index of the subscription, so the most recently
+		// added subscription wins. Neither path allocates.
+		let best = |(index, subscription): (usize, &'_ Subscription<PubTx>)| {
+			subscription
+				.filter
+				.matches_topic(topic)
+				.map(|matches| (matches, index))
+		};
+		let best = match &self.routing {
+			Some(routing) => {
+				let mut max = None;
+				routing.for_each_match(topic, |_, &index| {
+					max = max.max(best((index, &self.active_subscriptions[index])));
+				});
+				max
+			}
+			None => self
+				.active_subscriptions
+				.iter()
+				.enumerate()
+				.filter_map(best)
+				.max(),
+		};
+
+		let Some((matches, index)) = best else {
 			#[cfg(feature = "tokio-client")]
 			tracing::error!(topic = ?topic, "failed to find channel for");
 			return None;
 		};
+		let Subscription {
+			filter, channel, ..
+		} = &self.active_subscriptions[index];
 
 		let time = start.elapsed();
 		#[cfg(feature = "tokio-client")]
-		tracing::trace!(topic = ?topic, filter = ?filter, score = ?score, time = ?time, "found channel for");
+		tracing::trace!(topic = ?topic, filter = ?filter, matches = ?matches, time = ?time, "found channel for");
 
 		Some(channel)
 	}
@@ -386,7 +1333,7 @@ impl<PubTx: Clone + fmt::Debug, PubResp, SubResp, UnSubResp>
 	pub fn subscribe(&mut self, filters: Vec<(FilterBuf, QoS)>, channel: PubTx, response: SubResp) {
 		// Generate an ID for the subscribe packet.
 		let id = self.generate_subscribe_id();
-		self.enqueue_packet(&Subscribe {
+		self.enqueue_subscribe(&Subscribe {
 			id,
 			filters: filters
 				.iter()
@@ -406,15 +1353,25 @@ impl<PubTx: Clone + fmt::Debug, PubResp, SubResp, UnSubResp>
 					})
 					.collect(),
 				response,
-				expires: Instant::now(),
+				expires: Instant::now() + self.subscribe_timeout,
 			},
 		);
 	}
 
 	/// Handles an incoming SubAck packet.
-	pub fn suback(&mut self, ack: SubAck) -> Result<(SubResp, Vec<(FilterBuf, QoS)>), StateError> {
+	///
+	/// Returns `None` if the subscribe request has already timed out.
+	#[allow(clippy::type_complexity)]
+	pub fn suback(
+		&mut self,
+		ack: SubAck,
+	) -> Result<Option<(SubResp, Vec<(FilterBuf, QoS)>)>, StateError> {
 		let SubAck { id, result } = ack;
 
+		if self.expired_subscribes.remove(&id) {
+			return Ok(None);
+		}
+
 		// Confirm we have an active subscription request for the SubAck packet ID.
 		let subscribe_state = self
 			.subscribe_state
@@ -426,9 +1383,10 @@ impl<PubTx: Clone + fmt::Debug, PubResp, SubResp, UnSubResp>
 		} = subscribe_state;
 
 		if result.len() != filters.len() {
-			return Err(StateError::ProtocolError(
-				"SubAck payload length does not correspond to Subscribe payload length",
-			));
+			return Err(StateError::SubAckLengthMismatch {
+				requested: filters.len(),
+				returned: result.len(),
+			});
 		}
 
 		let successful_filters: Vec<_> = result
@@ -449,6 +1407,9 @@ impl<PubTx: Clone + fmt::Debug, PubResp, SubResp, UnSubResp>
 			)
 			.collect();
 
+		// Register the active subscriptions before handing the response back to the
+		// caller. The Server may send retained messages immediately after the SubAck,
+		// and these must find the subscription's channel when they are routed.
 		'outer: for (filter, _, qos, channel) in &successful_filters {
 			// If the filter matches a already subscribed filter, replace it.
 			for sub in self.active_subscriptions.iter_mut() {
@@ -469,13 +1430,539 @@ impl<PubTx: Clone + fmt::Debug, PubResp, SubResp, UnSubResp>
 				channel: channel.clone(),
 			});
 		}
+		self.rebuild_routing();
 
-		Ok((
+		Ok(Some((
 			response,
 			successful_filters
 				.into_iter()
 				.map(|(f, _, q, _)| (f, q))
 				.collect(),
-		))
+		)))
+	}
+}
+
+/// A fixed capacity set of hashes, evicting the least recently used.
+#[derive(Debug)]
+struct RecentPublishes {
+	capacity: usize,
+	order: VecDeque<u64>,
+	hashes: HashSet<u64>,
+}
+
+impl RecentPublishes {
+	fn new(capacity: usize) -> Self {
+		Self {
+			capacity,
+			order: VecDeque::with_capacity(capacity),
+			hashes: HashSet::with_capacity(capacity),
+		}
+	}
+
+	/// Inserts `hash`, marking it as the most recently used. Returns `true` if
+	/// it was already present.
+	fn insert(&mut self, hash: u64) -> bool {
+		if self.hashes.contains(&hash) {
+			if let Some(position) = self.order.iter().position(|&h| h == hash) {
+				self.order.remove(position);
+			}
+			self.order.push_back(hash);
+			return true;
+		}
+
+		if self.order.len() == self.capacity {
+			if let Some(oldest) = self.order.pop_front() {
+				self.hashes.remove(&oldest);
+			}
+		}
+		self.order.push_back(hash);
+		self.hashes.insert(hash);
+		false
+	}
+}
+
+/// Returns the remaining lifetime in whole seconds of a message with a message
+/// expiry interval of `interval` seconds, created at `created_at`. Returns
+/// `None` once the message has expired.
+#[cfg(feature = "v5")]
+pub fn remaining_lifetime(interval: u32, created_at: Instant) -> Option<u32> {
+	let elapsed = u32::try_from(created_at.elapsed().as_secs()).unwrap_or(u32::MAX);
+	interval
+		.checked_sub(elapsed)
+		.filter(|&remaining| remaining > 0)
+}
+
+/// Shortens `keep_alive` by a fraction of up to `jitter`, chosen by `random`.
+///
+/// `jitter` is clamped to `0.0..=0.5`, so the result is never longer than
+/// `keep_alive`, which would let the Server close the connection, nor short
+/// enough to flood it with PingReq packets.
+fn jittered(keep_alive: Duration, jitter: f64, random: u64) -> Duration {
+	let jitter = if jitter.is_nan() {
+		0.0
+	} else {
+		jitter.clamp(0.0, 0.5)
+	};
+	let fraction = random as f64 / u64::MAX as f64;
+	keep_alive.mul_f64(1.0 - jitter * fraction).min(keep_alive)
+}
+
+/// Converts an outgoing Publish packet into the form it is re-sent in, with
+/// the duplicate flag set. Returns `None` for AtMostOnce packets, which are
+/// never re-sent.
+fn resend_form(publish: Publish) -> Option<(PacketId, OwnedPublish)> {
+	match publish.into_owned() {
+		OwnedPublish::AtMostOnce { .. } => None,
+		OwnedPublish::AtLeastOnce {
+			id,
+			retain,
+			topic,
+			payload,
+			..
+		} => Some((
+			id,
+			OwnedPublish::AtLeastOnce {
+				id,
+				retain,
+				duplicate: true,
+				topic,
+				payload,
+			},
+		)),
+		OwnedPublish::ExactlyOnce {
+			id,
+			retain,
+			topic,
+			payload,
+			..
+		} => Some((
+			id,
+			OwnedPublish::ExactlyOnce {
+				id,
+				retain,
+				duplicate: true,
+				topic,
+				payload,
+			},
+		)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{jittered, ClientState, StateError};
+	use crate::{
+		packets::{Connect, Frame, Publish, SubAck, UnsubAck},
+		FilterBuf, Packet, PacketId, PacketType, QoS, Topic,
+	};
+	use bytes::{Bytes, BytesMut};
+	use std::time::Duration;
+
+	#[test]
+	fn filter_trie_routing() {
+		let filters = ["#", "a/#", "a/+", "+/b", "a/b", "a/b/c", "+/+/c"];
+
+		let mut linear = ClientState::<usize, (), (), ()>::new(&Connect::default());
+		let mut trie = ClientState::<usize, (), (), ()>::new(&Connect::default());
+		trie.use_filter_trie(true);
+
+		for state in [&mut linear, &mut trie] {
+			for (channel, filter) in filters.into_iter().enumerate() {
+				let filter = FilterBuf::new(filter).unwrap();
+				state.subscribe(vec![(filter, QoS::AtMostOnce)], channel, ());
+				state
+					.suback(SubAck {
+						id: PacketId::new(channel as u16 + 1).unwrap(),
+						result: vec![Ok(QoS::AtMostOnce)],
+					})
+					.unwrap();
+			}
+		}
+
+		for topic in ["a", "b", "a/b", "x/b", "a/c", "a/b/c", "x/y/c", "a/b/c/d"] {
+			let topic = Topic::new(topic).unwrap();
+			assert_eq!(
+				linear.find_publish_channel(topic),
+				trie.find_publish_channel(topic),
+				"topic {topic}"
+			);
+		}
+		assert_eq!(
+			trie.find_publish_channel(Topic::from_static("a/b/c")),
+			Some(&5)
+		);
+	}
+
+	#[test]
+	fn routes_borrowed_topics() {
+		let frame = Frame {
+			header: 0x30,
+			payload: b"\x00\x05a/b/cpayload"[..].into(),
+		};
+
+		for filter_trie in [false, true] {
+			let mut state = ClientState::<usize, (), (), ()>::new(&Connect::default());
+			state.use_filter_trie(filter_trie);
+			for (channel, filter) in ["a/#", "a/+/c", "a/b"].into_iter().enumerate() {
+				let filter = FilterBuf::new(filter).unwrap();
+				state.subscribe(vec![(filter, QoS::AtMostOnce)], channel, ());
+				state
+					.suback(SubAck {
+						id: PacketId::new(channel as u16 + 1).unwrap(),
+						result: vec![Ok(QoS::AtMostOnce)],
+					})
+					.unwrap();
+			}
+
+			// The topic is borrowed from the frame rather than copied.
+			let Packet::Publish(publish) = Packet::parse(&frame).unwrap() else {
+				panic!("expected a Publish packet");
+			};
+			let Publish::AtMostOnce { topic, .. } = *publish else {
+				panic!("expected an AtMostOnce Publish packet");
+			};
+			assert_eq!(topic.as_str().as_ptr(), frame.payload[2..].as_ptr());
+			assert_eq!(state.find_publish_channel(topic), Some(&1));
+		}
+	}
+
+	#[test]
+	fn duplicate_cache_evicts_least_recently_used() {
+		let mut state = ClientState::<(), (), (), ()>::new(&Connect::default());
+		let topic = Topic::from_static("a/b");
+		let publish = |id: u16, duplicate: bool| Publish::AtLeastOnce {
+			id: PacketId::new(id).unwrap(),
+			retain: false,
+			duplicate,
+			topic,
+			payload: "payload".into(),
+		};
+
+		// Disabled by default.
+		assert!(!state.is_redelivery(&publish(1, false)));
+		assert!(!state.is_redelivery(&publish(1, true)));
+
+		state.use_duplicate_cache(Some(2));
+		assert!(!state.is_redelivery(&publish(1, false)));
+		assert!(!state.is_redelivery(&publish(2, false)));
+		assert!(state.is_redelivery(&publish(1, true)));
+
+		// 1 was used more recently than 2, so 2 is evicted.
+		assert!(!state.is_redelivery(&publish(3, false)));
+		assert!(!state.is_redelivery(&publish(2, true)));
+		assert!(state.is_redelivery(&publish(3, true)));
+
+		// Without the duplicate flag, a packet is a new message.
+		assert!(!state.is_redelivery(&publish(3, false)));
+	}
+
+	#[test]
+	fn abandoned_publishes_are_reaped() {
+		let topic = Topic::from_static("a/b");
+		let mut state = ClientState::<(), bool, (), ()>::new(&Connect::default());
+		state.abandoned_publish_timeout = Duration::ZERO;
+
+		// The response is `true` if the caller has stopped waiting for it.
+		for abandoned in [true, false] {
+			for qos in [QoS::AtLeastOnce, QoS::ExactlyOnce] {
+				state.publish(topic, Default::default(), qos, false, abandoned);
+			}
+		}
+		assert_eq!(state.expire_publishes(|abandoned| *abandoned), 2);
+		assert_eq!(state.expire_publishes(|abandoned| *abandoned), 0);
+
+		// The publishes which are still awaited are acknowledged as usual.
+		let id = |id| PacketId::new(id).unwrap();
+		assert_eq!(state.puback(id(3)).unwrap(), Some(false));
+		state.pubrec(id(4)).unwrap();
+		assert_eq!(state.pubcomp(id(4)).unwrap(), Some(false));
+
+		// Late acknowledgements for the abandoned publishes are ignored, and the
+		// QoS 2 handshake is completed.
+		state.buffer();
+		assert_eq!(state.puback(id(1)).unwrap(), None);
+		state.pubrec(id(2)).unwrap();
+		assert_eq!(
+			&state.buffer().unwrap().into_bytes()[..],
+			b"\x62\x02\x00\x02"
+		);
+		assert_eq!(state.pubcomp(id(2)).unwrap(), None);
+
+		// But only once.
+		assert!(state.puback(id(1)).is_err());
+		assert!(state.pubcomp(id(2)).is_err());
+	}
+
+	#[test]
+	fn expired_requests_are_reaped() {
+		let mut state = ClientState::<(), (), u8, u8>::new(&Connect::default());
+		let filter = FilterBuf::new("a/b").unwrap();
+		state.subscribe_timeout = Duration::ZERO;
+
+		state.subscribe(vec![(filter.clone(), QoS::AtMostOnce)], (), 1);
+		state.unsubscribe(vec![filter.clone()], 2);
+		let subscribes = state.expire_subscribes();
+		assert_eq!(subscribes.len(), 1);
+		assert_eq!(subscribes[0].0, 1);
+		assert_eq!(subscribes[0].1, [filter.as_str()]);
+		assert_eq!(state.expire_unsubscribes(), [2]);
+
+		// Nothing is left to expire, and late acknowledgements are ignored.
+		assert!(state.expire_subscribes().is_empty());
+		assert!(state.expire_unsubscribes().is_empty());
+		let id = PacketId::new(1).unwrap();
+		let suback = SubAck {
+			id,
+			result: vec![Ok(QoS::AtMostOnce)],
+		};
+		assert!(state.suback(suback.clone()).unwrap().is_none());
+		assert!(state.unsuback(UnsubAck { id }).unwrap().is_none());
+
+		// But only once.
+		assert!(state.suback(suback).is_err());
+		assert!(state.unsuback(UnsubAck { id }).is_err());
+	}
+
+	#[test]
+	fn suback_length_mismatch() {
+		let mut state = ClientState::<(), (), u8, u8>::new(&Connect::default());
+		let filter = FilterBuf::new("a/b").unwrap();
+		state.subscribe(vec![(filter.clone(), QoS::AtMostOnce)], (), 1);
+
+		let suback = SubAck {
+			id: PacketId::new(1).unwrap(),
+			result: vec![Ok(QoS::AtMostOnce), Ok(QoS::AtLeastOnce)],
+		};
+		assert!(matches!(
+			state.suback(suback),
+			Err(StateError::SubAckLengthMismatch {
+				requested: 1,
+				returned: 2
+			})
+		));
+
+		// The filter was not subscribed.
+		assert!(state
+			.find_publish_channel(Topic::from_static("a/b"))
+			.is_none());
+	}
+
+	#[test]
+	fn reconnect_reuses_serialized_connect() {
+		let connect = Connect {
+			client_id: "client",
+			credentials: Some(("username", "password").into()),
+			..Default::default()
+		};
+		let mut expected = BytesMut::new();
+		connect.serialize_to_bytes(&mut expected).unwrap();
+
+		let mut state = ClientState::<(), (), (), ()>::new(&connect);
+		let cached = state.connect.as_ptr();
+		for _ in 0..2 {
+			state.reconnect();
+			assert_eq!(&state.buffer().unwrap().into_bytes()[..], &expected[..]);
+			assert_eq!(state.connect.as_ptr(), cached);
+		}
+	}
+
+	#[test]
+	fn keep_alive_jitter_stays_within_keep_alive() {
+		let keep_alive = Duration::from_secs(60);
+		for jitter in [0.0, 0.05, 0.25, 0.5] {
+			let min = keep_alive.mul_f64(1.0 - jitter);
+			for random in [0, 1, u64::MAX / 3, u64::MAX / 2, u64::MAX - 1, u64::MAX] {
+				let interval = jittered(keep_alive, jitter, random);
+				assert!(
+					(min..=keep_alive).contains(&interval),
+					"jitter={jitter} random={random} interval={interval:?}"
+				);
+			}
+			assert_eq!(jittered(keep_alive, jitter, 0), keep_alive);
+			assert_eq!(jittered(keep_alive, jitter, u64::MAX), min);
+		}
+
+		// Out of range jitter is clamped.
+		assert_eq!(jittered(keep_alive, -1.0, u64::MAX), keep_alive);
+		assert_eq!(jittered(keep_alive, f64::NAN, u64::MAX), keep_alive);
+		assert_eq!(jittered(keep_alive, 2.0, u64::MAX), Duration::from_secs(30));
+
+		let mut state = ClientState::<(), (), (), ()>::new(&Connect::default());
+		state.keep_alive_jitter = 0.05;
+		for _ in 0..100 {
+			let interval = state.ping_interval(keep_alive);
+			assert!((Duration::from_secs(57)..=keep_alive).contains(&interval));
+		}
+	}
+
+	#[test]
+	fn pending_outgoing_counts_queued_publishes() {
+		let topic = Topic::from_static("a/b");
+		let mut state = ClientState::<(), (), (), ()>::new(&Connect::default());
+		state.buffer();
+		assert_eq!(state.pending_outgoing(), 0);
+
+		let mut len = 0;
+		for (qos, id) in [
+			(QoS::AtMostOnce, None),
+			(QoS::AtLeastOnce, PacketId::new(1)),
+			(QoS::ExactlyOnce, PacketId::new(2)),
+		] {
+			state.publish(topic, Bytes::from_static(b"payload").into(), qos, false, ());
+			let publish = Publish::new(topic, "payload".into(), qos, false, id).unwrap();
+			let mut buffer = BytesMut::new();
+			publish.serialize_to_bytes(&mut buffer).unwrap();
+			len += buffer.len();
+			assert_eq!(state.pending_outgoing(), len);
+		}
+		// Only the AtLeastOnce and ExactlyOnce publishes await a response.
+		assert_eq!(state.pending_requests(), 2);
+
+		// Taking the outgoing buffer empties it, but the requests still await
+		// their responses.
+		assert_eq!(state.buffer().unwrap().len(), len);
+		assert_eq!(state.pending_outgoing(), 0);
+		assert_eq!(state.pending_requests(), 2);
+
+		state.puback(PacketId::new(1).unwrap()).unwrap();
+		assert_eq!(state.pending_requests(), 1);
+	}
+
+	#[test]
+	fn exactly_once_receiver_ids() {
+		let mut state = ClientState::<(), (), (), ()>::new(&Connect::default());
+		let id = PacketId::new(1).unwrap();
+
+		// A re-sent Publish packet is recognised until the ID is released.
+		assert!(!state.is_unreleased(id).unwrap());
+		assert!(state.is_unreleased(id).unwrap());
+		state.pubrel(id).unwrap();
+
+		// A re-sent PubRel packet no longer matches.
+		assert!(matches!(
+			state.pubrel(id),
+			Err(StateError::Unsolicited(PacketType::PubRel))
+		));
+
+		// The ID may then be reused for a new message.
+		assert!(!state.is_unreleased(id).unwrap());
+	}
+
+	#[test]
+	fn partially_saved_packet_is_removed_from_session_store() {
+		use crate::clients::SessionStore;
+		use std::{
+			io,
+			sync::{Arc, Mutex},
+		};
+
+		#[derive(Debug, Clone, Default)]
+		struct Memory(Arc<Mutex<Vec<u8>>>);
+
+		impl SessionStore for Memory {
+			fn save(&mut self, packet: &[u8]) -> io::Result<()> {
+				self.0.lock().unwrap().extend_from_slice(packet);
+				Ok(())
+			}
+
+			fn load(&mut self) -> io::Result<Vec<u8>> {
+				Ok(self.0.lock().unwrap().clone())
+			}
+
+			fn clear(&mut self) -> io::Result<()> {
+				self.0.lock().unwrap().clear();
+				Ok(())
+			}
+		}
+
+		let topic = Topic::from_static("a/b");
+		let mut log = BytesMut::new();
+		for id in 1..=2 {
+			let id = PacketId::new(id).unwrap();
+			Publish::new(topic, "payload".into(), QoS::AtLeastOnce, false, Some(id))
+				.unwrap()
+				.serialize_to_bytes(&mut log)
+				.unwrap();
+		}
+		let complete = log.len() / 2;
+		let store = Memory(Arc::new(Mutex::new(log[..log.len() - 1].to_vec())));
+
+		let mut state = ClientState::<(), (), (), ()>::new(&Connect::default());
+		state.use_session_store(Box::new(store.clone())).unwrap();
+		assert_eq!(state.pending_requests(), 1);
+		assert_eq!(store.0.lock().unwrap().len(), complete);
+	}
+
+	#[test]
+	#[cfg(feature = "v5")]
+	fn receive_maximum_limits_unreleased_ids() {
+		use crate::packets::ProtocolVersion;
+
+		let connect = Connect::default().with_protocol_version(ProtocolVersion::V5);
+		let mut state = ClientState::<(), (), (), ()>::new(&connect);
+		state.receive_maximum = Some(2);
+		let ids: Vec<_> = (1..=3).map(|id| PacketId::new(id).unwrap()).collect();
+
+		assert!(!state.is_unreleased(ids[0]).unwrap());
+		assert!(!state.is_unreleased(ids[1]).unwrap());
+		assert!(matches!(
+			state.is_unreleased(ids[2]),
+			Err(StateError::ProtocolError(_))
+		));
+
+		// Re-sent messages do not count towards the maximum.
+		assert!(state.is_unreleased(ids[1]).unwrap());
+		state.pubrel(ids[0]).unwrap();
+		assert!(!state.is_unreleased(ids[2]).unwrap());
+	}
+
+	#[test]
+	#[cfg(feature = "v5")]
+	fn publish_properties_are_sent_with_v5() {
+		use crate::packets::{ProtocolVersion, PublishProperties};
+
+		let topic = Topic::from_static("a/b");
+		let properties = PublishProperties {
+			content_type: Some("text/plain".into()),
+			..Default::default()
+		};
+		let connect = Connect::default().with_protocol_version(ProtocolVersion::V5);
+		let mut state = ClientState::<(), (), (), ()>::new(&connect);
+		state.buffer();
+
+		state.publish_with_properties(
+			topic,
+			Bytes::from_static(b"hi").into(),
+			QoS::AtLeastOnce,
+			false,
+			properties.clone(),
+			(),
+		);
+		let mut expected = b"\x32\x17\x00\x03a/b\x00\x01".to_vec();
+		expected.extend_from_slice(b"\x0d\x03\x00\x0atext/plain");
+		expected.extend_from_slice(b"hi");
+		assert_eq!(&state.buffer().unwrap().into_bytes()[..], &expected[..]);
+
+		// The properties are re-sent with the packet.
+		state.reconnect();
+		let buffer = state.buffer().unwrap().into_bytes();
+		expected[0] |= 0x08;
+		assert!(buffer.ends_with(&expected));
+
+		// Without MQTT v5, the properties are not sent.
+		let mut state = ClientState::<(), (), (), ()>::new(&Connect::default());
+		state.buffer();
+		state.publish_with_properties(
+			topic,
+			Bytes::from_static(b"hi").into(),
+			QoS::AtMostOnce,
+			false,
+			properties,
+			(),
+		);
+		assert_eq!(
+			&state.buffer().unwrap().into_bytes()[..],
+			b"\x30\x07\x00\x03a/bhi"
+		);
 	}
 }
@@ -1,24 +1,80 @@
 mod subscription;
 
-use super::{Command, CommandTx};
+use super::{publish_channel, Command, CommandTx, Overflow};
 use crate::{
 	clients::{
-		command::{PublishCommand, SubscribeCommand, UnsubscribeCommand},
-		Filters, FiltersWithQoS,
+		command::{
+			FlushCommand, KeepAliveCommand, PendingCountCommand, PublishCommand, ShutdownCommand,
+			SubscribeCommand, UnsubscribeCommand,
+		},
+		Filters, FiltersWithQoS, Message,
 	},
-	InvalidFilter, InvalidTopic, QoS, TopicBuf,
+	misc::{BytesVec, Retain},
+	packets::ConnAck,
+	InvalidFilter, InvalidTopic, InvalidTopicString, PacketType, QoS, TopicBuf,
 };
 use bytes::Bytes;
 use core::fmt;
-use std::convert;
-pub use subscription::Subscription;
+use std::{
+	convert,
+	future::Future,
+	pin::Pin,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
+	task::{Context, Poll},
+	time::Duration,
+};
+pub use subscription::{Lagged, Subscription};
 use thiserror::Error;
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+	sync::{broadcast, mpsc, oneshot},
+	time::{self, Instant},
+};
 
 /// An asychronous MQTT client, based on the tokio runtime.
 #[derive(Clone, Debug)]
 pub struct Client {
 	tx: CommandTx,
+	connected: Arc<AtomicBool>,
+	last_connack: Arc<Mutex<Option<ConnAckInfo>>>,
+	attempt: Arc<Mutex<Attempt>>,
+	overflow: Overflow,
+	events: broadcast::Sender<ConnectionEvent>,
+
+	// The client identifier sent in the Connect packet.
+	client_id: Arc<str>,
+
+	// Counts the live clones of the client.
+	handles: Arc<Handles>,
+}
+
+/// Shared by every clone of a [`Client`], so the last clone to disconnect can
+/// be identified.
+#[derive(Debug)]
+struct Handles {
+	task: Arc<TaskGuard>,
+}
+
+/// Shared by every clone of a [`Client`] and every [`Subscription`], so the
+/// client task can be shut down once none of them can use the connection.
+#[derive(Debug)]
+struct TaskGuard {
+	tx: CommandTx,
+
+	// Set once the last clone of the client has sent the Shutdown command
+	// itself.
+	shut_down: AtomicBool,
+}
+
+impl TaskGuard {
+	fn new(tx: CommandTx) -> Arc<Self> {
+		Arc::new(Self {
+			tx,
+			shut_down: AtomicBool::new(false),
+		})
+	}
 }
 
 #[derive(Debug, Error)]
@@ -27,13 +83,213 @@ pub enum ClientError {
 	ClientTaskClosed,
 	#[error("invalid filter(s): {0}")]
 	InvalidFilter(#[from] InvalidFilter),
-	#[error("invalid topic: {0}")]
-	InvalidTopic(#[from] InvalidTopic),
+	#[error("invalid topic {topic:?}: {source}")]
+	InvalidTopic {
+		topic: String,
+		#[source]
+		source: InvalidTopic,
+	},
+	#[error("the Server does not support the requested protocol version")]
+	UnsupportedProtocolVersion,
+	#[error("timed out waiting for the Server to respond")]
+	Timeout,
+	#[error("the connection to the Server was lost and could not be re-established")]
+	ConnectionLost,
+	#[error("the client is not connected to the Server")]
+	NotConnected,
+	#[error("keep alive of {0:?} is not between 1 and 65,535 seconds")]
+	InvalidKeepAlive(Duration),
+	#[cfg(feature = "v5")]
+	#[error("the message expired before the Server received it")]
+	MessageExpired,
+	#[cfg(feature = "v5")]
+	#[error(
+		"rejected by the Server with reason code {reason_code:#04x}: {}",
+		.properties.reason_string.as_deref().unwrap_or("no reason given")
+	)]
+	Rejected {
+		reason_code: u8,
+		properties: crate::packets::AckProperties,
+	},
+}
+
+/// The result of the last successful Connect/ConnAck handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnAckInfo {
+	/// Indicates that the Server has existing state from a previous session for
+	/// the client.
+	pub session_present: bool,
+
+	/// Status code. This is always `0` after a successful handshake.
+	pub code: u8,
+
+	/// The client identifier assigned by the Server, when the client connected
+	/// with an empty client identifier.
+	#[cfg(feature = "v5")]
+	pub assigned_client_identifier: Option<String>,
+}
+
+/// The state of the client task's connection to the Server, as returned by
+/// [`Client::connection_status`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+	/// The handshake with the Server has completed.
+	Connected,
+
+	/// The client task is opening a connection, or waiting for the Server to
+	/// complete the handshake.
+	Connecting,
+
+	/// The client task is waiting before its next connection attempt.
+	Backoff {
+		/// The time remaining until the next attempt.
+		next_attempt_in: Duration,
+	},
+
+	/// The client task is waiting for a command before it reconnects, or has
+	/// stopped.
+	Disconnected,
+}
+
+/// An event from the client task, received with [`Client::events`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+	/// The Server violated the protocol. The client task closes the connection,
+	/// first sending a Disconnect packet if
+	/// [`disconnect_on_protocol_error`] is set, then reconnects.
+	///
+	/// [`disconnect_on_protocol_error`]: super::Options::disconnect_on_protocol_error
+	ProtocolError(ProtocolError),
+}
+
+/// A violation of the protocol by the Server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Error)]
+pub enum ProtocolError {
+	#[error("unsolicited {0:?} packet")]
+	Unsolicited(PacketType),
+	#[error("the Server cannot send {0:?} packets")]
+	InvalidPacket(PacketType),
+	#[error("SubAck has {returned} results for {requested} filters")]
+	SubAckLengthMismatch { requested: usize, returned: usize },
+	#[error("{0}")]
+	Other(&'static str),
+}
+
+/// The reconnect state shared between the client task and [`Client`]s, when
+/// the client is not connected.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) enum Attempt {
+	#[default]
+	Connecting,
+	Backoff(Instant),
+	Disconnected,
+}
+
+impl From<ConnAck> for ConnAckInfo {
+	fn from(connack: ConnAck) -> Self {
+		Self {
+			session_present: connack.session_present,
+			code: connack.code,
+			#[cfg(feature = "v5")]
+			assigned_client_identifier: connack.properties.assigned_client_identifier,
+		}
+	}
 }
 
 impl Client {
-	pub(crate) fn new(tx: CommandTx) -> Self {
-		Self { tx }
+	pub(crate) fn new(
+		tx: CommandTx,
+		connected: Arc<AtomicBool>,
+		last_connack: Arc<Mutex<Option<ConnAckInfo>>>,
+		attempt: Arc<Mutex<Attempt>>,
+		overflow: Overflow,
+		events: broadcast::Sender<ConnectionEvent>,
+		client_id: Arc<str>,
+	) -> Self {
+		let handles = Arc::new(Handles {
+			task: TaskGuard::new(tx.clone()),
+		});
+		Self {
+			tx,
+			connected,
+			last_connack,
+			attempt,
+			overflow,
+			events,
+			client_id,
+			handles,
+		}
+	}
+
+	/// Returns the client identifier of the session.
+	///
+	/// With MQTT v5, this is the identifier assigned by the Server in the last
+	/// [`ConnAck`], if there was one. Otherwise, it is the identifier sent in
+	/// the Connect packet, which is generated if the [`Options`] did not
+	/// provide one.
+	///
+	/// [`ConnAck`]: crate::packets::ConnAck
+	/// [`Options`]: super::Options
+	pub fn client_id(&self) -> String {
+		#[cfg(feature = "v5")]
+		if let Some(ConnAckInfo {
+			assigned_client_identifier: Some(client_id),
+			..
+		}) = &*self.last_connack.lock().unwrap()
+		{
+			return client_id.clone();
+		}
+
+		self.client_id.to_string()
+	}
+
+	/// Returns a receiver for [`ConnectionEvent`]s from the client task.
+	///
+	/// Only events sent after the receiver is created are received. If the
+	/// receiver falls behind, the oldest events are skipped.
+	#[inline]
+	pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+		self.events.subscribe()
+	}
+
+	/// Returns `true` if the client task is connected to the Server.
+	///
+	/// The client is connected once a [`ConnAck`] has been received, until
+	/// the connection is closed. This does not wait for the client task.
+	///
+	/// [`ConnAck`]: crate::packets::ConnAck
+	#[inline]
+	pub fn is_connected(&self) -> bool {
+		self.connected.load(Ordering::Acquire)
+	}
+
+	/// Returns the [`ConnAck`] received in the last successful handshake with
+	/// the Server, or `None` if the client has not connected yet.
+	///
+	/// This is updated by the client task every time it reconnects, before
+	/// [`is_connected`](Self::is_connected) returns `true`.
+	///
+	/// [`ConnAck`]: crate::packets::ConnAck
+	pub fn last_connack(&self) -> Option<ConnAckInfo> {
+		self.last_connack.lock().unwrap().clone()
+	}
+
+	/// Returns the state of the client task's connection to the Server.
+	///
+	/// While the client task is reconnecting, this reports how long remains
+	/// until its next attempt.
+	pub fn connection_status(&self) -> ConnectionStatus {
+		if self.is_connected() {
+			return ConnectionStatus::Connected;
+		}
+
+		match *self.attempt.lock().unwrap() {
+			Attempt::Connecting => ConnectionStatus::Connecting,
+			Attempt::Backoff(at) => ConnectionStatus::Backoff {
+				next_attempt_in: at.saturating_duration_since(Instant::now()),
+			},
+			Attempt::Disconnected => ConnectionStatus::Disconnected,
+		}
 	}
 
 	/// Sends a [`Subscribe`] packet with the requested filters to the Server.
@@ -43,6 +299,13 @@ impl Client {
 	/// filters. The subscription will buffer upto the specified
 	/// number of messages.
 	///
+	/// # Cancellation
+	///
+	/// If the future is dropped after the request has been passed to the
+	/// client task, the request is not withdrawn. When the [`SubAck`] arrives,
+	/// the granted filters are unsubscribed again, as nothing can receive
+	/// their messages.
+	///
 	/// # Example
 	///
 	/// ```no_run
@@ -76,25 +339,120 @@ impl Client {
 		self.subscribe_impl(filters.try_into()?, len).await
 	}
 
+	/// Sends a [`Subscribe`] packet, in the same way as [`subscribe`], but fails
+	/// with [`ClientError::Timeout`] if the corresponding [`SubAck`] is not
+	/// received within `timeout`.
+	///
+	/// If the SubAck arrives after the timeout, the client unsubscribes from the
+	/// filters again.
+	///
+	/// Subscribe requests also fail if they are not acknowledged within
+	/// [`Options::subscribe_timeout`].
+	///
+	/// [`Subscribe`]: crate::packets::Subscribe
+	/// [`SubAck`]: crate::packets::SubAck
+	/// [`subscribe`]: Client::subscribe
+	/// [`Options::subscribe_timeout`]: super::Options::subscribe_timeout
+	#[inline]
+	pub async fn subscribe_timeout<T, E>(
+		&self,
+		filters: T,
+		len: usize,
+		timeout: Duration,
+	) -> Result<Subscription, ClientError>
+	where
+		T: TryInto<FiltersWithQoS, Error = E>,
+		ClientError: From<E>,
+	{
+		let subscribe = self.subscribe_impl(filters.try_into()?, len);
+		time::timeout(timeout, subscribe)
+			.await
+			.map_err(|_| ClientError::Timeout)?
+	}
+
+	/// Sends a [`Subscribe`] packet with the requested filters, returning the
+	/// [`Subscription`] without waiting for the [`SubAck`].
+	///
+	/// This allows several subscribe requests to be in flight at once, for
+	/// example when setting up subscriptions at startup. Messages are delivered
+	/// to the Subscription once the Server has granted the filters.
+	///
+	/// Until the SubAck is received, the QoS granted by the Server is not
+	/// known, and [`Subscription::filters`] returns the requested filters and
+	/// QoS. Use [`Subscription::subscribed`] to wait for the SubAck and find the
+	/// granted QoS. If the request fails, the Subscription receives no
+	/// messages.
+	///
+	/// [`Subscribe`]: crate::packets::Subscribe
+	/// [`SubAck`]: crate::packets::SubAck
+	pub async fn subscribe_nowait<T, E>(
+		&self,
+		filters: T,
+		len: usize,
+	) -> Result<Subscription, ClientError>
+	where
+		T: TryInto<FiltersWithQoS, Error = E>,
+		ClientError: From<E>,
+	{
+		let FiltersWithQoS(filters) = filters.try_into()?;
+		let (response, response_rx) = oneshot::channel();
+		let (channel, publish_rx) = publish_channel::channel(len, self.overflow);
+		let weak_channel = channel.downgrade();
+
+		self.tx
+			.send(
+				Command::Subscribe(SubscribeCommand {
+					filters: filters.clone(),
+					channel,
+					response,
+				})
+				.into(),
+			)
+			.await?;
+
+		// Keep the response receiver, or the client task would unsubscribe from
+		// the filters when the SubAck arrives.
+		let subscription = Subscription::new(
+			filters,
+			weak_channel,
+			publish_rx,
+			Arc::clone(&self.handles.task),
+		)
+		.with_pending(response_rx);
+		Ok(subscription)
+	}
+
 	async fn subscribe_impl(
 		&self,
 		FiltersWithQoS(filters): FiltersWithQoS,
 		buffer: usize,
 	) -> Result<Subscription, ClientError> {
+		for (filter, _) in filters.iter().filter(|(filter, _)| !filter.can_match_any()) {
+			tracing::warn!(%filter, "subscribing to a filter which cannot match any topic");
+		}
+
 		let (response, response_rx) = oneshot::channel();
-		let (channel, publish_rx) = mpsc::channel(buffer);
+		let (channel, publish_rx) = publish_channel::channel(buffer, self.overflow);
+		let weak_channel = channel.downgrade();
 
-		self.tx.send(
-			Command::Subscribe(SubscribeCommand {
-				filters,
-				channel,
-				response,
-			})
-			.into(),
-		)?;
+		self.tx
+			.send(
+				Command::Subscribe(SubscribeCommand {
+					filters,
+					channel,
+					response,
+				})
+				.into(),
+			)
+			.await?;
 
-		let subscribed_filters = response_rx.await?;
-		let subscription = Subscription::new(subscribed_filters, publish_rx, self.tx.clone());
+		let subscribed_filters = response_rx.await??;
+		let subscription = Subscription::new(
+			subscribed_filters,
+			weak_channel,
+			publish_rx,
+			Arc::clone(&self.handles.task),
+		);
 
 		Ok(subscription)
 	}
@@ -108,6 +466,25 @@ impl Client {
 	/// Server; and with [`ExactlyOnce`] the call will return when the
 	/// corresponding [`PubComp`] has been received.
 	///
+	/// The topic may be a `&str` or `String`, which is validated, or an already
+	/// validated [`&Topic`] or [`TopicBuf`], which is not. The retain flag may
+	/// be a `bool` or a [`Retain`].
+	///
+	/// # Cancellation
+	///
+	/// If the future is dropped after the message has been passed to the client
+	/// task, the message is still published. With a QoS of [`AtLeastOnce`] or
+	/// [`ExactlyOnce`], the client task keeps re-sending an unacknowledged
+	/// message for the [`abandoned_publish_timeout`], then drops it.
+	///
+	/// The payload is converted to [`Bytes`] before being passed to the client
+	/// task. Static payloads (`&'static [u8]` and `&'static str`) and owned
+	/// `Vec<u8>`, `String` or `Bytes` payloads are not copied by the conversion;
+	/// to publish borrowed data, copy it with [`Bytes::copy_from_slice`]. Short
+	/// payloads are copied once more, into the outgoing buffer; longer payloads
+	/// are written to the transport stream without being copied. To publish a
+	/// payload made up of several buffers, see [`publish_vectored`].
+	///
 	/// # Example
 	///
 	/// ```no_run
@@ -132,50 +509,268 @@ impl Client {
 	/// [`Publish`]: crate::packets::Publish
 	/// [`PubAck`]: crate::packets::PubAck
 	/// [`PubComp`]: crate::packets::PubComp
+	/// [`&Topic`]: crate::Topic
+	/// [`publish_vectored`]: Client::publish_vectored
+	/// [`abandoned_publish_timeout`]: super::Options::abandoned_publish_timeout
 	#[inline]
 	pub async fn publish<TryIntoTopic, E>(
 		&self,
 		topic: TryIntoTopic,
 		payload: impl Into<Bytes> + fmt::Debug,
 		qos: QoS,
-		retain: bool,
+		retain: impl Into<Retain>,
+	) -> Result<(), ClientError>
+	where
+		TryIntoTopic: TryInto<TopicBuf, Error = E>,
+		ClientError: From<E>,
+	{
+		let retain = retain.into().into();
+		self.publish_impl(
+			topic.try_into()?,
+			payload.into().into(),
+			PublishOptions::new(qos, retain),
+		)
+		.await
+	}
+
+	/// Sends a [`Publish`] packet with `options`.
+	///
+	/// This behaves like [`publish`], with the QoS and retain flag taken from
+	/// `options`. With the `v5` feature, the options also carry the MQTT v5
+	/// properties of the message, which are sent if the client connected with
+	/// MQTT v5.
+	///
+	/// # Example
+	/// ```no_run
+	/// # tokio_test::block_on(async {
+	/// use tjh_mqtt::{
+	/// 	clients::tokio::{self, PublishOptions},
+	/// 	QoS::AtLeastOnce,
+	/// };
+	/// let (client, handle) = tokio::tcp_client(("localhost", 1883));
+	///
+	/// let options = PublishOptions {
+	/// 	qos: AtLeastOnce,
+	/// 	..Default::default()
+	/// };
+	/// client.publish_with("a/b", "hello", options).await.unwrap();
+	/// # })
+	/// ```
+	///
+	/// [`Publish`]: crate::packets::Publish
+	/// [`publish`]: Client::publish
+	#[inline]
+	pub async fn publish_with<TryIntoTopic, E>(
+		&self,
+		topic: TryIntoTopic,
+		payload: impl Into<Bytes> + fmt::Debug,
+		options: PublishOptions,
 	) -> Result<(), ClientError>
 	where
 		TryIntoTopic: TryInto<TopicBuf, Error = E>,
 		ClientError: From<E>,
 	{
-		self.publish_impl(topic.try_into()?, payload.into(), qos, retain)
+		self.publish_impl(topic.try_into()?, payload.into().into(), options)
 			.await
 	}
 
+	/// Sends a [`Publish`] packet if the client task is connected to the
+	/// Server.
+	///
+	/// This behaves like [`publish`], but returns [`ClientError::NotConnected`]
+	/// immediately if the client is not connected (see [`is_connected`]), rather
+	/// than queuing the message until the client task reconnects. The connection
+	/// may still be lost after the check, in which case the message is queued.
+	///
+	/// [`Publish`]: crate::packets::Publish
+	/// [`publish`]: Client::publish
+	/// [`is_connected`]: Client::is_connected
+	#[inline]
+	pub async fn publish_require_connected<TryIntoTopic, E>(
+		&self,
+		topic: TryIntoTopic,
+		payload: impl Into<Bytes> + fmt::Debug,
+		qos: QoS,
+		retain: impl Into<Retain>,
+	) -> Result<(), ClientError>
+	where
+		TryIntoTopic: TryInto<TopicBuf, Error = E>,
+		ClientError: From<E>,
+	{
+		if !self.is_connected() {
+			return Err(ClientError::NotConnected);
+		}
+		self.publish(topic, payload, qos, retain).await
+	}
+
+	/// Publishes `message`, with its QoS and retain flag.
+	///
+	/// This behaves like [`publish`], and is convenient for re-publishing a
+	/// received [`Message`], see [`Message::republish_to`].
+	///
+	/// [`publish`]: Client::publish
+	#[inline]
+	pub async fn publish_message(&self, message: Message) -> Result<(), ClientError> {
+		let (topic, payload, qos, retain) = message.into_parts();
+		self.publish_impl(topic, payload.into(), PublishOptions::new(qos, retain))
+			.await
+	}
+
+	/// Sends a [`Publish`] packet with a payload made up of several chunks.
+	///
+	/// This behaves like [`publish`], but the chunks are not concatenated
+	/// before they are written to the transport stream; the stream is written
+	/// with vectored writes, where it supports them. Messages published with a
+	/// QoS of [`AtLeastOnce`] or [`ExactlyOnce`] are kept until they are
+	/// acknowledged, so their chunks are concatenated.
+	///
+	/// # Example
+	///
+	/// ```no_run
+	/// # tokio_test::block_on(async {
+	/// use bytes::Bytes;
+	/// use tjh_mqtt::{clients::tokio, QoS::AtMostOnce};
+	/// let (client, handle) = tokio::tcp_client(("localhost", 1883));
+	///
+	/// let header = Bytes::from_static(b"header:");
+	/// let body = Bytes::from(vec![0; 4096]);
+	/// client
+	/// 	.publish_vectored("a/b", [header, body], AtMostOnce, false)
+	/// 	.await
+	/// 	.unwrap();
+	/// # })
+	/// ```
+	///
+	/// [`Publish`]: crate::packets::Publish
+	/// [`publish`]: Client::publish
+	/// [`AtLeastOnce`]: crate::QoS#variant.AtLeastOnce
+	/// [`ExactlyOnce`]: crate::QoS#variant.ExactlyOnce
+	#[inline]
+	pub async fn publish_vectored<TryIntoTopic, E>(
+		&self,
+		topic: TryIntoTopic,
+		payload: impl Into<BytesVec>,
+		qos: QoS,
+		retain: impl Into<Retain>,
+	) -> Result<(), ClientError>
+	where
+		TryIntoTopic: TryInto<TopicBuf, Error = E>,
+		ClientError: From<E>,
+	{
+		let retain = retain.into().into();
+		self.publish_impl(
+			topic.try_into()?,
+			payload.into(),
+			PublishOptions::new(qos, retain),
+		)
+		.await
+	}
+
+	/// Clears the retained message on `topic` by publishing a zero-length
+	/// payload with the retain flag set.
+	///
+	/// The topic is validated in the same way as for [`publish`]. Most callers
+	/// will want a QoS of [`AtMostOnce`].
+	///
+	/// [`publish`]: Client::publish
+	/// [`AtMostOnce`]: crate::QoS#variant.AtMostOnce
+	#[inline]
+	pub async fn clear_retained<TryIntoTopic, E>(
+		&self,
+		topic: TryIntoTopic,
+		qos: QoS,
+	) -> Result<(), ClientError>
+	where
+		TryIntoTopic: TryInto<TopicBuf, Error = E>,
+		ClientError: From<E>,
+	{
+		self.publish_impl(
+			topic.try_into()?,
+			BytesVec::new(),
+			PublishOptions::new(qos, true),
+		)
+		.await
+	}
+
+	/// Queues a [`Publish`] packet with the provided topic and payload, without
+	/// waiting for it to be acknowledged.
+	///
+	/// The call returns once the client task has accepted the message. The
+	/// returned [`PublishAck`] resolves when the message has been delivered, in
+	/// the same way as [`publish`]. This allows many messages to be published
+	/// before awaiting any of their acknowledgements.
+	///
+	/// Dropping the [`PublishAck`] does not withdraw the message; see
+	/// [`publish`] for how the message is handled.
+	///
+	/// [`Publish`]: crate::packets::Publish
+	/// [`publish`]: Client::publish
+	#[inline]
+	pub async fn publish_deferred<TryIntoTopic, E>(
+		&self,
+		topic: TryIntoTopic,
+		payload: impl Into<Bytes> + fmt::Debug,
+		qos: QoS,
+		retain: impl Into<Retain>,
+	) -> Result<PublishAck, ClientError>
+	where
+		TryIntoTopic: TryInto<TopicBuf, Error = E>,
+		ClientError: From<E>,
+	{
+		let retain = retain.into().into();
+		self.send_publish(
+			topic.try_into()?,
+			payload.into().into(),
+			PublishOptions::new(qos, retain),
+		)
+		.await
+	}
+
 	async fn publish_impl(
 		&self,
 		topic: TopicBuf,
-		payload: Bytes,
-		qos: QoS,
-		retain: bool,
+		payload: BytesVec,
+		options: PublishOptions,
 	) -> Result<(), ClientError> {
+		self.send_publish(topic, payload, options).await?.await
+	}
+
+	async fn send_publish(
+		&self,
+		topic: TopicBuf,
+		payload: BytesVec,
+		options: PublishOptions,
+	) -> Result<PublishAck, ClientError> {
 		let (response, response_rx) = oneshot::channel();
 
-		self.tx.send(
-			Command::Publish(PublishCommand {
-				topic,
-				payload,
-				qos,
-				retain,
-				response,
-			})
-			.into(),
-		)?;
+		self.tx
+			.send(
+				Command::Publish(PublishCommand {
+					topic,
+					payload,
+					qos: options.qos,
+					retain: options.retain,
+					#[cfg(feature = "v5")]
+					properties: options.properties,
+					#[cfg(feature = "v5")]
+					created_at: std::time::Instant::now(),
+					response,
+				})
+				.into(),
+			)
+			.await?;
 
-		response_rx.await?;
-		Ok(())
+		Ok(PublishAck(response_rx))
 	}
 
 	/// Sends an [`Unsubscribe`] packet with `filters` to the Server. On
 	/// receiving a corresponding [`UnsubAck`], the client will drop any
 	/// matching filters.
 	///
+	/// If the Server does not respond within [`Options::subscribe_timeout`],
+	/// the filters are dropped anyway and [`ClientError::Timeout`] is returned.
+	///
+	/// [`Options::subscribe_timeout`]: super::Options::subscribe_timeout
 	/// [`Unsubscribe`]: crate::packets::Unsubscribe
 	/// [`UnsubAck`]: crate::packets::UnsubAck
 	#[inline]
@@ -190,24 +785,165 @@ impl Client {
 	async fn unsubscribe_impl(&self, Filters(filters): Filters) -> Result<(), ClientError> {
 		let (response, response_rx) = oneshot::channel();
 		self.tx
-			.send(Command::Unsubscribe(UnsubscribeCommand { filters, response }).into())?;
+			.send(Command::Unsubscribe(UnsubscribeCommand { filters, response }).into())
+			.await?;
+
+		response_rx.await??;
+		Ok(())
+	}
+
+	/// Waits until every packet queued by previous calls has been written to the
+	/// transport stream.
+	///
+	/// This is useful to make sure [`AtMostOnce`] messages have been sent, for
+	/// example before putting a device to sleep. It does not wait for any
+	/// acknowledgements from the Server.
+	///
+	/// [`AtMostOnce`]: crate::QoS#variant.AtMostOnce
+	#[inline]
+	pub async fn flush(&self) -> Result<(), ClientError> {
+		let (response, response_rx) = oneshot::channel();
+		self.tx
+			.send(Command::Flush(FlushCommand { response }).into())
+			.await?;
 
 		response_rx.await?;
 		Ok(())
 	}
 
+	/// Returns the number of publish, subscribe and unsubscribe requests sent
+	/// to the Server which are still waiting for a response.
+	///
+	/// This includes AtLeastOnce and ExactlyOnce publishes which have not been
+	/// acknowledged, which are re-sent after reconnecting. Requests still
+	/// queued for the client task are not counted, as the count is taken once
+	/// the client task has processed every earlier call.
+	#[inline]
+	pub async fn pending_count(&self) -> Result<usize, ClientError> {
+		let (response, response_rx) = oneshot::channel();
+		self.tx
+			.send(Command::PendingCount(PendingCountCommand { response }).into())
+			.await?;
+
+		Ok(response_rx.await?)
+	}
+
+	/// Changes the keep alive interval, rounded down to whole seconds.
+	///
+	/// The keep alive is sent to the Server in the Connect packet, so the client
+	/// disconnects and reconnects for the new value to take effect. Active
+	/// subscriptions are restored after reconnecting.
+	///
+	/// Returns [`ClientError::InvalidKeepAlive`] unless `keep_alive` is between
+	/// 1 and 65,535 seconds.
+	pub async fn set_keep_alive(&self, keep_alive: Duration) -> Result<(), ClientError> {
+		let keep_alive = u16::try_from(keep_alive.as_secs())
+			.ok()
+			.filter(|&seconds| seconds != 0)
+			.ok_or(ClientError::InvalidKeepAlive(keep_alive))?;
+
+		self.tx
+			.send(Command::KeepAlive(KeepAliveCommand { keep_alive }).into())
+			.await?;
+		Ok(())
+	}
+
 	/// Sends a [`Disconnect`] packet to the Server.
 	///
-	/// A compliant Server must immediately close the connection.
+	/// A compliant Server must immediately close the connection. The call will
+	/// return once the Disconnect packet has been written and flushed to the
+	/// transport stream, and the client task is shutting down.
+	///
+	/// If other clones of the client are still live, this only releases this
+	/// handle and returns immediately. The client task shuts down when the last
+	/// clone is disconnected, or once every clone and [`Subscription`] has been
+	/// dropped.
 	///
 	/// [`Disconnect`]: crate::packets::Disconnect
-	#[inline]
 	pub async fn disconnect(self) -> Result<(), ClientError> {
-		self.tx.send(Command::Shutdown.into())?;
+		let Some(handles) = Arc::into_inner(self.handles) else {
+			return Ok(());
+		};
+
+		// This is the last clone, so the Disconnect is sent here rather than when
+		// the last handle to the task is dropped.
+		let (response, response_rx) = oneshot::channel();
+		self.tx
+			.send(Command::Shutdown(ShutdownCommand { response }).into())
+			.await?;
+		handles.task.shut_down.store(true, Ordering::Release);
+
+		response_rx.await?;
 		Ok(())
 	}
 }
 
+/// Options for a message sent with [`Client::publish_with`].
+///
+/// The default is a QoS of [`AtMostOnce`], without the retain flag.
+///
+/// [`AtMostOnce`]: crate::QoS#variant.AtMostOnce
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PublishOptions {
+	pub qos: QoS,
+	pub retain: bool,
+
+	/// MQTT v5 properties, sent if the client connected with MQTT v5.
+	#[cfg(feature = "v5")]
+	pub properties: crate::packets::PublishProperties,
+}
+
+impl PublishOptions {
+	/// Creates options with `qos` and `retain`, without any properties.
+	#[inline]
+	pub fn new(qos: QoS, retain: bool) -> Self {
+		Self {
+			qos,
+			retain,
+			#[cfg(feature = "v5")]
+			properties: Default::default(),
+		}
+	}
+}
+
+/// A future which resolves when a message queued by
+/// [`Client::publish_deferred`] has been delivered.
+///
+/// With a QoS of [`AtMostOnce`] this is when the packet has been written to the
+/// transport stream, with [`AtLeastOnce`] when the [`PubAck`] has been
+/// received, and with [`ExactlyOnce`] when the [`PubComp`] has been received.
+///
+/// [`AtMostOnce`]: crate::QoS#variant.AtMostOnce
+/// [`AtLeastOnce`]: crate::QoS#variant.AtLeastOnce
+/// [`ExactlyOnce`]: crate::QoS#variant.ExactlyOnce
+/// [`PubAck`]: crate::packets::PubAck
+/// [`PubComp`]: crate::packets::PubComp
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct PublishAck(oneshot::Receiver<Result<(), ClientError>>);
+
+impl Future for PublishAck {
+	type Output = Result<(), ClientError>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		Pin::new(&mut self.0)
+			.poll(cx)
+			.map(|result| result.unwrap_or(Err(ClientError::ClientTaskClosed)))
+	}
+}
+
+impl Drop for TaskGuard {
+	fn drop(&mut self) {
+		// Every clone and Subscription has been dropped, so nothing can use the
+		// connection.
+		if !*self.shut_down.get_mut() {
+			let (response, _) = oneshot::channel();
+			self.tx
+				.send_detached(Command::Shutdown(ShutdownCommand { response }).into());
+		}
+	}
+}
+
 impl<T> From<mpsc::error::SendError<T>> for ClientError {
 	fn from(_: mpsc::error::SendError<T>) -> Self {
 		Self::ClientTaskClosed
@@ -220,6 +956,13 @@ impl From<oneshot::error::RecvError> for ClientError {
 	}
 }
 
+impl From<InvalidTopicString> for ClientError {
+	fn from(value: InvalidTopicString) -> Self {
+		let InvalidTopicString { topic, source } = value;
+		Self::InvalidTopic { topic, source }
+	}
+}
+
 impl From<convert::Infallible> for ClientError {
 	fn from(_: convert::Infallible) -> Self {
 		unreachable!("infallible conversions cannot fail")
@@ -1,25 +1,145 @@
-use super::{ClientError, CommandTx};
+use super::{ClientError, TaskGuard};
 use crate::{
 	clients::{
-		command::{Command, UnsubscribeCommand},
-		tokio::PublishRx,
+		command::{Command, SubscribeCommand, UnsubscribeCommand},
+		tokio::{publish_channel::WeakPublishTx, PublishRx},
 		Message,
 	},
 	FilterBuf, QoS,
 };
-use tokio::sync::oneshot;
+use futures_core::Stream;
+use std::{
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+	time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+	sync::oneshot::{self, error::TryRecvError},
+	time::{self, error::Elapsed},
+};
+
+/// Receives the filters granted by the Server in response to a Subscribe
+/// request.
+pub(crate) type SubscribeRx = oneshot::Receiver<Result<Vec<(FilterBuf, QoS)>, ClientError>>;
+
+/// Returned by the stream from [`Subscription::messages`] when messages have
+/// been dropped because the Subscription was full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Error)]
+#[error("subscription lagged, {skipped} messages skipped")]
+pub struct Lagged {
+	/// The number of messages dropped since the last item.
+	pub skipped: u64,
+}
 
 /// A subscription to one or more topics.
+///
+/// Messages on the same topic are received in the order the Server sent them,
+/// whatever their QoS; ExactlyOnce messages are delivered when they arrive,
+/// not when the Server releases them. There is no ordering guarantee between
+/// topics, or between different Subscriptions.
+///
+/// A Subscription survives reconnects. If the Server does not resume the
+/// session, the client task subscribes to the filters again on the new
+/// connection, and delivers messages to the same Subscription; requests which
+/// were not acknowledged before the connection was lost are re-sent. Messages
+/// published while the client was disconnected are only received if the
+/// session is resumed. If the Server rejects the filters, or does not respond
+/// within the [`subscribe_timeout`], they are removed, and [`recv`] returns
+/// `None` once no filters remain.
+///
+/// [`subscribe_timeout`]: crate::clients::tokio::Options::subscribe_timeout
+/// [`recv`]: Subscription::recv
 #[derive(Debug)]
 pub struct Subscription {
-	tx: CommandTx,
+	// Keeps the client task running while the Subscription is live, even once
+	// every Client has been dropped.
+	task: Arc<TaskGuard>,
 	rx: PublishRx,
 	filters: Vec<(FilterBuf, QoS)>,
+
+	// The sending half of the channel, for subscribing to more filters. This
+	// must not keep the channel open, so `recv` returns `None` once the client
+	// task drops its senders.
+	channel: WeakPublishTx,
+
+	// The response to a Subscribe request sent by `Client::subscribe_nowait`,
+	// and the number of filters at the start of `filters` it requested.
+	pending: Option<(SubscribeRx, usize)>,
 }
 
 impl Subscription {
-	pub(crate) fn new(filters: Vec<(FilterBuf, QoS)>, rx: PublishRx, tx: CommandTx) -> Self {
-		Self { tx, rx, filters }
+	pub(super) fn new(
+		filters: Vec<(FilterBuf, QoS)>,
+		channel: WeakPublishTx,
+		rx: PublishRx,
+		task: Arc<TaskGuard>,
+	) -> Self {
+		Self {
+			task,
+			rx,
+			filters,
+			channel,
+			pending: None,
+		}
+	}
+
+	/// Sets the response to the Subscribe request for the Subscription's
+	/// filters, which has not been received yet.
+	pub(crate) fn with_pending(mut self, pending: SubscribeRx) -> Self {
+		self.pending = Some((pending, self.filters.len()));
+		self
+	}
+
+	/// Waits for the Server to acknowledge the filters of a Subscription
+	/// returned by [`Client::subscribe_nowait`], returning the filters with the
+	/// QoS granted by the Server.
+	///
+	/// Filters rejected by the Server are removed from the Subscription. If the
+	/// filters have already been acknowledged, this returns immediately.
+	///
+	/// [`Client::subscribe_nowait`]: super::Client::subscribe_nowait
+	pub async fn subscribed(&mut self) -> Result<&[(FilterBuf, QoS)], ClientError> {
+		if let Some((pending, requested)) = self.pending.take() {
+			self.apply_response(pending.await, requested)?;
+		}
+		Ok(&self.filters)
+	}
+
+	/// Applies the response to a pending Subscribe request, if it has been
+	/// received.
+	fn update_pending(&mut self) {
+		let Some((pending, requested)) = &mut self.pending else {
+			return;
+		};
+		let response = match pending.try_recv() {
+			Err(TryRecvError::Empty) => return,
+			Err(TryRecvError::Closed) => Err(ClientError::ClientTaskClosed),
+			Ok(response) => response,
+		};
+		let requested = *requested;
+		self.pending = None;
+		let _ = self.apply_response(Ok(response), requested);
+	}
+
+	fn apply_response(
+		&mut self,
+		response: Result<Result<Vec<(FilterBuf, QoS)>, ClientError>, oneshot::error::RecvError>,
+		requested: usize,
+	) -> Result<(), ClientError> {
+		match response? {
+			Ok(granted) => {
+				self.filters.splice(..requested, granted);
+				Ok(())
+			}
+			Err(error) => {
+				// The filters were not subscribed, so there is nothing to
+				// unsubscribe from.
+				self.filters.drain(..requested);
+				Err(error)
+			}
+		}
 	}
 
 	/// Receive the next message from the Subscription.
@@ -38,6 +158,7 @@ impl Subscription {
 	/// ```
 	#[inline]
 	pub async fn recv(&mut self) -> Option<Message> {
+		self.update_pending();
 		let Some(next_message) = self.rx.recv().await else {
 			// All the matching senders for the channel have been closed or dropped.
 			//
@@ -49,6 +170,134 @@ impl Subscription {
 		Some(next_message)
 	}
 
+	/// Receive the next message from the Subscription, waiting at most
+	/// `timeout`.
+	///
+	/// Returns `Err(Elapsed)` if no message is received before the timeout
+	/// elapses.
+	///
+	/// # Example
+	/// ```no_run
+	/// # tokio_test::block_on(async {
+	/// # use std::time::Duration;
+	/// # use tjh_mqtt::clients::tokio;
+	/// # let (client, handle) = tokio::tcp_client(("localhost", 1883));
+	/// let mut subscription = client.subscribe("a/b", 2).await.unwrap();
+	/// match subscription.recv_timeout(Duration::from_secs(1)).await {
+	/// 	Ok(Some(message)) => println!("{}: {:?}", &message.topic, &message.payload[..]),
+	/// 	Ok(None) => println!("subscription closed"),
+	/// 	Err(_) => println!("no message received"),
+	/// }
+	/// # });
+	/// ```
+	#[inline]
+	pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Message>, Elapsed> {
+		time::timeout(timeout, self.recv()).await
+	}
+
+	/// Waits for the retained messages sent by the Server after subscribing,
+	/// returning every message received in the meantime.
+	///
+	/// This first waits for the Server to acknowledge the filters, as with
+	/// [`subscribed`](Self::subscribed). It then receives messages until no
+	/// retained message has arrived for `quiet`, or the Subscription is closed.
+	/// Messages which are not retained are returned in order with the retained
+	/// ones, but do not extend the wait.
+	///
+	/// This is a heuristic. MQTT v3.1.1 does not mark the end of the retained
+	/// messages, so a slow Server may send more after `quiet` has elapsed, and
+	/// a message retained by another client in the meantime is included.
+	///
+	/// # Example
+	/// ```no_run
+	/// # tokio_test::block_on(async {
+	/// # use std::time::Duration;
+	/// # use tjh_mqtt::clients::tokio;
+	/// # let (client, handle) = tokio::tcp_client(("localhost", 1883));
+	/// let mut subscription = client.subscribe("sensors/#", 64).await.unwrap();
+	/// let messages = subscription
+	/// 	.retained_complete(Duration::from_millis(200))
+	/// 	.await
+	/// 	.unwrap();
+	/// let retained = messages.iter().filter(|message| message.retain).count();
+	/// println!("{retained} retained messages");
+	/// # });
+	/// ```
+	pub async fn retained_complete(
+		&mut self,
+		quiet: Duration,
+	) -> Result<Vec<Message>, ClientError> {
+		self.subscribed().await?;
+
+		let mut messages = Vec::new();
+		let sleep = time::sleep(quiet);
+		tokio::pin!(sleep);
+		loop {
+			tokio::select! {
+				message = self.recv() => {
+					let Some(message) = message else {
+						break;
+					};
+					if message.retain {
+						sleep.as_mut().reset(time::Instant::now() + quiet);
+					}
+					messages.push(message);
+				}
+				_ = &mut sleep => break,
+			}
+		}
+		Ok(messages)
+	}
+
+	/// Subscribes to an additional `filter`, delivering matching messages to
+	/// this Subscription.
+	///
+	/// This will send a 'Subscribe' packet to the Server, and won't return
+	/// until a corresponding 'SubAck' packet has been received. If the Server
+	/// rejects the filter, it is not added to the Subscription.
+	///
+	/// Returns [`ClientError::ClientTaskClosed`] if the Subscription has been
+	/// closed, that is, [`recv`](Self::recv) has returned `None`.
+	///
+	/// # Example
+	/// ```no_run
+	/// # tokio_test::block_on(async {
+	/// # use tjh_mqtt::{clients::tokio, QoS};
+	/// # let (client, handle) = tokio::tcp_client(("localhost", 1883));
+	/// let mut subscription = client.subscribe("a/b", 2).await.unwrap();
+	/// subscription.add_filter("c/d", QoS::AtLeastOnce).await.unwrap();
+	/// # });
+	/// ```
+	#[tracing::instrument(skip(filter), ret, err)]
+	pub async fn add_filter<F, E>(&mut self, filter: F, qos: QoS) -> Result<(), ClientError>
+	where
+		F: TryInto<FilterBuf, Error = E>,
+		ClientError: From<E>,
+	{
+		let filter = filter.try_into()?;
+		let channel = self
+			.channel
+			.upgrade()
+			.ok_or(ClientError::ClientTaskClosed)?;
+
+		let (response, response_rx) = oneshot::channel();
+		self.task
+			.tx
+			.send(
+				Command::Subscribe(SubscribeCommand {
+					filters: vec![(filter, qos)],
+					channel,
+					response,
+				})
+				.into(),
+			)
+			.await?;
+
+		let subscribed_filters = response_rx.await??;
+		self.filters.extend(subscribed_filters);
+		Ok(())
+	}
+
 	/// Unsubscribe all the filters associated with the Subscription.
 	///
 	/// This will send an 'Unsubscribe' packet to the Server, and won't return
@@ -60,26 +309,91 @@ impl Subscription {
 		// Drain the filters from the Subscription. This will eliminate copying
 		// and prevent the Drop impl from doing anything.
 		let filters = self.filters.drain(..).map(|(f, _)| f).collect();
-		self.tx
-			.send(Command::Unsubscribe(UnsubscribeCommand { filters, response }).into())?;
+		self.task
+			.tx
+			.send(Command::Unsubscribe(UnsubscribeCommand { filters, response }).into())
+			.await?;
 
-		response_rx.await?;
+		response_rx.await??;
 		Ok(())
 	}
 
+	/// Returns the number of messages dropped because the Subscription was full.
+	///
+	/// Messages are only dropped with [`Overflow::Drop`].
+	///
+	/// [`Overflow::Drop`]: crate::clients::tokio::Overflow::Drop
+	#[inline]
+	pub fn dropped_count(&self) -> u64 {
+		self.channel.dropped_count()
+	}
+
+	/// Converts the Subscription into a [`Stream`] of messages.
+	///
+	/// Unlike [`recv`](Self::recv), messages dropped because the Subscription
+	/// was full are not lost silently. The stream yields [`Lagged`] with the
+	/// number of dropped messages before the next message. Messages are only
+	/// dropped with [`Overflow::Drop`].
+	///
+	/// [`Overflow::Drop`]: crate::clients::tokio::Overflow::Drop
+	pub fn messages(self) -> impl Stream<Item = Result<Message, Lagged>> {
+		Messages {
+			subscription: self,
+			reported: 0,
+		}
+	}
+
 	/// Returns a slice of the Filters associated with the Subscription.
+	///
+	/// For a Subscription returned by [`Client::subscribe_nowait`], this
+	/// includes the requested filters and QoS until the Server's response is
+	/// applied, see [`subscribed`](Self::subscribed).
+	///
+	/// [`Client::subscribe_nowait`]: super::Client::subscribe_nowait
 	#[inline]
 	pub fn filters(&self) -> &[(FilterBuf, QoS)] {
 		&self.filters
 	}
 }
 
+/// The stream returned by [`Subscription::messages`].
+struct Messages {
+	subscription: Subscription,
+	// The dropped count already reported with `Lagged`.
+	reported: u64,
+}
+
+impl Stream for Messages {
+	type Item = Result<Message, Lagged>;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let dropped = self.subscription.dropped_count();
+		if dropped > self.reported {
+			let skipped = dropped - self.reported;
+			self.reported = dropped;
+			return Poll::Ready(Some(Err(Lagged { skipped })));
+		}
+
+		let subscription = &mut self.subscription;
+		subscription.update_pending();
+		match subscription.rx.poll_recv(cx) {
+			Poll::Ready(Some(message)) => Poll::Ready(Some(Ok(message))),
+			Poll::Ready(None) => {
+				// As in `recv`, drain the filters so the Drop impl does nothing.
+				subscription.filters.drain(..);
+				Poll::Ready(None)
+			}
+			Poll::Pending => Poll::Pending,
+		}
+	}
+}
+
 impl Drop for Subscription {
 	#[inline]
 	fn drop(&mut self) {
 		if !self.filters.is_empty() {
 			let (tx, _) = oneshot::channel();
-			let _ = self.tx.send(
+			self.task.tx.send_detached(
 				Command::Unsubscribe(UnsubscribeCommand {
 					filters: self.filters.drain(..).map(|(f, _)| f).collect(),
 					response: tx,
@@ -89,3 +403,91 @@ impl Drop for Subscription {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{Lagged, Subscription, TaskGuard};
+	use crate::{
+		clients::{
+			tokio::{command_channel, publish_channel, Overflow},
+			Message,
+		},
+		QoS, TopicBuf,
+	};
+	use futures_core::Stream;
+	use std::{
+		future::poll_fn,
+		pin::{pin, Pin},
+		time::Duration,
+	};
+
+	fn message(topic: &str) -> Message {
+		Message::new(TopicBuf::new(topic).unwrap(), "payload")
+	}
+
+	async fn next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+		poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+	}
+
+	#[tokio::test]
+	async fn messages_reports_lag() {
+		let (command_tx, _command_rx) = command_channel::channel(None);
+		let (tx, rx) = publish_channel::channel(1, Overflow::Drop);
+		let subscription =
+			Subscription::new(Vec::new(), tx.downgrade(), rx, TaskGuard::new(command_tx));
+		let mut messages = pin!(subscription.messages());
+
+		// The channel holds one message; the next two are dropped.
+		for topic in ["a/1", "a/2", "a/3"] {
+			tx.send(message(topic)).await.unwrap();
+		}
+
+		assert_eq!(
+			next(&mut messages).await.unwrap().unwrap_err(),
+			Lagged { skipped: 2 }
+		);
+		assert_eq!(next(&mut messages).await.unwrap().unwrap().topic, "a/1");
+
+		tx.send(message("a/4")).await.unwrap();
+		assert_eq!(next(&mut messages).await.unwrap().unwrap().topic, "a/4");
+
+		drop(tx);
+		assert!(next(&mut messages).await.is_none());
+	}
+
+	#[tokio::test]
+	async fn recv_timeout() {
+		let (command_tx, _command_rx) = command_channel::channel(None);
+		let (tx, rx) = publish_channel::channel(1, Overflow::Wait);
+		let mut subscription =
+			Subscription::new(Vec::new(), tx.downgrade(), rx, TaskGuard::new(command_tx));
+
+		assert!(subscription
+			.recv_timeout(Duration::from_millis(10))
+			.await
+			.is_err());
+
+		tx.send(Message {
+			topic: TopicBuf::new("a/b").unwrap(),
+			qos: QoS::AtMostOnce,
+			retain: false,
+			payload: "payload".into(),
+		})
+		.await
+		.unwrap();
+
+		let message = subscription
+			.recv_timeout(Duration::from_millis(10))
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(message.topic, "a/b");
+
+		drop(tx);
+		assert!(subscription
+			.recv_timeout(Duration::from_millis(10))
+			.await
+			.unwrap()
+			.is_none());
+	}
+}
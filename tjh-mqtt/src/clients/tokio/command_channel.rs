@@ -0,0 +1,93 @@
+use super::Command;
+use tokio::{runtime::Handle, sync::mpsc};
+
+type SendError = mpsc::error::SendError<Box<Command>>;
+
+/// Sending half of the channel used to pass commands to the client task.
+///
+/// The channel may be bounded, in which case senders wait for capacity, or
+/// unbounded.
+#[derive(Clone, Debug)]
+pub(crate) enum CommandTx {
+	Bounded(mpsc::Sender<Box<Command>>),
+	Unbounded(mpsc::UnboundedSender<Box<Command>>),
+}
+
+/// Receiving half of the channel used to pass commands to the client task.
+#[derive(Debug)]
+pub(crate) enum CommandRx {
+	Bounded(mpsc::Receiver<Box<Command>>),
+	Unbounded(mpsc::UnboundedReceiver<Box<Command>>),
+}
+
+/// Creates a command channel. If `capacity` is `None` the channel is
+/// unbounded.
+pub(crate) fn channel(capacity: Option<usize>) -> (CommandTx, CommandRx) {
+	match capacity {
+		Some(capacity) => {
+			let (tx, rx) = mpsc::channel(capacity);
+			(CommandTx::Bounded(tx), CommandRx::Bounded(rx))
+		}
+		None => {
+			let (tx, rx) = mpsc::unbounded_channel();
+			(CommandTx::Unbounded(tx), CommandRx::Unbounded(rx))
+		}
+	}
+}
+
+impl CommandTx {
+	/// Sends a command to the client task, waiting for capacity if the channel
+	/// is bounded.
+	pub async fn send(&self, command: Box<Command>) -> Result<(), SendError> {
+		match self {
+			Self::Bounded(tx) => tx.send(command).await,
+			Self::Unbounded(tx) => tx.send(command),
+		}
+	}
+
+	/// Sends a command to the client task without waiting.
+	///
+	/// If the channel is bounded and full, the command is sent from a new task
+	/// on the current runtime. This is intended for use in `Drop` impls.
+	pub fn send_detached(&self, command: Box<Command>) {
+		match self {
+			Self::Bounded(tx) => {
+				if let Err(mpsc::error::TrySendError::Full(command)) = tx.try_send(command) {
+					if let Ok(handle) = Handle::try_current() {
+						let tx = tx.clone();
+						handle.spawn(async move { tx.send(command).await });
+					}
+				}
+			}
+			Self::Unbounded(tx) => {
+				let _ = tx.send(command);
+			}
+		}
+	}
+}
+
+impl CommandRx {
+	pub async fn recv(&mut self) -> Option<Box<Command>> {
+		match self {
+			Self::Bounded(rx) => rx.recv().await,
+			Self::Unbounded(rx) => rx.recv().await,
+		}
+	}
+
+	/// Closes the channel. Commands which have already been sent can still be
+	/// received.
+	pub fn close(&mut self) {
+		match self {
+			Self::Bounded(rx) => rx.close(),
+			Self::Unbounded(rx) => rx.close(),
+		}
+	}
+
+	/// Receives a command without waiting, if one is available.
+	pub fn try_recv(&mut self) -> Option<Box<Command>> {
+		match self {
+			Self::Bounded(rx) => rx.try_recv().ok(),
+			Self::Unbounded(rx) => rx.try_recv().ok(),
+		}
+	}
+}
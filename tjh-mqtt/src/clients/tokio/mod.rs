@@ -1,34 +1,62 @@
+mod builder;
 mod client;
+mod command_channel;
 mod mqtt_stream;
+mod packet_sink;
 mod packet_stream;
+mod publish_channel;
 mod task;
 
-use super::{holdoff::HoldOff, ClientState, Message, StateError};
+#[cfg(test)]
+mod tests;
+
+use super::{holdoff::HoldOff, ClientState, StateError};
+#[cfg(feature = "v5")]
+use crate::clients::Authenticator;
 use crate::{
-	clients::tokio::mqtt_stream::MqttStream,
-	misc::{Credentials, Will},
-	packets, FilterBuf, QoS,
+	clients::{tokio::mqtt_stream::MqttStream, SessionStore},
+	misc::{self, Credentials, Will},
+	packets::{self, OwnedPacket},
+	FilterBuf, Packet, ParseMode, QoS,
+};
+use bytes::BytesMut;
+use std::{
+	io,
+	ops::ControlFlow::{Break, Continue},
+	sync::{atomic::AtomicBool, Arc, Mutex},
+	time::Duration,
 };
-use std::{ops::ControlFlow::Break, time::Duration};
 use tokio::{
+	io::AsyncRead,
 	net::TcpStream,
-	sync::{mpsc, oneshot},
+	sync::{broadcast, oneshot},
 	task::JoinHandle,
+	time::Instant,
 };
 
-pub use client::{Client, Subscription};
+pub use client::{
+	Client, ClientError, ConnAckInfo, ConnectionEvent, ConnectionStatus, Lagged, ProtocolError,
+	PublishAck, PublishOptions, Subscription,
+};
 
-pub type PublishTx = mpsc::Sender<Message>;
-pub type PublishRx = mpsc::Receiver<Message>;
+pub use builder::{ClientBuilder, UrlError};
+pub use packet_sink::PacketSink;
+pub use publish_channel::{Overflow, PublishRx, PublishTx};
 
 type Command = super::command::Command<
-	mpsc::Sender<Message>,
+	PublishTx,
+	oneshot::Sender<Result<(), ClientError>>,
+	oneshot::Sender<Result<Vec<(FilterBuf, QoS)>, ClientError>>,
+	oneshot::Sender<Result<(), ClientError>>,
 	oneshot::Sender<()>,
-	oneshot::Sender<Vec<(FilterBuf, QoS)>>,
 	oneshot::Sender<()>,
+	oneshot::Sender<usize>,
 >;
-type CommandTx = mpsc::UnboundedSender<Box<Command>>;
-type CommandRx = mpsc::UnboundedReceiver<Box<Command>>;
+use command_channel::{CommandRx, CommandTx};
+use task::Reconnect;
+
+/// The number of [`ConnectionEvent`]s buffered for each receiver.
+const EVENT_CHANNEL_CAPACITY: usize = 16;
 
 #[derive(Debug)]
 pub struct Options<'a> {
@@ -36,10 +64,147 @@ pub struct Options<'a> {
 	pub port: u16,
 	pub tls: bool,
 	pub keep_alive: u16,
+
+	/// The fraction by which the interval between PingReq packets is randomly
+	/// shortened, up to `0.5`. Defaults to `0.05`.
+	///
+	/// This spreads out the PingReq packets from many clients with the same
+	/// keep alive. The interval is never longer than `keep_alive`.
+	pub keep_alive_jitter: f64,
+
 	pub clean_session: bool,
+
+	/// Whether the Server accepts client identifiers longer than
+	/// [`MAX_CLIENT_ID_LEN`] bytes.
+	///
+	/// Most Servers do, but MQTT v3.1.1 only requires them to accept 23 bytes.
+	/// If unset, longer identifiers are rejected by [`Options::validate`].
+	///
+	/// [`MAX_CLIENT_ID_LEN`]: misc::MAX_CLIENT_ID_LEN
+	pub long_client_id: bool,
+
+	/// Disable Nagle's algorithm on the connection, so small packets such as
+	/// acknowledgements are sent immediately rather than coalesced.
+	pub tcp_nodelay: bool,
+
+	/// The client identifier to connect with.
+	///
+	/// If empty and `clean_session` is set, an identifier is generated with
+	/// [`Options::generate_client_id`].
 	pub client_id: String,
+
+	/// Seed for generated client identifiers. If `None`, a random seed is used.
+	pub client_id_seed: Option<u64>,
+
 	pub credentials: Option<Credentials<'a>>,
 	pub will: Option<Will<'a>>,
+
+	/// Index active subscriptions with a [`FilterTrie`] to route incoming
+	/// messages. This is beneficial with a large number of subscriptions.
+	///
+	/// [`FilterTrie`]: crate::routing::FilterTrie
+	pub filter_trie: bool,
+
+	/// The number of commands which can be queued for the client task.
+	///
+	/// If `None`, the queue is unbounded. Otherwise, once the queue is full,
+	/// calls on the [`Client`] wait for the client task to catch up.
+	pub command_channel_capacity: Option<usize>,
+
+	/// Close the connection after a period with no commands from the
+	/// [`Client`] or packets from the Server, other than keep alives.
+	///
+	/// The client reconnects when the next command is received. Active
+	/// subscriptions are preserved.
+	pub idle_timeout: Option<Duration>,
+
+	/// Suppress redeliveries of incoming AtLeastOnce messages, remembering this
+	/// many of the most recent ones.
+	///
+	/// The Server redelivers a message if the PubAck from the client is lost.
+	/// Redeliveries are still acknowledged, but not delivered to the
+	/// [`Subscription`] again. If `None`, every message is delivered.
+	pub duplicate_cache: Option<usize>,
+
+	/// How long to wait for the Server to accept the connection, including any
+	/// enhanced authentication exchange, before reconnecting.
+	pub handshake_timeout: Duration,
+
+	/// How long to wait for the Server to acknowledge a subscribe or unsubscribe
+	/// request.
+	///
+	/// Pending requests are checked every keep alive interval, however often
+	/// the client sends packets; requests which have timed out fail with
+	/// [`ClientError::Timeout`].
+	pub subscribe_timeout: Duration,
+
+	/// How long to keep re-sending an unacknowledged AtLeastOnce or ExactlyOnce
+	/// Publish packet once nothing is waiting for it to be acknowledged, that
+	/// is, once the future returned by [`Client::publish`] or the
+	/// [`PublishAck`] has been dropped.
+	///
+	/// Abandoned packets are checked every keep alive interval. Packets which
+	/// are still awaited are kept until they are acknowledged.
+	pub abandoned_publish_timeout: Duration,
+
+	/// The number of consecutive connection attempts after which the client
+	/// task gives up, or `None` to retry forever.
+	///
+	/// The count is reset whenever the Server accepts a connection. When the
+	/// client task gives up, pending requests fail with
+	/// [`ClientError::ConnectionLost`], and the task returns the same error.
+	pub max_reconnect_attempts: Option<u32>,
+
+	/// What to do with incoming messages when a [`Subscription`] is full.
+	pub overflow: Overflow,
+
+	/// Send a Disconnect packet before closing the connection when the Server
+	/// violates the protocol. With MQTT v5, the Disconnect packet has the
+	/// reason code `0x82`, "Protocol Error".
+	///
+	/// With MQTT v3.1.1, the Server discards the will message when it receives
+	/// a Disconnect packet, so this is unset by default. Either way, a
+	/// [`ConnectionEvent::ProtocolError`] is sent before reconnecting.
+	pub disconnect_on_protocol_error: bool,
+
+	/// How strictly packets from the Server are parsed.
+	///
+	/// With [`ParseMode::Lenient`], common deviations from the specification
+	/// are accepted rather than treated as protocol errors.
+	pub parse_mode: ParseMode,
+
+	/// Persist Publish packets with a QoS of AtLeastOnce or ExactlyOnce until
+	/// they are acknowledged, so they are re-sent after a restart.
+	///
+	/// This should be used with `clean_session` unset, so the Server also keeps
+	/// the session.
+	pub session_store: Option<Box<dyn SessionStore>>,
+
+	/// The version of MQTT to connect with. Defaults to MQTT v3.1.1.
+	///
+	/// With [`ProtocolVersion::V5`], every packet is encoded as MQTT v5. The
+	/// Server refusing the version fails the client task with
+	/// [`ClientError::UnsupportedProtocolVersion`].
+	///
+	/// [`ProtocolVersion::V5`]: packets::ProtocolVersion::V5
+	#[cfg(feature = "v5")]
+	pub protocol_version: packets::ProtocolVersion,
+
+	/// Use MQTT v5 enhanced authentication when connecting.
+	///
+	/// If set, the Connect packet includes the authentication method and data
+	/// from the authenticator. This requires `protocol_version` to be MQTT v5.
+	#[cfg(feature = "v5")]
+	pub authenticator: Option<Box<dyn Authenticator>>,
+
+	/// The number of incoming ExactlyOnce messages the client will hold before
+	/// the Server releases them, sent in the Connect packet. This requires
+	/// `protocol_version` to be MQTT v5.
+	///
+	/// A Server which sends more is treated as violating the protocol. If
+	/// `None`, the Server may send up to 65,535.
+	#[cfg(feature = "v5")]
+	pub receive_maximum: Option<u16>,
 }
 
 impl<'a> Default for Options<'a> {
@@ -49,10 +214,32 @@ impl<'a> Default for Options<'a> {
 			port: 1883,
 			tls: false,
 			keep_alive: 60,
+			keep_alive_jitter: 0.05,
 			clean_session: true,
+			long_client_id: true,
+			tcp_nodelay: true,
 			client_id: Default::default(),
+			client_id_seed: None,
 			credentials: Default::default(),
 			will: Default::default(),
+			filter_trie: false,
+			command_channel_capacity: None,
+			idle_timeout: None,
+			duplicate_cache: None,
+			handshake_timeout: Duration::from_secs(30),
+			subscribe_timeout: Duration::from_secs(30),
+			abandoned_publish_timeout: Duration::from_secs(30),
+			max_reconnect_attempts: None,
+			overflow: Overflow::Wait,
+			disconnect_on_protocol_error: false,
+			parse_mode: ParseMode::Strict,
+			session_store: None,
+			#[cfg(feature = "v5")]
+			protocol_version: Default::default(),
+			#[cfg(feature = "v5")]
+			authenticator: None,
+			#[cfg(feature = "v5")]
+			receive_maximum: None,
 		}
 	}
 }
@@ -69,11 +256,119 @@ impl<'a, H: AsRef<str>> From<(H, u16)> for Options<'a> {
 	}
 }
 
+/// An error returned when [`Options`] cannot be used to connect to a Server.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+	#[error("client identifier cannot be empty unless clean_session is set")]
+	EmptyClientId,
+	#[error("client identifier exceeds the maximum length accepted by the Server")]
+	ClientIdTooLong,
+	#[error("keep alive cannot be zero")]
+	ZeroKeepAlive,
+	#[error("will payload cannot exceed maximum length (65,535 bytes)")]
+	WillPayloadTooLong,
+	#[error("TLS requires the `tls` feature")]
+	TlsNotSupported,
+	#[error("{0:?} is not a valid server name for TLS")]
+	InvalidServerName(String),
+	#[cfg(feature = "v5")]
+	#[error("{0} requires protocol_version to be MQTT v5")]
+	RequiresV5(&'static str),
+	#[cfg(feature = "v5")]
+	#[error("receive maximum cannot be zero")]
+	ZeroReceiveMaximum,
+}
+
+impl Options<'_> {
+	/// Checks the options can be used to connect to a Server.
+	///
+	/// The will topic does not need to be checked, as a [`Topic`] is always
+	/// valid.
+	///
+	/// [`Topic`]: crate::Topic
+	pub fn validate(&self) -> Result<(), ConfigError> {
+		// The Server can only assign an identifier to a new session.
+		if self.client_id.is_empty() && !self.clean_session {
+			return Err(ConfigError::EmptyClientId);
+		}
+
+		let max_client_id_len = match self.long_client_id {
+			true => u16::MAX as usize,
+			false => misc::MAX_CLIENT_ID_LEN,
+		};
+		if self.client_id.len() > max_client_id_len {
+			return Err(ConfigError::ClientIdTooLong);
+		}
+
+		// The client task relies on PingReq packets to detect a broken connection.
+		if self.keep_alive == 0 {
+			return Err(ConfigError::ZeroKeepAlive);
+		}
+
+		#[cfg(feature = "v5")]
+		if self.protocol_version != packets::ProtocolVersion::V5 {
+			if self.authenticator.is_some() {
+				return Err(ConfigError::RequiresV5("authenticator"));
+			}
+			if self.receive_maximum.is_some() {
+				return Err(ConfigError::RequiresV5("receive_maximum"));
+			}
+		}
+
+		#[cfg(feature = "v5")]
+		if self.receive_maximum == Some(0) {
+			return Err(ConfigError::ZeroReceiveMaximum);
+		}
+
+		if let Some(will) = &self.will {
+			if will.payload.len() > u16::MAX as usize {
+				return Err(ConfigError::WillPayloadTooLong);
+			}
+		}
+
+		if self.tls {
+			#[cfg(not(feature = "tls"))]
+			return Err(ConfigError::TlsNotSupported);
+			#[cfg(feature = "tls")]
+			tls::server_name(&self.host)?;
+		}
+
+		Ok(())
+	}
+
+	/// Generates a client identifier, seeded with `client_id_seed`.
+	///
+	/// See [`misc::generate_client_id`].
+	#[inline]
+	pub fn generate_client_id(&self) -> String {
+		misc::generate_client_id(self.client_id_seed)
+	}
+}
+
 pub fn tcp_client<'o>(
 	options: impl Into<Options<'o>>,
 ) -> (client::Client, JoinHandle<crate::Result<()>>) {
-	let (tx, mut rx) = mpsc::unbounded_channel();
-	let options = options.into();
+	let mut options = options.into();
+
+	// Don't attempt to connect with options the Server would refuse.
+	if let Err(error) = options.validate() {
+		let (tx, _) = command_channel::channel(None);
+		let client = client::Client::new(
+			tx,
+			Default::default(),
+			Default::default(),
+			Arc::new(Mutex::new(client::Attempt::Disconnected)),
+			options.overflow,
+			broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+			options.client_id.as_str().into(),
+		);
+		return (client, tokio::spawn(async move { Err(error.into()) }));
+	}
+
+	if options.client_id.is_empty() && options.clean_session {
+		options.client_id = options.generate_client_id();
+	}
+	let (tx, mut rx) = command_channel::channel(options.command_channel_capacity);
 
 	let keep_alive = Duration::from_secs(options.keep_alive.into());
 
@@ -86,60 +381,206 @@ pub fn tcp_client<'o>(
 		will: options.will,
 		..Default::default()
 	};
+	#[cfg(feature = "v5")]
+	let connect = connect.with_protocol_version(options.protocol_version);
+
+	// Servers may still accept the client ID, so only warn about it.
+	if let Err(error) = connect.validate() {
+		tracing::warn!(%error, "Server may reject the client ID");
+	}
+
+	let connected = Arc::new(AtomicBool::new(false));
+	let client_connected = Arc::clone(&connected);
+	let last_connack = Arc::new(Mutex::new(None));
+	let client_last_connack = Arc::clone(&last_connack);
+	let attempt = Arc::new(Mutex::new(client::Attempt::Connecting));
+	let client_attempt = Arc::clone(&attempt);
+	let client_id = Arc::from(options.client_id.as_str());
+	let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+	let client_events = events.clone();
 
 	let mut state = ClientState::new(&connect);
+	state.use_filter_trie(options.filter_trie);
+	state.use_duplicate_cache(options.duplicate_cache);
+	#[cfg(feature = "v5")]
+	{
+		state.authenticator = options.authenticator.take();
+		state.receive_maximum = options.receive_maximum;
+	}
 
 	let handle = tokio::spawn(async move {
-		state.keep_alive = keep_alive;
-
-		let mut reconnect_delay = HoldOff::new(Duration::from_millis(75)..keep_alive);
-		loop {
-			reconnect_delay
-				.wait_and_increase_with_async(|delay| delay * 2)
-				.await;
+		let set_attempt = |value| *attempt.lock().unwrap() = value;
+		let result = async {
+			state.keep_alive = keep_alive;
+			state.keep_alive_jitter = options.keep_alive_jitter;
+			state.idle_timeout = options.idle_timeout;
+			state.handshake_timeout = options.handshake_timeout;
+			state.subscribe_timeout = options.subscribe_timeout;
+			state.abandoned_publish_timeout = options.abandoned_publish_timeout;
+			state.disconnect_on_protocol_error = options.disconnect_on_protocol_error;
+			state.parse_mode = options.parse_mode;
+			if let Some(store) = options.session_store {
+				state.use_session_store(store)?;
+			}
 
-			// Open the the connection to the broker.
-			let Ok(stream) = TcpStream::connect((options.host.as_str(), options.port)).await else {
-				continue;
+			let mut reconnect_delay = HoldOff::new(Duration::from_millis(75)..keep_alive);
+			let mut pending = None;
+			let shared = task::Shared {
+				connected: &connected,
+				last_connack: &last_connack,
+				events: &events,
 			};
-			stream.set_linger(Some(keep_alive))?;
-			let mut connection = match options.tls {
-				#[cfg(feature = "tls")]
-				true => {
-					use std::sync::Arc;
-					use tokio_rustls::{rustls::ServerName, TlsConnector};
-
-					let config = tls::configure_tls();
-					let connector = TlsConnector::from(Arc::clone(&config));
-					let dnsname = ServerName::try_from(options.host.as_str()).unwrap();
-
-					let stream = connector.connect(dnsname, stream).await?;
-					MqttStream::new(Box::new(stream), 8 * 1024)
+			loop {
+				if options
+					.max_reconnect_attempts
+					.is_some_and(|max| reconnect_delay.attempts() >= max)
+				{
+					tracing::error!("giving up after too many connection attempts");
+					task::fail_pending(&mut state, &mut rx, pending.take());
+					break Err(ClientError::ConnectionLost.into());
 				}
-				#[cfg(not(feature = "tls"))]
-				true => {
-					panic!("TLS not supported");
+
+				if let Some(delay) = reconnect_delay.current() {
+					set_attempt(client::Attempt::Backoff(Instant::now() + delay));
 				}
-				false => MqttStream::new(Box::new(stream), 8 * 1024),
-			};
 
-			if let Ok(Break(_)) =
-				task::preconnect_task(&mut state, &mut rx, &mut connection, &mut reconnect_delay)
-					.await
-			{
-				tracing::info!("break from client_task");
-				break Ok(());
+				// The Server may be unreachable for a long time, so a request to shut
+				// down is answered without waiting to connect.
+				let connect = async {
+					reconnect_delay
+						.wait_and_increase_with_async(|delay| delay * 2)
+						.await;
+					set_attempt(client::Attempt::Connecting);
+
+					// Open the the connection to the broker.
+					TcpStream::connect((options.host.as_str(), options.port)).await
+				};
+				let stream = tokio::select! {
+					biased;
+					response = task::shutdown_requested(&mut pending, &mut rx) => {
+						task::fail_pending(&mut state, &mut rx, pending.take());
+						let _ = response.send(());
+						break Ok(());
+					}
+					stream = connect => stream,
+				};
+				let Ok(stream) = stream else {
+					continue;
+				};
+				configure_stream(&stream, keep_alive, options.tcp_nodelay)?;
+				let mut connection = match options.tls {
+					#[cfg(feature = "tls")]
+					true => {
+						use tokio_rustls::TlsConnector;
+
+						let config = tls::configure_tls();
+						let connector = TlsConnector::from(Arc::clone(&config));
+						let dnsname = tls::server_name(&options.host)?;
+
+						let stream = connector.connect(dnsname, stream).await?;
+						MqttStream::new(Box::new(stream), 8 * 1024)
+					}
+					#[cfg(not(feature = "tls"))]
+					true => break Err(ConfigError::TlsNotSupported.into()),
+					false => MqttStream::new(Box::new(stream), 8 * 1024),
+				};
+
+				match task::preconnect_task(
+					&mut state,
+					&mut rx,
+					&mut connection,
+					&mut reconnect_delay,
+					&shared,
+					&mut pending,
+				)
+				.await
+				{
+					Ok(Break(_)) => {
+						tracing::info!("break from client_task");
+						break Ok(());
+					}
+					Ok(Continue(Reconnect::OnCommand)) => {
+						// Stay disconnected until there is something to do.
+						set_attempt(client::Attempt::Disconnected);
+						let Some(command) = rx.recv().await else {
+							break Ok(());
+						};
+						pending = Some(command);
+					}
+					// Errors from the client are not transient, so don't reconnect.
+					Err(error) if error.is::<ClientError>() => break Err(error),
+					_ => {}
+				}
 			}
 		}
+		.await;
+
+		set_attempt(client::Attempt::Disconnected);
+		result
 	});
 
-	(client::Client::new(tx), handle)
+	let client = client::Client::new(
+		tx,
+		client_connected,
+		client_last_connack,
+		client_attempt,
+		options.overflow,
+		client_events,
+		client_id,
+	);
+	(client, handle)
+}
+
+/// Reads the next packet from `reader`, to use the MQTT protocol over any
+/// async reader.
+///
+/// Partial packets are kept in `buffer`, which should be reused for every call
+/// on the same reader. Returns `None` if the reader reaches EOF between
+/// packets.
+///
+/// # Example
+/// ```
+/// # tokio_test::block_on(async {
+/// use bytes::BytesMut;
+/// use tjh_mqtt::{clients::tokio::read_packet, packets::OwnedPacket};
+///
+/// let mut reader = &b"\xd0\x00"[..];
+/// let mut buffer = BytesMut::new();
+/// let packet = read_packet(&mut reader, &mut buffer).await.unwrap();
+/// assert!(matches!(packet, Some(OwnedPacket::PingResp)));
+/// assert!(read_packet(&mut reader, &mut buffer).await.unwrap().is_none());
+/// # });
+/// ```
+pub async fn read_packet<R: AsyncRead + Unpin>(
+	reader: &mut R,
+	buffer: &mut BytesMut,
+) -> io::Result<Option<OwnedPacket>> {
+	let Some(frame) = packet_stream::read_frame(reader, buffer).await? else {
+		return Ok(None);
+	};
+
+	let packet =
+		Packet::parse(&frame).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+	Ok(Some(packet.into_owned()))
+}
+
+/// Applies the socket options for a new connection to the Server.
+fn configure_stream(stream: &TcpStream, keep_alive: Duration, nodelay: bool) -> io::Result<()> {
+	#[allow(deprecated)]
+	stream.set_linger(Some(keep_alive))?;
+	stream.set_nodelay(nodelay)
 }
 
 #[cfg(feature = "tls")]
 mod tls {
+	use super::ConfigError;
 	use std::sync::Arc;
-	use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+	use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+
+	/// Parses `host` as the name to verify the Server's certificate against.
+	pub fn server_name(host: &str) -> Result<ServerName, ConfigError> {
+		ServerName::try_from(host).map_err(|_| ConfigError::InvalidServerName(host.into()))
+	}
 
 	pub fn configure_tls() -> Arc<ClientConfig> {
 		let mut root_cert_store = RootCertStore::empty();
@@ -27,6 +27,14 @@ impl MqttStream {
 		self.stream.write(buffer).await
 	}
 
+	pub async fn flush(&mut self) -> crate::Result<()> {
+		self.stream.flush().await
+	}
+
+	pub async fn shutdown(&mut self) -> crate::Result<()> {
+		self.stream.shutdown().await
+	}
+
 	pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
 		self.stream.read_frame().await
 	}
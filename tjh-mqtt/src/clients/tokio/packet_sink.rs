@@ -0,0 +1,76 @@
+use crate::Packet;
+use bytes::BytesMut;
+use std::io::{self, ErrorKind::InvalidInput};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Writes packets to any async writer, without the client state machine.
+///
+/// This is the counterpart to [`read_packet`](super::read_packet). Packets are
+/// serialized into an internal buffer, which is reused between writes.
+///
+/// # Example
+/// ```
+/// # tokio_test::block_on(async {
+/// use tjh_mqtt::{clients::tokio::PacketSink, Packet};
+///
+/// let mut sink = PacketSink::new(Vec::new());
+/// sink.send(&Packet::PingReq).await.unwrap();
+/// sink.send_all(&[Packet::PingReq, Packet::Disconnect])
+/// 	.await
+/// 	.unwrap();
+/// assert_eq!(sink.into_inner(), b"\xc0\x00\xc0\x00\xe0\x00");
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct PacketSink<W> {
+	writer: W,
+	buffer: BytesMut,
+}
+
+impl<W> PacketSink<W> {
+	/// Creates a new `PacketSink` writing to `writer`.
+	pub fn new(writer: W) -> Self {
+		Self {
+			writer,
+			buffer: BytesMut::new(),
+		}
+	}
+
+	/// Returns a reference to the underlying writer.
+	#[inline]
+	pub fn get_ref(&self) -> &W {
+		&self.writer
+	}
+
+	/// Returns the underlying writer.
+	#[inline]
+	pub fn into_inner(self) -> W {
+		self.writer
+	}
+
+	fn serialize(&mut self, packet: &Packet) -> io::Result<()> {
+		packet
+			.serialize_to_bytes(&mut self.buffer)
+			.map_err(|_| io::Error::new(InvalidInput, "packet cannot be serialized"))
+	}
+}
+
+impl<W: AsyncWrite + Unpin> PacketSink<W> {
+	/// Writes `packet` and flushes the writer.
+	pub async fn send(&mut self, packet: &Packet<'_>) -> io::Result<()> {
+		self.send_all(std::slice::from_ref(packet)).await
+	}
+
+	/// Writes all of `packets` with a single write, then flushes the writer.
+	///
+	/// If any of the packets cannot be serialized, none of them are written.
+	pub async fn send_all(&mut self, packets: &[Packet<'_>]) -> io::Result<()> {
+		self.buffer.clear();
+		for packet in packets {
+			self.serialize(packet)?;
+		}
+
+		self.writer.write_all_buf(&mut self.buffer).await?;
+		self.writer.flush().await
+	}
+}
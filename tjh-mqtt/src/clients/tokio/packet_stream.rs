@@ -1,6 +1,9 @@
-use crate::packets::{Frame, ParseError};
+use crate::packets::Frame;
 use bytes::{Buf, BytesMut};
-use std::io::Cursor;
+use std::io::{
+	self,
+	ErrorKind::{ConnectionReset, InvalidData},
+};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Debug)]
@@ -17,42 +20,41 @@ impl<T> PacketStream<T> {
 			buffer: BytesMut::with_capacity(len),
 		}
 	}
-
-	pub fn parse_frame(&mut self) -> Result<Option<Frame>, ParseError> {
-		use ParseError::Incomplete;
-
-		let mut buf = Cursor::new(&self.buffer[..]);
-		match Frame::check(&mut buf) {
-			Ok(extent) => {
-				let bytes = self.buffer.split_to(extent).freeze();
-				Ok(Some(Frame::parse(bytes)?))
-			}
-			Err(Incomplete) => Ok(None),
-			Err(error) => Err(error),
-		}
-	}
 }
 
 impl<T: AsyncRead + Unpin> PacketStream<T> {
 	pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
-		loop {
-			// Attempt to parse a packet from the buffered data.
-			if let Some(packet) = self.parse_frame()? {
-				return Ok(Some(packet));
-			}
+		Ok(read_frame(&mut self.stream, &mut self.buffer).await?)
+	}
+}
 
-			// There is not enough buffered data to read a packet. Attempt
-			// to read more.
+/// Reads the next [`Frame`] from `reader`, buffering partial frames in
+/// `buffer`.
+///
+/// Returns `None` if the reader reaches EOF between frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+	reader: &mut R,
+	buffer: &mut BytesMut,
+) -> io::Result<Option<Frame>> {
+	loop {
+		// Attempt to parse a packet from the buffered data.
+		let frame =
+			Frame::split_from(buffer).map_err(|error| io::Error::new(InvalidData, error))?;
+		if frame.is_some() {
+			return Ok(frame);
+		}
+
+		// There is not enough buffered data to read a packet. Attempt
+		// to read more.
+		//
+		if 0 == reader.read_buf(buffer).await? {
+			// If the buffer is empty the connection was shutdown cleanly,
+			// otherwise the peer closed the socket while sending a packet.
 			//
-			if 0 == self.stream.read_buf(&mut self.buffer).await? {
-				// If the buffer is empty the connection was shutdown cleanly,
-				// otherwise the peer closed the socket while sending a packet.
-				//
-				if self.buffer.is_empty() {
-					return Ok(None);
-				} else {
-					return Err("connection reset by peer".into());
-				}
+			if buffer.is_empty() {
+				return Ok(None);
+			} else {
+				return Err(io::Error::new(ConnectionReset, "connection reset by peer"));
 			}
 		}
 	}
@@ -64,4 +66,16 @@ impl<T: AsyncWrite + Unpin> PacketStream<T> {
 		self.stream.write_all_buf(&mut buffer).await?;
 		Ok(())
 	}
+
+	/// Flushes any data buffered by the stream.
+	pub async fn flush(&mut self) -> crate::Result<()> {
+		self.stream.flush().await?;
+		Ok(())
+	}
+
+	/// Flushes and shuts down the write half of the stream.
+	pub async fn shutdown(&mut self) -> crate::Result<()> {
+		self.stream.shutdown().await?;
+		Ok(())
+	}
 }
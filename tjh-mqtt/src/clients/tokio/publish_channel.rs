@@ -0,0 +1,107 @@
+use crate::clients::Message;
+use std::sync::{
+	atomic::{AtomicU64, Ordering},
+	Arc,
+};
+use tokio::sync::mpsc;
+
+pub type PublishRx = mpsc::Receiver<Message>;
+
+/// What the client task does with an incoming message when the buffer of the
+/// matching [`Subscription`] is full.
+///
+/// [`Subscription`]: super::Subscription
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+	/// Wait for the Subscription to receive a message. This applies
+	/// backpressure to the Server, but blocks delivery to every other
+	/// Subscription.
+	#[default]
+	Wait,
+	/// Drop the message. The number of dropped messages is counted by
+	/// [`Subscription::dropped_count`].
+	///
+	/// [`Subscription::dropped_count`]: super::Subscription::dropped_count
+	Drop,
+}
+
+/// Sending half of the channel used to pass incoming messages from the client
+/// task to a Subscription.
+#[derive(Clone, Debug)]
+pub struct PublishTx {
+	tx: mpsc::Sender<Message>,
+	overflow: Overflow,
+	dropped: Arc<AtomicU64>,
+}
+
+/// A [`PublishTx`] which does not keep the channel open.
+#[derive(Clone, Debug)]
+pub(crate) struct WeakPublishTx {
+	tx: mpsc::WeakSender<Message>,
+	overflow: Overflow,
+	dropped: Arc<AtomicU64>,
+}
+
+/// Creates a channel for passing incoming messages to a Subscription, buffering
+/// up to `len` messages.
+pub(crate) fn channel(len: usize, overflow: Overflow) -> (PublishTx, PublishRx) {
+	let (tx, rx) = mpsc::channel(len);
+	let tx = PublishTx {
+		tx,
+		overflow,
+		dropped: Default::default(),
+	};
+	(tx, rx)
+}
+
+impl PublishTx {
+	/// Sends `message` to the Subscription, according to the overflow policy.
+	///
+	/// Returns the message if the Subscription has been dropped.
+	pub async fn send(&self, message: Message) -> Result<(), Message> {
+		match self.overflow {
+			Overflow::Wait => self
+				.tx
+				.send(message)
+				.await
+				.map_err(|mpsc::error::SendError(message)| message),
+			Overflow::Drop => match self.tx.try_send(message) {
+				Ok(()) => Ok(()),
+				Err(mpsc::error::TrySendError::Full(message)) => {
+					self.dropped.fetch_add(1, Ordering::Relaxed);
+					tracing::warn!(topic = ?message.topic, "subscription full, dropping message");
+					Ok(())
+				}
+				Err(mpsc::error::TrySendError::Closed(message)) => Err(message),
+			},
+		}
+	}
+
+	/// Returns a sender which does not keep the channel open.
+	#[inline]
+	pub(crate) fn downgrade(&self) -> WeakPublishTx {
+		WeakPublishTx {
+			tx: self.tx.downgrade(),
+			overflow: self.overflow,
+			dropped: Arc::clone(&self.dropped),
+		}
+	}
+}
+
+impl WeakPublishTx {
+	/// Returns a sender for the channel, or `None` if it has been closed.
+	#[inline]
+	pub(crate) fn upgrade(&self) -> Option<PublishTx> {
+		Some(PublishTx {
+			tx: self.tx.upgrade()?,
+			overflow: self.overflow,
+			dropped: Arc::clone(&self.dropped),
+		})
+	}
+
+	/// Returns the number of messages dropped by this channel.
+	#[inline]
+	pub(crate) fn dropped_count(&self) -> u64 {
+		self.dropped.load(Ordering::Relaxed)
+	}
+}
@@ -1,7 +1,13 @@
-use super::{mqtt_stream::MqttStream, Command, CommandRx, HoldOff, StateError};
+use super::{
+	mqtt_stream::MqttStream, ClientError, Command, CommandRx, ConnAckInfo, ConnectionEvent,
+	HoldOff, ProtocolError, PublishTx, StateError,
+};
 use crate::{
 	clients::{
-		command::{PublishCommand, SubscribeCommand, UnsubscribeCommand},
+		command::{
+			FlushCommand, KeepAliveCommand, PendingCountCommand, PublishCommand, ShutdownCommand,
+			SubscribeCommand, UnsubscribeCommand,
+		},
 		Message,
 	},
 	packets::{self, DeserializePacket},
@@ -9,49 +15,144 @@ use crate::{
 };
 use std::{
 	ops::{ControlFlow, ControlFlow::Continue},
-	time::Instant,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Mutex,
+	},
+	time::{Duration, Instant},
 };
 use tokio::{
-	sync::{mpsc, oneshot},
+	sync::{broadcast, oneshot},
 	time,
 };
 
 type ClientState = super::ClientState<
-	mpsc::Sender<Message>,
-	oneshot::Sender<()>,
-	oneshot::Sender<Vec<(FilterBuf, QoS)>>,
-	oneshot::Sender<()>,
+	PublishTx,
+	oneshot::Sender<Result<(), ClientError>>,
+	oneshot::Sender<Result<Vec<(FilterBuf, QoS)>, ClientError>>,
+	oneshot::Sender<Result<(), ClientError>>,
 >;
 
+/// The MQTT v5 Disconnect reason code for a protocol error.
+const DISCONNECT_PROTOCOL_ERROR: u8 = 0x82;
+
+/// When the client task should reconnect to the Server.
+#[derive(Debug)]
+pub enum Reconnect {
+	/// Reconnect immediately, subject to the reconnect delay.
+	Immediately,
+	/// The connection was closed after the idle timeout. Reconnect when the
+	/// next command is received.
+	OnCommand,
+}
+
+/// The state the client task shares with the [`Client`](super::Client)s.
+pub struct Shared<'a> {
+	pub connected: &'a AtomicBool,
+	pub last_connack: &'a Mutex<Option<ConnAckInfo>>,
+	pub events: &'a broadcast::Sender<ConnectionEvent>,
+}
+
+/// Connects to the Server, then runs the client task until the connection is
+/// closed.
+///
+/// If `pending` is `Some`, the command is processed once connected.
 pub async fn preconnect_task(
 	state: &mut ClientState,
 	command_channel: &mut CommandRx,
 	connection: &mut MqttStream,
 	reconnect_delay: &mut HoldOff,
-) -> crate::Result<ControlFlow<(), ()>> {
+	shared: &Shared<'_>,
+	pending: &mut Option<Box<Command>>,
+) -> crate::Result<ControlFlow<(), Reconnect>> {
 	use packets::ConnAck;
 
+	// Messages which expired while the client was disconnected are not
+	// re-sent.
+	#[cfg(feature = "v5")]
+	for response in state.remove_expired_messages() {
+		let _ = response.send(Err(ClientError::MessageExpired));
+	}
+
 	// Send a Connect packet to the Server. `connect` is a `Bytes`, so this clone
 	// should be cheap.
 	state.reconnect();
 	connection.write(state.buffer().unwrap()).await?;
 
-	let sleep = time::sleep(state.keep_alive);
+	let sleep = time::sleep(state.handshake_timeout);
 	tokio::pin!(sleep);
 
 	// Wait for ConnAck
-	let frame = tokio::select! {
-		Ok(Some(frame)) = connection.read_frame() => frame,
-		_ = &mut sleep => return Ok(Continue(())),
+	#[cfg_attr(not(feature = "v5"), allow(clippy::never_loop))]
+	let frame = loop {
+		let frame = tokio::select! {
+			Ok(Some(frame)) = connection.read_frame() => frame,
+			_ = &mut sleep => return Ok(Continue(Reconnect::Immediately)),
+		};
+
+		// The Server may send any number of Auth packets before ConnAck.
+		#[cfg(feature = "v5")]
+		if let Packet::Auth(auth) = Packet::parse_with(&frame, state.parse_mode)? {
+			tracing::debug!(?auth, "continuing authentication");
+			if let Err(error) = state.authenticate(&auth) {
+				tracing::error!(?error, "authentication failed");
+				return Ok(Continue(Reconnect::Immediately));
+			}
+			connection.write(state.buffer().unwrap()).await?;
+			continue;
+		}
+
+		break frame;
 	};
 
 	let connack = ConnAck::from_frame(&frame)?;
 	let session_present = connack.session_present;
 
-	// TODO: Check return code.
+	match connack.code {
+		0 => {}
+		// Retrying will not change the protocol version the Server supports.
+		1 => return Err(ClientError::UnsupportedProtocolVersion.into()),
+		code => {
+			tracing::error!(code, "connection refused by Server");
+			return Ok(Continue(Reconnect::Immediately));
+		}
+	}
+
+	// A new session on the Server will not send PubRel packets for messages
+	// from the old one, so their packet IDs are free to be reused.
+	if !session_present {
+		state.incoming.clear();
+	}
+
+	// The Server may override the keep alive for the duration of the connection.
+	// A value of zero disables keep alive on the Server, but sending PingReq
+	// packets anyway is harmless, so the configured interval is kept.
+	#[cfg(feature = "v5")]
+	let keep_alive = match connack.properties.server_keep_alive {
+		Some(seconds @ 1..) => {
+			tracing::debug!(seconds, "using keep alive from Server");
+			Duration::from_secs(seconds.into())
+		}
+		_ => state.keep_alive,
+	};
+	#[cfg(not(feature = "v5"))]
+	let keep_alive = state.keep_alive;
 
 	reconnect_delay.reset();
-	connected_task(state, command_channel, connection, session_present).await
+	*shared.last_connack.lock().unwrap() = Some(connack.into());
+	shared.connected.store(true, Ordering::Release);
+	let result = connected_task(
+		state,
+		command_channel,
+		connection,
+		session_present,
+		keep_alive,
+		shared.events,
+		pending,
+	)
+	.await;
+	shared.connected.store(false, Ordering::Release);
+	result
 }
 
 async fn connected_task(
@@ -59,7 +160,10 @@ async fn connected_task(
 	command_channel: &mut CommandRx,
 	connection: &mut MqttStream,
 	session_present: bool,
-) -> crate::Result<ControlFlow<(), ()>> {
+	keep_alive_interval: Duration,
+	events: &broadcast::Sender<ConnectionEvent>,
+	pending: &mut Option<Box<Command>>,
+) -> crate::Result<ControlFlow<(), Reconnect>> {
 	//
 	// We've just connected to the Server and received a ConnAck packet.
 	//
@@ -69,59 +173,133 @@ async fn connected_task(
 	if !session_present && state.has_active_subscriptions() {
 		let (tx, rx) = oneshot::channel();
 		if state.generate_resubscribe(tx) {
-			let buffer = state.outgoing.split().freeze();
-			connection.write(buffer).await?;
+			if let Some(buffer) = state.buffer() {
+				connection.write(buffer).await?;
+			}
 		}
 
-		tokio::spawn(async move { tracing::debug!(?rx.await) });
+		// The receiver must be awaited even when the event is not logged, or the
+		// filters are unsubscribed as soon as the SubAck arrives.
+		tokio::spawn(async move {
+			let result = rx.await;
+			tracing::debug!(?result, "resubscribed");
+		});
 	}
 
-	let mut should_shutdown = false;
-	let mut keep_alive =
-		time::interval_at((Instant::now() + state.keep_alive).into(), state.keep_alive);
-
-	while !should_shutdown {
+	let mut shutdown_response = None;
+	let mut flush_responses = Vec::new();
+	let ping_interval = state.ping_interval(keep_alive_interval);
+	let mut keep_alive = time::interval_at((Instant::now() + ping_interval).into(), ping_interval);
+	// Unlike the keep alive, this is not pushed back by writes, so requests
+	// still time out while the client is busy.
+	let mut expiry = time::interval_at(
+		(Instant::now() + keep_alive_interval).into(),
+		keep_alive_interval,
+	);
+
+	let idle_timeout = state.idle_timeout;
+	let idle = time::sleep(idle_timeout.unwrap_or(keep_alive_interval));
+	tokio::pin!(idle);
+	let mut reconnect = None;
+
+	while shutdown_response.is_none() && reconnect.is_none() {
 		#[rustfmt::skip]
 		tokio::select! {
-			Some(command) = command_channel.recv() => {
-				match process_command(state, *command).await {
-					Ok(shutdown) => {
-						should_shutdown = shutdown;
+			command = next_command(pending, command_channel) => {
+				if let Some(idle_timeout) = idle_timeout {
+					idle.as_mut().reset((Instant::now() + idle_timeout).into());
+				}
+
+				match process_command(state, *command, &mut flush_responses).await {
+					Ok(Continue(next)) => reconnect = next,
+					Ok(ControlFlow::Break(response)) => {
+						shutdown_response = Some(response);
 					}
 					Err(error) => {
 						tracing::error!(error = ?error, "failed to process command");
-						return Ok(Continue(()))
+						return Ok(Continue(Reconnect::Immediately))
 					}
 				}
 			}
 			Ok(frame) = connection.read_frame() => {
 				let Some(frame) = frame else {
 					tracing::warn!("connection reset by peer");
-					return Ok(Continue(()))
+					return Ok(Continue(Reconnect::Immediately))
 				};
 
-				tracing::debug!(packet = ?frame, "read from stream");
-				let packet: Packet = Packet::parse(&frame)?;
-				if process_packet(state, packet).await.is_err() {
-					return Ok(Continue(()));
+				let packet: Packet = state.parse_packet(&frame)?;
+				tracing::debug!(%packet, "read from stream");
+
+				// Keep alives don't count as activity.
+				if let (Some(idle_timeout), false) = (idle_timeout, matches!(packet, Packet::PingResp)) {
+					idle.as_mut().reset((Instant::now() + idle_timeout).into());
+				}
+
+				let packet_type = packet.packet_type();
+				match process_packet(state, packet).await {
+					Ok(()) => {}
+					Err(StateError::DeliveryFailure(message)) => {
+						tracing::warn!(topic = ?message.topic, "no subscription for incoming message");
+					}
+					Err(error) => {
+						let Some(error) = protocol_error(error, packet_type) else {
+							return Ok(Continue(Reconnect::Immediately));
+						};
+
+						tracing::error!(%error, "protocol error from Server");
+						if state.disconnect_on_protocol_error {
+							state.enqueue_disconnect(DISCONNECT_PROTOCOL_ERROR);
+							if let Some(buffer) = state.buffer() {
+								connection.write(buffer).await?;
+							}
+							connection.shutdown().await?;
+						}
+						let _ = events.send(ConnectionEvent::ProtocolError(error));
+						return Ok(Continue(Reconnect::Immediately));
+					}
+				}
+
+			}
+			_ = &mut idle, if idle_timeout.is_some() => {
+				tracing::info!("closing idle connection");
+				state.enqueue_packet(&packets::Disconnect);
+				reconnect = Some(Reconnect::OnCommand);
+			}
+			_ = expiry.tick() => {
+				// Fail the subscribe requests the Server has not acknowledged in time.
+				// The Server may still subscribe to the filters, so unsubscribe them.
+				for (response, filters) in state.expire_subscribes() {
+					tracing::warn!(?filters, "subscribe request timed out");
+					let _ = response.send(Err(ClientError::Timeout));
+					let (response, _) = oneshot::channel();
+					state.unsubscribe(filters, response);
+				}
+				for response in state.expire_unsubscribes() {
+					tracing::warn!("unsubscribe request timed out");
+					let _ = response.send(Err(ClientError::Timeout));
+				}
+				let abandoned = state.expire_publishes(|response| response.is_closed());
+				if abandoned > 0 {
+					tracing::warn!(abandoned, "dropped unacknowledged publishes nothing is waiting for");
 				}
 			}
 			_ = keep_alive.tick() => {
-				if state.expired() {
-					tracing::error!("pending requests have exceeded keep_alive");
-					return Ok(Continue(()));
+				if state.expired(keep_alive_interval) {
+					tracing::error!("PingReq has not been answered within keep_alive");
+					return Ok(Continue(Reconnect::Immediately));
 				}
 
 				// If we are about to send a packet to the Server, we don't need to send a PingReq.
-				if state.outgoing.is_empty() {
+				// The ticks are shorter than keep_alive, so sending another while one is
+				// unanswered would stop it from ever expiring.
+				if state.pingreq_state.is_none() && !state.has_outgoing() {
 					state.pingreq_state = Some(Instant::now());
 					state.enqueue_packet(&packets::PingReq);
 				}
 			}
 		}
 
-		let update_keep_alive = if !state.outgoing.is_empty() {
-			let buffer = state.outgoing.split().freeze();
+		let update_keep_alive = if let Some(buffer) = state.buffer() {
 			connection.write(buffer).await?;
 			true
 		} else {
@@ -130,141 +308,219 @@ async fn connected_task(
 
 		if update_keep_alive {
 			// We've just sent a packet, update the keep alive.
-			keep_alive.reset_at((Instant::now() + state.keep_alive).into());
+			keep_alive.reset_at((Instant::now() + ping_interval).into());
+		}
+
+		// Everything queued before the Flush commands has now been written.
+		if !flush_responses.is_empty() {
+			connection.flush().await?;
+			for response in flush_responses.drain(..) {
+				let _ = response.send(());
+			}
 		}
 	}
 
+	// The Disconnect packet has been written, make sure it has been flushed
+	// before responding to the shutdown request.
+	connection.shutdown().await?;
+	let Some(response) = shutdown_response else {
+		return Ok(Continue(reconnect.unwrap_or(Reconnect::OnCommand)));
+	};
+
+	let _ = response.send(());
 	Ok(ControlFlow::Break(()))
 }
 
+/// Returns the pending command if there is one, otherwise waits for the next
+/// command from the command channel.
+///
+/// Once every sender has been dropped nothing can use the connection, so a
+/// Shutdown command is returned.
+async fn next_command(
+	pending: &mut Option<Box<Command>>,
+	command_channel: &mut CommandRx,
+) -> Box<Command> {
+	if let Some(command) = pending.take() {
+		return command;
+	}
+
+	command_channel.recv().await.unwrap_or_else(|| {
+		let (response, _) = oneshot::channel();
+		Command::Shutdown(ShutdownCommand { response }).into()
+	})
+}
+
+/// Waits for a Shutdown command while the client is disconnected, returning
+/// its response.
+///
+/// Any other command is held in `pending` to be processed once connected, after
+/// which this never resolves. Dropping the future does not lose a command.
+pub async fn shutdown_requested(
+	pending: &mut Option<Box<Command>>,
+	command_channel: &mut CommandRx,
+) -> oneshot::Sender<()> {
+	match *next_command(pending, command_channel).await {
+		Command::Shutdown(ShutdownCommand { response }) => response,
+		command => {
+			*pending = Some(command.into());
+			std::future::pending().await
+		}
+	}
+}
+
 async fn process_packet<'a>(
 	state: &'a mut ClientState,
 	packet: Packet<'a>,
-) -> Result<(), StateError<'a>> {
+) -> Result<(), StateError> {
 	use packets::Publish;
 
 	match packet {
-		Packet::Publish(publish) => match *publish {
-			Publish::AtMostOnce {
-				retain,
-				topic,
-				payload,
-			} => {
-				let Some(channel) = state.find_publish_channel(topic) else {
-					panic!();
-				};
-
-				channel
-					.send(Message {
+		Packet::Publish(publish) => {
+			let redelivery = state.is_redelivery(&publish);
+			match *publish {
+				Publish::AtMostOnce {
+					retain,
+					topic,
+					payload,
+				} => {
+					let message = Message {
 						topic: topic.to_topic_buf(),
+						qos: QoS::AtMostOnce,
 						retain,
 						payload,
-					})
-					.await
-					.unwrap();
-				// .map_err(|p| StateError::DeliveryFailure())?;
-
-				Ok(())
-			}
-			Publish::AtLeastOnce {
-				id,
-				retain,
-				duplicate,
-				topic,
-				payload,
-			} => {
-				if duplicate {
-					unimplemented!("duplicate Publish packets are not yet handled");
+					};
+					deliver(state, message).await
 				}
-
-				let Some(channel) = state.find_publish_channel(topic) else {
-					panic!();
-				};
-
-				channel
-					.send(Message {
+				Publish::AtLeastOnce {
+					id,
+					retain,
+					topic,
+					payload,
+					..
+				} => {
+					let message = Message {
 						topic: topic.to_topic_buf(),
+						qos: QoS::AtLeastOnce,
 						retain,
 						payload,
-					})
-					.await
-					.unwrap();
-				// .map_err(|p| StateError::DeliveryFailure(p.0))?;
-
-				state.enqueue_packet(&packets::PubAck { id });
-
-				Ok(())
-			}
-			Publish::ExactlyOnce {
-				id,
-				retain,
-				duplicate,
-				topic,
-				payload,
-			} => {
-				if duplicate {
-					unimplemented!("duplicate Publish packets are not yet handled");
+					};
+
+					// Acknowledge the message even if it can't be delivered, or has
+					// already been delivered, otherwise the Server will keep redelivering
+					// it.
+					let result = match redelivery {
+						true => {
+							tracing::debug!(?id, "suppressing redelivered Publish packet");
+							Ok(())
+						}
+						false => deliver(state, message).await,
+					};
+					state.enqueue_packet(&packets::PubAck::new(id));
+					result
 				}
-
-				state.incoming.insert(
+				Publish::ExactlyOnce {
 					id,
-					Message {
+					retain,
+					duplicate,
+					topic,
+					payload,
+				} => {
+					// The PubRec was lost, so the Server has re-sent the message. It
+					// has already been delivered, so only acknowledge it again.
+					if state.is_unreleased(id)? {
+						tracing::debug!(
+							?id,
+							duplicate,
+							"re-sending PubRec for unreleased Publish packet"
+						);
+						state.enqueue_packet(&packets::PubRec::new(id));
+						return Ok(());
+					}
+
+					// Deliver the message now rather than on PubRel, so it is not
+					// reordered with the AtMostOnce and AtLeastOnce messages which
+					// follow it on the same topic. The ID is kept until the Server
+					// releases it.
+					let message = Message {
 						topic: topic.to_topic_buf(),
+						qos: QoS::ExactlyOnce,
 						retain,
 						payload,
-					},
-				);
-
-				state.enqueue_packet(&packets::PubRec { id });
-
-				Ok(())
+					};
+					let result = deliver(state, message).await;
+					state.enqueue_packet(&packets::PubRec::new(id));
+					result
+				}
 			}
-		},
-		Packet::PubAck(packets::PubAck { id }) => {
-			let response = state.puback(id)?;
-			let _ = response.send(());
-			Ok(())
 		}
-		Packet::PubRec(packets::PubRec { id }) => {
-			state.pubrec(id)?;
+		Packet::PubAck(ack) => {
+			if let Some(response) = state.puback(ack.id)? {
+				#[cfg(feature = "v5")]
+				let _ = response.send(publish_result(ack.reason_code, ack.properties));
+				#[cfg(not(feature = "v5"))]
+				let _ = response.send(Ok(()));
+			}
 			Ok(())
 		}
-		Packet::PubRel(packets::PubRel { id }) => {
-			let Ok(publish) = state.pubrel(id) else {
-				return Err(StateError::ProtocolError(
-					"received PubRel for unknown Publish id",
-				));
-			};
-
-			let Some(channel) = state.find_publish_channel(&publish.topic) else {
-				panic!();
-				// return Err(StateError::DeliveryFailure(publish));
-			};
-
-			if let Err(publish) = channel.send(publish).await {
-				state.incoming.insert(id, publish.0);
-				return Err(StateError::HardDeliveryFailure);
-			};
+		Packet::PubRec(rec) => {
+			// The Server has discarded the message, so there is nothing to
+			// release.
+			#[cfg(feature = "v5")]
+			if rec.is_failure() {
+				if let Some(response) = state.pubrec_failed(rec.id)? {
+					let _ = response.send(publish_result(rec.reason_code, rec.properties));
+				}
+				return Ok(());
+			}
 
-			// We've successfully passed on the Publish message. Queue up a PubComp
-			// packet
-			state.enqueue_packet(&packets::PubComp { id });
+			state.pubrec(rec.id)?;
+			Ok(())
+		}
+		Packet::PubRel(packets::PubRel { id, .. }) => {
+			// If the PubComp was lost, the Server re-sends PubRel for an ID which
+			// has already been released. It must still be completed.
+			if state.pubrel(id).is_err() {
+				tracing::debug!(?id, "completing PubRel for unknown Publish id");
+			}
 
+			state.enqueue_packet(&packets::PubComp::new(id));
 			Ok(())
 		}
-		Packet::PubComp(packets::PubComp { id }) => {
-			let response = state.pubcomp(id)?;
-			let _ = response.send(());
+		Packet::PubComp(comp) => {
+			if let Some(response) = state.pubcomp(comp.id)? {
+				#[cfg(feature = "v5")]
+				let _ = response.send(publish_result(comp.reason_code, comp.properties));
+				#[cfg(not(feature = "v5"))]
+				let _ = response.send(Ok(()));
+			}
 			Ok(())
 		}
 		Packet::SubAck(ack) => {
-			let (sender, payload) = state.suback(*ack)?;
-			let _ = sender.send(payload);
+			// The request may have already timed out, in which case the filters
+			// have been unsubscribed.
+			let Some((sender, filters)) = state.suback(*ack)? else {
+				return Ok(());
+			};
+
+			// If the caller has given up on the request, nothing will receive messages
+			// for the filters, so unsubscribe from them rather than leaving them
+			// active.
+			if let Err(Ok(filters)) = sender.send(Ok(filters)) {
+				if !filters.is_empty() {
+					tracing::warn!(?filters, "subscribe request dropped, unsubscribing");
+					let (response, _) = oneshot::channel();
+					state.unsubscribe(
+						filters.into_iter().map(|(filter, _)| filter).collect(),
+						response,
+					);
+				}
+			}
 			Ok(())
 		}
 		Packet::UnsubAck(ack) => {
-			let response = state.unsuback(ack)?;
-			let _ = response.send(());
+			if let Some(response) = state.unsuback(ack)? {
+				let _ = response.send(Ok(()));
+			}
 			Ok(())
 		}
 		Packet::PingResp => {
@@ -281,25 +537,123 @@ async fn process_packet<'a>(
 		| Packet::Unsubscribe { .. }
 		| Packet::PingReq
 		| Packet::Disconnect => Err(StateError::InvalidPacket),
+		#[cfg(feature = "v5")]
+		Packet::Auth(_) => Err(StateError::InvalidPacket),
 	}
 }
 
-async fn process_command(state: &mut ClientState, command: Command) -> Result<bool, StateError> {
+/// Returns the [`ProtocolError`] for `error` from processing a packet of
+/// `packet_type`, or `None` if the Server did not violate the protocol.
+fn protocol_error(error: StateError, packet_type: PacketType) -> Option<ProtocolError> {
+	match error {
+		StateError::Unsolicited(packet_type) => Some(ProtocolError::Unsolicited(packet_type)),
+		StateError::InvalidPacket => Some(ProtocolError::InvalidPacket(packet_type)),
+		StateError::ProtocolError(message) => Some(ProtocolError::Other(message)),
+		StateError::SubAckLengthMismatch {
+			requested,
+			returned,
+		} => Some(ProtocolError::SubAckLengthMismatch {
+			requested,
+			returned,
+		}),
+		StateError::DeliveryFailure(_) | StateError::HardDeliveryFailure => None,
+	}
+}
+
+/// Passes `message` on to the channel of the matching subscription.
+async fn deliver(state: &ClientState, message: Message) -> Result<(), StateError> {
+	let Some(channel) = state.find_publish_channel(&message.topic) else {
+		return Err(StateError::DeliveryFailure(message));
+	};
+
+	channel
+		.send(message)
+		.await
+		.map_err(StateError::DeliveryFailure)
+}
+
+/// Returns the result of a Publish packet acknowledged with `reason_code`.
+#[cfg(feature = "v5")]
+fn publish_result(reason_code: u8, properties: packets::AckProperties) -> Result<(), ClientError> {
+	match reason_code {
+		0x80.. => Err(ClientError::Rejected {
+			reason_code,
+			properties,
+		}),
+		_ => Ok(()),
+	}
+}
+
+/// Processes a command from the Client.
+///
+/// Responses to Flush commands are pushed to `flush_responses`, to be sent
+/// once the outgoing buffer has been written. Returns `Continue(Some(_))` if
+/// the connection should be closed, once the outgoing buffer has been
+/// written.
+async fn process_command(
+	state: &mut ClientState,
+	command: Command,
+	flush_responses: &mut Vec<oneshot::Sender<()>>,
+) -> Result<ControlFlow<oneshot::Sender<()>, Option<Reconnect>>, StateError> {
 	match command {
-		Command::Shutdown => {
-			// TODO: This shutdown process could be better.
+		Command::KeepAlive(KeepAliveCommand { keep_alive }) => {
+			// The keep alive is only sent in the Connect packet, so reconnect for
+			// the Server to use it.
+			tracing::info!(keep_alive, "reconnecting to change keep alive");
+			state.set_keep_alive(keep_alive);
+			state.enqueue_packet(&packets::Disconnect);
+			return Ok(Continue(Some(Reconnect::Immediately)));
+		}
+		Command::Flush(FlushCommand { response }) => {
+			flush_responses.push(response);
+		}
+		Command::PendingCount(PendingCountCommand { response }) => {
+			let _ = response.send(state.pending_requests());
+		}
+		Command::Shutdown(ShutdownCommand { response }) => {
+			// Queue the Disconnect packet. The response is sent once the packet has
+			// been flushed to the connection.
 			state.enqueue_packet(&packets::Disconnect);
-			return Ok(true);
+			return Ok(ControlFlow::Break(response));
 		}
 		Command::Publish(PublishCommand {
 			topic,
 			payload,
 			qos,
 			retain,
+			#[cfg(feature = "v5")]
+			mut properties,
+			#[cfg(feature = "v5")]
+			created_at,
 			response: response_tx,
 		}) => {
-			if let Some(response) = state.publish(&topic, payload, qos, retain, response_tx) {
-				let _ = response.send(());
+			// The message may have waited for the client task to reconnect. The
+			// Server is sent its remaining lifetime.
+			#[cfg(feature = "v5")]
+			if let Some(interval) = properties.message_expiry_interval {
+				let Some(remaining) =
+					crate::clients::state::remaining_lifetime(interval, created_at)
+				else {
+					tracing::warn!(%topic, "dropping expired message");
+					let _ = response_tx.send(Err(ClientError::MessageExpired));
+					return Ok(Continue(None));
+				};
+				properties.message_expiry_interval = Some(remaining);
+			}
+
+			#[cfg(feature = "v5")]
+			let response = state.publish_with_properties(
+				&topic,
+				payload,
+				qos,
+				retain,
+				properties,
+				response_tx,
+			);
+			#[cfg(not(feature = "v5"))]
+			let response = state.publish(&topic, payload, qos, retain, response_tx);
+			if let Some(response) = response {
+				let _ = response.send(Ok(()));
 			};
 		}
 		Command::Subscribe(SubscribeCommand {
@@ -316,5 +670,46 @@ async fn process_command(state: &mut ClientState, command: Command) -> Result<bo
 			state.unsubscribe(filters, response_tx);
 		}
 	}
-	Ok(false)
+	Ok(Continue(None))
+}
+
+/// Fails the responses to every pending command with
+/// [`ClientError::ConnectionLost`], and closes the command channel.
+pub fn fail_pending(
+	state: &mut ClientState,
+	command_channel: &mut CommandRx,
+	pending: Option<Box<Command>>,
+) {
+	let (publishes, subscribes, unsubscribes) = state.take_responses();
+	for response in publishes {
+		let _ = response.send(Err(ClientError::ConnectionLost));
+	}
+	for response in subscribes {
+		let _ = response.send(Err(ClientError::ConnectionLost));
+	}
+	for response in unsubscribes {
+		let _ = response.send(Err(ClientError::ConnectionLost));
+	}
+
+	command_channel.close();
+	let commands = pending
+		.into_iter()
+		.chain(std::iter::from_fn(|| command_channel.try_recv()));
+	for command in commands {
+		match *command {
+			Command::Publish(PublishCommand { response, .. }) => {
+				let _ = response.send(Err(ClientError::ConnectionLost));
+			}
+			Command::Subscribe(SubscribeCommand { response, .. }) => {
+				let _ = response.send(Err(ClientError::ConnectionLost));
+			}
+			Command::Unsubscribe(UnsubscribeCommand { response, .. }) => {
+				let _ = response.send(Err(ClientError::ConnectionLost));
+			}
+			Command::Shutdown(_)
+			| Command::Flush(_)
+			| Command::KeepAlive(_)
+			| Command::PendingCount(_) => {}
+		}
+	}
 }
@@ -0,0 +1,2640 @@
+use super::{
+	client::Client, command_channel, configure_stream, read_packet, tcp_client, ClientBuilder,
+	ClientError, ConfigError, ConnectionEvent, ConnectionStatus, Options, Overflow, ProtocolError,
+	PublishOptions,
+};
+#[cfg(feature = "v5")]
+use crate::packets::ProtocolVersion;
+use crate::{
+	clients::command::Command,
+	misc::{Retain, Will},
+	packets::{ConnAck, Connect, Frame, PubComp, PubRec, PubRel, Publish, SubAck, Subscribe},
+	PacketId, PacketType, ParseMode, QoS, Topic, TopicBuf,
+};
+use bytes::{Buf, BytesMut};
+use std::{
+	io::{self, Read},
+	time::Duration,
+};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+	sync::broadcast,
+	time,
+};
+
+/// A minimal Server for exercising the client task over a local TCP socket.
+struct Broker {
+	stream: TcpStream,
+	buffer: BytesMut,
+}
+
+impl Broker {
+	/// Binds a listener on an ephemeral port, returning it and the port.
+	async fn bind() -> (TcpListener, u16) {
+		let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		(listener, port)
+	}
+
+	/// Accepts a connection from a client and completes the Connect/ConnAck
+	/// handshake.
+	async fn accept(listener: &TcpListener) -> Self {
+		let mut broker = Self::connect(listener).await;
+		broker.connack().await;
+		broker
+	}
+
+	/// Accepts a connection from a client and reads the Connect packet, without
+	/// responding.
+	async fn connect(listener: &TcpListener) -> Self {
+		let (stream, _) = listener.accept().await.unwrap();
+		let mut broker = Self {
+			stream,
+			buffer: BytesMut::new(),
+		};
+
+		let connect = broker.read_frame().await;
+		assert_eq!(connect.header, 0x10);
+		broker
+	}
+
+	/// Accepts the client's connection.
+	async fn connack(&mut self) {
+		self.connack_with_code(0).await;
+	}
+
+	/// Responds to the client's Connect packet with the return `code`.
+	async fn connack_with_code(&mut self, code: u8) {
+		self.write(&ConnAck {
+			code,
+			..Default::default()
+		})
+		.await;
+	}
+
+	/// Reads a Subscribe packet and grants every filter at the requested QoS.
+	async fn suback(&mut self) {
+		let frame = self.read_frame().await;
+		assert_eq!(frame.header, 0x82);
+		let subscribe = Subscribe::parse(&frame.payload).unwrap();
+		let suback = SubAck {
+			id: subscribe.id,
+			result: subscribe.filters.iter().map(|(_, qos)| Ok(*qos)).collect(),
+		};
+		self.write(&suback).await;
+	}
+
+	async fn read_frame(&mut self) -> Frame {
+		loop {
+			let mut cursor = io::Cursor::new(&self.buffer[..]);
+			if let Ok(extent) = Frame::check(&mut cursor) {
+				let packet = self.buffer.split_to(extent).freeze();
+				return Frame::parse(packet).unwrap();
+			}
+
+			let read = self.stream.read_buf(&mut self.buffer).await.unwrap();
+			assert_ne!(read, 0, "client closed the connection");
+		}
+	}
+
+	async fn write(&mut self, packet: &impl crate::packets::SerializePacket) {
+		let mut buffer = BytesMut::new();
+		packet.serialize_to_bytes(&mut buffer).unwrap();
+		self.stream.write_all(&buffer).await.unwrap();
+	}
+
+	/// Writes `publish` encoded as MQTT v5, without properties.
+	#[cfg(feature = "v5")]
+	async fn write_v5(&mut self, publish: &Publish<'_>) {
+		let mut buffer = BytesMut::new();
+		let payload = publish.payload();
+		publish
+			.serialize_header_v5(payload.len(), &Default::default(), &mut buffer)
+			.unwrap();
+		buffer.extend_from_slice(payload);
+		self.stream.write_all(&buffer).await.unwrap();
+	}
+
+	/// Returns all the bytes that have been written by the client *without*
+	/// waiting for more to arrive.
+	fn into_written(self) -> Vec<u8> {
+		let Self { stream, buffer } = self;
+		let mut written = buffer.chunk().to_vec();
+
+		let mut stream = stream.into_std().unwrap();
+		stream.set_nonblocking(true).unwrap();
+		let mut chunk = [0; 1024];
+		loop {
+			match stream.read(&mut chunk) {
+				Ok(0) => break,
+				Ok(n) => written.extend_from_slice(&chunk[..n]),
+				Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+				Err(error) => panic!("{error}"),
+			}
+		}
+		written
+	}
+}
+
+/// An authenticator for which the Server accepts the Connect packet without an
+/// authentication exchange.
+#[cfg(feature = "v5")]
+#[derive(Debug)]
+struct NoExchange;
+
+#[cfg(feature = "v5")]
+impl crate::clients::Authenticator for NoExchange {
+	fn method(&self) -> &str {
+		"NONE"
+	}
+
+	fn initial_data(&mut self) -> Option<Vec<u8>> {
+		None
+	}
+
+	fn continue_auth(&mut self, _: &[u8]) -> Vec<u8> {
+		unreachable!()
+	}
+}
+
+#[tokio::test]
+async fn client_id_assigned_by_server() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		client_id: "configured".into(),
+		..Default::default()
+	});
+	assert_eq!(client.client_id(), "configured");
+
+	let mut broker = Broker::connect(&listener).await;
+	#[cfg_attr(not(feature = "v5"), allow(unused_mut))]
+	let mut connack = ConnAck::default();
+	#[cfg(feature = "v5")]
+	{
+		connack.properties.assigned_client_identifier = Some("assigned".into());
+	}
+	broker.write(&connack).await;
+	time::timeout(Duration::from_secs(1), async {
+		while !client.is_connected() {
+			tokio::task::yield_now().await;
+		}
+	})
+	.await
+	.unwrap();
+
+	match cfg!(feature = "v5") {
+		true => assert_eq!(client.client_id(), "assigned"),
+		false => assert_eq!(client.client_id(), "configured"),
+	}
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn generated_client_id_is_reported() {
+	let (_listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	assert_eq!(client.client_id().len(), 23);
+	handle.abort();
+}
+
+#[tokio::test]
+async fn is_connected_after_connack() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	assert!(!client.is_connected());
+
+	let mut broker = Broker::connect(&listener).await;
+	assert!(!client.is_connected());
+
+	// The flag is set by the client task once it has read the ConnAck.
+	broker.connack().await;
+	time::timeout(Duration::from_secs(1), async {
+		while !client.is_connected() {
+			tokio::task::yield_now().await;
+		}
+	})
+	.await
+	.unwrap();
+
+	// The flag is cleared once the connection is lost.
+	drop(broker);
+	time::timeout(Duration::from_secs(1), async {
+		while client.is_connected() {
+			tokio::task::yield_now().await;
+		}
+	})
+	.await
+	.unwrap();
+	handle.abort();
+}
+
+#[tokio::test]
+async fn last_connack_reports_session_present() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	assert_eq!(client.last_connack(), None);
+
+	let mut broker = Broker::connect(&listener).await;
+	broker
+		.write(&ConnAck {
+			session_present: true,
+			..Default::default()
+		})
+		.await;
+	time::timeout(Duration::from_secs(1), async {
+		while !client.is_connected() {
+			tokio::task::yield_now().await;
+		}
+	})
+	.await
+	.unwrap();
+
+	let connack = client.last_connack().unwrap();
+	assert!(connack.session_present);
+	assert_eq!(connack.code, 0);
+
+	// The last ConnAck is kept once the connection is lost.
+	drop(broker);
+	time::timeout(Duration::from_secs(1), async {
+		while client.is_connected() {
+			tokio::task::yield_now().await;
+		}
+	})
+	.await
+	.unwrap();
+	assert!(client.last_connack().unwrap().session_present);
+	handle.abort();
+}
+
+#[tokio::test]
+async fn tcp_nodelay_is_applied() {
+	assert!(Options::default().tcp_nodelay);
+
+	let (listener, port) = Broker::bind().await;
+	let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+	let _ = listener.accept().await.unwrap();
+
+	for nodelay in [true, false] {
+		configure_stream(&stream, Duration::from_secs(1), nodelay).unwrap();
+		assert_eq!(stream.nodelay().unwrap(), nodelay);
+	}
+}
+
+#[test]
+fn invalid_options() {
+	assert!(Options::default().validate().is_ok());
+
+	let options = Options {
+		clean_session: false,
+		..Default::default()
+	};
+	assert!(matches!(
+		options.validate(),
+		Err(ConfigError::EmptyClientId)
+	));
+
+	let options = Options {
+		client_id: "a".repeat(24),
+		long_client_id: false,
+		..Default::default()
+	};
+	assert!(matches!(
+		options.validate(),
+		Err(ConfigError::ClientIdTooLong)
+	));
+	let options = Options {
+		long_client_id: true,
+		..options
+	};
+	assert!(options.validate().is_ok());
+	let options = Options {
+		client_id: "a".repeat(65_536),
+		..options
+	};
+	assert!(matches!(
+		options.validate(),
+		Err(ConfigError::ClientIdTooLong)
+	));
+
+	let options = Options {
+		keep_alive: 0,
+		..Default::default()
+	};
+	assert!(matches!(
+		options.validate(),
+		Err(ConfigError::ZeroKeepAlive)
+	));
+
+	#[cfg(feature = "v5")]
+	{
+		let options = Options {
+			authenticator: Some(Box::new(NoExchange)),
+			..Default::default()
+		};
+		assert!(matches!(
+			options.validate(),
+			Err(ConfigError::RequiresV5("authenticator"))
+		));
+
+		let options = Options {
+			receive_maximum: Some(8),
+			..Default::default()
+		};
+		assert!(matches!(
+			options.validate(),
+			Err(ConfigError::RequiresV5("receive_maximum"))
+		));
+
+		let options = Options {
+			protocol_version: ProtocolVersion::V5,
+			receive_maximum: Some(0),
+			..Default::default()
+		};
+		assert!(matches!(
+			options.validate(),
+			Err(ConfigError::ZeroReceiveMaximum)
+		));
+	}
+
+	// Will::new checks the length of the payload, but the fields are public.
+	let mut will = Will::new("a/b", "", QoS::AtMostOnce, false).unwrap();
+	will.payload = vec![0; 65_536].into();
+	let options = Options {
+		will: Some(will),
+		..Default::default()
+	};
+	assert!(matches!(
+		options.validate(),
+		Err(ConfigError::WillPayloadTooLong)
+	));
+}
+
+#[tokio::test]
+async fn invalid_options_are_not_connected() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		keep_alive: 0,
+		..Default::default()
+	});
+
+	let error = handle.await.unwrap().unwrap_err();
+	assert!(matches!(
+		error.downcast_ref::<ConfigError>(),
+		Some(ConfigError::ZeroKeepAlive)
+	));
+	assert!(matches!(
+		client
+			.publish("a/b", "payload", QoS::AtMostOnce, false)
+			.await,
+		Err(ClientError::ClientTaskClosed)
+	));
+	assert!(time::timeout(Duration::from_millis(50), listener.accept())
+		.await
+		.is_err());
+}
+
+#[tokio::test]
+async fn invalid_tls_server_name_is_not_connected() {
+	let (client, handle) = tcp_client(Options {
+		host: "not a host name".into(),
+		tls: true,
+		..Default::default()
+	});
+
+	// Fails cleanly rather than panicking in the client task.
+	let error = handle.await.unwrap().unwrap_err();
+	let error = error.downcast_ref::<ConfigError>().unwrap();
+	if cfg!(feature = "tls") {
+		assert!(matches!(error, ConfigError::InvalidServerName(host) if host == "not a host name"));
+	} else {
+		assert!(matches!(error, ConfigError::TlsNotSupported));
+	}
+	assert!(!client.is_connected());
+}
+
+#[tokio::test]
+async fn slow_connack_within_handshake_timeout() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		keep_alive: 1,
+		handshake_timeout: Duration::from_secs(5),
+		..Default::default()
+	});
+
+	// The ConnAck takes longer than the keep alive.
+	let mut broker = Broker::connect(&listener).await;
+	time::sleep(Duration::from_secs(2)).await;
+	broker.connack().await;
+
+	time::timeout(Duration::from_secs(1), async {
+		while !client.is_connected() {
+			tokio::task::yield_now().await;
+		}
+	})
+	.await
+	.unwrap();
+
+	// The client did not give up on the connection and reconnect.
+	assert!(time::timeout(Duration::from_millis(100), listener.accept())
+		.await
+		.is_err());
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn client_builder_sets_options() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = ClientBuilder::new("127.0.0.1")
+		.port(port)
+		.client_id("built")
+		.keep_alive(30)
+		.credentials(("username", "password"))
+		.build();
+
+	let (stream, _) = listener.accept().await.unwrap();
+	let mut broker = Broker {
+		stream,
+		buffer: BytesMut::new(),
+	};
+	let frame = broker.read_frame().await;
+	assert_eq!(frame.header, 0x10);
+	let connect = Connect::parse(&frame.payload).unwrap();
+	assert_eq!(connect.client_id, "built");
+	assert_eq!(connect.keep_alive, 30);
+	let credentials = connect.credentials.unwrap();
+	assert_eq!(credentials.username, Some("username"));
+	assert_eq!(credentials.password, Some("password"));
+	broker.connack().await;
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "v5")]
+#[tokio::test]
+async fn rejected_publish_returns_reason() {
+	use crate::packets::{AckProperties, PubAck};
+
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (result, ()) = tokio::join!(
+		client.publish("a/b", "payload", QoS::AtLeastOnce, false),
+		async {
+			let frame = broker.read_frame().await;
+			let publish = Publish::parse(&frame.payload, frame.header & 0x0f).unwrap();
+			broker
+				.write(&PubAck {
+					reason_code: 0x87,
+					properties: AckProperties {
+						reason_string: Some("Not authorized".into()),
+						..Default::default()
+					},
+					..PubAck::new(publish.id().unwrap())
+				})
+				.await;
+		}
+	);
+
+	let error = result.unwrap_err();
+	assert_eq!(
+		error.to_string(),
+		"rejected by the Server with reason code 0x87: Not authorized"
+	);
+	assert!(matches!(
+		error,
+		ClientError::Rejected {
+			reason_code: 0x87,
+			..
+		}
+	));
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn publish_require_connected_fails_when_disconnected() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+
+	// The client task has not completed the handshake.
+	let mut broker = Broker::connect(&listener).await;
+	assert!(matches!(
+		client
+			.publish_require_connected("a/b", "payload", QoS::AtMostOnce, false)
+			.await,
+		Err(ClientError::NotConnected)
+	));
+
+	broker.connack().await;
+	time::timeout(Duration::from_secs(1), async {
+		while !client.is_connected() {
+			tokio::task::yield_now().await;
+		}
+	})
+	.await
+	.unwrap();
+
+	// Nothing was queued while disconnected.
+	client
+		.publish_require_connected("a/b", "second", QoS::AtMostOnce, false)
+		.await
+		.unwrap();
+	let frame = broker.read_frame().await;
+	let publish = Publish::parse(&frame.payload, frame.header & 0x0f).unwrap();
+	assert_eq!(&publish.payload()[..], b"second");
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn disconnect_flushes_before_returning() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let broker = Broker::accept(&listener).await;
+
+	client.disconnect().await.unwrap();
+
+	// The Disconnect packet must already be readable by the Server.
+	assert_eq!(broker.into_written(), [0xe0, 0x00]);
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn bounded_command_channel_applies_backpressure() {
+	use crate::clients::command::FlushCommand;
+	use tokio::sync::oneshot;
+
+	let command = || {
+		let (response, _) = oneshot::channel();
+		Box::new(Command::Flush(FlushCommand { response }))
+	};
+
+	// The first command fills the channel, so sending the next must wait until
+	// the client task receives one.
+	let (tx, mut rx) = command_channel::channel(Some(1));
+	tx.send(command()).await.unwrap();
+	{
+		let second = tx.send(command());
+		tokio::pin!(second);
+		assert!(time::timeout(Duration::from_millis(50), &mut second)
+			.await
+			.is_err());
+
+		assert!(rx.recv().await.is_some());
+		time::timeout(Duration::from_millis(50), second)
+			.await
+			.unwrap()
+			.unwrap();
+	}
+
+	// Sending to an unbounded channel never waits.
+	let (tx, _rx) = command_channel::channel(None);
+	for _ in 0..16 {
+		time::timeout(Duration::from_millis(50), tx.send(command()))
+			.await
+			.unwrap()
+			.unwrap();
+	}
+}
+
+#[tokio::test]
+async fn publish_accepts_validated_topics() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let topic = Topic::from_static("a/b");
+	client
+		.publish(topic, "1", QoS::AtMostOnce, false)
+		.await
+		.unwrap();
+	client
+		.publish(topic.to_owned(), "2", QoS::AtMostOnce, false)
+		.await
+		.unwrap();
+
+	for payload in ["1", "2"] {
+		let frame = broker.read_frame().await;
+		let Publish::AtMostOnce {
+			topic: published,
+			payload: published_payload,
+			..
+		} = Publish::parse(&frame.payload, frame.header & 0x0f).unwrap()
+		else {
+			panic!("expected an AtMostOnce Publish packet");
+		};
+		assert_eq!(published, topic);
+		assert_eq!(published_payload, payload.as_bytes());
+	}
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn publish_rejects_wildcards_without_contacting_broker() {
+	// The listener never accepts the connection, so a publish which reached
+	// the client task would not complete.
+	let (_listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+
+	for topic in ["a/+", "a/#"] {
+		let result = time::timeout(
+			Duration::from_millis(100),
+			client.publish(topic, "payload", QoS::AtLeastOnce, false),
+		)
+		.await
+		.unwrap();
+		let Err(ClientError::InvalidTopic {
+			topic: rejected, ..
+		}) = result
+		else {
+			panic!("expected an invalid topic, got {result:?}");
+		};
+		assert_eq!(rejected, topic);
+	}
+
+	handle.abort();
+}
+
+#[tokio::test]
+async fn clear_retained_publishes_empty_retained_message() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	assert!(matches!(
+		client.clear_retained("a/+", QoS::AtMostOnce).await,
+		Err(ClientError::InvalidTopic { .. })
+	));
+	client.clear_retained("a/b", QoS::AtMostOnce).await.unwrap();
+
+	let frame = broker.read_frame().await;
+	let publish = Publish::parse(&frame.payload, frame.header & 0x0f).unwrap();
+	assert_eq!(frame.header & 0x01, 0x01);
+	assert!(publish.retain());
+	assert!(publish.is_empty_payload());
+	assert_eq!(publish.topic(), Topic::from_static("a/b"));
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn received_message_is_republished() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(client.subscribe("a/#", 8), broker.suback());
+	let mut subscription = subscription.unwrap();
+	broker
+		.write(
+			&Publish::new(
+				Topic::from_static("a/b"),
+				"payload".into(),
+				QoS::AtMostOnce,
+				true,
+				None,
+			)
+			.unwrap(),
+		)
+		.await;
+
+	let message = subscription.recv().await.unwrap();
+	client
+		.publish_message(message.republish_to(Topic::from_static("b/c")))
+		.await
+		.unwrap();
+
+	let frame = broker.read_frame().await;
+	let publish = Publish::parse(&frame.payload, frame.header & 0x0f).unwrap();
+	assert_eq!(publish.topic(), Topic::from_static("b/c"));
+	assert_eq!(&publish.payload()[..], b"payload");
+	assert_eq!(publish.qos(), QoS::AtMostOnce);
+	assert!(publish.retain());
+
+	drop(subscription);
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn publish_with_uses_options() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let options = PublishOptions {
+		retain: true,
+		..Default::default()
+	};
+	client
+		.publish_with("a/b", "payload", options)
+		.await
+		.unwrap();
+
+	let frame = broker.read_frame().await;
+	let publish = Publish::parse(&frame.payload, frame.header & 0x0f).unwrap();
+	assert_eq!(publish.qos(), QoS::AtMostOnce);
+	assert!(publish.retain());
+	assert_eq!(&publish.payload()[..], b"payload");
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "v5")]
+#[tokio::test]
+async fn expired_messages_are_not_sent_after_reconnecting() {
+	use crate::packets::PubAck;
+
+	let (listener, port) = Broker::bind().await;
+	let options = Options {
+		protocol_version: ProtocolVersion::V5,
+		..("127.0.0.1", port).into()
+	};
+	let (client, handle) = tcp_client(options);
+	let mut broker = Broker::accept(&listener).await;
+
+	let publish = |expiry: Option<u32>| {
+		let client = client.clone();
+		let mut options = PublishOptions::new(QoS::AtLeastOnce, false);
+		options.properties.message_expiry_interval = expiry;
+		tokio::spawn(async move { client.publish_with("a/b", "payload", options).await })
+	};
+
+	// The first message is sent, but not acknowledged before the connection
+	// is lost.
+	let in_flight = publish(Some(1));
+	let frame = broker.read_frame().await;
+	assert_eq!(frame.header & 0xf0, 0x30);
+	drop(broker);
+	drop(listener);
+	time::timeout(Duration::from_secs(1), async {
+		while client.is_connected() {
+			tokio::task::yield_now().await;
+		}
+	})
+	.await
+	.unwrap();
+
+	// The others wait for the client to reconnect.
+	let queued = publish(Some(1));
+	let unlimited = publish(None);
+
+	time::sleep(Duration::from_secs(2)).await;
+	let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+	let mut broker = time::timeout(Duration::from_secs(5), Broker::accept(&listener))
+		.await
+		.unwrap();
+
+	// Only the message without an expiry interval is sent.
+	let frame = broker.read_frame().await;
+	let publish = Publish::parse_v5(&frame.payload, frame.header & 0x0f).unwrap();
+	assert_eq!(&publish.payload()[..], b"payload");
+	broker.write(&PubAck::new(publish.id().unwrap())).await;
+
+	unlimited.await.unwrap().unwrap();
+	assert!(matches!(
+		in_flight.await.unwrap(),
+		Err(ClientError::MessageExpired)
+	));
+	assert!(matches!(
+		queued.await.unwrap(),
+		Err(ClientError::MessageExpired)
+	));
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "v5")]
+#[tokio::test]
+async fn requests_are_resent_after_authenticated_reconnect() {
+	use crate::packets::PubAck;
+
+	let (listener, port) = Broker::bind().await;
+	let options = Options {
+		protocol_version: ProtocolVersion::V5,
+		authenticator: Some(Box::new(NoExchange)),
+		..("127.0.0.1", port).into()
+	};
+	let (client, handle) = tcp_client(options);
+	let mut broker = Broker::accept(&listener).await;
+
+	let publish = {
+		let client = client.clone();
+		tokio::spawn(async move {
+			client
+				.publish("a/b", "payload", QoS::AtLeastOnce, false)
+				.await
+		})
+	};
+	assert_eq!(broker.read_frame().await.header & 0xf0, 0x30);
+	let subscribe = {
+		let client = client.clone();
+		tokio::spawn(async move { client.subscribe(("c/d", QoS::AtMostOnce), 8).await })
+	};
+	assert_eq!(broker.read_frame().await.header, 0x82);
+	drop(broker);
+
+	// Both requests are re-sent after the new Connect packet.
+	let mut broker = time::timeout(Duration::from_secs(5), Broker::accept(&listener))
+		.await
+		.unwrap();
+	let frame = broker.read_frame().await;
+	assert_eq!(
+		frame.header, 0x3a,
+		"expected a duplicate AtLeastOnce Publish"
+	);
+	let id = Publish::parse_v5(&frame.payload, frame.header & 0x0f)
+		.unwrap()
+		.id()
+		.unwrap();
+	assert_eq!(broker.read_frame().await.header, 0x82);
+
+	broker.write(&PubAck::new(id)).await;
+	broker
+		.stream
+		.write_all(b"\x90\x04\x00\x01\x00\x00")
+		.await
+		.unwrap();
+	publish.await.unwrap().unwrap();
+	subscribe.await.unwrap().unwrap();
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn pending_count_reflects_unacknowledged_publishes() {
+	use crate::packets::PubAck;
+
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+	assert_eq!(client.pending_count().await.unwrap(), 0);
+
+	let mut acks = Vec::new();
+	for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::AtLeastOnce] {
+		let ack = client
+			.publish_deferred("a/b", "payload", qos, false)
+			.await
+			.unwrap();
+		acks.push(ack);
+	}
+	// AtMostOnce publishes are complete once written.
+	assert_eq!(client.pending_count().await.unwrap(), 2);
+
+	broker.read_frame().await;
+	for _ in 0..2 {
+		let frame = broker.read_frame().await;
+		let publish = Publish::parse(&frame.payload, frame.header & 0x0f).unwrap();
+		broker.write(&PubAck::new(publish.id().unwrap())).await;
+	}
+	for ack in acks {
+		ack.await.unwrap();
+	}
+	assert_eq!(client.pending_count().await.unwrap(), 0);
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn deferred_publishes_are_acknowledged_concurrently() {
+	use crate::packets::PubAck;
+
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let mut acks = Vec::new();
+	for payload in ["1", "2", "3"] {
+		let ack = client
+			.publish_deferred("a/b", payload, QoS::AtLeastOnce, false)
+			.await
+			.unwrap();
+		acks.push(ack);
+	}
+
+	let mut ids = Vec::new();
+	for _ in 0..3 {
+		let frame = broker.read_frame().await;
+		let publish = Publish::parse(&frame.payload, frame.header & 0x0f).unwrap();
+		ids.push(publish.id().unwrap());
+	}
+
+	// None of the messages have been delivered until they are acknowledged.
+	let [first, second, third]: [_; 3] = acks.try_into().unwrap();
+	let mut all = Box::pin(async { tokio::join!(first, second, third) });
+	assert!(time::timeout(Duration::from_millis(50), &mut all)
+		.await
+		.is_err());
+
+	for id in ids.into_iter().rev() {
+		broker.write(&PubAck::new(id)).await;
+	}
+	let (first, second, third) = time::timeout(Duration::from_secs(1), all).await.unwrap();
+	first.unwrap();
+	second.unwrap();
+	third.unwrap();
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn failed_connect_reports_backoff() {
+	// Nothing is listening on the port once the listener is dropped.
+	let (listener, port) = Broker::bind().await;
+	drop(listener);
+
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let next_attempt_in = time::timeout(Duration::from_secs(1), async {
+		loop {
+			if let ConnectionStatus::Backoff { next_attempt_in } = client.connection_status() {
+				break next_attempt_in;
+			}
+			time::sleep(Duration::from_millis(1)).await;
+		}
+	})
+	.await
+	.unwrap();
+	assert!(next_attempt_in > Duration::ZERO);
+	assert!(!client.is_connected());
+
+	handle.abort();
+}
+
+#[tokio::test]
+async fn disconnect_while_server_is_unreachable() {
+	// Nothing is listening on the port once the listener is dropped.
+	let (listener, port) = Broker::bind().await;
+	drop(listener);
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	time::sleep(Duration::from_millis(200)).await;
+	assert!(!client.is_connected());
+
+	time::timeout(Duration::from_secs(1), client.disconnect())
+		.await
+		.unwrap()
+		.unwrap();
+	time::timeout(Duration::from_secs(1), handle)
+		.await
+		.unwrap()
+		.unwrap()
+		.unwrap();
+}
+
+#[tokio::test]
+async fn gives_up_after_max_reconnect_attempts() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		keep_alive: 1,
+		max_reconnect_attempts: Some(2),
+		..Default::default()
+	});
+
+	// The request waits for a connection that is never established.
+	let publish = tokio::spawn({
+		let client = client.clone();
+		async move {
+			client
+				.publish("a/b", "payload", QoS::AtLeastOnce, false)
+				.await
+		}
+	});
+
+	// Refuse every connection.
+	for _ in 0..2 {
+		let mut broker = Broker::connect(&listener).await;
+		broker.connack_with_code(5).await;
+	}
+
+	let result = time::timeout(Duration::from_secs(5), handle).await.unwrap();
+	let error = result.unwrap().unwrap_err();
+	assert!(matches!(
+		error.downcast_ref::<ClientError>(),
+		Some(ClientError::ConnectionLost)
+	));
+	assert!(matches!(
+		publish.await.unwrap(),
+		Err(ClientError::ConnectionLost)
+	));
+
+	// There was no third attempt.
+	assert!(time::timeout(Duration::from_millis(200), listener.accept())
+		.await
+		.is_err());
+}
+
+#[tokio::test]
+async fn set_keep_alive_reconnects_with_new_value() {
+	use crate::packets::Connect;
+
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		keep_alive: 60,
+		..Default::default()
+	});
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(client.subscribe("a/#", 8), broker.suback());
+	let _subscription = subscription.unwrap();
+
+	assert!(matches!(
+		client.set_keep_alive(Duration::ZERO).await,
+		Err(ClientError::InvalidKeepAlive(_))
+	));
+	client
+		.set_keep_alive(Duration::from_secs(30))
+		.await
+		.unwrap();
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+
+	// The next Connect packet advertises the new keep alive.
+	let (stream, _) = listener.accept().await.unwrap();
+	let mut broker = Broker {
+		stream,
+		buffer: BytesMut::new(),
+	};
+	let frame = broker.read_frame().await;
+	assert_eq!(frame.header, 0x10);
+	assert_eq!(Connect::parse(&frame.payload).unwrap().keep_alive, 30);
+	broker.connack().await;
+
+	// The subscription is restored.
+	let frame = broker.read_frame().await;
+	assert_eq!(frame.header, 0x82);
+	let subscribe = Subscribe::parse(&frame.payload).unwrap();
+	assert_eq!(subscribe.filters[0].0, "a/#");
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn subscription_survives_reconnects() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(client.subscribe("a/#", 8), broker.suback());
+	let mut subscription = subscription.unwrap();
+
+	let publish = Publish::new(
+		Topic::from_static("a/b"),
+		"payload".into(),
+		QoS::AtMostOnce,
+		false,
+		None,
+	)
+	.unwrap();
+	broker.write(&publish).await;
+	assert_eq!(subscription.recv().await.unwrap().topic, "a/b");
+
+	// Force a reconnect. The client task re-subscribes, as the Server has not
+	// kept the session.
+	drop(broker);
+	let mut broker = Broker::accept(&listener).await;
+	let frame = broker.read_frame().await;
+	assert_eq!(frame.header, 0x82);
+
+	// The connection is lost again before the SubAck, so the Subscribe packet
+	// is re-sent on the next connection.
+	drop(broker);
+	let mut broker = Broker::accept(&listener).await;
+	broker.suback().await;
+
+	broker.write(&publish).await;
+	let message = subscription
+		.recv_timeout(Duration::from_secs(1))
+		.await
+		.unwrap()
+		.unwrap();
+	assert_eq!(message.topic, "a/b");
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn publish_after_reconnect_sends_full_topic() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		keep_alive: 1,
+		clean_session: false,
+		client_id: "client".into(),
+		..Default::default()
+	});
+
+	for _ in 0..2 {
+		let mut broker = Broker::accept(&listener).await;
+		client
+			.publish("a/b", "payload", QoS::AtMostOnce, false)
+			.await
+			.unwrap();
+
+		// Publish packets always carry the full topic, even when the session is
+		// resumed on a new connection.
+		let frame = broker.read_frame().await;
+		let publish = Publish::parse(&frame.payload, frame.header & 0x0f).unwrap();
+		assert_eq!(publish.topic(), Topic::from_static("a/b"));
+	}
+
+	handle.abort();
+}
+
+#[tokio::test]
+async fn invalid_options_report_disconnected() {
+	let (client, handle) = tcp_client(Options {
+		keep_alive: 0,
+		..Default::default()
+	});
+	assert_eq!(client.connection_status(), ConnectionStatus::Disconnected);
+	assert!(handle.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn vectored_payload_is_reassembled() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	// Long enough that the chunks are not copied into the outgoing buffer.
+	let first = bytes::Bytes::from(vec![b'a'; 1024]);
+	let second = bytes::Bytes::from_static(b"second chunk");
+	for qos in [QoS::AtMostOnce, QoS::AtLeastOnce] {
+		let client = client.clone();
+		let payload = [first.clone(), second.clone()];
+		tokio::spawn(async move { client.publish_vectored("a/b", payload, qos, false).await });
+
+		let frame = broker.read_frame().await;
+		let publish = Publish::parse(&frame.payload, frame.header & 0x0f).unwrap();
+		assert_eq!(publish.qos(), qos);
+		assert_eq!(publish.payload_len(), 1024 + 12);
+		assert_eq!(&publish.payload()[..1024], &first[..]);
+		assert_eq!(&publish.payload()[1024..], b"second chunk");
+	}
+
+	client.disconnect().await.unwrap();
+	handle.abort();
+}
+
+#[tokio::test]
+async fn dropping_last_client_disconnects() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	// Dropping a handle to the client must not disconnect a shared connection.
+	let clone = client.clone();
+	drop(client);
+	time::sleep(Duration::from_millis(50)).await;
+	assert!(!handle.is_finished());
+
+	// Once every handle has been dropped, nothing can use the connection.
+	drop(clone);
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+	time::timeout(Duration::from_secs(1), handle)
+		.await
+		.unwrap()
+		.unwrap()
+		.unwrap();
+}
+
+#[tokio::test]
+async fn subscription_outlives_dropped_client() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(client.subscribe("a/#", 8), broker.suback());
+	let mut subscription = subscription.unwrap();
+
+	// The Subscription can still receive messages without a Client.
+	drop(client);
+	let topic = Topic::from_static("a/b");
+	broker
+		.write(&Publish::new(topic, "payload".into(), QoS::AtMostOnce, false, None).unwrap())
+		.await;
+	assert_eq!(subscription.recv().await.unwrap().topic, "a/b");
+
+	// Dropping it too unsubscribes, then disconnects.
+	drop(subscription);
+	assert_eq!(broker.read_frame().await.header, 0xa2);
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+	time::timeout(Duration::from_secs(1), handle)
+		.await
+		.unwrap()
+		.unwrap()
+		.unwrap();
+}
+
+#[tokio::test]
+async fn disconnect_waits_for_last_clone() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(client.subscribe("a/#", 8), broker.suback());
+	let mut subscription = subscription.unwrap();
+
+	// Disconnecting one clone only releases that handle.
+	let clone = client.clone();
+	clone.disconnect().await.unwrap();
+	assert!(!handle.is_finished());
+
+	broker
+		.write(
+			&Publish::new(
+				Topic::from_static("a/b"),
+				"payload".into(),
+				QoS::AtMostOnce,
+				false,
+				None,
+			)
+			.unwrap(),
+		)
+		.await;
+	assert_eq!(subscription.recv().await.unwrap().topic, "a/b");
+
+	client
+		.publish("a/b", "payload", QoS::AtMostOnce, false)
+		.await
+		.unwrap();
+	assert_eq!(broker.read_frame().await.header, 0x30);
+
+	// The last clone disconnects the client.
+	client.disconnect().await.unwrap();
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn dropping_last_clone_completes_disconnect() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let clone = client.clone();
+	clone.disconnect().await.unwrap();
+	drop(client);
+
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn unsupported_protocol_version_is_not_retried() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+
+	let mut broker = Broker::connect(&listener).await;
+	broker.connack_with_code(0x01).await;
+
+	let error = handle.await.unwrap().unwrap_err();
+	assert!(matches!(
+		error.downcast_ref::<ClientError>(),
+		Some(ClientError::UnsupportedProtocolVersion)
+	));
+	assert!(!client.is_connected());
+
+	// The client task must not attempt to reconnect.
+	assert!(time::timeout(Duration::from_millis(200), listener.accept())
+		.await
+		.is_err());
+}
+
+#[tokio::test]
+async fn unmatched_publish_completes_handshake() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	// There are no subscriptions, so none of these messages can be delivered.
+	let topic = Topic::from_static("a/b");
+	let id = PacketId::new(1).unwrap();
+	broker
+		.write(&Publish::new(topic, "0".into(), QoS::AtMostOnce, false, None).unwrap())
+		.await;
+	broker
+		.write(&Publish::new(topic, "1".into(), QoS::AtLeastOnce, false, Some(id)).unwrap())
+		.await;
+	assert_eq!(broker.read_frame().await.header, 0x40);
+
+	broker
+		.write(&Publish::new(topic, "2".into(), QoS::ExactlyOnce, false, Some(id)).unwrap())
+		.await;
+	assert_eq!(broker.read_frame().await.header, 0x50);
+	broker.write(&PubRel::new(id)).await;
+	assert_eq!(broker.read_frame().await.header, 0x70);
+
+	assert!(client.is_connected());
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn subscriptions_are_restored_in_a_new_session() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(client.subscribe("a/b", 8), broker.suback());
+	let mut subscription = subscription.unwrap();
+	drop(broker);
+
+	// The Server starts a new session, so the client subscribes again.
+	let mut broker = time::timeout(Duration::from_secs(5), Broker::accept(&listener))
+		.await
+		.unwrap();
+	broker.suback().await;
+
+	let topic = Topic::from_static("a/b");
+	let id = PacketId::new(1).unwrap();
+	broker
+		.write(&Publish::new(topic, "1".into(), QoS::AtLeastOnce, false, Some(id)).unwrap())
+		.await;
+	assert_eq!(broker.read_frame().await.header, 0x40);
+	assert_eq!(&subscription.recv().await.unwrap().payload[..], b"1");
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+}
+
+#[tokio::test]
+async fn unreleased_messages_are_forgotten_with_the_session() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(client.subscribe("a/b", 8), broker.suback());
+	let mut subscription = subscription.unwrap();
+
+	// The connection is lost before the Server releases the message.
+	let topic = Topic::from_static("a/b");
+	let id = PacketId::new(1).unwrap();
+	broker
+		.write(&Publish::new(topic, "1".into(), QoS::ExactlyOnce, false, Some(id)).unwrap())
+		.await;
+	assert_eq!(broker.read_frame().await.header, 0x50);
+	assert_eq!(&subscription.recv().await.unwrap().payload[..], b"1");
+	drop(broker);
+
+	// The Server starts a new session, in which the packet ID is reused.
+	let mut broker = time::timeout(Duration::from_secs(5), Broker::accept(&listener))
+		.await
+		.unwrap();
+	broker.suback().await;
+	broker
+		.write(&Publish::new(topic, "2".into(), QoS::ExactlyOnce, false, Some(id)).unwrap())
+		.await;
+	assert_eq!(broker.read_frame().await.header, 0x50);
+	assert_eq!(&subscription.recv().await.unwrap().payload[..], b"2");
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn lenient_parse_mode_accepts_pubrel_without_flags() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		parse_mode: ParseMode::Lenient,
+		..Default::default()
+	});
+	let mut broker = Broker::accept(&listener).await;
+
+	let topic = Topic::from_static("a/b");
+	let id = PacketId::new(1).unwrap();
+	broker
+		.write(&Publish::new(topic, "2".into(), QoS::ExactlyOnce, false, Some(id)).unwrap())
+		.await;
+	assert_eq!(broker.read_frame().await.header, 0x50);
+
+	// PubRel with flags 0x00 rather than the reserved 0x02.
+	broker
+		.stream
+		.write_all(&[0x60, 0x02, 0x00, 0x01])
+		.await
+		.unwrap();
+	let frame = broker.read_frame().await;
+	assert_eq!(frame.header, 0x70);
+	assert_eq!(&frame.payload[..], &[0x00, 0x01]);
+
+	assert!(client.is_connected());
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn mixed_qos_messages_are_delivered_in_order() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(
+		client.subscribe(("a/b", QoS::ExactlyOnce), 8),
+		broker.suback()
+	);
+	let mut subscription = subscription.unwrap();
+
+	// Interleave ExactlyOnce and AtLeastOnce messages, releasing the
+	// ExactlyOnce messages only after all of them have been sent.
+	let topic = Topic::from_static("a/b");
+	let qos = [
+		QoS::ExactlyOnce,
+		QoS::AtLeastOnce,
+		QoS::ExactlyOnce,
+		QoS::AtMostOnce,
+		QoS::AtLeastOnce,
+	];
+	for (n, qos) in qos.into_iter().enumerate() {
+		let id = (qos != QoS::AtMostOnce).then(|| PacketId::new(n as u16 + 1).unwrap());
+		let publish = Publish::new(topic, n.to_string().into(), qos, false, id).unwrap();
+		broker.write(&publish).await;
+	}
+	for id in [1, 3] {
+		broker.write(&PubRel::new(PacketId::new(id).unwrap())).await;
+	}
+
+	for n in 0..qos.len() {
+		let message = subscription.recv().await.unwrap();
+		assert_eq!(message.payload, n.to_string());
+	}
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn lost_pubrec_is_resent_without_redelivery() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(
+		client.subscribe(("a/b", QoS::ExactlyOnce), 8),
+		broker.suback()
+	);
+	let mut subscription = subscription.unwrap();
+
+	let topic = Topic::from_static("a/b");
+	let id = PacketId::new(1).unwrap();
+	for (payload, duplicate) in [("1", false), ("1", true)] {
+		let publish = Publish::ExactlyOnce {
+			id,
+			retain: false,
+			duplicate,
+			topic,
+			payload: payload.into(),
+		};
+		broker.write(&publish).await;
+
+		// The PubRec is "lost", so the Server re-sends the Publish packet.
+		let frame = broker.read_frame().await;
+		assert_eq!(frame.header, 0x50);
+		assert_eq!(PubRec::parse(&frame.payload).unwrap().id, id);
+	}
+	broker.write(&PubRel::new(id)).await;
+	assert_eq!(broker.read_frame().await.header, 0x70);
+
+	// Once released, the ID is used for a new message.
+	broker
+		.write(&Publish::new(topic, "2".into(), QoS::ExactlyOnce, false, Some(id)).unwrap())
+		.await;
+	assert_eq!(broker.read_frame().await.header, 0x50);
+
+	assert_eq!(subscription.recv().await.unwrap().payload, "1");
+	assert_eq!(subscription.recv().await.unwrap().payload, "2");
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn lost_pubcomp_is_resent() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut events = client.events();
+	let mut broker = Broker::accept(&listener).await;
+
+	let topic = Topic::from_static("a/b");
+	let id = PacketId::new(1).unwrap();
+	broker
+		.write(&Publish::new(topic, "1".into(), QoS::ExactlyOnce, false, Some(id)).unwrap())
+		.await;
+	assert_eq!(broker.read_frame().await.header, 0x50);
+
+	// The PubComp is "lost", so the Server re-sends the PubRel packet.
+	for _ in 0..2 {
+		broker.write(&PubRel::new(id)).await;
+		let frame = broker.read_frame().await;
+		assert_eq!(frame.header, 0x70);
+		assert_eq!(PubComp::parse(&frame.payload).unwrap().id, id);
+	}
+
+	// The re-sent PubRel is not a protocol error.
+	assert!(client.is_connected());
+	assert!(events.try_recv().is_err());
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn retained_complete_waits_for_quiet_period() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(client.subscribe("a/#", 8), broker.suback());
+	let mut subscription = subscription.unwrap();
+
+	let topic = Topic::from_static("a/b");
+	let quiet = Duration::from_millis(100);
+	let (messages, ()) = tokio::join!(subscription.retained_complete(quiet), async {
+		for (payload, retain) in [("1", true), ("2", false), ("3", true)] {
+			broker
+				.write(&Publish::new(topic, payload.into(), QoS::AtMostOnce, retain, None).unwrap())
+				.await;
+			time::sleep(quiet / 4).await;
+		}
+	});
+	let messages = messages.unwrap();
+	let payloads: Vec<_> = messages
+		.iter()
+		.map(|message| &message.payload[..])
+		.collect();
+	assert_eq!(payloads, [&b"1"[..], b"2", b"3"]);
+
+	// Messages after the quiet period are received as usual.
+	broker
+		.write(&Publish::new(topic, "4".into(), QoS::AtMostOnce, false, None).unwrap())
+		.await;
+	assert_eq!(subscription.recv().await.unwrap().payload, "4");
+
+	// Without any retained messages, this resolves after the quiet period.
+	let started = time::Instant::now();
+	assert!(subscription
+		.retained_complete(quiet)
+		.await
+		.unwrap()
+		.is_empty());
+	assert!(started.elapsed() >= quiet);
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn idle_connection_is_closed() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		idle_timeout: Some(Duration::from_millis(100)),
+		..Default::default()
+	});
+	let mut broker = Broker::accept(&listener).await;
+
+	// With no activity, the client disconnects and closes the socket.
+	let disconnect = time::timeout(Duration::from_secs(1), broker.read_frame())
+		.await
+		.unwrap();
+	assert_eq!(disconnect.header, 0xe0);
+	assert_eq!(broker.stream.read(&mut [0; 1]).await.unwrap(), 0);
+	assert!(!client.is_connected());
+
+	// The next command reconnects.
+	let publish = tokio::spawn({
+		let client = client.clone();
+		async move { client.publish("a/b", "1", QoS::AtMostOnce, false).await }
+	});
+	let mut broker = Broker::accept(&listener).await;
+	publish.await.unwrap().unwrap();
+	assert_eq!(broker.read_frame().await.header, 0x30);
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn static_payloads_are_not_copied() {
+	static PAYLOAD: &[u8] = b"static payload";
+
+	let (tx, mut rx) = command_channel::channel(None);
+	let client = Client::new(
+		tx,
+		Default::default(),
+		Default::default(),
+		Default::default(),
+		Default::default(),
+		broadcast::channel(1).0,
+		"".into(),
+	);
+	let publish =
+		tokio::spawn(async move { client.publish("a/b", PAYLOAD, QoS::AtMostOnce, false).await });
+
+	let command = rx.recv().await.unwrap();
+	let Command::Publish(command) = *command else {
+		panic!("expected a Publish command");
+	};
+	let chunk = command.payload.iter().next().unwrap();
+	assert_eq!(chunk.as_ptr(), PAYLOAD.as_ptr());
+
+	command.response.send(Ok(())).unwrap();
+	publish.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn full_subscription_drops_messages() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		overflow: Overflow::Drop,
+		..Default::default()
+	});
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(client.subscribe("a/#", 1), broker.suback());
+	let mut subscription = subscription.unwrap();
+	assert_eq!(subscription.dropped_count(), 0);
+
+	// The first message fills the Subscription, so the rest are dropped.
+	let topic = Topic::from_static("a/b");
+	let id = PacketId::new(1).unwrap();
+	for payload in ["0", "1"] {
+		broker
+			.write(&Publish::new(topic, payload.into(), QoS::AtMostOnce, false, None).unwrap())
+			.await;
+	}
+	broker
+		.write(&Publish::new(topic, "2".into(), QoS::AtLeastOnce, false, Some(id)).unwrap())
+		.await;
+
+	// Dropped messages are still acknowledged.
+	assert_eq!(broker.read_frame().await.header, 0x40);
+	assert_eq!(subscription.dropped_count(), 2);
+	assert_eq!(&subscription.recv().await.unwrap().payload[..], b"0");
+
+	drop(subscription);
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn redelivered_messages_are_suppressed() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		duplicate_cache: Some(8),
+		..Default::default()
+	});
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(client.subscribe("a/#", 8), broker.suback());
+	let mut subscription = subscription.unwrap();
+
+	let topic = Topic::from_static("a/b");
+	let id = PacketId::new(1).unwrap();
+	broker
+		.write(&Publish::new(topic, "0".into(), QoS::AtLeastOnce, false, Some(id)).unwrap())
+		.await;
+	assert_eq!(broker.read_frame().await.header, 0x40);
+
+	// The PubAck was "lost", so the Server redelivers the message. It must be
+	// acknowledged again.
+	broker
+		.write(&Publish::AtLeastOnce {
+			id,
+			retain: false,
+			duplicate: true,
+			topic,
+			payload: "0".into(),
+		})
+		.await;
+	assert_eq!(broker.read_frame().await.header, 0x40);
+
+	// The packet identifier is reused for a new message.
+	broker
+		.write(&Publish::new(topic, "1".into(), QoS::AtLeastOnce, false, Some(id)).unwrap())
+		.await;
+	assert_eq!(broker.read_frame().await.header, 0x40);
+
+	assert_eq!(&subscription.recv().await.unwrap().payload[..], b"0");
+	assert_eq!(&subscription.recv().await.unwrap().payload[..], b"1");
+	assert!(subscription
+		.recv_timeout(Duration::from_millis(50))
+		.await
+		.is_err());
+
+	drop(subscription);
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn add_filter_delivers_to_existing_subscription() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(client.subscribe("a/#", 8), broker.suback());
+	let mut subscription = subscription.unwrap();
+
+	let (result, ()) = tokio::join!(
+		subscription.add_filter("b/#", QoS::AtMostOnce),
+		broker.suback()
+	);
+	result.unwrap();
+	assert_eq!(subscription.filters().len(), 2);
+	assert_eq!(subscription.filters()[1].0, "b/#");
+
+	for topic in ["a/1", "b/2"] {
+		broker
+			.write(
+				&Publish::new(
+					topic.try_into().unwrap(),
+					"payload".into(),
+					QoS::AtMostOnce,
+					false,
+					None,
+				)
+				.unwrap(),
+			)
+			.await;
+	}
+	assert_eq!(subscription.recv().await.unwrap().topic, "a/1");
+	assert_eq!(subscription.recv().await.unwrap().topic, "b/2");
+
+	drop(subscription);
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+/// Reads a Subscribe packet and writes the SubAck, followed immediately by a
+/// retained Publish on `topic`, in a single write.
+async fn suback_with_retained(broker: &mut Broker, topic: &str) {
+	let frame = broker.read_frame().await;
+	assert_eq!(frame.header, 0x82);
+	let subscribe = Subscribe::parse(&frame.payload).unwrap();
+
+	let mut buffer = BytesMut::new();
+	SubAck {
+		id: subscribe.id,
+		result: subscribe.filters.iter().map(|(_, qos)| Ok(*qos)).collect(),
+	}
+	.serialize_to_bytes(&mut buffer)
+	.unwrap();
+	Publish::new(
+		topic.try_into().unwrap(),
+		"retained".into(),
+		QoS::AtMostOnce,
+		true,
+		None,
+	)
+	.unwrap()
+	.serialize_to_bytes(&mut buffer)
+	.unwrap();
+	broker.stream.write_all(&buffer).await.unwrap();
+}
+
+#[tokio::test]
+async fn retained_publish_immediately_after_suback_is_delivered() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let (subscription, ()) = tokio::join!(
+		client.subscribe("a/#", 8),
+		suback_with_retained(&mut broker, "a/1")
+	);
+	let mut subscription = subscription.unwrap();
+	let message = subscription.recv().await.unwrap();
+	assert_eq!(message.topic, "a/1");
+	assert!(message.retain);
+
+	// Filters added mid-session must also receive their retained messages.
+	let (result, ()) = tokio::join!(
+		subscription.add_filter("b/#", QoS::AtMostOnce),
+		suback_with_retained(&mut broker, "b/2")
+	);
+	result.unwrap();
+	let message = subscription.recv().await.unwrap();
+	assert_eq!(message.topic, "b/2");
+	assert!(message.retain);
+
+	drop(subscription);
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn subscribe_nowait_routes_messages() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	// Both requests are sent before either is acknowledged.
+	let mut first = client.subscribe_nowait("a/#", 8).await.unwrap();
+	let mut second = client
+		.subscribe_nowait(("b/#", QoS::AtLeastOnce), 8)
+		.await
+		.unwrap();
+	assert_eq!(first.filters()[0].0, "a/#");
+	assert_eq!(
+		second.filters(),
+		[("b/#".try_into().unwrap(), QoS::AtLeastOnce)]
+	);
+
+	broker.suback().await;
+	let frame = broker.read_frame().await;
+	let subscribe = Subscribe::parse(&frame.payload).unwrap();
+	broker
+		.write(&SubAck {
+			id: subscribe.id,
+			result: vec![Ok(QoS::AtMostOnce)],
+		})
+		.await;
+
+	for topic in ["b/2", "a/1"] {
+		broker
+			.write(
+				&Publish::new(
+					topic.try_into().unwrap(),
+					"payload".into(),
+					QoS::AtMostOnce,
+					false,
+					None,
+				)
+				.unwrap(),
+			)
+			.await;
+	}
+	assert_eq!(first.recv().await.unwrap().topic, "a/1");
+	assert_eq!(second.recv().await.unwrap().topic, "b/2");
+
+	// The granted QoS is known once the SubAck has been received.
+	assert_eq!(
+		second.subscribed().await.unwrap(),
+		[("b/#".try_into().unwrap(), QoS::AtMostOnce)]
+	);
+
+	// The filters were not unsubscribed when the SubAck arrived.
+	client.disconnect().await.unwrap();
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn dropped_subscribe_request_is_unsubscribed() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	// Cancel the request once the Subscribe packet has been sent, but before the
+	// SubAck is received.
+	let mut subscribe = Box::pin(client.subscribe("a/#", 1));
+	let frame = tokio::select! {
+		_ = &mut subscribe => panic!("subscribe completed without a SubAck"),
+		frame = broker.read_frame() => frame,
+	};
+	drop(subscribe);
+
+	assert_eq!(frame.header, 0x82);
+	let subscribe = Subscribe::parse(&frame.payload).unwrap();
+	broker
+		.write(&SubAck {
+			id: subscribe.id,
+			result: vec![Ok(QoS::AtMostOnce)],
+		})
+		.await;
+
+	let frame = time::timeout(Duration::from_secs(1), broker.read_frame())
+		.await
+		.unwrap();
+	assert_eq!(frame.header, 0xa2);
+	let unsubscribe = crate::packets::Unsubscribe::parse(&frame.payload).unwrap();
+	assert_eq!(unsubscribe.filters, ["a/#"]);
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn subscribe_without_suback_times_out() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		keep_alive: 1,
+		subscribe_timeout: Duration::from_millis(100),
+		..Default::default()
+	});
+	let mut broker = Broker::accept(&listener).await;
+
+	// The Server accepts the Subscribe packets, but never acknowledges them.
+	let subscribe = client.subscribe_timeout("a/#", 1, Duration::from_millis(50));
+	let (result, frame) = tokio::join!(subscribe, broker.read_frame());
+	assert!(matches!(result, Err(ClientError::Timeout)));
+	assert_eq!(frame.header, 0x82);
+
+	// Without a timeout of its own, the request is failed by the client task.
+	let (result, frame) = tokio::join!(client.subscribe("b/#", 1), broker.read_frame());
+	assert!(matches!(result, Err(ClientError::Timeout)));
+	assert_eq!(frame.header, 0x82);
+
+	// Both requests are unsubscribed, in case the Server subscribed anyway.
+	let mut unsubscribed = Vec::new();
+	while unsubscribed.len() < 2 {
+		let frame = time::timeout(Duration::from_secs(1), broker.read_frame())
+			.await
+			.unwrap();
+		if frame.header == 0xa2 {
+			let unsubscribe = crate::packets::Unsubscribe::parse(&frame.payload).unwrap();
+			unsubscribed.extend(unsubscribe.filters.iter().map(|f| f.to_string()));
+		}
+	}
+	unsubscribed.sort();
+	assert_eq!(unsubscribed, ["a/#", "b/#"]);
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn subscribe_times_out_while_publishing() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		keep_alive: 1,
+		subscribe_timeout: Duration::from_millis(100),
+		..Default::default()
+	});
+	let _broker = Broker::accept(&listener).await;
+
+	// The client writes more often than the keep alive interval.
+	let publisher = tokio::spawn({
+		let client = client.clone();
+		async move {
+			loop {
+				client
+					.publish("a/b", "payload", QoS::AtMostOnce, false)
+					.await
+					.unwrap();
+				time::sleep(Duration::from_millis(100)).await;
+			}
+		}
+	});
+
+	// The Server never acknowledges the Subscribe packet.
+	let subscribe = tokio::spawn({
+		let client = client.clone();
+		async move { client.subscribe("c/#", 1).await }
+	});
+	let result = time::timeout(Duration::from_secs(3), subscribe)
+		.await
+		.unwrap()
+		.unwrap();
+	assert!(matches!(result, Err(ClientError::Timeout)));
+
+	publisher.abort();
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn subscribe_with_topic_buf() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let topic = TopicBuf::new("a/b").unwrap();
+	let (subscription, ()) = tokio::join!(client.subscribe(topic.clone(), 1), broker.suback());
+	let subscription = subscription.unwrap();
+	assert_eq!(subscription.filters(), [(topic.into(), QoS::AtMostOnce)]);
+
+	drop(subscription);
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn flush_waits_for_queued_publishes() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut broker = Broker::accept(&listener).await;
+
+	// Queue the messages without waiting for them to be processed.
+	let publishes: Vec<_> = (0..5)
+		.map(|n| {
+			let client = client.clone();
+			tokio::spawn(async move {
+				client
+					.publish("a/b", format!("{n}"), QoS::AtMostOnce, false)
+					.await
+			})
+		})
+		.collect();
+	for publish in publishes {
+		publish.await.unwrap().unwrap();
+	}
+	client.flush().await.unwrap();
+
+	// Every message has been written to the socket, so the Server should
+	// receive them without the Client doing anything else.
+	for n in 0..5 {
+		let frame = time::timeout(Duration::from_secs(1), broker.read_frame())
+			.await
+			.unwrap();
+		let Publish::AtMostOnce { payload, .. } =
+			Publish::parse(&frame.payload, frame.header & 0x0f).unwrap()
+		else {
+			panic!("expected an AtMostOnce Publish packet");
+		};
+		assert_eq!(payload, format!("{n}").as_bytes());
+	}
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "v5")]
+#[tokio::test]
+async fn enhanced_authentication() {
+	use crate::{
+		clients::Authenticator,
+		packets::{Auth, AuthProperties, AuthReasonCode, Connect},
+	};
+
+	/// Answers each challenge with the challenge reversed.
+	#[derive(Debug, Default)]
+	struct Reverse;
+
+	impl Authenticator for Reverse {
+		fn method(&self) -> &str {
+			"REVERSE"
+		}
+
+		fn initial_data(&mut self) -> Option<Vec<u8>> {
+			Some(b"hello".to_vec())
+		}
+
+		fn continue_auth(&mut self, challenge: &[u8]) -> Vec<u8> {
+			challenge.iter().rev().copied().collect()
+		}
+	}
+
+	let (listener, port) = Broker::bind().await;
+	let options = Options {
+		protocol_version: ProtocolVersion::V5,
+		authenticator: Some(Box::<Reverse>::default()),
+		..("127.0.0.1", port).into()
+	};
+	let (client, handle) = tcp_client(options);
+
+	let (stream, _) = listener.accept().await.unwrap();
+	let mut broker = Broker {
+		stream,
+		buffer: BytesMut::new(),
+	};
+	let frame = broker.read_frame().await;
+	let connect = Connect::parse(&frame.payload).unwrap();
+	assert_eq!(connect.protocol_level, 5);
+	assert_eq!(connect.properties.authentication_method, Some("REVERSE"));
+	assert_eq!(connect.properties.authentication_data, Some(&b"hello"[..]));
+
+	for challenge in [&b"abc"[..], &b"xyz"[..]] {
+		broker
+			.write(&Auth {
+				reason_code: AuthReasonCode::ContinueAuthentication,
+				properties: AuthProperties {
+					authentication_method: Some("REVERSE"),
+					authentication_data: Some(challenge),
+					..Default::default()
+				},
+			})
+			.await;
+
+		let frame = broker.read_frame().await;
+		assert_eq!(frame.header, 0xf0);
+		let response = Auth::parse(&frame.payload).unwrap();
+		assert_eq!(response.reason_code, AuthReasonCode::ContinueAuthentication);
+		let expected: Vec<u8> = challenge.iter().rev().copied().collect();
+		assert_eq!(response.properties.authentication_data, Some(&expected[..]));
+	}
+
+	broker.connack().await;
+	time::timeout(Duration::from_secs(1), async {
+		while !client.is_connected() {
+			tokio::task::yield_now().await;
+		}
+	})
+	.await
+	.unwrap();
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn session_store_resends_after_restart() {
+	use crate::{
+		clients::FileSessionStore,
+		packets::{PubAck, PubComp, PubRec},
+	};
+
+	let path = std::env::temp_dir().join(format!("tjh-mqtt-session-{}", std::process::id()));
+	let _ = std::fs::remove_file(&path);
+	let options = |port| Options {
+		clean_session: false,
+		client_id: "session".into(),
+		session_store: Some(Box::new(FileSessionStore::open(&path).unwrap())),
+		..("127.0.0.1", port).into()
+	};
+
+	// Publish two messages, but only receive a PubRec for the second before the
+	// process "exits".
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(options(port));
+	let mut broker = Broker::accept(&listener).await;
+	for (payload, qos) in [("one", QoS::AtLeastOnce), ("two", QoS::ExactlyOnce)] {
+		let client = client.clone();
+		tokio::spawn(async move { client.publish("a/b", payload, qos, false).await });
+		let frame = broker.read_frame().await;
+		assert_eq!(frame.header & 0xf0, 0x30);
+	}
+	broker.write(&PubRec::new(PacketId::new(2).unwrap())).await;
+	assert_eq!(broker.read_frame().await.header, 0x62);
+	handle.abort();
+	drop(client);
+
+	// After restarting, the Publish packet without a PubAck and the PubRel are
+	// re-sent with their original packet IDs.
+	let (_client, handle) = tcp_client(options(port));
+	let mut broker = Broker::accept(&listener).await;
+
+	let frame = broker.read_frame().await;
+	assert_eq!(
+		frame.header, 0x3a,
+		"expected a duplicate AtLeastOnce Publish"
+	);
+	let Publish::AtLeastOnce { id, payload, .. } =
+		Publish::parse(&frame.payload, frame.header & 0x0f).unwrap()
+	else {
+		unreachable!();
+	};
+	assert_eq!(id.get(), 1);
+	assert_eq!(&payload[..], b"one");
+
+	let frame = broker.read_frame().await;
+	assert_eq!(frame.header, 0x62);
+	assert_eq!(PubRel::parse(&frame.payload).unwrap().id.get(), 2);
+
+	// Once both are acknowledged, the log is cleared.
+	broker.write(&PubAck::new(id)).await;
+	broker.write(&PubComp::new(PacketId::new(2).unwrap())).await;
+	time::timeout(Duration::from_secs(1), async {
+		while std::fs::metadata(&path).unwrap().len() != 0 {
+			time::sleep(Duration::from_millis(10)).await;
+		}
+	})
+	.await
+	.unwrap();
+
+	handle.abort();
+	let _ = std::fs::remove_file(&path);
+}
+
+#[cfg(feature = "v5")]
+#[tokio::test]
+async fn server_keep_alive_overrides_configured_interval() {
+	use crate::packets::ConnAckProperties;
+
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		keep_alive: 60,
+		..Default::default()
+	});
+
+	let mut broker = Broker::connect(&listener).await;
+	broker
+		.write(&ConnAck {
+			properties: ConnAckProperties {
+				server_keep_alive: Some(1),
+				..Default::default()
+			},
+			..Default::default()
+		})
+		.await;
+
+	// With the configured interval, the first PingReq would be sent after 60
+	// seconds.
+	let frame = time::timeout(Duration::from_secs(3), broker.read_frame())
+		.await
+		.unwrap();
+	assert_eq!(frame.header, 0xc0);
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn unanswered_pingreq_reconnects_with_jitter() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		keep_alive: 1,
+		keep_alive_jitter: 0.05,
+		..Default::default()
+	});
+	let mut broker = Broker::accept(&listener).await;
+
+	// The Server never answers the PingReq.
+	let frame = time::timeout(Duration::from_secs(2), broker.read_frame())
+		.await
+		.unwrap();
+	assert_eq!(frame.header, 0xc0);
+
+	let _broker = time::timeout(Duration::from_secs(3), Broker::accept(&listener))
+		.await
+		.unwrap();
+	drop(client);
+	handle.abort();
+}
+
+#[tokio::test]
+async fn read_packets_from_duplex() {
+	use crate::packets::{OwnedPacket, OwnedPublish, PubAck};
+
+	let (mut client, mut server) = tokio::io::duplex(64);
+	let id = PacketId::new(1).unwrap();
+	let mut buffer = BytesMut::new();
+	Publish::new(
+		Topic::from_static("a/b"),
+		"payload".into(),
+		QoS::AtLeastOnce,
+		false,
+		Some(id),
+	)
+	.unwrap()
+	.serialize_to_bytes(&mut buffer)
+	.unwrap();
+	PubAck::new(id).serialize_to_bytes(&mut buffer).unwrap();
+	crate::packets::PingResp
+		.serialize_to_bytes(&mut buffer)
+		.unwrap();
+
+	// Split the first packet across writes.
+	let writer = tokio::spawn(async move {
+		let (first, rest) = buffer.split_at(5);
+		server.write_all(first).await.unwrap();
+		tokio::task::yield_now().await;
+		server.write_all(rest).await.unwrap();
+	});
+
+	let mut buffer = BytesMut::new();
+	let packet = read_packet(&mut client, &mut buffer).await.unwrap();
+	let Some(OwnedPacket::Publish(publish)) = packet else {
+		panic!("expected a Publish packet, got {packet:?}");
+	};
+	let OwnedPublish::AtLeastOnce { topic, payload, .. } = *publish else {
+		panic!("expected an AtLeastOnce Publish packet");
+	};
+	assert_eq!(topic, "a/b");
+	assert_eq!(&payload[..], b"payload");
+
+	let packet = read_packet(&mut client, &mut buffer).await.unwrap();
+	assert!(matches!(packet, Some(OwnedPacket::PubAck(PubAck { id: ack_id, .. })) if ack_id == id));
+	let packet = read_packet(&mut client, &mut buffer).await.unwrap();
+	assert!(matches!(packet, Some(OwnedPacket::PingResp)));
+
+	// The writer is dropped, so the stream is closed between packets.
+	writer.await.unwrap();
+	assert!(read_packet(&mut client, &mut buffer)
+		.await
+		.unwrap()
+		.is_none());
+}
+
+#[tokio::test]
+async fn packet_sink_round_trip() {
+	use super::PacketSink;
+	use crate::{
+		packets::{OwnedPacket, PubAck},
+		Packet,
+	};
+
+	let (mut client, server) = tokio::io::duplex(64);
+	let id = PacketId::new(1).unwrap();
+	let writer = tokio::spawn(async move {
+		let mut sink = PacketSink::new(server);
+		let publish = Publish::new(
+			Topic::from_static("a/b"),
+			"payload".into(),
+			QoS::AtLeastOnce,
+			false,
+			Some(id),
+		)
+		.unwrap();
+		sink.send(&publish.into()).await.unwrap();
+		sink.send_all(&[PubAck::new(id).into(), Packet::PingReq, Packet::Disconnect])
+			.await
+			.unwrap();
+	});
+
+	let mut buffer = BytesMut::new();
+	let packet = read_packet(&mut client, &mut buffer).await.unwrap();
+	assert!(matches!(packet, Some(OwnedPacket::Publish(_))));
+	let packet = read_packet(&mut client, &mut buffer).await.unwrap();
+	assert!(matches!(packet, Some(OwnedPacket::PubAck(PubAck { id: ack_id, .. })) if ack_id == id));
+	let packet = read_packet(&mut client, &mut buffer).await.unwrap();
+	assert!(matches!(packet, Some(OwnedPacket::PingReq)));
+	let packet = read_packet(&mut client, &mut buffer).await.unwrap();
+	assert!(matches!(packet, Some(OwnedPacket::Disconnect)));
+
+	writer.await.unwrap();
+	assert!(read_packet(&mut client, &mut buffer)
+		.await
+		.unwrap()
+		.is_none());
+}
+
+#[tokio::test]
+async fn protocol_error_sends_disconnect_and_event() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		disconnect_on_protocol_error: true,
+		..Default::default()
+	});
+	let mut events = client.events();
+
+	// The Server cannot send a Connect packet.
+	let mut broker = Broker::accept(&listener).await;
+	broker.write(&Connect::default()).await;
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+
+	let event = time::timeout(Duration::from_secs(1), events.recv())
+		.await
+		.unwrap()
+		.unwrap();
+	assert_eq!(
+		event,
+		ConnectionEvent::ProtocolError(ProtocolError::InvalidPacket(PacketType::Connect))
+	);
+
+	// The client reconnects after the protocol error.
+	let mut broker = Broker::accept(&listener).await;
+	client.disconnect().await.unwrap();
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+	handle.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "v5")]
+#[tokio::test]
+async fn exceeding_receive_maximum_is_a_protocol_error() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		disconnect_on_protocol_error: true,
+		protocol_version: ProtocolVersion::V5,
+		receive_maximum: Some(1),
+		..("127.0.0.1", port).into()
+	});
+	let mut events = client.events();
+
+	let (stream, _) = listener.accept().await.unwrap();
+	let mut broker = Broker {
+		stream,
+		buffer: BytesMut::new(),
+	};
+	let frame = broker.read_frame().await;
+	let connect = Connect::parse(&frame.payload).unwrap();
+	assert_eq!(connect.properties.receive_maximum, Some(1));
+	broker.connack().await;
+
+	// The first message is held until the Server releases it, so the second
+	// exceeds the receive maximum.
+	let topic = Topic::from_static("a/b");
+	for id in [1, 2] {
+		let id = PacketId::new(id).unwrap();
+		broker
+			.write_v5(
+				&Publish::new(topic, "payload".into(), QoS::ExactlyOnce, false, Some(id)).unwrap(),
+			)
+			.await;
+	}
+	assert_eq!(broker.read_frame().await.header, 0x50);
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+
+	let event = time::timeout(Duration::from_secs(1), events.recv())
+		.await
+		.unwrap()
+		.unwrap();
+	assert_eq!(
+		event,
+		ConnectionEvent::ProtocolError(ProtocolError::Other("receive maximum exceeded"))
+	);
+
+	handle.abort();
+}
+
+#[cfg(feature = "v5")]
+#[tokio::test]
+async fn v5_session_encodes_every_packet_as_v5() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		protocol_version: ProtocolVersion::V5,
+		..("127.0.0.1", port).into()
+	});
+
+	let (stream, _) = listener.accept().await.unwrap();
+	let mut broker = Broker {
+		stream,
+		buffer: BytesMut::new(),
+	};
+	let frame = broker.read_frame().await;
+	assert_eq!(Connect::parse(&frame.payload).unwrap().protocol_level, 5);
+	broker.connack().await;
+
+	// The Subscribe packet has an empty property length before the filters,
+	// and the SubAck has one before the reason codes.
+	let (subscription, ()) = tokio::join!(client.subscribe("a/b", 8), async {
+		let frame = broker.read_frame().await;
+		assert_eq!(frame.header, 0x82);
+		assert_eq!(&frame.payload[..], b"\x00\x01\x00\x00\x03a/b\x00");
+		broker
+			.stream
+			.write_all(b"\x90\x04\x00\x01\x00\x00")
+			.await
+			.unwrap();
+	});
+	let mut subscription = subscription.unwrap();
+
+	// Incoming Publish packets have properties between the topic and payload;
+	// here a content type of "text/plain".
+	let mut publish = b"\x30\x16\x00\x03a/b\x0d\x03\x00\x0atext/plainhi".to_vec();
+	publish[1] = (publish.len() - 2) as u8;
+	broker.stream.write_all(&publish).await.unwrap();
+	let message = subscription.recv().await.unwrap();
+	assert_eq!(message.topic, "a/b");
+	assert_eq!(&message.payload[..], b"hi");
+
+	let (result, ()) = tokio::join!(subscription.unsubscribe(), async {
+		let frame = broker.read_frame().await;
+		assert_eq!(frame.header, 0xa2);
+		assert_eq!(&frame.payload[..], b"\x00\x01\x00\x00\x03a/b");
+		broker
+			.stream
+			.write_all(b"\xb0\x04\x00\x01\x00\x00")
+			.await
+			.unwrap();
+	});
+	result.unwrap();
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn suback_length_mismatch_closes_connection() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(("127.0.0.1", port));
+	let mut events = client.events();
+	let mut broker = Broker::accept(&listener).await;
+
+	let (result, ()) = tokio::join!(client.subscribe("a/#", 1), async {
+		let frame = broker.read_frame().await;
+		let subscribe = Subscribe::parse(&frame.payload).unwrap();
+		broker
+			.write(&SubAck {
+				id: subscribe.id,
+				result: vec![Ok(QoS::AtMostOnce), Ok(QoS::AtMostOnce)],
+			})
+			.await;
+	});
+	assert!(result.is_err());
+
+	let event = time::timeout(Duration::from_secs(1), events.recv())
+		.await
+		.unwrap()
+		.unwrap();
+	assert_eq!(
+		event,
+		ConnectionEvent::ProtocolError(ProtocolError::SubAckLengthMismatch {
+			requested: 1,
+			returned: 2
+		})
+	);
+
+	// The connection is closed, and the client task reconnects.
+	let mut closed = [0; 1];
+	assert_eq!(broker.stream.read(&mut closed).await.unwrap(), 0);
+	let mut broker = Broker::accept(&listener).await;
+	client.disconnect().await.unwrap();
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn publish_with_retain_flag() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		..Default::default()
+	});
+	let mut broker = Broker::accept(&listener).await;
+
+	for (retain, header) in [
+		(Retain::Retained, 0x31),
+		(Retain::Transient, 0x30),
+		(true.into(), 0x31),
+	] {
+		client
+			.publish("a/b", "payload", QoS::AtMostOnce, retain)
+			.await
+			.unwrap();
+		assert_eq!(broker.read_frame().await.header, header);
+	}
+
+	// The bool overload is equivalent.
+	client
+		.publish("a/b", "payload", QoS::AtMostOnce, true)
+		.await
+		.unwrap();
+	assert_eq!(broker.read_frame().await.header, 0x31);
+
+	client.disconnect().await.unwrap();
+	handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn abandoned_publish_is_not_resent() {
+	let (listener, port) = Broker::bind().await;
+	let (client, handle) = tcp_client(Options {
+		host: "127.0.0.1".into(),
+		port,
+		keep_alive: 1,
+		abandoned_publish_timeout: Duration::ZERO,
+		..Default::default()
+	});
+	let mut broker = Broker::accept(&listener).await;
+
+	// Drop the publish future once the packet has been sent, without a PubAck.
+	let publish = client.publish("a/b", "payload", QoS::AtLeastOnce, false);
+	assert!(time::timeout(Duration::from_millis(100), publish)
+		.await
+		.is_err());
+	assert_eq!(broker.read_frame().await.header, 0x32);
+
+	// The publish is dropped by the check every keep alive interval, which
+	// runs around the time of the first PingReq.
+	assert_eq!(broker.read_frame().await.header, 0xc0);
+	broker.write(&crate::packets::PingResp).await;
+	time::sleep(Duration::from_millis(200)).await;
+	drop(broker);
+
+	// After reconnecting, the publish is not re-sent.
+	let mut broker = Broker::accept(&listener).await;
+	assert_eq!(broker.read_frame().await.header, 0xc0);
+
+	client.disconnect().await.unwrap();
+	assert_eq!(broker.read_frame().await.header, 0xe0);
+	handle.await.unwrap().unwrap();
+}
@@ -1,11 +1,14 @@
-use crate::{Topic, TopicBuf};
-use std::{borrow, cmp, convert, fmt, ops};
+use crate::{topic::is_disallowed, InvalidTopic, Topic, TopicBuf};
+use std::{
+	borrow::{self, Cow},
+	cmp, convert, fmt, ops,
+};
 
 const LEVEL_SEPARATOR: char = '/';
 const SINGLE_LEVEL_WILDCARD: char = '+';
-const SINGLE_LEVEL_WILDCARD_STR: &str = "+";
+pub(crate) const SINGLE_LEVEL_WILDCARD_STR: &str = "+";
 const MULTI_LEVEL_WILDCARD: char = '#';
-const MULTI_LEVEL_WILDCARD_STR: &str = "#";
+pub(crate) const MULTI_LEVEL_WILDCARD_STR: &str = "#";
 const WILDCARDS: [char; 2] = [SINGLE_LEVEL_WILDCARD, MULTI_LEVEL_WILDCARD];
 
 const DEFAULT: &Filter = Filter::from_static(MULTI_LEVEL_WILDCARD_STR);
@@ -16,6 +19,13 @@ const DEFAULT: &Filter = Filter::from_static(MULTI_LEVEL_WILDCARD_STR);
 #[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Filter(str);
 
+/// How a [`Filter`] matched a topic, as returned by [`Filter::matches_topic`].
+///
+/// Matches are ordered so that a *greater* value is a *more* specific match.
+/// They are compared first by the number of levels matched exactly, then by the
+/// number of levels matched by single-level wildcards, and finally by the
+/// number of levels matched by a multi-level wildcard. For a given topic this
+/// agrees with the ordering of the filters' [`Specificity`].
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct Matches {
 	pub exact: usize,
@@ -23,6 +33,30 @@ pub struct Matches {
 	pub multi_wildcard: usize,
 }
 
+/// The specificity of a [`Filter`], independent of any topic.
+///
+/// Specificities are ordered so that a *greater* value is *more* specific.
+/// Filters are compared first by the number of exact levels, then by the
+/// number of single-level wildcards, and finally a filter without a
+/// multi-level wildcard is more specific than one with. This is consistent with
+/// the ordering of [`Matches`] for a given topic.
+///
+/// # Example
+/// ```
+/// # use tjh_mqtt::Filter;
+/// let exact = Filter::from_static("a/b").specificity();
+/// let single = Filter::from_static("a/+").specificity();
+/// let multi = Filter::from_static("a/#").specificity();
+/// assert!(exact > single);
+/// assert!(single > multi);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Specificity {
+	pub exact: usize,
+	pub wildcard: usize,
+	pub multi_wildcard: bool,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum InvalidFilter {
 	#[error("filter cannot be empty")]
@@ -35,6 +69,26 @@ pub enum InvalidFilter {
 	MultipleMultiLevelWildcards,
 	#[error("multi-level wildcard can only appear in final filter level")]
 	NonTerminalMultiLevelWildcard,
+	#[error("filter cannot contain a null character (at position {0})")]
+	NullCharacter(usize),
+	#[error("filter cannot contain a control character or non-character ({1:?} at position {0})")]
+	DisallowedCharacter(usize, char),
+}
+
+/// An error returned by [`Filter::expand`].
+#[derive(Debug, thiserror::Error)]
+pub enum ExpandError {
+	#[error("multi-level wildcards cannot be expanded")]
+	MultiLevelWildcard,
+	#[error("filter has {wildcards} single-level wildcards, but {substitutions} substitutions were provided")]
+	SubstitutionCount {
+		wildcards: usize,
+		substitutions: usize,
+	},
+	#[error("substitution {0:?} cannot contain a level separator")]
+	InvalidSubstitution(String),
+	#[error("expanded topic is invalid: {0}")]
+	InvalidTopic(#[from] InvalidTopic),
 }
 
 impl Filter {
@@ -49,6 +103,10 @@ impl Filter {
 			return Err(InvalidFilter::TooLong);
 		}
 
+		if let Some(position) = filter.chars().position(|c| c == '\0') {
+			return Err(InvalidFilter::NullCharacter(position));
+		}
+
 		let mut multi_wildcard_position = None;
 		let mut total_levels = 0;
 		for (position, level) in filter.split(LEVEL_SEPARATOR).enumerate() {
@@ -74,12 +132,107 @@ impl Filter {
 		Ok(unsafe { &*(filter as *const str as *const Filter) })
 	}
 
+	/// Creates a new Filter, also rejecting control characters and
+	/// non-characters.
+	///
+	/// The MQTT specification recommends these are not included in filters,
+	/// but does not require Servers to reject them, so [`Filter::new`] accepts
+	/// them.
+	pub fn new_strict<S: AsRef<str> + ?Sized>(filter: &S) -> Result<&Filter, InvalidFilter> {
+		let filter = Self::new(filter)?;
+		if let Some((position, character)) = filter
+			.0
+			.chars()
+			.enumerate()
+			.find(|(_, c)| is_disallowed(*c))
+		{
+			return Err(InvalidFilter::DisallowedCharacter(position, character));
+		}
+
+		Ok(filter)
+	}
+
 	/// Checks `topic` to determine if it would be matched by the `Filter`.
 	///
 	/// Returns `None` if the topic does not match. If `topic` does match, a
 	/// tuple of the number of levels matched exactly and the number of levels
 	/// matched by wildcards is returned.
+	///
+	/// Empty levels are matched like any other level, so a leading or trailing
+	/// separator is significant: the filter `a/b` does not match the topic
+	/// `a/b/`, but `a/b/+` and `a/#` do.
+	///
+	/// Filters without wildcards are compared with the topic directly, without
+	/// splitting either into levels.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::{Filter, Topic};
+	/// let topic = Topic::new("a/b/").unwrap();
+	/// assert!(Filter::new("a/b").unwrap().matches_topic(topic).is_none());
+	/// assert!(Filter::new("a/b/+").unwrap().matches_topic(topic).is_some());
+	/// ```
 	pub fn matches_topic(&self, topic: &Topic) -> Option<Matches> {
+		// Topics cannot contain wildcards, so a filter identical to the topic
+		// matches every level exactly.
+		if self.as_str() == topic.as_str() {
+			return Some(Matches {
+				exact: self
+					.0
+					.bytes()
+					.filter(|&b| b == LEVEL_SEPARATOR as u8)
+					.count() + 1,
+				..Default::default()
+			});
+		}
+
+		if !self.has_wildcards() {
+			return None;
+		}
+
+		self.match_levels(topic)
+	}
+
+	/// Checks if `topic` would be matched by the `Filter`.
+	///
+	/// This is equivalent to `matches_topic(topic).is_some()`, but stops at the
+	/// first level which does not match without counting the levels.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::{Filter, Topic};
+	/// let filter = Filter::new("a/+/c").unwrap();
+	/// assert!(filter.matches(Topic::new("a/b/c").unwrap()));
+	/// assert!(!filter.matches(Topic::new("a/b").unwrap()));
+	/// ```
+	pub fn matches(&self, topic: &Topic) -> bool {
+		if self.as_str() == topic.as_str() {
+			return true;
+		}
+
+		if !self.has_wildcards() {
+			return false;
+		}
+
+		let mut topic_levels = topic.levels();
+		for level in self.levels() {
+			match (level, topic_levels.next()) {
+				(_, None) => return false,
+				(MULTI_LEVEL_WILDCARD_STR, Some(_)) => return true,
+				(SINGLE_LEVEL_WILDCARD_STR, Some(_)) => {}
+				(level, Some(topic_level)) if level == topic_level => {}
+				_ => return false,
+			}
+		}
+
+		topic_levels.next().is_none()
+	}
+
+	/// Matches `topic` level by level, as [`matches_topic`] does for filters
+	/// with wildcards.
+	///
+	/// [`matches_topic`]: Filter::matches_topic
+	fn match_levels(&self, topic: &Topic) -> Option<Matches> {
 		let filter_levels = self.as_str().split(LEVEL_SEPARATOR);
 		let mut topic_levels = topic.levels();
 
@@ -97,7 +250,7 @@ impl Filter {
 					result.wildcard += 1;
 				}
 				exact_match => {
-					if !topic_levels.next().map_or(false, |t| t == exact_match) {
+					if topic_levels.next().is_none_or(|t| t != exact_match) {
 						return None;
 					}
 					result.exact += 1;
@@ -109,6 +262,80 @@ impl Filter {
 		(topic_levels.count() == 0).then_some(result)
 	}
 
+	/// Checks if every topic matched by `other` is also matched by the
+	/// `Filter`.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::Filter;
+	/// let filter = Filter::new("a/#").unwrap();
+	/// assert!(filter.subsumes(Filter::new("a/b").unwrap()));
+	/// assert!(filter.subsumes(Filter::new("a/+/#").unwrap()));
+	/// assert!(!filter.subsumes(Filter::new("b/#").unwrap()));
+	/// ```
+	pub fn subsumes(&self, other: &Filter) -> bool {
+		let mut other_levels = other.levels();
+		for level in self.levels() {
+			let Some(other_level) = other_levels.next() else {
+				// Even a multi-level wildcard must match at least one level.
+				return false;
+			};
+
+			match (level, other_level) {
+				(MULTI_LEVEL_WILDCARD_STR, _) => return true,
+				(_, MULTI_LEVEL_WILDCARD_STR) => return false,
+				(SINGLE_LEVEL_WILDCARD_STR, _) => {}
+				(level, other_level) if level == other_level => {}
+				_ => return false,
+			}
+		}
+
+		other_levels.next().is_none()
+	}
+
+	/// Returns `true` if the filter contains a single or multi-level wildcard.
+	///
+	/// A filter without wildcards only matches the identical topic.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::Filter;
+	/// assert!(Filter::new("a/+").unwrap().has_wildcards());
+	/// assert!(!Filter::new("a/b").unwrap().has_wildcards());
+	/// ```
+	#[inline]
+	pub fn has_wildcards(&self) -> bool {
+		self.0
+			.bytes()
+			.any(|b| b == SINGLE_LEVEL_WILDCARD as u8 || b == MULTI_LEVEL_WILDCARD as u8)
+	}
+
+	/// Returns the [`Specificity`] of the filter.
+	///
+	/// To sort filters from most to least specific:
+	/// ```
+	/// # use tjh_mqtt::FilterBuf;
+	/// use std::cmp::Reverse;
+	///
+	/// let mut filters: Vec<FilterBuf> = ["#", "a/+", "a/b"]
+	/// 	.into_iter()
+	/// 	.map(|f| FilterBuf::new(f).unwrap())
+	/// 	.collect();
+	/// filters.sort_by_key(|f| Reverse(f.specificity()));
+	/// assert_eq!(filters[0].as_str(), "a/b");
+	/// ```
+	pub fn specificity(&self) -> Specificity {
+		let mut specificity = Specificity::default();
+		for level in self.levels() {
+			match level {
+				MULTI_LEVEL_WILDCARD_STR => specificity.multi_wildcard = true,
+				SINGLE_LEVEL_WILDCARD_STR => specificity.wildcard += 1,
+				_ => specificity.exact += 1,
+			}
+		}
+		specificity
+	}
+
 	/// Returns the length of the filter in bytes when encoded as UTF-8.
 	#[inline]
 	pub const fn len(&self) -> usize {
@@ -126,6 +353,30 @@ impl Filter {
 		&self.0
 	}
 
+	/// Creates a Filter from a borrowed or owned string, without allocating.
+	///
+	/// A borrowed `&str` produces a borrowed `&Filter`, as with [`Filter::new`].
+	/// An owned `String` is moved into a [`FilterBuf`], as with
+	/// [`FilterBuf::new`]. Validation never allocates, so the only allocation
+	/// is the one made by the caller to create an owned string.
+	///
+	/// # Example
+	/// ```
+	/// # use std::borrow::Cow;
+	/// # use tjh_mqtt::Filter;
+	/// let filter = Filter::new_cow("a/+").unwrap();
+	/// assert!(matches!(filter, Cow::Borrowed(_)));
+	///
+	/// let filter = Filter::new_cow(format!("a/{}", "#")).unwrap();
+	/// assert!(matches!(filter, Cow::Owned(_)));
+	/// ```
+	pub fn new_cow<'a>(filter: impl Into<Cow<'a, str>>) -> Result<Cow<'a, Filter>, InvalidFilter> {
+		match filter.into() {
+			Cow::Borrowed(filter) => Filter::new(filter).map(Cow::Borrowed),
+			Cow::Owned(filter) => FilterBuf::new(filter).map(Cow::Owned),
+		}
+	}
+
 	/// Converts a `Filter` to a owned [`FilterBuf`]
 	#[inline]
 	pub fn to_filter_buf(&self) -> FilterBuf {
@@ -148,6 +399,136 @@ impl Filter {
 		self.0.split(LEVEL_SEPARATOR)
 	}
 
+	/// Returns the level at index `n`, or `None` if the filter has fewer
+	/// levels.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::Filter;
+	/// let filter = Filter::new("devices/+/status").unwrap();
+	/// assert_eq!(filter.level(1), Some("+"));
+	/// assert_eq!(filter.level(3), None);
+	/// ```
+	#[inline]
+	pub fn level(&self, n: usize) -> Option<&str> {
+		self.levels().nth(n)
+	}
+
+	/// Returns the number of levels in the filter, without allocating.
+	///
+	/// Empty levels are counted, so `"a//b"` has three levels.
+	#[inline]
+	pub fn level_count(&self) -> usize {
+		self.0.matches(LEVEL_SEPARATOR).count() + 1
+	}
+
+	/// Checks if there is any topic the `Filter` could match.
+	///
+	/// This is only `false` for degenerate filters, which [`Filter::new`]
+	/// rejects but [`Filter::from_static`] does not check for: empty filters,
+	/// and filters with a wildcard character in a level of its own, a null
+	/// character, or a multi-level wildcard before the final level.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::Filter;
+	/// assert!(Filter::from_static("a/+/#").can_match_any());
+	/// assert!(!Filter::from_static("a+/b").can_match_any());
+	/// ```
+	pub fn can_match_any(&self) -> bool {
+		if self.0.is_empty() || self.0.contains('\0') {
+			return false;
+		}
+
+		let mut levels = self.levels().peekable();
+		while let Some(level) = levels.next() {
+			match level {
+				MULTI_LEVEL_WILDCARD_STR => return levels.peek().is_none(),
+				SINGLE_LEVEL_WILDCARD_STR => {}
+				// Topics cannot contain wildcard characters.
+				level if level.contains(WILDCARDS) => return false,
+				_ => {}
+			}
+		}
+
+		true
+	}
+
+	/// Expands the single-level wildcards in the filter into the topics they
+	/// match, given the possible values for each wildcard.
+	///
+	/// `substitutions` has an entry for each single-level wildcard, in order,
+	/// with the values to substitute for it. A topic is returned for every
+	/// combination of values.
+	///
+	/// Returns an error if the number of entries does not match the number of
+	/// single-level wildcards, or the filter has a multi-level wildcard, which
+	/// could match any number of levels.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::Filter;
+	/// let filter = Filter::new("home/+/temperature").unwrap();
+	/// let topics = filter.expand(&[&["kitchen", "hall"]]).unwrap();
+	/// assert_eq!(topics[0], "home/kitchen/temperature");
+	/// assert_eq!(topics[1], "home/hall/temperature");
+	/// ```
+	pub fn expand(&self, substitutions: &[&[&str]]) -> Result<Vec<TopicBuf>, ExpandError> {
+		if self.levels().any(|level| level == MULTI_LEVEL_WILDCARD_STR) {
+			return Err(ExpandError::MultiLevelWildcard);
+		}
+
+		let wildcards = self
+			.levels()
+			.filter(|&level| level == SINGLE_LEVEL_WILDCARD_STR)
+			.count();
+		if wildcards != substitutions.len() {
+			return Err(ExpandError::SubstitutionCount {
+				wildcards,
+				substitutions: substitutions.len(),
+			});
+		}
+
+		// Substituting a value with a level separator would change the number of
+		// levels.
+		if let Some(value) = substitutions
+			.iter()
+			.flat_map(|values| values.iter())
+			.find(|value| value.contains(LEVEL_SEPARATOR))
+		{
+			return Err(ExpandError::InvalidSubstitution(value.to_string()));
+		}
+
+		let mut substitutions = substitutions.iter();
+		let mut topics = vec![String::with_capacity(self.0.len())];
+		for (index, level) in self.levels().enumerate() {
+			let exact = [level];
+			let values = match level {
+				SINGLE_LEVEL_WILDCARD_STR => substitutions.next().unwrap(),
+				_ => &exact[..],
+			};
+
+			topics = topics
+				.iter()
+				.flat_map(|prefix| {
+					values.iter().map(move |value| {
+						let mut topic = prefix.clone();
+						if index != 0 {
+							topic.push(LEVEL_SEPARATOR);
+						}
+						topic.push_str(value);
+						topic
+					})
+				})
+				.collect();
+		}
+
+		topics
+			.into_iter()
+			.map(|topic| Ok(TopicBuf::new(topic)?))
+			.collect()
+	}
+
 	/// Creates a Filter from an `&'static str`. The validity of the filter is
 	/// *not* checked.
 	///
@@ -294,6 +675,15 @@ impl borrow::Borrow<Filter> for FilterBuf {
 	}
 }
 
+// `Filter` and `FilterBuf` hash and compare as their string, so a map keyed by
+// `FilterBuf` can be queried with a `&str`.
+impl borrow::Borrow<str> for FilterBuf {
+	#[inline]
+	fn borrow(&self) -> &str {
+		self.as_str()
+	}
+}
+
 impl From<&Filter> for FilterBuf {
 	#[inline]
 	fn from(value: &Filter) -> Self {
@@ -355,6 +745,62 @@ impl fmt::Display for FilterBuf {
 	}
 }
 
+impl PartialEq<str> for Filter {
+	#[inline]
+	fn eq(&self, other: &str) -> bool {
+		self.as_str() == other
+	}
+}
+
+impl PartialEq<&str> for Filter {
+	#[inline]
+	fn eq(&self, other: &&str) -> bool {
+		self.as_str() == *other
+	}
+}
+
+impl PartialEq<FilterBuf> for Filter {
+	#[inline]
+	fn eq(&self, other: &FilterBuf) -> bool {
+		self.as_str() == other.as_str()
+	}
+}
+
+impl PartialEq<FilterBuf> for &Filter {
+	#[inline]
+	fn eq(&self, other: &FilterBuf) -> bool {
+		self.as_str() == other.as_str()
+	}
+}
+
+impl PartialEq<str> for FilterBuf {
+	#[inline]
+	fn eq(&self, other: &str) -> bool {
+		self.as_str() == other
+	}
+}
+
+impl PartialEq<&str> for FilterBuf {
+	#[inline]
+	fn eq(&self, other: &&str) -> bool {
+		self.as_str() == *other
+	}
+}
+
+impl PartialEq<Filter> for FilterBuf {
+	#[inline]
+	fn eq(&self, other: &Filter) -> bool {
+		self.as_str() == other.as_str()
+	}
+}
+
+impl PartialEq<&Filter> for FilterBuf {
+	#[inline]
+	fn eq(&self, other: &&Filter) -> bool {
+		self.as_str() == other.as_str()
+	}
+}
+
 #[cfg(feature = "serde")]
 struct FilterBufVisitor;
 
@@ -405,6 +851,10 @@ impl serde::Serialize for FilterBuf {
 }
 
 impl Matches {
+	/// Returns a single numeric score for the match.
+	///
+	/// The score only agrees with the ordering of `Matches` for filters with
+	/// fewer than 10 levels. Prefer comparing `Matches` directly.
 	#[inline]
 	pub fn score(&self) -> usize {
 		self.exact * 100 + self.wildcard * 10 + self.multi_wildcard
@@ -421,14 +871,55 @@ impl cmp::PartialOrd for Matches {
 impl cmp::Ord for Matches {
 	#[inline]
 	fn cmp(&self, other: &Self) -> cmp::Ordering {
-		self.score().cmp(&other.score())
+		self.exact
+			.cmp(&other.exact)
+			.then(self.wildcard.cmp(&other.wildcard))
+			.then(self.multi_wildcard.cmp(&other.multi_wildcard))
+	}
+}
+
+impl cmp::PartialOrd for Specificity {
+	#[inline]
+	fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl cmp::Ord for Specificity {
+	#[inline]
+	fn cmp(&self, other: &Self) -> cmp::Ordering {
+		self.exact
+			.cmp(&other.exact)
+			.then(self.wildcard.cmp(&other.wildcard))
+			.then(other.multi_wildcard.cmp(&self.multi_wildcard))
 	}
 }
 
 #[cfg(test)]
 mod tests {
-	use super::{Filter, Matches};
-	use crate::Topic;
+	use super::{ExpandError, Filter, InvalidFilter, Matches};
+	use crate::{routing::FilterTrie, FilterBuf, Topic};
+	use std::cmp::Reverse;
+
+	#[test]
+	fn rejects_disallowed_characters() {
+		assert!(matches!(
+			Filter::new("a/\0/#"),
+			Err(InvalidFilter::NullCharacter(2))
+		));
+		assert!(matches!(
+			Filter::new_strict("+/\0"),
+			Err(InvalidFilter::NullCharacter(2))
+		));
+
+		assert!(Filter::new("+/\u{7f}").is_ok());
+		assert!(matches!(
+			Filter::new_strict("+/\u{7f}"),
+			Err(InvalidFilter::DisallowedCharacter(2, '\u{7f}'))
+		));
+
+		Filter::new_strict("+/b c/\u{e9}/#").unwrap();
+	}
 
 	#[test]
 	fn parses_filters() {
@@ -481,6 +972,185 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn degenerate_filters_cannot_match() {
+		for filter in ["#", "+", "/", "a/+/#", "+/+", "$SYS/#", "a//b"] {
+			assert!(Filter::new(filter).unwrap().can_match_any(), "{filter}");
+		}
+
+		for filter in ["", "a+", "a/b#", "#/a", "a/#/#", "a/\0"] {
+			assert!(Filter::new(filter).is_err(), "{filter}");
+			assert!(!Filter::from_static(filter).can_match_any(), "{filter}");
+		}
+	}
+
+	#[test]
+	fn literal_filters_match_as_levels() {
+		let topics = [
+			"a", "a/b", "a/b/c", "a/b/", "/a/b", "a//b", "/", "//", "b/a",
+		];
+		for filter in ["a", "a/b", "a/b/c", "a/b/", "/a/b", "a//b", "/", "//"] {
+			let filter = Filter::new(filter).unwrap();
+			assert!(!filter.has_wildcards());
+			for topic in topics {
+				let topic = Topic::new(topic).unwrap();
+				assert_eq!(
+					filter.matches_topic(topic),
+					filter.match_levels(topic),
+					"{filter:?} matching {topic:?}"
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn matches_empty_levels() {
+		let m = |exact, wildcard, multi_wildcard| {
+			Some(Matches {
+				exact,
+				wildcard,
+				multi_wildcard,
+			})
+		};
+
+		for (filter, topic, expected) in [
+			// A trailing separator adds an empty level to the end.
+			("a/b", "a/b/", None),
+			("a/b/", "a/b", None),
+			("a/b/", "a/b/", m(3, 0, 0)),
+			("a/+", "a/b/", None),
+			("a/b/+", "a/b", None),
+			("a/b/+", "a/b/", m(2, 1, 0)),
+			("a/+/", "a/b/", m(2, 1, 0)),
+			("a/#", "a/b/", m(1, 0, 2)),
+			("a/b/#", "a/b/", m(2, 0, 1)),
+			// A leading separator adds an empty level to the start.
+			("a/b", "/a/b", None),
+			("/a/b", "a/b", None),
+			("/a/b", "/a/b", m(3, 0, 0)),
+			("+/b", "/a/b", None),
+			("+/a/b", "/a/b", m(2, 1, 0)),
+			("/+/b", "/a/b", m(2, 1, 0)),
+			("#", "/a/b", m(0, 0, 3)),
+			("/#", "/a/b", m(1, 0, 2)),
+			// Both.
+			("/a/", "/a/", m(3, 0, 0)),
+			("+/a/+", "/a/", m(1, 2, 0)),
+			("+", "/", None),
+			("+/+", "/", m(0, 2, 0)),
+			("/", "/", m(2, 0, 0)),
+			("/#", "/", m(1, 0, 1)),
+		] {
+			let filter = Filter::new(filter).unwrap();
+			let topic = Topic::new(topic).unwrap();
+			assert_eq!(
+				filter.matches_topic(topic),
+				expected,
+				"{filter:?} matching {topic:?}"
+			);
+
+			// The filter trie agrees.
+			let mut trie = FilterTrie::new();
+			trie.insert(filter, ());
+			assert_eq!(
+				trie.matching_filters(topic).next().is_some(),
+				expected.is_some(),
+				"{filter:?} routing {topic:?}"
+			);
+		}
+	}
+
+	#[test]
+	fn compares_with_str() {
+		let filter = Filter::from_static("a/+");
+		let filter_buf = FilterBuf::new("a/+").unwrap();
+
+		assert_eq!(*filter, *"a/+");
+		assert_eq!(*filter, "a/+");
+		assert_eq!(filter, filter_buf);
+		assert_eq!(filter_buf, *"a/+");
+		assert_eq!(filter_buf, "a/+");
+		assert_eq!(filter_buf, *filter);
+		assert_eq!(filter_buf, filter);
+
+		assert_ne!(*filter, "a/#");
+		assert_ne!(filter_buf, "a/#");
+		assert_ne!(filter, FilterBuf::new("a/#").unwrap());
+	}
+
+	#[test]
+	fn subsumes_agrees_with_matches_topic() {
+		const FILTERS: [&str; 12] = [
+			"#", "+", "a", "a/b", "a/+", "+/b", "a/#", "+/#", "a/b/c", "a/+/c", "a/b/#", "+/+",
+		];
+		const TOPICS: [&str; 8] = ["a", "b", "a/b", "a/c", "b/b", "a/b/c", "a/c/c", "a/b/c/d"];
+
+		for filter in FILTERS.map(Filter::from_static) {
+			for other in FILTERS.map(Filter::from_static) {
+				// Over this set of topics, `filter` must match every topic `other`
+				// matches.
+				let covered = TOPICS
+					.map(Topic::from_static)
+					.into_iter()
+					.filter(|topic| other.matches_topic(topic).is_some())
+					.all(|topic| filter.matches_topic(topic).is_some());
+				if filter.subsumes(other) {
+					assert!(covered, "{filter} should not subsume {other}");
+				}
+			}
+		}
+
+		assert!(Filter::from_static("#").subsumes(Filter::from_static("a/+/#")));
+		assert!(Filter::from_static("a/+").subsumes(Filter::from_static("a/b")));
+		assert!(!Filter::from_static("a/+").subsumes(Filter::from_static("a/#")));
+		assert!(!Filter::from_static("a/#").subsumes(Filter::from_static("a")));
+		assert!(!Filter::from_static("a/b").subsumes(Filter::from_static("a/+")));
+		assert!(!Filter::from_static("a/b/c").subsumes(Filter::from_static("a/b")));
+	}
+
+	#[test]
+	fn sorts_by_specificity() {
+		let mut filters: Vec<FilterBuf> = [
+			"#", "a/#", "+/+", "a/b/c", "a/+", "a/b", "+/#", "a/+/c", "+", "a/b/#",
+		]
+		.into_iter()
+		.map(|filter| FilterBuf::new(filter).unwrap())
+		.collect();
+
+		filters.sort_by_key(|filter| Reverse(filter.specificity()));
+
+		let sorted: Vec<_> = filters.iter().map(|filter| filter.as_str()).collect();
+		assert_eq!(
+			sorted,
+			["a/b/c", "a/+/c", "a/b", "a/b/#", "a/+", "a/#", "+/+", "+", "+/#", "#"]
+		);
+	}
+
+	#[test]
+	fn matches_agree_with_specificity() {
+		let filters = [
+			"#", "a/#", "+/#", "+/+/+/#", "a/+/+/d", "a/b/#", "+/b/c/d", "a/+/c/d", "a/b/c/d",
+		];
+		let topic = Topic::from_static("a/b/c/d");
+
+		let mut by_matches: Vec<_> = filters.iter().map(|f| Filter::new(f).unwrap()).collect();
+		by_matches.sort_by_key(|f| f.matches_topic(topic).unwrap());
+
+		let mut by_specificity = by_matches.clone();
+		by_specificity.sort_by_key(|f| f.specificity());
+
+		assert_eq!(by_matches, by_specificity);
+	}
+
+	#[test]
+	fn orders_long_matches() {
+		// A single exact level outranks any number of wildcard levels.
+		let exact = Filter::from_static("a/+/+/+/+/+/+/+/+/+/+");
+		let wildcard = Filter::from_static("+/+/+/+/+/+/+/+/+/+/+");
+		let topic = Topic::from_static("a/b/c/d/e/f/g/h/i/j/k");
+		assert!(exact.matches_topic(topic) > wildcard.matches_topic(topic));
+	}
+
 	#[test]
 	#[cfg(feature = "serde")]
 	fn deserialize_filter() {
@@ -514,4 +1184,192 @@ mod tests {
 		let serialized = serde_json::to_string(&filter).unwrap();
 		assert_eq!(serialized, "\"gamma/beta/alpha\"");
 	}
+
+	#[test]
+	fn level_access() {
+		let filter = Filter::new("a/+/#").unwrap();
+		assert_eq!(filter.level_count(), 3);
+		assert_eq!(filter.level(0), Some("a"));
+		assert_eq!(filter.level(1), Some("+"));
+		assert_eq!(filter.level(2), Some("#"));
+		assert_eq!(filter.level(3), None);
+		assert_eq!(filter.level(usize::MAX), None);
+
+		let filter = Filter::new("a//b").unwrap();
+		assert_eq!(filter.level_count(), 3);
+		assert_eq!(filter.level(1), Some(""));
+		assert_eq!(filter.level(2), Some("b"));
+		assert_eq!(filter.level_count(), filter.levels().count());
+	}
+
+	#[test]
+	fn new_cow_borrows_str() {
+		use std::borrow::Cow;
+
+		const FILTER: &str = "a/+/#";
+		let Cow::Borrowed(filter) = Filter::new_cow(FILTER).unwrap() else {
+			panic!("expected a borrowed filter");
+		};
+		assert!(std::ptr::eq(filter.as_str(), FILTER));
+
+		let owned = String::from("a/+/#");
+		let ptr = owned.as_ptr();
+		let Cow::Owned(filter) = Filter::new_cow(owned).unwrap() else {
+			panic!("expected an owned filter");
+		};
+		assert_eq!(filter.as_str().as_ptr(), ptr);
+
+		assert!(matches!(
+			Filter::new_cow("a/#/b"),
+			Err(InvalidFilter::NonTerminalMultiLevelWildcard)
+		));
+		assert!(Filter::new_cow(String::new()).is_err());
+	}
+
+	#[test]
+	fn map_lookup_by_str() {
+		use crate::FilterBuf;
+		use std::collections::HashMap;
+
+		let map: HashMap<FilterBuf, usize> = ["a/+", "a/#", "a/b"]
+			.into_iter()
+			.enumerate()
+			.map(|(n, s)| (FilterBuf::new(s).unwrap(), n))
+			.collect();
+
+		assert_eq!(map.get("a/#"), Some(&1));
+		assert_eq!(map.get(Filter::from_static("a/b")), Some(&2));
+		assert_eq!(map.get("a/#/"), None);
+	}
+
+	mod properties {
+		use crate::{routing::FilterTrie, Filter, Topic};
+		use proptest::prelude::*;
+
+		/// Topics of up to four levels, drawn from a small alphabet so that
+		/// filters and topics often share levels.
+		fn topic() -> impl Strategy<Value = String> {
+			prop::collection::vec("[ab$]{0,2}", 1..=4)
+				.prop_map(|levels| levels.join("/"))
+				.prop_filter("topics cannot be empty", |topic| !topic.is_empty())
+		}
+
+		/// Filters built from the same levels as [`topic`], with wildcards.
+		fn filter() -> impl Strategy<Value = String> {
+			let level = prop_oneof!["[ab$]{0,2}", Just("+".to_owned())];
+			(prop::collection::vec(level, 1..=4), any::<bool>())
+				.prop_map(|(mut levels, multi)| {
+					if multi {
+						levels.push("#".to_owned());
+					}
+					levels.join("/")
+				})
+				.prop_filter("filters cannot be empty", |filter| !filter.is_empty())
+		}
+
+		proptest! {
+			#[test]
+			fn filter_equal_to_topic_matches(topic in topic()) {
+				let filter = Filter::new(&topic).unwrap();
+				let topic = Topic::new(&topic).unwrap();
+
+				let matches = filter.matches_topic(topic).unwrap();
+				prop_assert_eq!(matches.exact, topic.levels().count());
+				prop_assert_eq!(matches.wildcard, 0);
+				prop_assert_eq!(matches.multi_wildcard, 0);
+				prop_assert!(filter.matches(topic));
+			}
+
+			#[test]
+			fn root_multi_level_wildcard_matches(
+				topic in topic().prop_filter("topics beginning with $ are reserved", |topic| {
+					!topic.starts_with('$')
+				})
+			) {
+				let topic = Topic::new(&topic).unwrap();
+
+				let matches = Filter::from_static("#").matches_topic(topic).unwrap();
+				prop_assert_eq!(matches.multi_wildcard, topic.levels().count());
+				prop_assert!(Filter::from_static("#").matches(topic));
+			}
+
+			#[test]
+			fn matching_agrees(filter in filter(), topic in topic()) {
+				let filter = Filter::new(&filter).unwrap();
+				let topic = Topic::new(&topic).unwrap();
+
+				let matches = filter.matches_topic(topic);
+				prop_assert_eq!(matches.is_some(), filter.matches(topic));
+
+				let mut trie = FilterTrie::new();
+				trie.insert(filter, ());
+				prop_assert_eq!(matches.is_some(), trie.matches(topic).next().is_some());
+
+				// A match accounts for every level of the topic.
+				if let Some(matches) = matches {
+					prop_assert_eq!(
+						matches.exact + matches.wildcard + matches.multi_wildcard,
+						topic.levels().count()
+					);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn expand_single_wildcard() {
+		let filter = Filter::new("home/+/temperature").unwrap();
+		let topics = filter.expand(&[&["kitchen", "hall"]]).unwrap();
+		assert_eq!(
+			topics,
+			["home/kitchen/temperature", "home/hall/temperature"]
+		);
+
+		// Filters without wildcards expand to themselves.
+		let filter = Filter::new("home/kitchen").unwrap();
+		assert_eq!(filter.expand(&[]).unwrap(), ["home/kitchen"]);
+	}
+
+	#[test]
+	fn expand_multiple_wildcards() {
+		let filter = Filter::new("+/sensors/+").unwrap();
+		let topics = filter
+			.expand(&[&["home", "office"], &["temperature", "humidity"]])
+			.unwrap();
+		assert_eq!(
+			topics,
+			[
+				"home/sensors/temperature",
+				"home/sensors/humidity",
+				"office/sensors/temperature",
+				"office/sensors/humidity",
+			]
+		);
+	}
+
+	#[test]
+	fn expand_errors() {
+		let filter = Filter::new("home/+/#").unwrap();
+		assert!(matches!(
+			filter.expand(&[&["kitchen"]]),
+			Err(ExpandError::MultiLevelWildcard)
+		));
+
+		let filter = Filter::new("home/+/+").unwrap();
+		assert!(matches!(
+			filter.expand(&[&["kitchen"]]),
+			Err(ExpandError::SubstitutionCount {
+				wildcards: 2,
+				substitutions: 1
+			})
+		));
+		assert!(matches!(
+			filter.expand(&[&["kitchen/oven"], &["temperature"]]),
+			Err(ExpandError::InvalidSubstitution(value)) if value == "kitchen/oven"
+		));
+		assert!(matches!(
+			filter.expand(&[&["kitchen"], &["+"]]),
+			Err(ExpandError::InvalidTopic(_))
+		));
+	}
 }
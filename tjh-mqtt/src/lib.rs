@@ -11,12 +11,13 @@ mod topic;
 pub mod clients;
 pub mod misc;
 pub mod packets;
+pub mod routing;
 
 pub use self::{
-	filter::{Filter, FilterBuf, InvalidFilter},
-	packet::{Packet, PacketType},
+	filter::{ExpandError, Filter, FilterBuf, InvalidFilter, Matches, Specificity},
+	packet::{Packet, PacketType, ParseMode},
 	qos::{InvalidQoS, QoS},
-	topic::{InvalidTopic, Topic, TopicBuf},
+	topic::{InvalidTopic, InvalidTopicString, Topic, TopicBuf},
 };
 
 pub type PacketId = core::num::NonZeroU16;
@@ -1,13 +1,61 @@
-use crate::{QoS, Topic};
-use bytes::Bytes;
-use std::{num::NonZeroU16, ops};
+use crate::{InvalidTopic, QoS, Topic};
+use bytes::{Buf, Bytes, BytesMut};
+use std::{
+	collections::{hash_map::RandomState, VecDeque},
+	hash::{BuildHasher, Hasher},
+	io::IoSlice,
+	num::NonZeroU16,
+	ops,
+};
+
+/// The maximum length of a client identifier that a Server MUST accept.
+pub const MAX_CLIENT_ID_LEN: usize = 23;
+
+const CLIENT_ID_CHARACTERS: &[u8; 62] =
+	b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Generates a random client identifier.
+///
+/// The identifier is [`MAX_CLIENT_ID_LEN`] alphanumeric characters long, and
+/// so must be accepted by any MQTT v3.1.1 compliant Server. If `seed` is
+/// provided, the same identifier is generated for the same seed.
+///
+/// # Example
+/// ```
+/// # use tjh_mqtt::misc::generate_client_id;
+/// let client_id = generate_client_id(None);
+/// assert_eq!(client_id.len(), 23);
+/// assert!(client_id.chars().all(|c| c.is_ascii_alphanumeric()));
+///
+/// assert_eq!(generate_client_id(Some(42)), generate_client_id(Some(42)));
+/// ```
+pub fn generate_client_id(seed: Option<u64>) -> String {
+	let mut state = seed.unwrap_or_else(|| RandomState::new().build_hasher().finish());
+	(0..MAX_CLIENT_ID_LEN)
+		.map(|_| {
+			let index = splitmix64(&mut state) % CLIENT_ID_CHARACTERS.len() as u64;
+			char::from(CLIENT_ID_CHARACTERS[index as usize])
+		})
+		.collect()
+}
+
+/// Advances `state` and returns the next value of the SplitMix64 generator.
+#[inline]
+fn splitmix64(state: &mut u64) -> u64 {
+	*state = state.wrapping_add(0x9e3779b97f4a7c15);
+	let mut z = *state;
+	z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+	z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+	z ^ (z >> 31)
+}
 
 /// Client credentials
 ///
-/// Note that is not possible to set a password without also setting a username.
+/// Before MQTT v5 it is not possible to send a password without also sending a
+/// username.
 #[derive(Clone, Debug)]
 pub struct Credentials<'a> {
-	pub username: &'a str,
+	pub username: Option<&'a str>,
 	pub password: Option<&'a str>,
 }
 
@@ -15,7 +63,7 @@ impl<'a> From<&'a str> for Credentials<'a> {
 	#[inline]
 	fn from(username: &'a str) -> Self {
 		Self {
-			username,
+			username: Some(username),
 			password: None,
 		}
 	}
@@ -25,19 +73,63 @@ impl<'a> From<(&'a str, &'a str)> for Credentials<'a> {
 	#[inline]
 	fn from((username, password): (&'a str, &'a str)) -> Self {
 		Self {
-			username,
+			username: Some(username),
 			password: Some(password),
 		}
 	}
 }
 
+/// Whether the Server should retain a published message.
+///
+/// This can be used in place of the `retain` flag when publishing, so the
+/// call site does not rely on the position of a bare `bool`. Anything which
+/// accepts a `Retain` also accepts a `bool`.
+///
+/// # Example
+/// ```
+/// use tjh_mqtt::misc::Retain;
+///
+/// assert_eq!(Retain::from(true), Retain::Retained);
+/// assert_eq!(Retain::default(), Retain::Transient);
+/// assert!(bool::from(Retain::Retained));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Retain {
+	/// The message is delivered to current subscribers only.
+	#[default]
+	Transient,
+	/// The Server keeps the message, and delivers it to future subscribers.
+	Retained,
+}
+
+impl From<bool> for Retain {
+	#[inline]
+	fn from(retain: bool) -> Self {
+		match retain {
+			true => Self::Retained,
+			false => Self::Transient,
+		}
+	}
+}
+
+impl From<Retain> for bool {
+	#[inline]
+	fn from(retain: Retain) -> Self {
+		retain == Retain::Retained
+	}
+}
+
 /// Will Message
 ///
 /// The will message is set by the Client when it connects to the Server. If the
 /// Client disconnects abnormally, the Server publishes the will message to the
 /// topic on behalf of the Client. The will message MUST be published with the
 /// Will QoS and Retain flags as specified.
+///
+/// The `v5` feature adds a field, so a `Will` cannot be built with a struct
+/// expression outside this crate. Use [`Will::new`] instead.
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct Will<'a> {
 	/// The topic to publish the will message to.
 	pub topic: &'a Topic,
@@ -50,6 +142,234 @@ pub struct Will<'a> {
 
 	/// Whether or not the will message should be retained.
 	pub retain: bool,
+
+	/// MQTT v5 will properties. These are only sent if the protocol level is 5.
+	#[cfg(feature = "v5")]
+	pub properties: crate::packets::WillProperties,
+}
+
+/// The parameters provided to [`Will::new`] are invalid.
+#[derive(Debug, thiserror::Error)]
+pub enum WillError {
+	#[error("invalid will topic: {0}")]
+	InvalidTopic(#[from] InvalidTopic),
+	#[error("will payload cannot exceed 65,535 bytes (got {0} bytes)")]
+	PayloadTooLong(usize),
+}
+
+impl<'a> Will<'a> {
+	/// Creates a new will message.
+	///
+	/// The will payload is a length-prefixed field in the Connect packet, so it
+	/// must not exceed 65,535 bytes.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::{misc::{Will, WillError}, QoS};
+	/// let will = Will::new("status/client", "offline", QoS::AtLeastOnce, true).unwrap();
+	/// assert_eq!(will.topic.as_str(), "status/client");
+	///
+	/// assert!(matches!(
+	/// 	Will::new("status/+", "offline", QoS::AtLeastOnce, true),
+	/// 	Err(WillError::InvalidTopic(_))
+	/// ));
+	/// ```
+	pub fn new<S: AsRef<str> + ?Sized>(
+		topic: &'a S,
+		payload: impl Into<Bytes>,
+		qos: QoS,
+		retain: bool,
+	) -> Result<Self, WillError> {
+		let topic = Topic::new(topic)?;
+		let payload = payload.into();
+		if payload.len() > u16::MAX as usize {
+			return Err(WillError::PayloadTooLong(payload.len()));
+		}
+
+		Ok(Self {
+			topic,
+			payload,
+			qos,
+			retain,
+			#[cfg(feature = "v5")]
+			properties: Default::default(),
+		})
+	}
+
+	/// Sets the number of seconds the Server waits after the Client disconnects
+	/// before publishing the will message.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::{misc::Will, QoS};
+	/// let will = Will::new("status/client", "offline", QoS::AtLeastOnce, true)
+	/// 	.unwrap()
+	/// 	.with_delay_interval(30);
+	/// assert_eq!(will.properties.delay_interval, Some(30));
+	/// ```
+	#[cfg(feature = "v5")]
+	pub fn with_delay_interval(mut self, seconds: u32) -> Self {
+		self.properties.delay_interval = Some(seconds);
+		self
+	}
+}
+
+/// A payload made up of one or more [`Bytes`] chunks.
+///
+/// This allows a payload assembled from several buffers to be published without
+/// first concatenating them. The chunks are written to the transport stream
+/// with vectored writes, where the stream supports them.
+///
+/// # Example
+/// ```
+/// # use tjh_mqtt::misc::BytesVec;
+/// use bytes::{Buf, Bytes};
+///
+/// let mut payload = BytesVec::from([Bytes::from("Hello, "), Bytes::from("world!")]);
+/// assert_eq!(payload.len(), 13);
+/// assert_eq!(payload.copy_to_bytes(13), "Hello, world!");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BytesVec {
+	chunks: VecDeque<Bytes>,
+	len: usize,
+}
+
+impl BytesVec {
+	/// Creates an empty `BytesVec`.
+	#[inline]
+	pub const fn new() -> Self {
+		Self {
+			chunks: VecDeque::new(),
+			len: 0,
+		}
+	}
+
+	/// Appends `chunk` to the end of the payload.
+	pub fn push(&mut self, chunk: Bytes) {
+		if !chunk.is_empty() {
+			self.len += chunk.len();
+			self.chunks.push_back(chunk);
+		}
+	}
+
+	/// Appends all of the chunks of `other` to the end of the payload.
+	pub fn append(&mut self, other: &mut Self) {
+		self.len += other.len;
+		self.chunks.append(&mut other.chunks);
+		other.len = 0;
+	}
+
+	/// Returns the total length of the payload in bytes.
+	#[inline]
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Returns an iterator over the chunks of the payload.
+	#[inline]
+	pub fn iter(&self) -> impl Iterator<Item = &Bytes> {
+		self.chunks.iter()
+	}
+
+	/// Converts the payload into contiguous [`Bytes`].
+	///
+	/// The chunks are only copied if there is more than one.
+	pub fn into_bytes(mut self) -> Bytes {
+		match self.chunks.len() {
+			0 => Bytes::new(),
+			1 => self.chunks.pop_front().unwrap(),
+			_ => {
+				let mut bytes = BytesMut::with_capacity(self.len);
+				for chunk in &self.chunks {
+					bytes.extend_from_slice(chunk);
+				}
+				bytes.freeze()
+			}
+		}
+	}
+}
+
+impl Buf for BytesVec {
+	#[inline]
+	fn remaining(&self) -> usize {
+		self.len
+	}
+
+	#[inline]
+	fn chunk(&self) -> &[u8] {
+		self.chunks
+			.front()
+			.map(|chunk| &chunk[..])
+			.unwrap_or_default()
+	}
+
+	fn advance(&mut self, mut cnt: usize) {
+		assert!(cnt <= self.len, "cannot advance past the end of a BytesVec");
+		self.len -= cnt;
+		while cnt > 0 {
+			let front = self.chunks.front_mut().unwrap();
+			if cnt < front.len() {
+				front.advance(cnt);
+				return;
+			}
+			cnt -= front.len();
+			self.chunks.pop_front();
+		}
+	}
+
+	fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+		let mut n = 0;
+		for (slice, chunk) in dst.iter_mut().zip(&self.chunks) {
+			*slice = IoSlice::new(chunk);
+			n += 1;
+		}
+		n
+	}
+}
+
+impl From<Bytes> for BytesVec {
+	fn from(chunk: Bytes) -> Self {
+		let mut bytes = Self::new();
+		bytes.push(chunk);
+		bytes
+	}
+}
+
+impl From<Vec<Bytes>> for BytesVec {
+	#[inline]
+	fn from(chunks: Vec<Bytes>) -> Self {
+		chunks.into_iter().collect()
+	}
+}
+
+impl<const N: usize> From<[Bytes; N]> for BytesVec {
+	#[inline]
+	fn from(chunks: [Bytes; N]) -> Self {
+		chunks.into_iter().collect()
+	}
+}
+
+impl FromIterator<Bytes> for BytesVec {
+	fn from_iter<I: IntoIterator<Item = Bytes>>(iter: I) -> Self {
+		let mut bytes = Self::new();
+		for chunk in iter {
+			bytes.push(chunk);
+		}
+		bytes
+	}
+}
+
+impl From<BytesVec> for Bytes {
+	#[inline]
+	fn from(value: BytesVec) -> Self {
+		value.into_bytes()
+	}
 }
 
 #[allow(unused)]
@@ -82,3 +402,74 @@ impl ops::AddAssign<u16> for WrappingNonZeroU16 {
 		*inner = inner.checked_add(rhs).unwrap_or(NonZeroU16::MIN);
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{generate_client_id, BytesVec, Will, WillError, MAX_CLIENT_ID_LEN};
+	use crate::QoS;
+	use bytes::{Buf, Bytes};
+	use std::io::IoSlice;
+
+	#[test]
+	fn generated_client_ids_are_compliant() {
+		for seed in [None, None, Some(0), Some(1), Some(u64::MAX)] {
+			let client_id = generate_client_id(seed);
+			assert_eq!(client_id.len(), MAX_CLIENT_ID_LEN);
+			assert!(client_id.bytes().all(|b| b.is_ascii_alphanumeric()));
+		}
+	}
+
+	#[test]
+	fn seeded_client_ids_are_deterministic() {
+		assert_eq!(generate_client_id(Some(7)), generate_client_id(Some(7)));
+		assert_ne!(generate_client_id(Some(7)), generate_client_id(Some(8)));
+		assert_ne!(generate_client_id(None), generate_client_id(None));
+	}
+
+	#[test]
+	fn will_rejects_oversized_payload() {
+		let payload = vec![0; u16::MAX as usize];
+		Will::new("a/b", payload, QoS::AtMostOnce, false).unwrap();
+
+		let payload = vec![0; u16::MAX as usize + 1];
+		assert!(matches!(
+			Will::new("a/b", payload, QoS::AtMostOnce, false),
+			Err(WillError::PayloadTooLong(65_536))
+		));
+	}
+
+	#[test]
+	fn will_rejects_invalid_topic() {
+		for topic in ["", "a/#", "+/b"] {
+			assert!(matches!(
+				Will::new(topic, "", QoS::AtMostOnce, false),
+				Err(WillError::InvalidTopic(_))
+			));
+		}
+	}
+
+	#[test]
+	fn bytes_vec_is_a_buf() {
+		let mut bytes = BytesVec::from([
+			Bytes::from_static(b"abc"),
+			Bytes::new(),
+			Bytes::from_static(b"de"),
+			Bytes::from_static(b"fgh"),
+		]);
+		assert_eq!(bytes.len(), 8);
+		assert_eq!(bytes.iter().count(), 3, "empty chunks are skipped");
+
+		let mut slices = [IoSlice::new(&[]); 2];
+		assert_eq!(bytes.chunks_vectored(&mut slices), 2);
+		assert_eq!(&*slices[1], b"de");
+
+		bytes.advance(4);
+		assert_eq!(bytes.chunk(), b"e");
+		assert_eq!(bytes.remaining(), 4);
+		assert_eq!(bytes.clone().into_bytes(), "efgh");
+
+		bytes.advance(4);
+		assert!(bytes.is_empty());
+		assert_eq!(bytes.chunk(), b"");
+	}
+}
@@ -1,14 +1,16 @@
+#[cfg(feature = "v5")]
+use crate::packets::Auth;
 use crate::{
 	packets::{
-		ConnAck, Connect, Disconnect, Frame, ParseError, PingReq, PingResp, PubAck, PubComp,
+		ConnAck, Connect, DeserializeError, Disconnect, Frame, PingReq, PingResp, PubAck, PubComp,
 		PubRec, PubRel, Publish, SubAck, Subscribe, UnsubAck, Unsubscribe,
 	},
 	serde,
 };
 use bytes::BufMut;
-use std::io;
+use std::{fmt, io};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Packet<'a> {
 	Connect(Box<Connect<'a>>),
 	ConnAck(ConnAck),
@@ -24,6 +26,8 @@ pub enum Packet<'a> {
 	PingReq,
 	PingResp,
 	Disconnect,
+	#[cfg(feature = "v5")]
+	Auth(Box<Auth<'a>>),
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -42,6 +46,20 @@ pub enum PacketType {
 	PingReq,
 	PingResp,
 	Disconnect,
+	#[cfg(feature = "v5")]
+	Auth,
+}
+
+/// How strictly [`Packet::parse_with`] checks the flags in the fixed header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParseMode {
+	/// Reject packets with flags other than those required by the
+	/// specification.
+	#[default]
+	Strict,
+	/// Accept common deviations from the specification, such as a PubRel
+	/// packet with flags `0x00` rather than `0x02`.
+	Lenient,
 }
 
 const CONNECT: u8 = 0x10;
@@ -58,14 +76,16 @@ const UNSUBACK: u8 = 0xb0;
 const PINGREQ: u8 = 0xc0;
 const PINGRESP: u8 = 0xd0;
 const DISCONNECT: u8 = 0xe0;
+#[cfg(feature = "v5")]
+const AUTH: u8 = 0xf0;
 
 impl<'a> Packet<'a> {
 	/// Checks if a complete [`Packet`] can be decoded from `src`. If so,
 	/// returns the length of the packet.
-	pub fn check(src: &mut io::Cursor<&[u8]>) -> Result<u64, ParseError> {
+	pub fn check(src: &mut io::Cursor<&[u8]>) -> Result<u64, DeserializeError> {
 		let header = serde::get_u8(src)?;
-		if header == 0 || header == 0xf0 {
-			return Err(ParseError::InvalidHeader);
+		if header == 0 || (header == 0xf0 && !cfg!(feature = "v5")) {
+			return Err(DeserializeError::InvalidHeader);
 		}
 
 		let length = serde::get_var(src)?;
@@ -74,7 +94,16 @@ impl<'a> Packet<'a> {
 	}
 
 	/// Parses a [`Packet`] from src.
-	pub fn parse(frame: &'a Frame) -> Result<Self, ParseError> {
+	///
+	/// This is [`Packet::parse_with`] using [`ParseMode::Strict`].
+	#[inline]
+	pub fn parse(frame: &'a Frame) -> Result<Self, DeserializeError> {
+		Self::parse_with(frame, ParseMode::Strict)
+	}
+
+	/// Parses a [`Packet`] from src, checking the flags in the fixed header
+	/// according to `mode`.
+	pub fn parse_with(frame: &'a Frame, mode: ParseMode) -> Result<Self, DeserializeError> {
 		let header = frame.header;
 		// let length = frame.payload.len();
 		let payload = &frame.payload;
@@ -86,6 +115,7 @@ impl<'a> Packet<'a> {
 			(PUBACK, 0x00) => Ok(PubAck::parse(payload)?.into()),
 			(PUBREC, 0x00) => Ok(PubRec::parse(payload)?.into()),
 			(PUBREL, 0x02) => Ok(PubRel::parse(payload)?.into()),
+			(PUBREL, 0x00) if mode == ParseMode::Lenient => Ok(PubRel::parse(payload)?.into()),
 			(PUBCOMP, 0x00) => Ok(PubComp::parse(payload)?.into()),
 			(SUBSCRIBE, 0x02) => Ok(Subscribe::parse(payload)?.into()),
 			(SUBACK, 0x00) => Ok(SubAck::parse(payload)?.into()),
@@ -94,7 +124,26 @@ impl<'a> Packet<'a> {
 			(PINGREQ, 0x00) => Ok(PingReq::parse(payload)?.into()),
 			(PINGRESP, 0x00) => Ok(PingResp::parse(payload)?.into()),
 			(DISCONNECT, 0x00) => Ok(Disconnect::parse(payload)?.into()),
-			_ => Err(ParseError::InvalidHeader),
+			#[cfg(feature = "v5")]
+			(AUTH, 0x00) => Ok(Auth::parse(payload)?.into()),
+			_ => Err(DeserializeError::InvalidHeader),
+		}
+	}
+
+	/// Parses a [`Packet`] sent with MQTT v5, checking the flags in the fixed
+	/// header according to `mode`.
+	///
+	/// The properties of the Publish, SubAck and UnsubAck packets are skipped,
+	/// as is the reason code of a Disconnect packet.
+	#[cfg(feature = "v5")]
+	pub fn parse_v5(frame: &'a Frame, mode: ParseMode) -> Result<Self, DeserializeError> {
+		let payload = &frame.payload;
+		match (frame.header & 0xf0, frame.header & 0x0f) {
+			(PUBLISH, flags) => Ok(Publish::parse_v5(payload, flags)?.into()),
+			(SUBACK, 0x00) => Ok(SubAck::parse_v5(payload)?.into()),
+			(UNSUBACK, 0x00) => Ok(UnsubAck::parse_v5(payload)?.into()),
+			(DISCONNECT, 0x00) => Ok(Self::Disconnect),
+			_ => Self::parse_with(frame, mode),
 		}
 	}
 
@@ -114,6 +163,8 @@ impl<'a> Packet<'a> {
 			Self::PingReq => PingReq.serialize_to_bytes(dst),
 			Self::PingResp => PingResp.serialize_to_bytes(dst),
 			Self::Disconnect => Disconnect.serialize_to_bytes(dst),
+			#[cfg(feature = "v5")]
+			Self::Auth(auth) => auth.serialize_to_bytes(dst),
 		}
 	}
 
@@ -134,6 +185,103 @@ impl<'a> Packet<'a> {
 			Self::PingReq => PacketType::PingReq,
 			Self::PingResp => PacketType::PingResp,
 			Self::Disconnect => PacketType::Disconnect,
+			#[cfg(feature = "v5")]
+			Self::Auth(_) => PacketType::Auth,
+		}
+	}
+}
+
+/// Formats a one-line summary of the packet, suitable for logs.
+///
+/// The summary includes the fields of the fixed and variable headers, but not
+/// payloads or MQTT v5 properties.
+///
+/// # Example
+/// ```
+/// # use tjh_mqtt::{packets::Publish, Packet, PacketId, QoS, Topic};
+/// let publish = Publish::new(
+/// 	Topic::from_static("a/b"),
+/// 	vec![0; 128].into(),
+/// 	QoS::AtLeastOnce,
+/// 	false,
+/// 	PacketId::new(7),
+/// )
+/// .unwrap();
+/// assert_eq!(
+/// 	Packet::from(publish).to_string(),
+/// 	"PUBLISH qos=1 dup=false retain=false topic=a/b len=128 id=7"
+/// );
+/// ```
+impl fmt::Display for Packet<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Connect(connect) => write!(
+				f,
+				"CONNECT client_id={} level={} clean_session={} keep_alive={} will={} credentials={}",
+				connect.client_id,
+				connect.protocol_level,
+				connect.clean_session,
+				connect.keep_alive,
+				connect.will.is_some(),
+				connect.credentials.is_some(),
+			),
+			Self::ConnAck(connack) => write!(
+				f,
+				"CONNACK session_present={} code={}",
+				connack.session_present, connack.code
+			),
+			Self::Publish(publish) => {
+				write!(
+					f,
+					"PUBLISH qos={} dup={} retain={} topic={} len={}",
+					publish.qos().as_u8(),
+					publish.duplicate(),
+					publish.retain(),
+					publish.topic(),
+					publish.payload_len(),
+				)?;
+				if let Some(id) = publish.id() {
+					write!(f, " id={id}")?;
+				}
+				Ok(())
+			}
+			Self::PubAck(PubAck { id, .. }) => write!(f, "PUBACK id={id}"),
+			Self::PubRec(PubRec { id, .. }) => write!(f, "PUBREC id={id}"),
+			Self::PubRel(PubRel { id, .. }) => write!(f, "PUBREL id={id}"),
+			Self::PubComp(PubComp { id, .. }) => write!(f, "PUBCOMP id={id}"),
+			Self::Subscribe(subscribe) => {
+				write!(f, "SUBSCRIBE id={} filters=", subscribe.id)?;
+				for (n, (filter, qos)) in subscribe.filters.iter().enumerate() {
+					let separator = if n == 0 { "" } else { "," };
+					write!(f, "{separator}{filter}:{}", qos.as_u8())?;
+				}
+				Ok(())
+			}
+			Self::SubAck(suback) => {
+				write!(f, "SUBACK id={} result=", suback.id)?;
+				for (n, result) in suback.result.iter().enumerate() {
+					let separator = if n == 0 { "" } else { "," };
+					match result {
+						Ok(qos) => write!(f, "{separator}{}", qos.as_u8())?,
+						Err(_) => write!(f, "{separator}failed")?,
+					}
+				}
+				Ok(())
+			}
+			Self::Unsubscribe(unsubscribe) => {
+				write!(f, "UNSUBSCRIBE id={} filters=", unsubscribe.id)?;
+				for (n, filter) in unsubscribe.filters.iter().enumerate() {
+					let separator = if n == 0 { "" } else { "," };
+					write!(f, "{separator}{filter}")?;
+				}
+				Ok(())
+			}
+			Self::UnsubAck(UnsubAck { id }) => write!(f, "UNSUBACK id={id}"),
+			Self::PingReq => f.write_str("PINGREQ"),
+			Self::PingResp => f.write_str("PINGRESP"),
+			Self::Disconnect => f.write_str("DISCONNECT"),
+			#[cfg(feature = "v5")]
+			Self::Auth(auth) => write!(f, "AUTH reason_code={:?}", auth.reason_code),
 		}
 	}
 }
@@ -179,3 +327,118 @@ impl<'a> From<Unsubscribe<'a>> for Packet<'a> {
 		Self::Unsubscribe(value.into())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{Packet, ParseMode};
+	use crate::{
+		packets::{
+			ConnAck, Connect, DeserializeError, Frame, PubAck, Publish, SubAck, Subscribe,
+			SubscribeFailed, Unsubscribe,
+		},
+		Filter, PacketId, QoS, Topic,
+	};
+
+	#[test]
+	fn display_summaries() {
+		let id = PacketId::new(7).unwrap();
+		let packets: [(Packet, &str); 9] = [
+			(
+				Connect {
+					client_id: "client",
+					keep_alive: 60,
+					..Default::default()
+				}
+				.into(),
+				"CONNECT client_id=client level=4 clean_session=true keep_alive=60 will=false credentials=false",
+			),
+			(
+				ConnAck::default().into(),
+				"CONNACK session_present=false code=0",
+			),
+			(
+				Publish::new(
+					Topic::from_static("a/b"),
+					"payload".into(),
+					QoS::AtMostOnce,
+					true,
+					None,
+				)
+				.unwrap()
+				.into(),
+				"PUBLISH qos=0 dup=false retain=true topic=a/b len=7",
+			),
+			(PubAck::new(id).into(), "PUBACK id=7"),
+			(
+				Subscribe {
+					id,
+					filters: vec![
+						(Filter::from_static("a/#"), QoS::AtLeastOnce),
+						(Filter::from_static("b"), QoS::AtMostOnce),
+					],
+				}
+				.into(),
+				"SUBSCRIBE id=7 filters=a/#:1,b:0",
+			),
+			(
+				SubAck {
+					id,
+					result: vec![Ok(QoS::ExactlyOnce), Err(SubscribeFailed)],
+				}
+				.into(),
+				"SUBACK id=7 result=2,failed",
+			),
+			(
+				Unsubscribe {
+					id,
+					filters: vec![Filter::from_static("a/#")],
+				}
+				.into(),
+				"UNSUBSCRIBE id=7 filters=a/#",
+			),
+			(Packet::PingReq, "PINGREQ"),
+			(Packet::Disconnect, "DISCONNECT"),
+		];
+
+		for (packet, summary) in packets {
+			assert_eq!(packet.to_string(), summary);
+		}
+	}
+
+	#[test]
+	fn pubrel_flags() {
+		let id = PacketId::new(7).unwrap();
+		let frame = |header| Frame {
+			header,
+			payload: vec![0x00, 0x07].into(),
+		};
+
+		let reserved = frame(0x62);
+		for mode in [ParseMode::Strict, ParseMode::Lenient] {
+			let Packet::PubRel(pubrel) = Packet::parse_with(&reserved, mode).unwrap() else {
+				panic!("expected PubRel");
+			};
+			assert_eq!(pubrel.id, id);
+		}
+
+		let zero = frame(0x60);
+		assert!(matches!(
+			Packet::parse(&zero),
+			Err(DeserializeError::InvalidHeader)
+		));
+		assert!(matches!(
+			Packet::parse_with(&zero, ParseMode::Strict),
+			Err(DeserializeError::InvalidHeader)
+		));
+		let Packet::PubRel(pubrel) = Packet::parse_with(&zero, ParseMode::Lenient).unwrap() else {
+			panic!("expected PubRel");
+		};
+		assert_eq!(pubrel.id, id);
+
+		// Other flags are rejected either way.
+		assert!(matches!(
+			Packet::parse_with(&frame(0x61), ParseMode::Lenient),
+			Err(DeserializeError::InvalidHeader)
+		));
+	}
+}
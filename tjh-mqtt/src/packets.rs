@@ -3,23 +3,101 @@ use crate::{
 	misc::{self, Credentials, Will},
 	serde, Filter, InvalidQoS, Packet, PacketId, QoS, Topic,
 };
-use bytes::{Buf, BufMut, Bytes};
-use std::{error, fmt, io, str::Utf8Error};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{fmt, io, iter, slice, str::Utf8Error};
+
+#[cfg(feature = "v5")]
+mod auth;
+#[cfg(feature = "debug-tools")]
+mod hexdump;
+mod owned;
+mod validate;
+
+#[cfg(feature = "v5")]
+pub use auth::{
+	AckProperties, Auth, AuthProperties, AuthReasonCode, ConnAckProperties, ConnectProperties,
+	PublishProperties, WillProperties,
+};
+pub use connect::ConnectBuilder;
+#[cfg(feature = "v5")]
+pub use owned::OwnedAuth;
+pub use owned::{
+	OwnedConnect, OwnedCredentials, OwnedPacket, OwnedPublish, OwnedSubscribe, OwnedUnsubscribe,
+	OwnedWill,
+};
+pub use validate::{validate, ProtocolViolation};
 
 const DEFAULT_PROTOCOL_NAME: &str = "MQTT";
+const V3_1_PROTOCOL_NAME: &str = "MQIsdp";
+
+/// The version of the MQTT protocol requested in a [`Connect`] packet.
+///
+/// The version determines the protocol name and level of the Connect packet.
+/// The other packets are encoded identically for v3.1 and v3.1.1. With the `v5`
+/// feature, MQTT v5 properties are supported in the Connect, Auth and
+/// acknowledgement packets, and outgoing Publish packets. The other packets a
+/// Client sends and receives have `_v5` variants of their methods, which skip
+/// or omit the properties.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProtocolVersion {
+	/// MQTT v3.1, with the protocol name `"MQIsdp"` and level 3.
+	V3_1,
+	/// MQTT v3.1.1, with the protocol name `"MQTT"` and level 4.
+	#[default]
+	V3_1_1,
+	/// MQTT v5, with the protocol name `"MQTT"` and level 5.
+	V5,
+}
+
+impl ProtocolVersion {
+	/// Returns the protocol name sent in the Connect packet.
+	#[inline]
+	pub const fn name(self) -> &'static str {
+		match self {
+			Self::V3_1 => V3_1_PROTOCOL_NAME,
+			Self::V3_1_1 | Self::V5 => DEFAULT_PROTOCOL_NAME,
+		}
+	}
+
+	/// Returns the protocol level sent in the Connect packet.
+	#[inline]
+	pub const fn level(self) -> u8 {
+		match self {
+			Self::V3_1 => 3,
+			Self::V3_1_1 => 4,
+			Self::V5 => 5,
+		}
+	}
+
+	/// Returns the protocol version with the given name and level, if any.
+	pub fn from_name_and_level(name: &str, level: u8) -> Option<Self> {
+		[Self::V3_1, Self::V3_1_1, Self::V5]
+			.into_iter()
+			.find(|version| version.name() == name && version.level() == level)
+	}
+}
 
 pub trait SerializePacket {
 	fn serialize_to_bytes(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError>;
 }
 
 pub trait DeserializePacket<'a>: Sized {
-	fn from_frame(frame: &'a Frame) -> Result<Self, ParseError>;
+	fn from_frame(frame: &'a Frame) -> Result<Self, DeserializeError>;
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct SubscribeFailed;
 
-#[derive(Debug)]
+/// The parameters provided to [`Publish::new`] are inconsistent.
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidPublish {
+	#[error("Publish packets with QoS of AtLeastOnce or ExactlyOnce require a packet ID")]
+	MissingPacketId,
+	#[error("Publish packets with QoS of AtMostOnce cannot have a packet ID")]
+	UnexpectedPacketId,
+}
+
+#[derive(Clone, Debug)]
 pub struct Frame {
 	pub header: u8,
 	pub payload: Bytes,
@@ -28,26 +106,71 @@ pub struct Frame {
 impl Frame {
 	/// Checks if a complete [`Packet`] can be decoded from `src`. If so,
 	/// returns the length of the packet.
-	pub fn check(src: &mut io::Cursor<&[u8]>) -> Result<usize, ParseError> {
-		let header = serde::get_u8(src)?;
-		if header == 0 || header == 0xf0 {
-			return Err(ParseError::InvalidHeader);
-		}
-
-		let length = serde::get_var(src)?;
+	pub fn check(src: &mut io::Cursor<&[u8]>) -> Result<usize, DeserializeError> {
+		let (_, length) = Self::decode_header(src)?;
 		let _ = serde::get_slice(src, length)?;
 		Ok(src.position() as _)
 	}
 
 	/// Parses a [`Frame`] from `src`.
-	pub fn parse(mut packet: Bytes) -> Result<Self, ParseError> {
+	///
+	/// `packet` must contain exactly one packet, as determined by
+	/// [`Frame::check`].
+	pub fn parse(mut packet: Bytes) -> Result<Self, DeserializeError> {
 		let mut cursor = io::Cursor::new(&packet[..]);
 		let header = serde::get_u8(&mut cursor)?;
-		let _ = serde::get_var(&mut cursor)?;
+		let length = serde::get_var(&mut cursor)?;
+		if length != cursor.remaining() {
+			return Err(DeserializeError::MalformedLength);
+		}
 
 		let payload = packet.split_off(cursor.position() as _);
 		Ok(Self { header, payload })
 	}
+
+	/// Splits a complete [`Frame`] from the front of `buffer`, if there is one.
+	///
+	/// This is equivalent to [`Frame::check`] followed by [`Frame::parse`], but
+	/// the fixed header is only decoded once. Returns `None`, leaving `buffer`
+	/// unchanged, if `buffer` does not contain a complete frame.
+	///
+	/// # Example
+	/// ```
+	/// # use bytes::BytesMut;
+	/// # use tjh_mqtt::packets::Frame;
+	/// let mut buffer = BytesMut::from(&b"\xd0\x00\x30"[..]);
+	/// let frame = Frame::split_from(&mut buffer).unwrap().unwrap();
+	/// assert_eq!(frame.header, 0xd0);
+	/// assert!(Frame::split_from(&mut buffer).unwrap().is_none());
+	/// assert_eq!(&buffer[..], b"\x30");
+	/// ```
+	pub fn split_from(buffer: &mut BytesMut) -> Result<Option<Self>, DeserializeError> {
+		let mut cursor = io::Cursor::new(&buffer[..]);
+		let (header, length) = match Self::decode_header(&mut cursor) {
+			Ok(fixed_header) => fixed_header,
+			Err(DeserializeError::Incomplete) => return Ok(None),
+			Err(error) => return Err(error),
+		};
+
+		let offset = cursor.position() as usize;
+		if cursor.remaining() < length {
+			return Ok(None);
+		}
+
+		buffer.advance(offset);
+		let payload = buffer.split_to(length).freeze();
+		Ok(Some(Self { header, payload }))
+	}
+
+	/// Decodes the packet type and remaining length from the fixed header.
+	fn decode_header(src: &mut io::Cursor<&[u8]>) -> Result<(u8, usize), DeserializeError> {
+		let header = serde::get_u8(src)?;
+		if header == 0 || (header == 0xf0 && !cfg!(feature = "v5")) {
+			return Err(DeserializeError::InvalidHeader);
+		}
+
+		Ok((header, serde::get_var(src)?))
+	}
 }
 
 //
@@ -80,6 +203,10 @@ pub struct Connect<'a> {
 
 	/// Login credentials.
 	pub credentials: Option<Credentials<'a>>,
+
+	/// MQTT v5 properties. These are only sent if the protocol level is 5.
+	#[cfg(feature = "v5")]
+	pub properties: ConnectProperties<'a>,
 }
 
 /// A ConnAck packet is sent by the Server to the Client to acknowledge a
@@ -87,7 +214,7 @@ pub struct Connect<'a> {
 ///
 /// The Client may send packets to the Server before receiving ConnAck, however
 /// the Server shouldn't send any packets to the Client before ConnAck.
-#[derive(Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct ConnAck {
 	/// Indicates that the Server has existing state from a previous session for
 	/// the client.
@@ -95,8 +222,14 @@ pub struct ConnAck {
 
 	/// Status code.
 	pub code: u8,
+
+	/// MQTT v5 properties. These are only present when the Client connected
+	/// with MQTT v5.
+	#[cfg(feature = "v5")]
+	pub properties: ConnAckProperties,
 }
 
+#[derive(Clone)]
 pub enum Publish<'a> {
 	AtMostOnce {
 		retain: bool,
@@ -119,24 +252,24 @@ pub enum Publish<'a> {
 	},
 }
 
-id_packet!(PubAck, Packet::PubAck, 0x40);
-id_packet!(PubRec, Packet::PubRec, 0x50);
-id_packet!(PubRel, Packet::PubRel, 0x62);
-id_packet!(PubComp, Packet::PubComp, 0x70);
+ack_packet!(PubAck, Packet::PubAck, 0x40);
+ack_packet!(PubRec, Packet::PubRec, 0x50);
+ack_packet!(PubRel, Packet::PubRel, 0x62);
+ack_packet!(PubComp, Packet::PubComp, 0x70);
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Subscribe<'a> {
 	pub id: PacketId,
 	pub filters: Vec<(&'a Filter, QoS)>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SubAck {
 	pub id: PacketId,
 	pub result: Vec<Result<QoS, SubscribeFailed>>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Unsubscribe<'a> {
 	pub id: PacketId,
 	pub filters: Vec<&'a Filter>,
@@ -160,27 +293,232 @@ mod connect {
 				clean_session: true,
 				will: None,
 				credentials: None,
+				#[cfg(feature = "v5")]
+				properties: Default::default(),
 			}
 		}
 	}
 
+	/// A builder for [`Connect`] packets, returned by [`Connect::builder`].
+	///
+	/// The username and password are set separately, so a password without a
+	/// username is rejected by [`build`](Self::build) before MQTT v5, rather than
+	/// silently producing an invalid packet.
+	#[derive(Clone, Debug, Default)]
+	pub struct ConnectBuilder<'a> {
+		connect: Connect<'a>,
+		username: Option<&'a str>,
+		password: Option<&'a str>,
+	}
+
+	impl<'a> ConnectBuilder<'a> {
+		/// Sets the client ID.
+		#[inline]
+		pub fn client_id(mut self, client_id: &'a str) -> Self {
+			self.connect.client_id = client_id;
+			self
+		}
+
+		/// Sets the protocol name and level for `version`.
+		#[inline]
+		pub fn protocol_version(mut self, version: ProtocolVersion) -> Self {
+			self.connect = self.connect.with_protocol_version(version);
+			self
+		}
+
+		/// Sets the keep-alive timeout in seconds.
+		#[inline]
+		pub fn keep_alive(mut self, keep_alive: u16) -> Self {
+			self.connect.keep_alive = keep_alive;
+			self
+		}
+
+		/// Sets whether to request a clean session.
+		#[inline]
+		pub fn clean_session(mut self, clean_session: bool) -> Self {
+			self.connect.clean_session = clean_session;
+			self
+		}
+
+		/// Sets the last will and testament for the Client.
+		#[inline]
+		pub fn will(mut self, will: Will<'a>) -> Self {
+			self.connect.will = Some(will);
+			self
+		}
+
+		/// Sets the username.
+		#[inline]
+		pub fn username(mut self, username: &'a str) -> Self {
+			self.username = Some(username);
+			self
+		}
+
+		/// Sets the password. Before MQTT v5, a username must also be set.
+		#[inline]
+		pub fn password(mut self, password: &'a str) -> Self {
+			self.password = Some(password);
+			self
+		}
+
+		/// Sets the username and password from `credentials`.
+		#[inline]
+		pub fn credentials(mut self, credentials: impl Into<Credentials<'a>>) -> Self {
+			let Credentials { username, password } = credentials.into();
+			self.username = username;
+			self.password = password;
+			self
+		}
+
+		/// Builds the Connect packet.
+		///
+		/// Returns [`InvalidCredentials::PasswordWithoutUsername`] if a password
+		/// was set without a username, for a protocol version before MQTT v5.
+		///
+		/// # Example
+		/// ```
+		/// # use tjh_mqtt::{misc::Will, packets::{Connect, InvalidCredentials}, QoS};
+		/// let connect = Connect::builder()
+		/// 	.client_id("client")
+		/// 	.credentials(("user", "pass"))
+		/// 	.will(Will::new("status/client", "offline", QoS::AtLeastOnce, true).unwrap())
+		/// 	.build()
+		/// 	.unwrap();
+		/// assert_eq!(connect.credentials.unwrap().password, Some("pass"));
+		///
+		/// assert!(matches!(
+		/// 	Connect::builder().password("pass").build(),
+		/// 	Err(InvalidCredentials::PasswordWithoutUsername)
+		/// ));
+		/// ```
+		pub fn build(self) -> Result<Connect<'a>, InvalidCredentials> {
+			let Self {
+				mut connect,
+				username,
+				password,
+			} = self;
+
+			connect.credentials = match (username, password) {
+				(None, None) => None,
+				(None, Some(_)) if connect.protocol_level < ProtocolVersion::V5.level() => {
+					return Err(InvalidCredentials::PasswordWithoutUsername);
+				}
+				(username, password) => Some(Credentials { username, password }),
+			};
+
+			Ok(connect)
+		}
+	}
+
 	impl<'a> Connect<'a> {
-		pub fn parse(payload: &'a [u8]) -> Result<Self, ParseError> {
+		/// Returns a [`ConnectBuilder`] with the default values.
+		#[inline]
+		pub fn builder() -> ConnectBuilder<'a> {
+			ConnectBuilder::default()
+		}
+
+		/// Sets the protocol name and level for `version`.
+		///
+		/// # Example
+		/// ```
+		/// # use tjh_mqtt::packets::{Connect, ProtocolVersion};
+		/// let connect = Connect::default().with_protocol_version(ProtocolVersion::V3_1);
+		/// assert_eq!(connect.protocol_name, "MQIsdp");
+		/// assert_eq!(connect.protocol_level, 3);
+		/// ```
+		#[inline]
+		pub fn with_protocol_version(self, version: ProtocolVersion) -> Self {
+			Self {
+				protocol_name: version.name(),
+				protocol_level: version.level(),
+				..self
+			}
+		}
+
+		/// Returns the protocol version requested by the Connect packet, if the
+		/// protocol name and level are recognised.
+		#[inline]
+		pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+			ProtocolVersion::from_name_and_level(self.protocol_name, self.protocol_level)
+		}
+
+		/// Checks the client ID against the restrictions of the protocol version.
+		///
+		/// Before MQTT v5, Servers are only required to accept client IDs of up to
+		/// 23 ASCII alphanumeric characters, and may reject any other ID with a
+		/// return code of `0x02`. MQTT v5 requires the same of Servers, but the
+		/// Server can assign an ID if the ID is rejected, so MQTT v5 client IDs are
+		/// not checked.
+		///
+		/// # Example
+		/// ```
+		/// # use tjh_mqtt::packets::{Connect, InvalidClientId, ProtocolVersion};
+		/// let connect = Connect {
+		/// 	client_id: "a-client-id-which-is-too-long",
+		/// 	..Default::default()
+		/// };
+		/// assert!(matches!(
+		/// 	connect.validate(),
+		/// 	Err(InvalidClientId::TooLong(29))
+		/// ));
+		///
+		/// let connect = connect.with_protocol_version(ProtocolVersion::V5);
+		/// assert!(connect.validate().is_ok());
+		/// ```
+		pub fn validate(&self) -> Result<(), InvalidClientId> {
+			if self.protocol_level >= ProtocolVersion::V5.level() {
+				return Ok(());
+			}
+
+			let len = self.client_id.chars().count();
+			if len > misc::MAX_CLIENT_ID_LEN {
+				return Err(InvalidClientId::TooLong(len));
+			}
+
+			if let Some((position, character)) = self
+				.client_id
+				.chars()
+				.enumerate()
+				.find(|(_, c)| !c.is_ascii_alphanumeric())
+			{
+				return Err(InvalidClientId::InvalidCharacter(position, character));
+			}
+
+			Ok(())
+		}
+
+		pub fn parse(payload: &'a [u8]) -> Result<Self, DeserializeError> {
 			let mut cursor = io::Cursor::new(payload);
 			let protocol_name = match serde::get_str(&mut cursor)? {
 				DEFAULT_PROTOCOL_NAME => DEFAULT_PROTOCOL_NAME,
+				V3_1_PROTOCOL_NAME => V3_1_PROTOCOL_NAME,
 				_ => {
-					return Err(ParseError::MalformedPacket("invalid protocol name"));
+					return Err(DeserializeError::MalformedPacket("invalid protocol name"));
 				}
 			};
 
 			let protocol_level = serde::get_u8(&mut cursor)?;
 			let flags = serde::get_u8(&mut cursor)?;
 			let keep_alive = serde::get_u16(&mut cursor)?;
+
+			#[cfg(feature = "v5")]
+			let properties = if protocol_level == ProtocolVersion::V5.level() {
+				ConnectProperties::parse(&mut cursor)?
+			} else {
+				ConnectProperties::default()
+			};
+
 			let client_id = serde::get_str(&mut cursor)?;
 
 			let clean_session = flags & 0x02 == 0x02;
 			let will = if flags & 0x04 == 0x04 {
+				#[cfg(feature = "v5")]
+				let properties = if protocol_level == ProtocolVersion::V5.level() {
+					WillProperties::parse(&mut cursor)?
+				} else {
+					WillProperties::default()
+				};
+
 				let topic = serde::get_str(&mut cursor)?;
 				let len = serde::get_u16(&mut cursor)?;
 
@@ -194,21 +532,35 @@ mod connect {
 					payload: Bytes::from(payload),
 					qos,
 					retain,
+					#[cfg(feature = "v5")]
+					properties,
 				})
 			} else {
 				None
 			};
 
-			let credentials = if flags & 0x40 == 0x40 {
-				let username = serde::get_str(&mut cursor)?;
-				let password = if flags & 0x80 == 0x80 {
-					Some(serde::get_str(&mut cursor)?)
-				} else {
-					None
-				};
-				Some(misc::Credentials { username, password })
-			} else {
-				None
+			// The username flag is 0x80 and the password flag is 0x40. Before MQTT v5
+			// a password cannot be sent without a username.
+			let credentials = match (flags & 0x80 == 0x80, flags & 0x40 == 0x40) {
+				(false, false) => None,
+				(false, true) if protocol_level < ProtocolVersion::V5.level() => {
+					return Err(DeserializeError::MalformedPacket(
+						"password flag set without username flag",
+					));
+				}
+				(has_username, has_password) => {
+					let username = if has_username {
+						Some(serde::get_str(&mut cursor)?)
+					} else {
+						None
+					};
+					let password = if has_password {
+						Some(serde::get_str(&mut cursor)?)
+					} else {
+						None
+					};
+					Some(misc::Credentials { username, password })
+				}
 			};
 
 			Ok(Self {
@@ -219,6 +571,8 @@ mod connect {
 				clean_session,
 				will,
 				credentials,
+				#[cfg(feature = "v5")]
+				properties,
 			})
 		}
 
@@ -227,26 +581,81 @@ mod connect {
 			serde::put_u8(dst, 0x10)?;
 			serde::put_var(dst, self.payload_len())?;
 
+			self.serialize_variable_header(dst)?;
+
+			#[cfg(feature = "v5")]
+			if self.is_v5() {
+				self.properties.serialize_to_bytes(dst)?;
+			}
+
+			self.serialize_payload(dst)
+		}
+
+		/// Splits the serialized packet into the variable header, up to the
+		/// properties, and the payload.
+		///
+		/// This allows the Connect packet to be serialized again with different
+		/// properties.
+		#[cfg(all(feature = "v5", feature = "tokio-client"))]
+		pub(crate) fn serialize_parts(&self) -> Result<(Bytes, Bytes), serde::WriteError> {
+			let mut variable_header = bytes::BytesMut::new();
+			self.serialize_variable_header(&mut variable_header)?;
+			let mut payload = bytes::BytesMut::new();
+			self.serialize_payload(&mut payload)?;
+			Ok((variable_header.freeze(), payload.freeze()))
+		}
+
+		/// Serializes a Connect packet from the parts returned by
+		/// [`Connect::serialize_parts`] and `properties`.
+		#[cfg(all(feature = "v5", feature = "tokio-client"))]
+		pub(crate) fn serialize_from_parts(
+			(variable_header, payload): &(Bytes, Bytes),
+			properties: &ConnectProperties,
+			dst: &mut impl BufMut,
+		) -> Result<(), serde::WriteError> {
+			let len = variable_header.len() + properties.encoded_len() + payload.len();
+			serde::put_u8(dst, 0x10)?;
+			serde::put_var(dst, len)?;
+			serde::put_slice(dst, variable_header)?;
+			properties.serialize_to_bytes(dst)?;
+			serde::put_slice(dst, payload)
+		}
+
+		fn serialize_variable_header(
+			&self,
+			dst: &mut impl BufMut,
+		) -> Result<(), serde::WriteError> {
 			// Write the protocol name and level.
 			serde::put_str(dst, self.protocol_name)?;
 			serde::put_u8(dst, self.protocol_level)?;
 
 			// Write the flags and keep alive.
 			serde::put_u8(dst, self.flags())?;
-			serde::put_u16(dst, self.keep_alive)?;
+			serde::put_u16(dst, self.keep_alive)
+		}
 
+		fn serialize_payload(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
 			// Write the client ID.
 			serde::put_str(dst, self.client_id)?;
 
 			// Write the will.
 			if let Some(will) = &self.will {
+				#[cfg(feature = "v5")]
+				if self.is_v5() {
+					will.properties.serialize_to_bytes(dst)?;
+				}
+
 				serde::put_str(dst, will.topic.as_str())?;
+				let len = u16::try_from(will.payload.len()).map_err(|_| serde::WriteError)?;
+				serde::put_u16(dst, len)?;
 				serde::put_slice(dst, &will.payload)?;
 			}
 
 			// Write the credentials.
 			if let Some(credentials) = &self.credentials {
-				serde::put_str(dst, credentials.username)?;
+				if let Some(username) = credentials.username {
+					serde::put_str(dst, username)?;
+				}
 				if let Some(password) = &credentials.password {
 					serde::put_str(dst, password)?;
 				}
@@ -265,8 +674,18 @@ mod connect {
 				len += 2 + will.topic.len() + 2 + will.payload.len();
 			}
 
+			#[cfg(feature = "v5")]
+			if self.is_v5() {
+				len += self.properties.encoded_len();
+				if let Some(will) = &self.will {
+					len += will.properties.encoded_len();
+				}
+			}
+
 			if let Some(credentials) = &self.credentials {
-				len += 2 + credentials.username.len();
+				if let Some(username) = credentials.username {
+					len += 2 + username.len();
+				}
 				if let Some(password) = &credentials.password {
 					len += 2 + password.len();
 				}
@@ -275,6 +694,12 @@ mod connect {
 			len
 		}
 
+		#[cfg(feature = "v5")]
+		#[inline(always)]
+		fn is_v5(&self) -> bool {
+			self.protocol_level == ProtocolVersion::V5.level()
+		}
+
 		fn flags(&self) -> u8 {
 			let mut flags = 0;
 
@@ -284,14 +709,16 @@ mod connect {
 
 			if let Some(will) = &self.will {
 				flags |= 0x04;
-				flags |= (will.qos as u8) << 3;
+				flags |= will.qos.as_u8() << 3;
 				if will.retain {
 					flags |= 0x20;
 				}
 			}
 
 			if let Some(credentials) = &self.credentials {
-				flags |= 0x80;
+				if credentials.username.is_some() {
+					flags |= 0x80;
+				}
 				if credentials.password.is_some() {
 					flags |= 0x40;
 				}
@@ -304,9 +731,12 @@ mod connect {
 
 impl ConnAck {
 	/// Parses the payload of a ConnAck packet.
-	pub fn parse(payload: &[u8]) -> Result<Self, ParseError> {
-		if payload.len() != 2 {
-			return Err(ParseError::MalformedPacket(
+	///
+	/// With the `v5` feature, the MQTT v5 properties following the reason code
+	/// are parsed into [`properties`](Self::properties).
+	pub fn parse(payload: &[u8]) -> Result<Self, DeserializeError> {
+		if payload.len() != 2 && !cfg!(feature = "v5") {
+			return Err(DeserializeError::MalformedPacket(
 				"ConnAck packet must have length 2",
 			));
 		}
@@ -315,8 +745,20 @@ impl ConnAck {
 		let flags = serde::get_u8(&mut cursor)?;
 		let code = serde::get_u8(&mut cursor)?;
 
+		#[cfg(feature = "v5")]
+		let mut properties = ConnAckProperties::default();
+		#[cfg(feature = "v5")]
+		if cursor.has_remaining() {
+			properties = ConnAckProperties::parse(&mut cursor)?;
+			if cursor.has_remaining() {
+				return Err(DeserializeError::MalformedPacket(
+					"trailing bytes after ConnAck properties",
+				));
+			}
+		}
+
 		if flags & 0xe0 != 0 {
-			return Err(ParseError::MalformedPacket(
+			return Err(DeserializeError::MalformedPacket(
 				"upper 7 bits in ConnAck flags must be zero",
 			));
 		}
@@ -326,18 +768,39 @@ impl ConnAck {
 		Ok(Self {
 			session_present,
 			code,
+			#[cfg(feature = "v5")]
+			properties,
 		})
 	}
 
+	/// Serializes the ConnAck packet.
+	///
+	/// With the `v5` feature, the properties are only written when they are not
+	/// empty, so an MQTT v3.1.1 Client can still parse the packet.
 	pub fn serialize_to_bytes(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
 		let Self {
 			session_present,
 			code,
+			..
 		} = self;
+
+		#[cfg(feature = "v5")]
+		let len = match self.properties.is_empty() {
+			true => 2,
+			false => 2 + self.properties.encoded_len(),
+		};
+		#[cfg(not(feature = "v5"))]
+		let len = 2;
+
 		serde::put_u8(dst, 0x20)?;
-		serde::put_var(dst, 2)?;
+		serde::put_var(dst, len)?;
 		serde::put_u8(dst, if *session_present { 0x01 } else { 0x00 })?;
 		serde::put_u8(dst, *code)?;
+
+		#[cfg(feature = "v5")]
+		if !self.properties.is_empty() {
+			self.properties.serialize_to_bytes(dst)?;
+		}
 		Ok(())
 	}
 }
@@ -348,7 +811,73 @@ const PUBLISH_HEADER_DUPLICATE_FLAG: u8 = 0x08;
 const PUBLISH_HEADER_QOS_MASK: u8 = 0x06;
 
 impl<'a> Publish<'a> {
-	pub fn parse(payload: &'a [u8], flags: u8) -> Result<Self, ParseError> {
+	/// Creates a new Publish packet, selecting the variant from `qos`.
+	///
+	/// A packet ID must be provided for a `qos` of [`AtLeastOnce`] or
+	/// [`ExactlyOnce`], and must not be provided for [`AtMostOnce`]. The
+	/// duplicate flag is always cleared.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::{packets::Publish, PacketId, QoS, Topic};
+	/// let topic = Topic::from_static("a/b");
+	/// let id = PacketId::new(1);
+	/// let publish = Publish::new(topic, "hello".into(), QoS::AtLeastOnce, false, id).unwrap();
+	/// assert_eq!(publish.id(), id);
+	/// ```
+	///
+	/// [`AtMostOnce`]: QoS#variant.AtMostOnce
+	/// [`AtLeastOnce`]: QoS#variant.AtLeastOnce
+	/// [`ExactlyOnce`]: QoS#variant.ExactlyOnce
+	pub fn new(
+		topic: &'a Topic,
+		payload: Bytes,
+		qos: QoS,
+		retain: bool,
+		id: Option<PacketId>,
+	) -> Result<Self, InvalidPublish> {
+		match (qos, id) {
+			(QoS::AtMostOnce, None) => Ok(Self::AtMostOnce {
+				retain,
+				topic,
+				payload,
+			}),
+			(QoS::AtMostOnce, Some(_)) => Err(InvalidPublish::UnexpectedPacketId),
+			(QoS::AtLeastOnce, Some(id)) => Ok(Self::AtLeastOnce {
+				id,
+				retain,
+				duplicate: false,
+				topic,
+				payload,
+			}),
+			(QoS::ExactlyOnce, Some(id)) => Ok(Self::ExactlyOnce {
+				id,
+				retain,
+				duplicate: false,
+				topic,
+				payload,
+			}),
+			(_, None) => Err(InvalidPublish::MissingPacketId),
+		}
+	}
+
+	pub fn parse(payload: &'a [u8], flags: u8) -> Result<Self, DeserializeError> {
+		Self::parse_impl(payload, flags, false)
+	}
+
+	/// Parses the payload of an MQTT v5 Publish packet.
+	///
+	/// The properties are not kept.
+	#[cfg(feature = "v5")]
+	pub fn parse_v5(payload: &'a [u8], flags: u8) -> Result<Self, DeserializeError> {
+		Self::parse_impl(payload, flags, true)
+	}
+
+	fn parse_impl(
+		payload: &'a [u8],
+		flags: u8,
+		properties: bool,
+	) -> Result<Self, DeserializeError> {
 		let mut cursor = io::Cursor::new(payload);
 		// Extract properties from the header flags.
 		let retain = flags & PUBLISH_HEADER_RETAIN_FLAG == PUBLISH_HEADER_RETAIN_FLAG;
@@ -357,106 +886,99 @@ impl<'a> Publish<'a> {
 
 		let topic = Topic::new(serde::get_str(&mut cursor)?)?;
 
-		// The interpretation of the remaining bytes depends on the QoS.
-		match qos {
+		// Only AtLeastOnce and ExactlyOnce packets have a packet ID.
+		let id = match qos {
 			QoS::AtMostOnce => {
 				if duplicate {
-					return Err(ParseError::MalformedPacket(
+					return Err(DeserializeError::MalformedPacket(
 						"duplicate flag must be 0 for Publish packets with QoS of AtMostOnce",
 					));
 				}
-				let remaining = cursor.remaining();
-				let payload = serde::get_slice(&mut cursor, remaining)?.to_vec();
-				let payload = Bytes::from(payload);
-
-				Ok(Self::AtMostOnce {
-					retain,
-					topic,
-					payload,
-				})
-			}
-			QoS::AtLeastOnce => {
-				let id = serde::get_id(&mut cursor)?;
-				let remaining = cursor.remaining();
-				let payload = serde::get_slice(&mut cursor, remaining)?.to_vec();
-				let payload = Bytes::from(payload);
-
-				Ok(Self::AtLeastOnce {
-					id,
-					retain,
-					duplicate,
-					topic,
-					payload,
-				})
+				None
 			}
-			QoS::ExactlyOnce => {
-				let id = serde::get_id(&mut cursor)?;
-				let remaining = cursor.remaining();
-				let payload = serde::get_slice(&mut cursor, remaining)?.to_vec();
-				let payload = Bytes::from(payload);
+			QoS::AtLeastOnce | QoS::ExactlyOnce => Some(serde::get_id(&mut cursor)?),
+		};
 
-				Ok(Self::ExactlyOnce {
-					id,
-					retain,
-					duplicate,
-					topic,
-					payload,
-				})
-			}
+		if properties {
+			skip_properties(&mut cursor)?;
 		}
-	}
 
-	pub fn serialize_to_bytes(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
-		match self {
-			Self::AtMostOnce {
+		let remaining = cursor.remaining();
+		let payload = serde::get_slice(&mut cursor, remaining)?.to_vec();
+		let payload = Bytes::from(payload);
+
+		match (qos, id) {
+			(QoS::AtLeastOnce, Some(id)) => Ok(Self::AtLeastOnce {
+				id,
 				retain,
+				duplicate,
 				topic,
 				payload,
-			} => {
-				let flags = retain.then_some(PUBLISH_HEADER_RETAIN_FLAG).unwrap_or(0)
-					| (QoS::AtMostOnce as u8) << 1;
-				serde::put_u8(dst, PUBLISH_HEADER_CONTROL | flags)?;
-				serde::put_var(dst, 2 + topic.len() + payload.len())?;
-				serde::put_str(dst, topic.as_str())?;
-				serde::put_slice(dst, payload)?;
-			}
-			Self::AtLeastOnce {
+			}),
+			(QoS::ExactlyOnce, Some(id)) => Ok(Self::ExactlyOnce {
 				id,
 				retain,
 				duplicate,
 				topic,
 				payload,
-			} => {
-				let flags = retain.then_some(PUBLISH_HEADER_RETAIN_FLAG).unwrap_or(0)
-					| duplicate
-						.then_some(PUBLISH_HEADER_DUPLICATE_FLAG)
-						.unwrap_or(0) | (QoS::AtLeastOnce as u8) << 1;
-				serde::put_u8(dst, PUBLISH_HEADER_CONTROL | flags)?;
-				serde::put_var(dst, 4 + topic.len() + payload.len())?;
-				serde::put_str(dst, topic.as_str())?;
-				serde::put_u16(dst, id.get())?;
-				serde::put_slice(dst, payload)?;
-			}
-			Self::ExactlyOnce {
-				id,
+			}),
+			_ => Ok(Self::AtMostOnce {
 				retain,
-				duplicate,
 				topic,
 				payload,
-			} => {
-				let flags = retain.then_some(PUBLISH_HEADER_RETAIN_FLAG).unwrap_or(0)
-					| duplicate
-						.then_some(PUBLISH_HEADER_DUPLICATE_FLAG)
-						.unwrap_or(0) | (QoS::ExactlyOnce as u8) << 1;
-				serde::put_u8(dst, PUBLISH_HEADER_CONTROL | flags)?;
-				serde::put_var(dst, 4 + topic.len() + payload.len())?;
+			}),
+		}
+	}
+
+	pub fn serialize_to_bytes(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
+		let payload = self.payload();
+		self.serialize_header(payload.len(), dst)?;
+		serde::put_slice(dst, payload)
+	}
+
+	/// Serializes the Publish packet up to the payload, for a payload of
+	/// `payload_len` bytes. The payload of `self` is ignored.
+	pub(crate) fn serialize_header(
+		&self,
+		payload_len: usize,
+		dst: &mut impl BufMut,
+	) -> Result<(), serde::WriteError> {
+		let topic = self.topic();
+		let mut flags = self.qos().as_u8() << 1;
+		if self.retain() {
+			flags |= PUBLISH_HEADER_RETAIN_FLAG;
+		}
+		if self.duplicate() {
+			flags |= PUBLISH_HEADER_DUPLICATE_FLAG;
+		}
+
+		serde::put_u8(dst, PUBLISH_HEADER_CONTROL | flags)?;
+		match self.id() {
+			None => {
+				serde::put_var(dst, 2 + topic.len() + payload_len)?;
+				serde::put_str(dst, topic.as_str())
+			}
+			Some(id) => {
+				serde::put_var(dst, 4 + topic.len() + payload_len)?;
 				serde::put_str(dst, topic.as_str())?;
-				serde::put_u16(dst, id.get())?;
-				serde::put_slice(dst, payload)?;
+				serde::put_u16(dst, id.get())
 			}
 		}
+	}
 
-		Ok(())
+	/// Serializes the Publish packet up to the payload as MQTT v5, with
+	/// `properties`, for a payload of `payload_len` bytes.
+	#[cfg(all(feature = "v5", feature = "tokio-client"))]
+	pub(crate) fn serialize_header_v5(
+		&self,
+		payload_len: usize,
+		properties: &PublishProperties,
+		dst: &mut impl BufMut,
+	) -> Result<(), serde::WriteError> {
+		// The properties precede the payload, so are counted in the remaining
+		// length along with it.
+		self.serialize_header(properties.encoded_len() + payload_len, dst)?;
+		properties.serialize_to_bytes(dst)
 	}
 
 	/// Returns the topic of the Publish packet.
@@ -479,6 +1001,21 @@ impl<'a> Publish<'a> {
 		}
 	}
 
+	/// Returns the length of the payload in bytes.
+	#[inline]
+	pub fn payload_len(&self) -> usize {
+		self.payload().len()
+	}
+
+	/// Returns `true` if the payload is empty.
+	///
+	/// A retained Publish packet with an empty payload clears the retained
+	/// message for the topic.
+	#[inline]
+	pub fn is_empty_payload(&self) -> bool {
+		self.payload().is_empty()
+	}
+
 	/// Returns the QoS of the Publish packet.
 	#[inline]
 	pub fn qos(&self) -> QoS {
@@ -546,7 +1083,7 @@ impl fmt::Debug for Publish<'_> {
 
 impl<'a> Subscribe<'a> {
 	/// Parses the payload of a [`Subscribe`] packet.
-	pub fn parse(payload: &'a [u8]) -> Result<Self, ParseError> {
+	pub fn parse(payload: &'a [u8]) -> Result<Self, DeserializeError> {
 		let mut cursor = io::Cursor::new(payload);
 		let id = serde::get_id(&mut cursor)?;
 
@@ -560,19 +1097,49 @@ impl<'a> Subscribe<'a> {
 		Ok(Self { id, filters })
 	}
 
-	pub fn serialize_to_bytes(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
-		let Self { id, filters } = self;
-		serde::put_u8(dst, 0x82)?;
+	/// Returns an iterator over the filters and requested QoS of the packet.
+	#[inline]
+	pub fn filters(&self) -> iter::Copied<slice::Iter<'_, (&'a Filter, QoS)>> {
+		self.filters.iter().copied()
+	}
 
-		let len = 2 + filters
+	/// Returns the length of the packet following the fixed header, which is
+	/// encoded as the remaining length.
+	#[inline]
+	pub fn payload_len(&self) -> usize {
+		// Each filter is length-prefixed, and followed by the requested QoS.
+		let filters: usize = self
+			.filters
 			.iter()
-			.fold(0usize, |acc, (filter, _)| acc + 3 + filter.len());
+			.map(|(filter, _)| 3 + filter.len())
+			.sum();
+		2 + filters
+	}
 
-		serde::put_var(dst, len)?;
+	pub fn serialize_to_bytes(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
+		self.serialize_impl(false, dst)
+	}
+
+	/// Serializes the packet as MQTT v5, without properties.
+	///
+	/// The subscription options other than the maximum QoS are left unset.
+	#[cfg(feature = "v5")]
+	pub fn serialize_to_bytes_v5(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
+		self.serialize_impl(true, dst)
+	}
+
+	fn serialize_impl(&self, v5: bool, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
+		let Self { id, filters } = self;
+		serde::put_u8(dst, 0x82)?;
+		serde::put_var(dst, self.payload_len() + usize::from(v5))?;
 		serde::put_u16(dst, id.get())?;
+		if v5 {
+			// Empty properties.
+			serde::put_var(dst, 0)?;
+		}
 		for (filter, qos) in filters {
 			serde::put_str(dst, filter.as_str())?;
-			serde::put_u8(dst, *qos as u8)?;
+			serde::put_u8(dst, qos.as_u8())?;
 		}
 
 		Ok(())
@@ -580,7 +1147,32 @@ impl<'a> Subscribe<'a> {
 }
 
 impl SubAck {
-	pub fn parse(payload: &[u8]) -> Result<Self, ParseError> {
+	/// Parses the payload of an MQTT v5 SubAck packet.
+	///
+	/// Reason codes of `0x80` and above are failures. The specific reason and
+	/// the properties are not kept.
+	#[cfg(feature = "v5")]
+	pub fn parse_v5(payload: &[u8]) -> Result<Self, DeserializeError> {
+		let mut cursor = io::Cursor::new(payload);
+		let id = serde::get_id(&mut cursor)?;
+		skip_properties(&mut cursor)?;
+
+		let mut result = Vec::new();
+		while cursor.has_remaining() {
+			let reason_code = serde::get_u8(&mut cursor)?;
+			let qos = match reason_code {
+				0x80.. => Err(SubscribeFailed),
+				_ => Ok(reason_code.try_into().map_err(|_| {
+					DeserializeError::MalformedPacket("invalid reason code in SubAck")
+				})?),
+			};
+			result.push(qos);
+		}
+
+		Ok(Self { id, result })
+	}
+
+	pub fn parse(payload: &[u8]) -> Result<Self, DeserializeError> {
 		let mut cursor = io::Cursor::new(payload);
 		let id = serde::get_id(&mut cursor)?;
 
@@ -593,7 +1185,9 @@ impl SubAck {
 					if return_code == 0x80 {
 						Err(SubscribeFailed)
 					} else {
-						return Err(ParseError::MalformedPacket("invalid return code in SubAck"));
+						return Err(DeserializeError::MalformedPacket(
+							"invalid return code in SubAck",
+						));
 					}
 				}
 			};
@@ -604,25 +1198,56 @@ impl SubAck {
 		Ok(Self { id, result })
 	}
 
+	/// Returns an iterator over the results of the packet, one for each filter
+	/// in the Subscribe packet.
+	#[inline]
+	pub fn results(&self) -> iter::Copied<slice::Iter<'_, Result<QoS, SubscribeFailed>>> {
+		self.result.iter().copied()
+	}
+
+	/// Returns the length of the packet following the fixed header, which is
+	/// encoded as the remaining length.
+	#[inline]
+	pub fn payload_len(&self) -> usize {
+		2 + self.result.len()
+	}
+
 	pub fn serialize_to_bytes(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
 		let Self { id, result } = self;
 		serde::put_u8(dst, 0x90)?;
-
-		let len = 2 + result.len();
-
-		serde::put_var(dst, len)?;
+		serde::put_var(dst, self.payload_len())?;
 		serde::put_u16(dst, id.get())?;
 		for qos in result {
-			serde::put_u8(dst, qos.as_ref().map(|qos| *qos as u8).unwrap_or(0x80))?;
+			serde::put_u8(dst, qos.as_ref().map(|qos| qos.as_u8()).unwrap_or(0x80))?;
 		}
 
 		Ok(())
 	}
 }
 
+impl<'b, 'a> IntoIterator for &'b Subscribe<'a> {
+	type Item = (&'a Filter, QoS);
+	type IntoIter = iter::Copied<slice::Iter<'b, (&'a Filter, QoS)>>;
+
+	#[inline]
+	fn into_iter(self) -> Self::IntoIter {
+		self.filters()
+	}
+}
+
+impl<'b> IntoIterator for &'b SubAck {
+	type Item = Result<QoS, SubscribeFailed>;
+	type IntoIter = iter::Copied<slice::Iter<'b, Result<QoS, SubscribeFailed>>>;
+
+	#[inline]
+	fn into_iter(self) -> Self::IntoIter {
+		self.results()
+	}
+}
+
 impl<'a> Unsubscribe<'a> {
 	/// Parses the payload of a [`Subscribe`] packet.
-	pub fn parse(payload: &'a [u8]) -> Result<Self, ParseError> {
+	pub fn parse(payload: &'a [u8]) -> Result<Self, DeserializeError> {
 		let mut cursor = io::Cursor::new(payload);
 		let id = serde::get_id(&mut cursor)?;
 
@@ -635,16 +1260,33 @@ impl<'a> Unsubscribe<'a> {
 		Ok(Self { id, filters })
 	}
 
+	/// Returns the length of the packet following the fixed header, which is
+	/// encoded as the remaining length.
+	#[inline]
+	pub fn payload_len(&self) -> usize {
+		let filters: usize = self.filters.iter().map(|filter| 2 + filter.len()).sum();
+		2 + filters
+	}
+
 	pub fn serialize_to_bytes(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
-		let Self { id, filters } = self;
-		serde::put_u8(dst, 0xa2)?;
+		self.serialize_impl(false, dst)
+	}
 
-		let len = 2 + filters
-			.iter()
-			.fold(0usize, |acc, filter| acc + 2 + filter.len());
+	/// Serializes the packet as MQTT v5, without properties.
+	#[cfg(feature = "v5")]
+	pub fn serialize_to_bytes_v5(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
+		self.serialize_impl(true, dst)
+	}
 
-		serde::put_var(dst, len)?;
+	fn serialize_impl(&self, v5: bool, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
+		let Self { id, filters } = self;
+		serde::put_u8(dst, 0xa2)?;
+		serde::put_var(dst, self.payload_len() + usize::from(v5))?;
 		serde::put_u16(dst, id.get())?;
+		if v5 {
+			// Empty properties.
+			serde::put_var(dst, 0)?;
+		}
 		for filter in filters {
 			serde::put_str(dst, filter.as_str())?;
 		}
@@ -653,55 +1295,97 @@ impl<'a> Unsubscribe<'a> {
 	}
 }
 
-#[derive(Debug)]
-pub enum ParseError {
+#[cfg(feature = "v5")]
+impl UnsubAck {
+	/// Parses the payload of an MQTT v5 UnsubAck packet.
+	///
+	/// The reason codes for each filter and the properties are not kept.
+	pub fn parse_v5(payload: &[u8]) -> Result<Self, DeserializeError> {
+		let mut cursor = io::Cursor::new(payload);
+		let id = serde::get_id(&mut cursor)?;
+		skip_properties(&mut cursor)?;
+		Ok(Self { id })
+	}
+}
+
+/// Skips over the MQTT v5 properties at the cursor.
+fn skip_properties(cursor: &mut io::Cursor<&[u8]>) -> Result<(), DeserializeError> {
+	let len = serde::get_var(cursor)?;
+	serde::get_slice(cursor, len)?;
+	Ok(())
+}
+
+/// An error returned when a packet cannot be deserialized.
+#[derive(Debug, thiserror::Error)]
+pub enum DeserializeError {
+	#[error("packet is incomplete")]
 	Incomplete,
+	#[error("invalid QoS level")]
 	InvalidQoS,
-	InvalidFilter(filter::InvalidFilter),
-	InvalidTopic(crate::InvalidTopic),
+	#[error("invalid filter in packet")]
+	InvalidFilter(#[source] filter::InvalidFilter),
+	#[error("invalid topic in packet")]
+	InvalidTopic(#[source] crate::InvalidTopic),
+	#[error("invalid fixed header")]
 	InvalidHeader,
+	#[error("packet identifier cannot be zero")]
 	ZeroPacketId,
+	#[error("malformed remaining length")]
 	MalformedLength,
+	#[error("malformed packet: {0}")]
 	MalformedPacket(&'static str),
-	Utf8Error(Utf8Error),
+	#[error("invalid UTF-8 string in packet")]
+	Utf8Error(#[source] Utf8Error),
 }
 
-impl From<Utf8Error> for ParseError {
+/// An error returned by [`Connect::validate`] for a client ID which the Server
+/// may reject.
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidClientId {
+	#[error("client ID is {0} characters long, Servers may reject IDs longer than 23 characters")]
+	TooLong(usize),
+	#[error("client ID has non-alphanumeric character {1:?} at position {0}")]
+	InvalidCharacter(usize, char),
+}
+
+/// An error returned by [`ConnectBuilder::build`] for credentials which cannot
+/// be sent in a Connect packet.
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidCredentials {
+	#[error("a password cannot be sent without a username before MQTT v5")]
+	PasswordWithoutUsername,
+}
+
+#[deprecated(note = "renamed to `DeserializeError`")]
+pub type ParseError = DeserializeError;
+
+impl From<Utf8Error> for DeserializeError {
 	#[inline]
 	fn from(value: Utf8Error) -> Self {
 		Self::Utf8Error(value)
 	}
 }
 
-impl From<InvalidQoS> for ParseError {
+impl From<InvalidQoS> for DeserializeError {
 	#[inline]
 	fn from(_: InvalidQoS) -> Self {
 		Self::InvalidQoS
 	}
 }
 
-impl From<crate::InvalidTopic> for ParseError {
+impl From<crate::InvalidTopic> for DeserializeError {
 	fn from(value: crate::InvalidTopic) -> Self {
 		Self::InvalidTopic(value)
 	}
 }
 
-impl From<filter::InvalidFilter> for ParseError {
+impl From<filter::InvalidFilter> for DeserializeError {
 	#[inline]
 	fn from(value: filter::InvalidFilter) -> Self {
 		Self::InvalidFilter(value)
 	}
 }
 
-impl fmt::Display for ParseError {
-	#[inline]
-	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		write!(f, "{self:?}")
-	}
-}
-
-impl error::Error for ParseError {}
-
 macro_rules! impl_serialize {
 	($name:tt) => {
 		impl SerializePacket for $name {
@@ -733,24 +1417,28 @@ impl_serialize!(UnsubAck);
 impl_serialize!(PingReq);
 impl_serialize!(PingResp);
 impl_serialize!(Disconnect);
+#[cfg(feature = "v5")]
+impl_serialize!(Auth, a);
 
 impl<'a> DeserializePacket<'a> for ConnAck {
-	fn from_frame(frame: &'a Frame) -> Result<Self, ParseError> {
+	fn from_frame(frame: &'a Frame) -> Result<Self, DeserializeError> {
 		Self::parse(&frame.payload[..])
 	}
 }
 
 macro_rules! id_packet {
 	($name:tt,$variant:expr,$header:literal) => {
-		#[derive(Debug)]
+		#[derive(Clone, Debug)]
 		pub struct $name {
 			pub id: PacketId,
 		}
 
 		impl $name {
-			pub fn parse(payload: &[u8]) -> Result<Self, ParseError> {
+			pub fn parse(payload: &[u8]) -> Result<Self, DeserializeError> {
 				if payload.len() != 2 {
-					return Err(ParseError::MalformedPacket("packet must have length 2"));
+					return Err(DeserializeError::MalformedPacket(
+						"packet must have length 2",
+					));
 				}
 
 				let mut buf = io::Cursor::new(payload);
@@ -780,15 +1468,124 @@ macro_rules! id_packet {
 }
 use id_packet;
 
+/// Defines an acknowledgement in the QoS handshake for a Publish packet.
+///
+/// With the `v5` feature, these carry an MQTT v5 reason code and properties.
+/// Both are omitted when the reason code is `0x00` and there are no
+/// properties, which is also the MQTT v3.1.1 encoding.
+macro_rules! ack_packet {
+	($name:tt,$variant:expr,$header:literal) => {
+		#[derive(Clone, Debug)]
+		pub struct $name {
+			pub id: PacketId,
+
+			/// The MQTT v5 reason code. Values of `0x80` and above indicate a
+			/// failure.
+			#[cfg(feature = "v5")]
+			pub reason_code: u8,
+
+			/// MQTT v5 properties.
+			#[cfg(feature = "v5")]
+			pub properties: AckProperties,
+		}
+
+		impl $name {
+			/// Creates a packet with a successful reason code and no properties.
+			#[inline]
+			pub fn new(id: PacketId) -> Self {
+				Self {
+					id,
+					#[cfg(feature = "v5")]
+					reason_code: 0x00,
+					#[cfg(feature = "v5")]
+					properties: AckProperties::default(),
+				}
+			}
+
+			/// Returns `true` if the reason code indicates a failure.
+			#[cfg(feature = "v5")]
+			#[inline]
+			pub fn is_failure(&self) -> bool {
+				self.reason_code >= 0x80
+			}
+
+			pub fn parse(payload: &[u8]) -> Result<Self, DeserializeError> {
+				if payload.len() != 2 && !cfg!(feature = "v5") {
+					return Err(DeserializeError::MalformedPacket(
+						"packet must have length 2",
+					));
+				}
+
+				let mut buf = io::Cursor::new(payload);
+				#[cfg_attr(not(feature = "v5"), allow(unused_mut))]
+				let mut packet = Self::new(crate::serde::get_id(&mut buf)?);
+
+				#[cfg(feature = "v5")]
+				if buf.has_remaining() {
+					packet.reason_code = crate::serde::get_u8(&mut buf)?;
+					if buf.has_remaining() {
+						packet.properties = AckProperties::parse(&mut buf)?;
+					}
+					if buf.has_remaining() {
+						return Err(DeserializeError::MalformedPacket(
+							"trailing bytes after properties",
+						));
+					}
+				}
+
+				Ok(packet)
+			}
+
+			pub fn serialize_to_bytes(
+				&self,
+				dst: &mut impl BufMut,
+			) -> Result<(), crate::serde::WriteError> {
+				#[cfg(feature = "v5")]
+				let len = match (self.reason_code, self.properties.is_empty()) {
+					(0x00, true) => 2,
+					(_, true) => 3,
+					(_, false) => 3 + self.properties.encoded_len(),
+				};
+				#[cfg(not(feature = "v5"))]
+				let len = 2;
+
+				crate::serde::put_u8(dst, $header)?;
+				crate::serde::put_var(dst, len)?;
+				crate::serde::put_u16(dst, self.id.get())?;
+
+				#[cfg(feature = "v5")]
+				if len > 2 {
+					crate::serde::put_u8(dst, self.reason_code)?;
+				}
+				#[cfg(feature = "v5")]
+				if !self.properties.is_empty() {
+					self.properties.serialize_to_bytes(dst)?;
+				}
+				Ok(())
+			}
+		}
+
+		impl<'a> From<$name> for Packet<'a> {
+			#[inline]
+			fn from(value: $name) -> Packet<'a> {
+				$variant(value)
+			}
+		}
+	};
+}
+use ack_packet;
+
 macro_rules! nul_packet {
 	($name:tt,$variant:expr,$header:literal) => {
-		#[derive(Debug)]
+		#[derive(Clone, Debug)]
 		pub struct $name;
 
 		impl $name {
-			pub fn parse(payload: &[u8]) -> Result<Self, ParseError> {
+			pub fn parse(payload: &[u8]) -> Result<Self, DeserializeError> {
 				if payload.len() != 0 {
-					return Err(ParseError::MalformedPacket("packet must have length 0"));
+					return Err(DeserializeError::MalformedPacket(
+						"packet must have length 0",
+					));
 				}
 				Ok(Self)
 			}
@@ -812,3 +1609,490 @@ macro_rules! nul_packet {
 	};
 }
 use nul_packet;
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		Connect, DeserializeError, Frame, InvalidClientId, InvalidCredentials, InvalidPublish,
+		ProtocolVersion, Publish, SubAck, Subscribe, SubscribeFailed, Unsubscribe,
+	};
+	use crate::{
+		misc::{Credentials, Will},
+		Filter, PacketId, QoS, Topic,
+	};
+	use bytes::{Bytes, BytesMut};
+
+	#[test]
+	fn connect_protocol_version_round_trip() {
+		for version in [
+			ProtocolVersion::V3_1,
+			ProtocolVersion::V3_1_1,
+			ProtocolVersion::V5,
+		] {
+			let connect = Connect {
+				client_id: "client",
+				..Default::default()
+			}
+			.with_protocol_version(version);
+
+			let mut buffer = BytesMut::new();
+			connect.serialize_to_bytes(&mut buffer).unwrap();
+			let frame = Frame::parse(buffer.freeze()).unwrap();
+
+			let parsed = Connect::parse(&frame.payload).unwrap();
+			assert_eq!(parsed.protocol_name, version.name());
+			assert_eq!(parsed.protocol_level, version.level());
+			assert_eq!(parsed.protocol_version(), Some(version));
+			assert_eq!(parsed.client_id, "client");
+		}
+
+		// MQTT v3.1 uses a different protocol name.
+		let mut buffer = BytesMut::new();
+		Connect::default()
+			.with_protocol_version(ProtocolVersion::V3_1)
+			.serialize_to_bytes(&mut buffer)
+			.unwrap();
+		assert_eq!(&buffer[2..11], b"\x00\x06MQIsdp\x03");
+	}
+
+	#[test]
+	fn connect_client_id_length() {
+		let id = "a".repeat(24);
+		for version in [
+			ProtocolVersion::V3_1,
+			ProtocolVersion::V3_1_1,
+			ProtocolVersion::V5,
+		] {
+			let connect = |client_id| {
+				Connect {
+					client_id,
+					..Default::default()
+				}
+				.with_protocol_version(version)
+			};
+
+			assert!(connect("").validate().is_ok());
+			assert!(connect(&id[..23]).validate().is_ok());
+			if version == ProtocolVersion::V5 {
+				assert!(connect(&id).validate().is_ok());
+				assert!(connect("client-1").validate().is_ok());
+			} else {
+				assert!(matches!(
+					connect(&id).validate(),
+					Err(InvalidClientId::TooLong(24))
+				));
+				assert!(matches!(
+					connect("client-1").validate(),
+					Err(InvalidClientId::InvalidCharacter(6, '-'))
+				));
+			}
+		}
+	}
+
+	#[test]
+	fn connect_builder_credentials() {
+		// Returns the connect flags from the serialized packet.
+		let flags = |connect: Connect| {
+			let mut buffer = BytesMut::new();
+			connect.serialize_to_bytes(&mut buffer).unwrap();
+			buffer[9]
+		};
+
+		let connect = Connect::builder().username("user").build().unwrap();
+		let credentials = connect.credentials.as_ref().unwrap();
+		assert_eq!(credentials.username, Some("user"));
+		assert_eq!(credentials.password, None);
+		assert_eq!(flags(connect), 0x82);
+
+		assert!(matches!(
+			Connect::builder().password("pass").build(),
+			Err(InvalidCredentials::PasswordWithoutUsername)
+		));
+		assert!(matches!(
+			Connect::builder()
+				.client_id("client")
+				.password("pass")
+				.build(),
+			Err(InvalidCredentials::PasswordWithoutUsername)
+		));
+
+		// MQTT v5 allows a password without a username.
+		let connect = Connect::builder()
+			.protocol_version(ProtocolVersion::V5)
+			.password("pass")
+			.build()
+			.unwrap();
+		let credentials = connect.credentials.as_ref().unwrap();
+		assert_eq!(credentials.username, None);
+		assert_eq!(credentials.password, Some("pass"));
+		assert_eq!(flags(connect), 0x42);
+
+		let connect = Connect::builder()
+			.username("user")
+			.password("pass")
+			.clean_session(false)
+			.will(Will::new("a/b", "offline", QoS::ExactlyOnce, true).unwrap())
+			.build()
+			.unwrap();
+		assert_eq!(flags(connect), 0xf4);
+	}
+
+	#[test]
+	fn connect_credentials_round_trip() {
+		for (username, password) in [(Some("user"), None), (Some("user"), Some("pass"))] {
+			let connect = Connect {
+				client_id: "client",
+				credentials: Some(Credentials { username, password }),
+				will: Some(Will::new("a/b", "offline", QoS::AtMostOnce, false).unwrap()),
+				..Default::default()
+			};
+
+			let mut buffer = BytesMut::new();
+			connect.serialize_to_bytes(&mut buffer).unwrap();
+			let frame = Frame::parse(buffer.freeze()).unwrap();
+
+			let parsed = Connect::parse(&frame.payload).unwrap();
+			let credentials = parsed.credentials.unwrap();
+			assert_eq!(credentials.username, username);
+			assert_eq!(credentials.password, password);
+			assert_eq!(parsed.will.unwrap().payload, "offline");
+		}
+
+		// Connect flags with the password flag set, but not the username flag.
+		let mut buffer = BytesMut::new();
+		Connect::default().serialize_to_bytes(&mut buffer).unwrap();
+		buffer[9] |= 0x40;
+		let frame = Frame::parse(buffer.freeze()).unwrap();
+		assert!(matches!(
+			Connect::parse(&frame.payload),
+			Err(DeserializeError::MalformedPacket(_))
+		));
+
+		// MQTT v5 allows a password without a username.
+		let mut payload = b"\x00\x04MQTT\x05\x42\x00\x3c".to_vec();
+		#[cfg(feature = "v5")]
+		payload.push(0x00);
+		payload.extend_from_slice(b"\x00\x00\x00\x04pass");
+		let connect = Connect::parse(&payload).unwrap();
+		let credentials = connect.credentials.as_ref().unwrap();
+		assert_eq!(credentials.username, None);
+		assert_eq!(credentials.password, Some("pass"));
+
+		let mut buffer = BytesMut::new();
+		connect.serialize_to_bytes(&mut buffer).unwrap();
+		assert_eq!(&buffer[2..], &payload[..]);
+	}
+
+	#[test]
+	fn connect_will_round_trip() {
+		let connect = Connect {
+			client_id: "client",
+			will: Some(Will::new("a/b", "offline", QoS::AtLeastOnce, true).unwrap()),
+			..Default::default()
+		};
+
+		let mut buffer = BytesMut::new();
+		connect.serialize_to_bytes(&mut buffer).unwrap();
+		let frame = Frame::parse(buffer.freeze()).unwrap();
+
+		let parsed = Connect::parse(&frame.payload).unwrap();
+		let will = parsed.will.unwrap();
+		assert_eq!(will.topic, "a/b");
+		assert_eq!(&will.payload[..], b"offline");
+		assert_eq!(will.qos, QoS::AtLeastOnce);
+		assert!(will.retain);
+	}
+
+	#[test]
+	fn publish_new() {
+		let topic = Topic::from_static("a/b");
+		let id = PacketId::new(7);
+
+		let publish = Publish::new(topic, Bytes::new(), QoS::AtMostOnce, true, None).unwrap();
+		assert!(matches!(publish, Publish::AtMostOnce { retain: true, .. }));
+
+		let publish = Publish::new(topic, Bytes::new(), QoS::AtLeastOnce, false, id).unwrap();
+		assert!(matches!(
+			publish,
+			Publish::AtLeastOnce {
+				duplicate: false,
+				..
+			}
+		));
+		assert_eq!(publish.id(), id);
+
+		let publish = Publish::new(topic, Bytes::new(), QoS::ExactlyOnce, false, id).unwrap();
+		assert_eq!(publish.qos(), QoS::ExactlyOnce);
+		assert_eq!(publish.id(), id);
+	}
+
+	#[test]
+	fn publish_new_requires_id() {
+		let topic = Topic::from_static("a/b");
+		for qos in [QoS::AtLeastOnce, QoS::ExactlyOnce] {
+			assert!(matches!(
+				Publish::new(topic, Bytes::new(), qos, false, None),
+				Err(InvalidPublish::MissingPacketId)
+			));
+		}
+	}
+
+	#[test]
+	fn publish_new_forbids_id() {
+		let topic = Topic::from_static("a/b");
+		assert!(matches!(
+			Publish::new(
+				topic,
+				Bytes::new(),
+				QoS::AtMostOnce,
+				false,
+				PacketId::new(1)
+			),
+			Err(InvalidPublish::UnexpectedPacketId)
+		));
+	}
+
+	#[test]
+	fn iterate_suback_results() {
+		let frame = Frame {
+			header: 0x90,
+			payload: b"\x00\x01\x00\x80\x02"[..].into(),
+		};
+		let suback = SubAck::parse(&frame.payload).unwrap();
+
+		let mut results = Vec::new();
+		for result in &suback {
+			results.push(result);
+		}
+		assert_eq!(
+			results,
+			[
+				Ok(QoS::AtMostOnce),
+				Err(SubscribeFailed),
+				Ok(QoS::ExactlyOnce)
+			]
+		);
+		assert_eq!(suback.results().filter(Result::is_ok).count(), 2);
+	}
+
+	#[test]
+	fn frame_length_mismatch() {
+		// Remaining length claims 4 bytes, but only 2 follow.
+		let packet = Bytes::from_static(&[0x40, 0x04, 0x00, 0x01]);
+		assert!(matches!(
+			Frame::parse(packet),
+			Err(DeserializeError::MalformedLength)
+		));
+
+		let packet = Bytes::from_static(&[0x40, 0x02, 0x00, 0x01]);
+		let frame = Frame::parse(packet).unwrap();
+		assert_eq!(frame.header, 0x40);
+		assert_eq!(&frame.payload[..], &[0x00, 0x01]);
+	}
+
+	#[test]
+	fn split_frames_match_parsed_frames() {
+		use super::{PubAck, Subscribe};
+		use crate::{Filter, Packet};
+
+		let mut stream = BytesMut::new();
+		Connect::default().serialize_to_bytes(&mut stream).unwrap();
+		Publish::new(
+			Topic::from_static("a/b"),
+			vec![0; 200].into(),
+			QoS::AtLeastOnce,
+			true,
+			PacketId::new(1),
+		)
+		.unwrap()
+		.serialize_to_bytes(&mut stream)
+		.unwrap();
+		PubAck::new(PacketId::new(2).unwrap())
+			.serialize_to_bytes(&mut stream)
+			.unwrap();
+		Subscribe {
+			id: PacketId::new(3).unwrap(),
+			filters: vec![(Filter::from_static("a/#"), QoS::AtMostOnce)],
+		}
+		.serialize_to_bytes(&mut stream)
+		.unwrap();
+		let stream = stream.freeze();
+
+		// Split the frames, feeding the buffer one byte at a time.
+		let mut buffer = BytesMut::new();
+		let mut split = Vec::new();
+		for byte in stream.iter() {
+			buffer.extend_from_slice(&[*byte]);
+			if let Some(frame) = Frame::split_from(&mut buffer).unwrap() {
+				split.push(frame);
+			}
+		}
+		assert!(buffer.is_empty());
+
+		let mut remaining = stream.clone();
+		for frame in split {
+			let extent = Frame::check(&mut std::io::Cursor::new(&remaining[..])).unwrap();
+			let parsed = Frame::parse(remaining.split_to(extent)).unwrap();
+			assert_eq!(frame.header, parsed.header);
+			assert_eq!(frame.payload, parsed.payload);
+			assert_eq!(
+				Packet::parse(&frame).unwrap().to_string(),
+				Packet::parse(&parsed).unwrap().to_string()
+			);
+		}
+		assert!(remaining.is_empty());
+
+		// Invalid headers are rejected as soon as they are read.
+		let mut buffer = BytesMut::from(&[0x00][..]);
+		assert!(matches!(
+			Frame::split_from(&mut buffer),
+			Err(DeserializeError::InvalidHeader)
+		));
+	}
+
+	#[test]
+	fn deserialize_error_source() {
+		use std::error::Error;
+
+		// Publish with a topic which is not valid UTF-8.
+		let err = Publish::parse(b"\x00\x02\xff\xfepayload", 0).unwrap_err();
+		assert!(matches!(err, DeserializeError::Utf8Error(_)));
+		assert_eq!(err.to_string(), "invalid UTF-8 string in packet");
+		assert!(err
+			.source()
+			.and_then(|source| source.downcast_ref::<core::str::Utf8Error>())
+			.is_some());
+
+		// Publish with a wildcard in the topic.
+		let err = Publish::parse(b"\x00\x03a/+payload", 0).unwrap_err();
+		assert!(matches!(err, DeserializeError::InvalidTopic(_)));
+		assert!(err
+			.source()
+			.and_then(|source| source.downcast_ref::<crate::InvalidTopic>())
+			.is_some());
+
+		// Subscribe with an invalid multi-level wildcard.
+		let err = super::Subscribe::parse(b"\x00\x01\x00\x03#/a\x00").unwrap_err();
+		assert!(matches!(err, DeserializeError::InvalidFilter(_)));
+		assert!(err
+			.source()
+			.and_then(|source| source.downcast_ref::<crate::InvalidFilter>())
+			.is_some());
+
+		// Errors without an underlying cause have no source.
+		assert!(DeserializeError::ZeroPacketId.source().is_none());
+		assert_eq!(
+			DeserializeError::MalformedPacket("bad flags").to_string(),
+			"malformed packet: bad flags"
+		);
+	}
+
+	#[test]
+	fn subscribe_payload_len() {
+		// Returns the length of the serialized packet after the fixed header.
+		fn serialized_len(serialize: impl FnOnce(&mut BytesMut)) -> usize {
+			let mut buffer = BytesMut::new();
+			serialize(&mut buffer);
+			Frame::parse(buffer.freeze()).unwrap().payload.len()
+		}
+
+		let id = PacketId::new(1).unwrap();
+		let filters = ["a/b", "a/+", "#", "a/very/long/filter/name/#"].map(Filter::from_static);
+
+		// Long enough for a multi-byte remaining length.
+		for count in [0, 1, 4, 100] {
+			let filters: Vec<_> = filters.iter().copied().cycle().take(count).collect();
+
+			let subscribe = Subscribe {
+				id,
+				filters: filters.iter().map(|&f| (f, QoS::AtLeastOnce)).collect(),
+			};
+			let len = serialized_len(|dst| subscribe.serialize_to_bytes(dst).unwrap());
+			assert_eq!(subscribe.payload_len(), len);
+
+			let unsubscribe = Unsubscribe { id, filters };
+			let len = serialized_len(|dst| unsubscribe.serialize_to_bytes(dst).unwrap());
+			assert_eq!(unsubscribe.payload_len(), len);
+
+			let suback = SubAck {
+				id,
+				result: (0..count * 50).map(|_| Err(SubscribeFailed)).collect(),
+			};
+			let len = serialized_len(|dst| suback.serialize_to_bytes(dst).unwrap());
+			assert_eq!(suback.payload_len(), len);
+		}
+	}
+
+	#[test]
+	fn publish_payload_len() {
+		// Clearing a retained message.
+		let clear = Publish::new(
+			Topic::from_static("a/b"),
+			Bytes::new(),
+			QoS::AtLeastOnce,
+			true,
+			PacketId::new(1),
+		)
+		.unwrap();
+
+		let mut buffer = BytesMut::new();
+		clear.serialize_to_bytes(&mut buffer).unwrap();
+		let frame = Frame::parse(buffer.freeze()).unwrap();
+		let parsed = Publish::parse(&frame.payload, frame.header & 0x0f).unwrap();
+		assert!(parsed.retain());
+		assert!(parsed.is_empty_payload());
+		assert_eq!(parsed.payload_len(), 0);
+
+		let publish = Publish::new(
+			Topic::from_static("a/b"),
+			"payload".into(),
+			QoS::AtMostOnce,
+			false,
+			None,
+		)
+		.unwrap();
+		assert!(!publish.is_empty_payload());
+		assert_eq!(publish.payload_len(), 7);
+	}
+
+	#[test]
+	#[cfg(feature = "v5")]
+	fn v5_encodings() {
+		use super::UnsubAck;
+
+		let id = PacketId::new(1).unwrap();
+		let subscribe = Subscribe {
+			id,
+			filters: vec![(Filter::from_static("a/#"), QoS::AtLeastOnce)],
+		};
+		let mut buffer = BytesMut::new();
+		subscribe.serialize_to_bytes_v5(&mut buffer).unwrap();
+		assert_eq!(&buffer[..], b"\x82\x09\x00\x01\x00\x00\x03a/#\x01");
+
+		let unsubscribe = Unsubscribe {
+			id,
+			filters: vec![Filter::from_static("a/#")],
+		};
+		let mut buffer = BytesMut::new();
+		unsubscribe.serialize_to_bytes_v5(&mut buffer).unwrap();
+		assert_eq!(&buffer[..], b"\xa2\x08\x00\x01\x00\x00\x03a/#");
+
+		// Reason codes of 0x80 and above are failures.
+		let suback = SubAck::parse_v5(b"\x00\x01\x00\x01\x87").unwrap();
+		assert_eq!(
+			suback.result,
+			vec![Ok(QoS::AtLeastOnce), Err(SubscribeFailed)]
+		);
+		assert!(SubAck::parse_v5(b"\x00\x01\x00\x03").is_err());
+
+		let unsuback = UnsubAck::parse_v5(b"\x00\x01\x00\x00\x11").unwrap();
+		assert_eq!(unsuback.id, id);
+
+		// A message expiry interval of 60 seconds.
+		let payload = b"\x00\x03a/b\x00\x01\x05\x02\x00\x00\x00\x3chello";
+		let publish = Publish::parse_v5(payload, 0x02).unwrap();
+		assert_eq!(publish.topic(), Topic::from_static("a/b"));
+		assert_eq!(publish.id(), Some(id));
+		assert_eq!(&publish.payload()[..], b"hello");
+	}
+}
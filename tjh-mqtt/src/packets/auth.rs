@@ -0,0 +1,998 @@
+use super::DeserializeError;
+use crate::{packet::Packet, serde, TopicBuf};
+use bytes::{Buf, BufMut, Bytes};
+use std::io;
+
+const AUTH: u8 = 0xf0;
+
+const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+const CONTENT_TYPE: u8 = 0x03;
+const RESPONSE_TOPIC: u8 = 0x08;
+const CORRELATION_DATA: u8 = 0x09;
+const SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+const SERVER_KEEP_ALIVE: u8 = 0x13;
+const AUTHENTICATION_METHOD: u8 = 0x15;
+const AUTHENTICATION_DATA: u8 = 0x16;
+const REASON_STRING: u8 = 0x1f;
+const RESPONSE_INFORMATION: u8 = 0x1a;
+const SERVER_REFERENCE: u8 = 0x1c;
+const RECEIVE_MAXIMUM: u8 = 0x21;
+const TOPIC_ALIAS_MAXIMUM: u8 = 0x22;
+const MAXIMUM_QOS: u8 = 0x24;
+const RETAIN_AVAILABLE: u8 = 0x25;
+const WILL_DELAY_INTERVAL: u8 = 0x18;
+const USER_PROPERTY: u8 = 0x26;
+const MAXIMUM_PACKET_SIZE: u8 = 0x27;
+const WILDCARD_SUBSCRIPTION_AVAILABLE: u8 = 0x28;
+const SUBSCRIPTION_IDENTIFIER_AVAILABLE: u8 = 0x29;
+const SHARED_SUBSCRIPTION_AVAILABLE: u8 = 0x2a;
+
+/// The reason code of an [`Auth`] packet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AuthReasonCode {
+	/// Authentication is successful.
+	#[default]
+	Success = 0x00,
+	/// Continue the authentication with another step.
+	ContinueAuthentication = 0x18,
+	/// Initiate a re-authentication.
+	ReAuthenticate = 0x19,
+}
+
+impl TryFrom<u8> for AuthReasonCode {
+	type Error = DeserializeError;
+
+	fn try_from(value: u8) -> Result<Self, Self::Error> {
+		match value {
+			0x00 => Ok(Self::Success),
+			0x18 => Ok(Self::ContinueAuthentication),
+			0x19 => Ok(Self::ReAuthenticate),
+			_ => Err(DeserializeError::MalformedPacket(
+				"invalid Auth reason code",
+			)),
+		}
+	}
+}
+
+/// The properties of an [`Auth`] packet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuthProperties<'a> {
+	/// The name of the authentication method, e.g. `"SCRAM-SHA-1"`.
+	pub authentication_method: Option<&'a str>,
+
+	/// Data for the authentication method. The contents are defined by the
+	/// method.
+	pub authentication_data: Option<&'a [u8]>,
+
+	/// A human readable reason for the packet, intended for diagnostics.
+	pub reason_string: Option<&'a str>,
+
+	/// Name and value pairs. The same name may appear more than once.
+	pub user_properties: Vec<(&'a str, &'a str)>,
+}
+
+/// The MQTT v5 properties of a [`Connect`] packet.
+///
+/// Only the properties used for enhanced authentication and flow control are
+/// supported.
+///
+/// [`Connect`]: super::Connect
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectProperties<'a> {
+	/// The name of the authentication method, e.g. `"SCRAM-SHA-1"`.
+	pub authentication_method: Option<&'a str>,
+
+	/// The initial data for the authentication method.
+	pub authentication_data: Option<&'a [u8]>,
+
+	/// The number of AtLeastOnce and ExactlyOnce messages the Client will
+	/// process concurrently. If absent, the Server assumes 65,535.
+	pub receive_maximum: Option<u16>,
+}
+
+/// The MQTT v5 properties of a [`ConnAck`] packet.
+///
+/// Only the properties the Client acts upon are kept; the others are parsed
+/// and discarded.
+///
+/// [`ConnAck`]: super::ConnAck
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnAckProperties {
+	/// The client identifier assigned by the Server, when the Client connected
+	/// with an empty client identifier.
+	pub assigned_client_identifier: Option<String>,
+
+	/// The keep alive interval in seconds the Server requires the Client to
+	/// use instead of the one it requested.
+	pub server_keep_alive: Option<u16>,
+}
+
+/// The MQTT v5 properties of a [`Will`] message.
+///
+/// The properties are sent in the Connect packet when the protocol level is 5,
+/// and ignored otherwise.
+///
+/// [`Will`]: crate::misc::Will
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WillProperties {
+	/// The number of seconds the Server waits after the Client disconnects
+	/// before publishing the will message.
+	pub delay_interval: Option<u32>,
+
+	/// The lifetime of the will message in seconds.
+	pub message_expiry_interval: Option<u32>,
+
+	/// A description of the content of the will payload, e.g. a MIME type.
+	pub content_type: Option<String>,
+}
+
+/// The MQTT v5 properties of a [`PubAck`], [`PubRec`], [`PubRel`] or
+/// [`PubComp`] packet.
+///
+/// [`PubAck`]: super::PubAck
+/// [`PubRec`]: super::PubRec
+/// [`PubRel`]: super::PubRel
+/// [`PubComp`]: super::PubComp
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AckProperties {
+	/// A human readable reason for the reason code, intended for diagnostics.
+	pub reason_string: Option<String>,
+
+	/// Name and value pairs. The same name may appear more than once.
+	pub user_properties: Vec<(String, String)>,
+}
+
+/// The MQTT v5 properties of an outgoing [`Publish`] packet.
+///
+/// The properties are sent when the protocol level is 5, and ignored
+/// otherwise.
+///
+/// [`Publish`]: super::Publish
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PublishProperties {
+	/// The lifetime of the message in seconds. The Server does not deliver the
+	/// message to subscribers once it has expired.
+	pub message_expiry_interval: Option<u32>,
+
+	/// A description of the content of the payload, e.g. a MIME type.
+	pub content_type: Option<String>,
+
+	/// The topic for the receiver to publish a response to.
+	pub response_topic: Option<TopicBuf>,
+
+	/// Data sent with the response to identify the request it belongs to.
+	pub correlation_data: Option<Bytes>,
+
+	/// Name and value pairs. The same name may appear more than once.
+	pub user_properties: Vec<(String, String)>,
+}
+
+/// An `Auth` packet is sent by either the Client or the Server as part of an
+/// MQTT v5 extended authentication exchange.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Auth<'a> {
+	pub reason_code: AuthReasonCode,
+	pub properties: AuthProperties<'a>,
+}
+
+impl<'a> AuthProperties<'a> {
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.authentication_method.is_none()
+			&& self.authentication_data.is_none()
+			&& self.reason_string.is_none()
+			&& self.user_properties.is_empty()
+	}
+
+	fn parse(cursor: &mut io::Cursor<&'a [u8]>) -> Result<Self, DeserializeError> {
+		let len = serde::get_var(cursor)?;
+		let mut cursor = io::Cursor::new(serde::get_slice(cursor, len)?);
+
+		let mut properties = Self::default();
+		while cursor.has_remaining() {
+			match serde::get_u8(&mut cursor)? {
+				AUTHENTICATION_METHOD => {
+					let method = serde::get_str(&mut cursor)?;
+					set_once(&mut properties.authentication_method, method)?;
+				}
+				AUTHENTICATION_DATA => {
+					let len = serde::get_u16(&mut cursor)?;
+					let data = serde::get_slice(&mut cursor, len as usize)?;
+					set_once(&mut properties.authentication_data, data)?;
+				}
+				REASON_STRING => {
+					let reason = serde::get_str(&mut cursor)?;
+					set_once(&mut properties.reason_string, reason)?;
+				}
+				USER_PROPERTY => {
+					let name = serde::get_str(&mut cursor)?;
+					let value = serde::get_str(&mut cursor)?;
+					properties.user_properties.push((name, value));
+				}
+				_ => {
+					return Err(DeserializeError::MalformedPacket(
+						"invalid property in Auth packet",
+					))
+				}
+			}
+		}
+
+		Ok(properties)
+	}
+
+	fn serialize_to_bytes(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
+		serde::put_var(dst, self.len())?;
+
+		if let Some(method) = self.authentication_method {
+			serde::put_u8(dst, AUTHENTICATION_METHOD)?;
+			serde::put_str(dst, method)?;
+		}
+
+		if let Some(data) = self.authentication_data {
+			let len = u16::try_from(data.len()).map_err(|_| serde::WriteError)?;
+			serde::put_u8(dst, AUTHENTICATION_DATA)?;
+			serde::put_u16(dst, len)?;
+			serde::put_slice(dst, data)?;
+		}
+
+		if let Some(reason) = self.reason_string {
+			serde::put_u8(dst, REASON_STRING)?;
+			serde::put_str(dst, reason)?;
+		}
+
+		for (name, value) in &self.user_properties {
+			serde::put_u8(dst, USER_PROPERTY)?;
+			serde::put_str(dst, name)?;
+			serde::put_str(dst, value)?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns the length of the encoded properties, excluding the length
+	/// prefix.
+	fn len(&self) -> usize {
+		let mut len = 0;
+		if let Some(method) = self.authentication_method {
+			len += 3 + method.len();
+		}
+		if let Some(data) = self.authentication_data {
+			len += 3 + data.len();
+		}
+		if let Some(reason) = self.reason_string {
+			len += 3 + reason.len();
+		}
+		for (name, value) in &self.user_properties {
+			len += 5 + name.len() + value.len();
+		}
+		len
+	}
+}
+
+impl<'a> ConnectProperties<'a> {
+	pub(super) fn parse(cursor: &mut io::Cursor<&'a [u8]>) -> Result<Self, DeserializeError> {
+		let len = serde::get_var(cursor)?;
+		let mut cursor = io::Cursor::new(serde::get_slice(cursor, len)?);
+
+		let mut properties = Self::default();
+		while cursor.has_remaining() {
+			match serde::get_u8(&mut cursor)? {
+				AUTHENTICATION_METHOD => {
+					let method = serde::get_str(&mut cursor)?;
+					set_once(&mut properties.authentication_method, method)?;
+				}
+				AUTHENTICATION_DATA => {
+					let len = serde::get_u16(&mut cursor)?;
+					let data = serde::get_slice(&mut cursor, len as usize)?;
+					set_once(&mut properties.authentication_data, data)?;
+				}
+				RECEIVE_MAXIMUM => {
+					let maximum = serde::get_u16(&mut cursor)?;
+					if maximum == 0 {
+						return Err(DeserializeError::MalformedPacket(
+							"receive maximum cannot be zero",
+						));
+					}
+					set_once(&mut properties.receive_maximum, maximum)?;
+				}
+				_ => {
+					return Err(DeserializeError::MalformedPacket(
+						"unsupported property in Connect packet",
+					))
+				}
+			}
+		}
+
+		Ok(properties)
+	}
+
+	pub(super) fn serialize_to_bytes(
+		&self,
+		dst: &mut impl BufMut,
+	) -> Result<(), serde::WriteError> {
+		serde::put_var(dst, self.len())?;
+
+		if let Some(method) = self.authentication_method {
+			serde::put_u8(dst, AUTHENTICATION_METHOD)?;
+			serde::put_str(dst, method)?;
+		}
+
+		if let Some(data) = self.authentication_data {
+			let len = u16::try_from(data.len()).map_err(|_| serde::WriteError)?;
+			serde::put_u8(dst, AUTHENTICATION_DATA)?;
+			serde::put_u16(dst, len)?;
+			serde::put_slice(dst, data)?;
+		}
+
+		if let Some(maximum) = self.receive_maximum {
+			serde::put_u8(dst, RECEIVE_MAXIMUM)?;
+			serde::put_u16(dst, maximum)?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns the length of the encoded properties, including the length
+	/// prefix.
+	pub(super) fn encoded_len(&self) -> usize {
+		let len = self.len();
+		serde::var_len(len) + len
+	}
+
+	fn len(&self) -> usize {
+		let mut len = 0;
+		if let Some(method) = self.authentication_method {
+			len += 3 + method.len();
+		}
+		if let Some(data) = self.authentication_data {
+			len += 3 + data.len();
+		}
+		if self.receive_maximum.is_some() {
+			len += 3;
+		}
+		len
+	}
+}
+
+impl ConnAckProperties {
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.assigned_client_identifier.is_none() && self.server_keep_alive.is_none()
+	}
+
+	pub(super) fn parse(cursor: &mut io::Cursor<&[u8]>) -> Result<Self, DeserializeError> {
+		let len = serde::get_var(cursor)?;
+		let mut cursor = io::Cursor::new(serde::get_slice(cursor, len)?);
+
+		let mut properties = Self::default();
+		while cursor.has_remaining() {
+			match serde::get_u8(&mut cursor)? {
+				ASSIGNED_CLIENT_IDENTIFIER => {
+					let client_id = serde::get_str(&mut cursor)?;
+					set_once(
+						&mut properties.assigned_client_identifier,
+						client_id.to_owned(),
+					)?;
+				}
+				SERVER_KEEP_ALIVE => {
+					let keep_alive = serde::get_u16(&mut cursor)?;
+					set_once(&mut properties.server_keep_alive, keep_alive)?;
+				}
+				MAXIMUM_QOS
+				| RETAIN_AVAILABLE
+				| WILDCARD_SUBSCRIPTION_AVAILABLE
+				| SUBSCRIPTION_IDENTIFIER_AVAILABLE
+				| SHARED_SUBSCRIPTION_AVAILABLE => {
+					serde::get_u8(&mut cursor)?;
+				}
+				RECEIVE_MAXIMUM | TOPIC_ALIAS_MAXIMUM => {
+					serde::get_u16(&mut cursor)?;
+				}
+				SESSION_EXPIRY_INTERVAL | MAXIMUM_PACKET_SIZE => {
+					serde::get_slice(&mut cursor, 4)?;
+				}
+				REASON_STRING | RESPONSE_INFORMATION | SERVER_REFERENCE | AUTHENTICATION_METHOD => {
+					serde::get_str(&mut cursor)?;
+				}
+				AUTHENTICATION_DATA => {
+					let len = serde::get_u16(&mut cursor)?;
+					serde::get_slice(&mut cursor, len as usize)?;
+				}
+				USER_PROPERTY => {
+					serde::get_str(&mut cursor)?;
+					serde::get_str(&mut cursor)?;
+				}
+				_ => {
+					return Err(DeserializeError::MalformedPacket(
+						"invalid property in ConnAck packet",
+					))
+				}
+			}
+		}
+
+		Ok(properties)
+	}
+
+	pub(super) fn serialize_to_bytes(
+		&self,
+		dst: &mut impl BufMut,
+	) -> Result<(), serde::WriteError> {
+		serde::put_var(dst, self.len())?;
+
+		if let Some(client_id) = &self.assigned_client_identifier {
+			serde::put_u8(dst, ASSIGNED_CLIENT_IDENTIFIER)?;
+			serde::put_str(dst, client_id)?;
+		}
+
+		if let Some(keep_alive) = self.server_keep_alive {
+			serde::put_u8(dst, SERVER_KEEP_ALIVE)?;
+			serde::put_u16(dst, keep_alive)?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns the length of the encoded properties, including the length
+	/// prefix.
+	pub(super) fn encoded_len(&self) -> usize {
+		let len = self.len();
+		serde::var_len(len) + len
+	}
+
+	fn len(&self) -> usize {
+		let mut len = 0;
+		if let Some(client_id) = &self.assigned_client_identifier {
+			len += 3 + client_id.len();
+		}
+		if self.server_keep_alive.is_some() {
+			len += 3;
+		}
+		len
+	}
+}
+
+impl WillProperties {
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.delay_interval.is_none()
+			&& self.message_expiry_interval.is_none()
+			&& self.content_type.is_none()
+	}
+
+	pub(super) fn parse(cursor: &mut io::Cursor<&[u8]>) -> Result<Self, DeserializeError> {
+		let len = serde::get_var(cursor)?;
+		let mut cursor = io::Cursor::new(serde::get_slice(cursor, len)?);
+
+		let mut properties = Self::default();
+		while cursor.has_remaining() {
+			match serde::get_u8(&mut cursor)? {
+				WILL_DELAY_INTERVAL => {
+					let interval = serde::get_u32(&mut cursor)?;
+					set_once(&mut properties.delay_interval, interval)?;
+				}
+				MESSAGE_EXPIRY_INTERVAL => {
+					let interval = serde::get_u32(&mut cursor)?;
+					set_once(&mut properties.message_expiry_interval, interval)?;
+				}
+				CONTENT_TYPE => {
+					let content_type = serde::get_str(&mut cursor)?;
+					set_once(&mut properties.content_type, content_type.to_owned())?;
+				}
+				PAYLOAD_FORMAT_INDICATOR => {
+					serde::get_u8(&mut cursor)?;
+				}
+				RESPONSE_TOPIC => {
+					serde::get_str(&mut cursor)?;
+				}
+				CORRELATION_DATA => {
+					let len = serde::get_u16(&mut cursor)?;
+					serde::get_slice(&mut cursor, len as usize)?;
+				}
+				USER_PROPERTY => {
+					serde::get_str(&mut cursor)?;
+					serde::get_str(&mut cursor)?;
+				}
+				_ => {
+					return Err(DeserializeError::MalformedPacket(
+						"invalid will property in Connect packet",
+					))
+				}
+			}
+		}
+
+		Ok(properties)
+	}
+
+	pub(super) fn serialize_to_bytes(
+		&self,
+		dst: &mut impl BufMut,
+	) -> Result<(), serde::WriteError> {
+		serde::put_var(dst, self.len())?;
+
+		if let Some(interval) = self.delay_interval {
+			serde::put_u8(dst, WILL_DELAY_INTERVAL)?;
+			serde::put_u32(dst, interval)?;
+		}
+
+		if let Some(interval) = self.message_expiry_interval {
+			serde::put_u8(dst, MESSAGE_EXPIRY_INTERVAL)?;
+			serde::put_u32(dst, interval)?;
+		}
+
+		if let Some(content_type) = &self.content_type {
+			serde::put_u8(dst, CONTENT_TYPE)?;
+			serde::put_str(dst, content_type)?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns the length of the encoded properties, including the length
+	/// prefix.
+	pub(super) fn encoded_len(&self) -> usize {
+		let len = self.len();
+		serde::var_len(len) + len
+	}
+
+	fn len(&self) -> usize {
+		let mut len = 0;
+		if self.delay_interval.is_some() {
+			len += 5;
+		}
+		if self.message_expiry_interval.is_some() {
+			len += 5;
+		}
+		if let Some(content_type) = &self.content_type {
+			len += 3 + content_type.len();
+		}
+		len
+	}
+}
+
+impl AckProperties {
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.reason_string.is_none() && self.user_properties.is_empty()
+	}
+
+	pub(super) fn parse(cursor: &mut io::Cursor<&[u8]>) -> Result<Self, DeserializeError> {
+		let len = serde::get_var(cursor)?;
+		let mut cursor = io::Cursor::new(serde::get_slice(cursor, len)?);
+
+		let mut properties = Self::default();
+		while cursor.has_remaining() {
+			match serde::get_u8(&mut cursor)? {
+				REASON_STRING => {
+					let reason = serde::get_str(&mut cursor)?;
+					set_once(&mut properties.reason_string, reason.to_owned())?;
+				}
+				USER_PROPERTY => {
+					let name = serde::get_str(&mut cursor)?;
+					let value = serde::get_str(&mut cursor)?;
+					properties
+						.user_properties
+						.push((name.to_owned(), value.to_owned()));
+				}
+				_ => {
+					return Err(DeserializeError::MalformedPacket(
+						"invalid property in acknowledgement packet",
+					))
+				}
+			}
+		}
+
+		Ok(properties)
+	}
+
+	pub(super) fn serialize_to_bytes(
+		&self,
+		dst: &mut impl BufMut,
+	) -> Result<(), serde::WriteError> {
+		serde::put_var(dst, self.len())?;
+
+		if let Some(reason) = &self.reason_string {
+			serde::put_u8(dst, REASON_STRING)?;
+			serde::put_str(dst, reason)?;
+		}
+
+		for (name, value) in &self.user_properties {
+			serde::put_u8(dst, USER_PROPERTY)?;
+			serde::put_str(dst, name)?;
+			serde::put_str(dst, value)?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns the length of the encoded properties, including the length
+	/// prefix.
+	pub(super) fn encoded_len(&self) -> usize {
+		let len = self.len();
+		serde::var_len(len) + len
+	}
+
+	fn len(&self) -> usize {
+		let mut len = 0;
+		if let Some(reason) = &self.reason_string {
+			len += 3 + reason.len();
+		}
+		for (name, value) in &self.user_properties {
+			len += 5 + name.len() + value.len();
+		}
+		len
+	}
+}
+
+impl PublishProperties {
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.message_expiry_interval.is_none()
+			&& self.content_type.is_none()
+			&& self.response_topic.is_none()
+			&& self.correlation_data.is_none()
+			&& self.user_properties.is_empty()
+	}
+
+	#[cfg(feature = "tokio-client")]
+	pub(crate) fn serialize_to_bytes(
+		&self,
+		dst: &mut impl BufMut,
+	) -> Result<(), serde::WriteError> {
+		serde::put_var(dst, self.len())?;
+
+		if let Some(interval) = self.message_expiry_interval {
+			serde::put_u8(dst, MESSAGE_EXPIRY_INTERVAL)?;
+			serde::put_u32(dst, interval)?;
+		}
+
+		if let Some(content_type) = &self.content_type {
+			serde::put_u8(dst, CONTENT_TYPE)?;
+			serde::put_str(dst, content_type)?;
+		}
+
+		if let Some(topic) = &self.response_topic {
+			serde::put_u8(dst, RESPONSE_TOPIC)?;
+			serde::put_str(dst, topic.as_str())?;
+		}
+
+		if let Some(data) = &self.correlation_data {
+			let len = u16::try_from(data.len()).map_err(|_| serde::WriteError)?;
+			serde::put_u8(dst, CORRELATION_DATA)?;
+			serde::put_u16(dst, len)?;
+			serde::put_slice(dst, data)?;
+		}
+
+		for (name, value) in &self.user_properties {
+			serde::put_u8(dst, USER_PROPERTY)?;
+			serde::put_str(dst, name)?;
+			serde::put_str(dst, value)?;
+		}
+
+		Ok(())
+	}
+
+	#[cfg(feature = "tokio-client")]
+	/// Returns the length of the encoded properties, including the length
+	/// prefix.
+	pub(crate) fn encoded_len(&self) -> usize {
+		let len = self.len();
+		serde::var_len(len) + len
+	}
+
+	#[cfg(feature = "tokio-client")]
+	fn len(&self) -> usize {
+		let mut len = 0;
+		if self.message_expiry_interval.is_some() {
+			len += 5;
+		}
+		if let Some(content_type) = &self.content_type {
+			len += 3 + content_type.len();
+		}
+		if let Some(topic) = &self.response_topic {
+			len += 3 + topic.len();
+		}
+		if let Some(data) = &self.correlation_data {
+			len += 3 + data.len();
+		}
+		for (name, value) in &self.user_properties {
+			len += 5 + name.len() + value.len();
+		}
+		len
+	}
+}
+
+/// Sets a property which must not appear more than once.
+fn set_once<T>(property: &mut Option<T>, value: T) -> Result<(), DeserializeError> {
+	if property.replace(value).is_some() {
+		return Err(DeserializeError::MalformedPacket(
+			"duplicate property in Auth packet",
+		));
+	}
+	Ok(())
+}
+
+impl<'a> Auth<'a> {
+	/// Parses the payload of an Auth packet.
+	///
+	/// An empty payload is a successful Auth packet with no properties.
+	pub fn parse(payload: &'a [u8]) -> Result<Self, DeserializeError> {
+		let mut cursor = io::Cursor::new(payload);
+		if !cursor.has_remaining() {
+			return Ok(Self::default());
+		}
+
+		let reason_code = serde::get_u8(&mut cursor)?.try_into()?;
+		let properties = if cursor.has_remaining() {
+			AuthProperties::parse(&mut cursor)?
+		} else {
+			AuthProperties::default()
+		};
+
+		if cursor.has_remaining() {
+			return Err(DeserializeError::MalformedPacket(
+				"trailing bytes after Auth properties",
+			));
+		}
+
+		Ok(Self {
+			reason_code,
+			properties,
+		})
+	}
+
+	pub fn serialize_to_bytes(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
+		serde::put_u8(dst, AUTH)?;
+
+		// The reason code and properties may be omitted entirely for a successful
+		// Auth packet with no properties.
+		if self.reason_code == AuthReasonCode::Success && self.properties.is_empty() {
+			return serde::put_var(dst, 0);
+		}
+
+		let properties_len = self.properties.len();
+		serde::put_var(dst, 1 + serde::var_len(properties_len) + properties_len)?;
+		serde::put_u8(dst, self.reason_code as u8)?;
+		self.properties.serialize_to_bytes(dst)
+	}
+}
+
+impl<'a> From<Auth<'a>> for Packet<'a> {
+	#[inline]
+	fn from(value: Auth<'a>) -> Self {
+		Self::Auth(value.into())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		AckProperties, Auth, AuthProperties, AuthReasonCode, ConnAckProperties, ConnectProperties,
+		WillProperties,
+	};
+	use crate::{
+		misc::Will,
+		packets::{ConnAck, Connect, DeserializeError, Frame, ProtocolVersion, PubAck},
+		Packet, PacketId, PacketType, QoS,
+	};
+	use bytes::BytesMut;
+	use std::io;
+
+	fn round_trip(auth: &Auth) -> Frame {
+		let mut buffer = BytesMut::new();
+		auth.serialize_to_bytes(&mut buffer).unwrap();
+
+		let mut cursor = io::Cursor::new(&buffer[..]);
+		let len = Frame::check(&mut cursor).unwrap();
+		assert_eq!(len, buffer.len());
+		Frame::parse(buffer.freeze()).unwrap()
+	}
+
+	#[test]
+	fn connect_properties_round_trip() {
+		let connect = Connect {
+			client_id: "client",
+			properties: ConnectProperties {
+				authentication_method: Some("SCRAM-SHA-1"),
+				authentication_data: Some(b"client-first-message"),
+				receive_maximum: Some(10),
+			},
+			will: Some(Will::new("a/b", "offline", QoS::AtMostOnce, false).unwrap()),
+			..Default::default()
+		}
+		.with_protocol_version(ProtocolVersion::V5);
+
+		let mut buffer = BytesMut::new();
+		connect.serialize_to_bytes(&mut buffer).unwrap();
+		let frame = Frame::parse(buffer.freeze()).unwrap();
+
+		let parsed = Connect::parse(&frame.payload).unwrap();
+		assert_eq!(parsed.properties, connect.properties);
+		assert_eq!(parsed.client_id, "client");
+		assert_eq!(parsed.will.unwrap().topic, "a/b");
+
+		// Properties are only sent for MQTT v5.
+		let connect = connect.with_protocol_version(ProtocolVersion::V3_1_1);
+		let mut buffer = BytesMut::new();
+		connect.serialize_to_bytes(&mut buffer).unwrap();
+		let frame = Frame::parse(buffer.freeze()).unwrap();
+		let parsed = Connect::parse(&frame.payload).unwrap();
+		assert_eq!(parsed.properties, ConnectProperties::default());
+	}
+
+	#[test]
+	fn will_properties_round_trip() {
+		let mut will = Will::new("status/client", "offline", QoS::AtLeastOnce, true).unwrap();
+		will.properties = WillProperties {
+			delay_interval: Some(30),
+			message_expiry_interval: Some(3600),
+			content_type: Some("text/plain".into()),
+		};
+		let connect = Connect {
+			client_id: "client",
+			will: Some(will),
+			..Default::default()
+		}
+		.with_protocol_version(ProtocolVersion::V5);
+
+		let mut buffer = BytesMut::new();
+		connect.serialize_to_bytes(&mut buffer).unwrap();
+		let frame = Frame::parse(buffer.freeze()).unwrap();
+
+		let parsed = Connect::parse(&frame.payload).unwrap().will.unwrap();
+		assert_eq!(parsed.topic, "status/client");
+		assert_eq!(parsed.payload, "offline");
+		assert_eq!(parsed.properties, connect.will.as_ref().unwrap().properties);
+
+		// Will properties are only sent for MQTT v5.
+		let connect = connect.with_protocol_version(ProtocolVersion::V3_1_1);
+		let mut buffer = BytesMut::new();
+		connect.serialize_to_bytes(&mut buffer).unwrap();
+		let frame = Frame::parse(buffer.freeze()).unwrap();
+		let parsed = Connect::parse(&frame.payload).unwrap().will.unwrap();
+		assert_eq!(parsed.properties, WillProperties::default());
+		assert_eq!(parsed.payload, "offline");
+	}
+
+	#[test]
+	fn connack_properties() {
+		let connack = ConnAck {
+			session_present: true,
+			code: 0,
+			properties: ConnAckProperties {
+				assigned_client_identifier: Some("auto-1234".into()),
+				server_keep_alive: Some(30),
+			},
+		};
+
+		let mut buffer = BytesMut::new();
+		connack.serialize_to_bytes(&mut buffer).unwrap();
+		let frame = Frame::parse(buffer.freeze()).unwrap();
+		let parsed = ConnAck::parse(&frame.payload).unwrap();
+		assert!(parsed.session_present);
+		assert_eq!(parsed.properties, connack.properties);
+
+		// Properties the Client does not act upon are skipped.
+		let payload = b"\x00\x00\x0d\x21\x00\x0a\x1f\x00\x02ok\x13\x00\x3c\x24\x01";
+		let parsed = ConnAck::parse(payload).unwrap();
+		assert_eq!(parsed.properties.server_keep_alive, Some(60));
+		assert_eq!(parsed.properties.assigned_client_identifier, None);
+
+		// Empty properties are not written, so MQTT v3.1.1 ConnAck packets are
+		// unchanged.
+		let mut buffer = BytesMut::new();
+		ConnAck::default().serialize_to_bytes(&mut buffer).unwrap();
+		assert_eq!(&buffer[..], b"\x20\x02\x00\x00");
+	}
+
+	#[test]
+	fn puback_reason_round_trip() {
+		let puback = PubAck {
+			reason_code: 0x10,
+			properties: AckProperties {
+				reason_string: Some("No matching subscribers".into()),
+				user_properties: vec![("a".into(), "1".into())],
+			},
+			..PubAck::new(PacketId::new(7).unwrap())
+		};
+
+		let mut buffer = BytesMut::new();
+		puback.serialize_to_bytes(&mut buffer).unwrap();
+		let frame = Frame::parse(buffer.freeze()).unwrap();
+		assert_eq!(frame.header, 0x40);
+
+		let Packet::PubAck(parsed) = Packet::parse(&frame).unwrap() else {
+			panic!("expected a PubAck packet");
+		};
+		assert_eq!(parsed.id.get(), 7);
+		assert_eq!(parsed.reason_code, 0x10);
+		assert!(!parsed.is_failure());
+		assert_eq!(parsed.properties, puback.properties);
+
+		// The properties length may be omitted.
+		let parsed = PubAck::parse(&[0x00, 0x07, 0x87]).unwrap();
+		assert_eq!(parsed.reason_code, 0x87);
+		assert!(parsed.is_failure());
+		assert!(parsed.properties.is_empty());
+
+		// A successful acknowledgement is written as in MQTT v3.1.1.
+		let mut buffer = BytesMut::new();
+		PubAck::new(PacketId::new(7).unwrap())
+			.serialize_to_bytes(&mut buffer)
+			.unwrap();
+		assert_eq!(&buffer[..], b"\x40\x02\x00\x07");
+	}
+
+	#[test]
+	fn auth_round_trip() {
+		let auth = Auth {
+			reason_code: AuthReasonCode::ContinueAuthentication,
+			properties: AuthProperties {
+				authentication_method: Some("SCRAM-SHA-1"),
+				authentication_data: Some(b"client-first-message"),
+				reason_string: Some("more data required"),
+				user_properties: vec![("a", "1"), ("a", "2")],
+			},
+		};
+
+		let frame = round_trip(&auth);
+		assert_eq!(frame.header, 0xf0);
+
+		let packet = Packet::parse(&frame).unwrap();
+		assert_eq!(packet.packet_type(), PacketType::Auth);
+		let Packet::Auth(parsed) = packet else {
+			unreachable!();
+		};
+		assert_eq!(*parsed, auth);
+	}
+
+	#[test]
+	fn auth_reason_code_only() {
+		let auth = Auth {
+			reason_code: AuthReasonCode::ReAuthenticate,
+			..Default::default()
+		};
+
+		let frame = round_trip(&auth);
+		assert_eq!(&frame.payload[..], &[0x19, 0x00]);
+		assert_eq!(Auth::parse(&frame.payload).unwrap(), auth);
+
+		// The properties length may be omitted as well.
+		assert_eq!(Auth::parse(&[0x19]).unwrap(), auth);
+	}
+
+	#[test]
+	fn auth_success_is_empty() {
+		let frame = round_trip(&Auth::default());
+		assert!(frame.payload.is_empty());
+		assert_eq!(Auth::parse(&frame.payload).unwrap(), Auth::default());
+	}
+
+	#[test]
+	fn auth_invalid() {
+		// Unknown reason code.
+		assert!(matches!(
+			Auth::parse(&[0x01, 0x00]),
+			Err(DeserializeError::MalformedPacket(_))
+		));
+
+		// Duplicate authentication method.
+		let payload = [0x18, 0x08, 0x15, 0x00, 0x01, b'a', 0x15, 0x00, 0x01, b'b'];
+		assert!(matches!(
+			Auth::parse(&payload),
+			Err(DeserializeError::MalformedPacket(_))
+		));
+
+		// Reserved flags.
+		let frame = Frame {
+			header: 0xf1,
+			payload: Default::default(),
+		};
+		assert!(matches!(
+			Packet::parse(&frame),
+			Err(DeserializeError::InvalidHeader)
+		));
+	}
+}
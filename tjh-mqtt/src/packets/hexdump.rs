@@ -0,0 +1,118 @@
+use super::Frame;
+use crate::serde;
+use std::fmt::Write;
+
+/// The number of bytes shown on each line of a hex dump.
+const BYTES_PER_LINE: usize = 16;
+
+impl Frame {
+	/// Returns an annotated hex and ASCII dump of the frame.
+	///
+	/// The first line decodes the fixed header; the payload is not parsed, so
+	/// this works for any frame, including malformed ones.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::packets::Frame;
+	/// let frame = Frame {
+	/// 	header: 0xe0,
+	/// 	payload: Default::default(),
+	/// };
+	/// let dump = frame.hexdump();
+	/// assert!(dump.starts_with("Disconnect (0xe0), flags: 0b0000, remaining length: 0\n"));
+	/// assert!(dump.ends_with("|..|\n"));
+	/// ```
+	pub fn hexdump(&self) -> String {
+		let (packet_type, flags) = (self.header & 0xf0, self.header & 0x0f);
+		let mut dump = format!(
+			"{} ({packet_type:#04x}), flags: {flags:#06b}, remaining length: {}\n",
+			packet_type_label(packet_type),
+			self.payload.len()
+		);
+
+		// Reconstruct the bytes of the fixed header.
+		let mut bytes = vec![self.header];
+		if serde::put_var(&mut bytes, self.payload.len()).is_err() {
+			bytes.push(0xff);
+		}
+		bytes.extend_from_slice(&self.payload);
+
+		for (line, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+			let _ = write!(dump, "{:08x} ", line * BYTES_PER_LINE);
+			for index in 0..BYTES_PER_LINE {
+				if index % 8 == 0 {
+					dump.push(' ');
+				}
+				match chunk.get(index) {
+					Some(byte) => {
+						let _ = write!(dump, "{byte:02x} ");
+					}
+					None => dump.push_str("   "),
+				}
+			}
+
+			dump.push_str(" |");
+			dump.extend(chunk.iter().map(|&byte| {
+				if byte.is_ascii_graphic() || byte == b' ' {
+					char::from(byte)
+				} else {
+					'.'
+				}
+			}));
+			dump.push_str("|\n");
+		}
+
+		dump
+	}
+}
+
+fn packet_type_label(packet_type: u8) -> &'static str {
+	match packet_type {
+		0x10 => "Connect",
+		0x20 => "ConnAck",
+		0x30 => "Publish",
+		0x40 => "PubAck",
+		0x50 => "PubRec",
+		0x60 => "PubRel",
+		0x70 => "PubComp",
+		0x80 => "Subscribe",
+		0x90 => "SubAck",
+		0xa0 => "Unsubscribe",
+		0xb0 => "UnsubAck",
+		0xc0 => "PingReq",
+		0xd0 => "PingResp",
+		0xe0 => "Disconnect",
+		#[cfg(feature = "v5")]
+		0xf0 => "Auth",
+		_ => "Reserved",
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::packets::Frame;
+
+	#[test]
+	fn hexdump_publish() {
+		let frame = Frame {
+			header: 0x32,
+			payload: b"\x00\x03a/b\x00\x01hello, world!"[..].into(),
+		};
+
+		let dump = frame.hexdump();
+		let mut lines = dump.lines();
+		assert_eq!(
+			lines.next(),
+			Some("Publish (0x30), flags: 0b0010, remaining length: 20")
+		);
+		assert_eq!(
+			lines.next(),
+			Some("00000000  32 14 00 03 61 2f 62 00  01 68 65 6c 6c 6f 2c 20  |2...a/b..hello, |")
+		);
+		assert_eq!(
+			lines.next(),
+			Some("00000010  77 6f 72 6c 64 21                                 |world!|")
+		);
+		assert_eq!(lines.next(), None);
+	}
+}
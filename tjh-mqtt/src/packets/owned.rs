@@ -0,0 +1,552 @@
+#[cfg(feature = "v5")]
+use super::{Auth, AuthProperties, AuthReasonCode, ConnectProperties, WillProperties};
+use super::{
+	ConnAck, Connect, PubAck, PubComp, PubRec, PubRel, Publish, SubAck, Subscribe, UnsubAck,
+	Unsubscribe,
+};
+use crate::{
+	misc::{Credentials, Will},
+	serde, FilterBuf, Packet, PacketId, PacketType, QoS, TopicBuf,
+};
+use bytes::{BufMut, Bytes};
+
+/// An owned [`Packet`].
+///
+/// Unlike a [`Packet`], this does not borrow from the [`Frame`] it was parsed
+/// from, so it can be stored. Use [`Packet::into_owned`] to create one, and
+/// [`OwnedPacket::as_packet`] to borrow it as a [`Packet`] again.
+///
+/// [`Frame`]: super::Frame
+#[derive(Clone, Debug)]
+pub enum OwnedPacket {
+	Connect(Box<OwnedConnect>),
+	ConnAck(ConnAck),
+	Publish(Box<OwnedPublish>),
+	PubAck(PubAck),
+	PubRec(PubRec),
+	PubRel(PubRel),
+	PubComp(PubComp),
+	Subscribe(Box<OwnedSubscribe>),
+	SubAck(Box<SubAck>),
+	Unsubscribe(Box<OwnedUnsubscribe>),
+	UnsubAck(UnsubAck),
+	PingReq,
+	PingResp,
+	Disconnect,
+	#[cfg(feature = "v5")]
+	Auth(Box<OwnedAuth>),
+}
+
+/// An owned [`Connect`] packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedConnect {
+	pub protocol_name: String,
+	pub protocol_level: u8,
+	pub client_id: String,
+	pub keep_alive: u16,
+	pub clean_session: bool,
+	pub will: Option<OwnedWill>,
+	pub credentials: Option<OwnedCredentials>,
+	#[cfg(feature = "v5")]
+	pub authentication_method: Option<String>,
+	#[cfg(feature = "v5")]
+	pub authentication_data: Option<Bytes>,
+	#[cfg(feature = "v5")]
+	pub receive_maximum: Option<u16>,
+}
+
+/// An owned [`Will`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedWill {
+	pub topic: TopicBuf,
+	pub payload: Bytes,
+	pub qos: QoS,
+	pub retain: bool,
+	#[cfg(feature = "v5")]
+	pub properties: WillProperties,
+}
+
+/// Owned [`Credentials`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedCredentials {
+	pub username: Option<String>,
+	pub password: Option<String>,
+}
+
+/// An owned [`Publish`] packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OwnedPublish {
+	AtMostOnce {
+		retain: bool,
+		topic: TopicBuf,
+		payload: Bytes,
+	},
+	AtLeastOnce {
+		id: PacketId,
+		retain: bool,
+		duplicate: bool,
+		topic: TopicBuf,
+		payload: Bytes,
+	},
+	ExactlyOnce {
+		id: PacketId,
+		retain: bool,
+		duplicate: bool,
+		topic: TopicBuf,
+		payload: Bytes,
+	},
+}
+
+/// An owned [`Subscribe`] packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedSubscribe {
+	pub id: PacketId,
+	pub filters: Vec<(FilterBuf, QoS)>,
+}
+
+/// An owned [`Unsubscribe`] packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedUnsubscribe {
+	pub id: PacketId,
+	pub filters: Vec<FilterBuf>,
+}
+
+/// An owned [`Auth`] packet.
+#[cfg(feature = "v5")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedAuth {
+	pub reason_code: AuthReasonCode,
+	pub authentication_method: Option<String>,
+	pub authentication_data: Option<Bytes>,
+	pub reason_string: Option<String>,
+	pub user_properties: Vec<(String, String)>,
+}
+
+impl<'a> Packet<'a> {
+	/// Converts the packet into an [`OwnedPacket`], copying any borrowed
+	/// fields.
+	pub fn into_owned(self) -> OwnedPacket {
+		match self {
+			Self::Connect(connect) => OwnedPacket::Connect(connect.into_owned().into()),
+			Self::ConnAck(connack) => OwnedPacket::ConnAck(connack),
+			Self::Publish(publish) => OwnedPacket::Publish(publish.into_owned().into()),
+			Self::PubAck(puback) => OwnedPacket::PubAck(puback),
+			Self::PubRec(pubrec) => OwnedPacket::PubRec(pubrec),
+			Self::PubRel(pubrel) => OwnedPacket::PubRel(pubrel),
+			Self::PubComp(pubcomp) => OwnedPacket::PubComp(pubcomp),
+			Self::Subscribe(subscribe) => OwnedPacket::Subscribe(subscribe.into_owned().into()),
+			Self::SubAck(suback) => OwnedPacket::SubAck(suback),
+			Self::Unsubscribe(unsubscribe) => {
+				OwnedPacket::Unsubscribe(unsubscribe.into_owned().into())
+			}
+			Self::UnsubAck(unsuback) => OwnedPacket::UnsubAck(unsuback),
+			Self::PingReq => OwnedPacket::PingReq,
+			Self::PingResp => OwnedPacket::PingResp,
+			Self::Disconnect => OwnedPacket::Disconnect,
+			#[cfg(feature = "v5")]
+			Self::Auth(auth) => OwnedPacket::Auth(auth.into_owned().into()),
+		}
+	}
+}
+
+impl OwnedPacket {
+	/// Borrows the packet as a [`Packet`].
+	pub fn as_packet(&self) -> Packet<'_> {
+		match self {
+			Self::Connect(connect) => connect.as_connect().into(),
+			Self::ConnAck(connack) => Packet::ConnAck(connack.clone()),
+			Self::Publish(publish) => publish.as_publish().into(),
+			Self::PubAck(puback) => Packet::PubAck(puback.clone()),
+			Self::PubRec(pubrec) => Packet::PubRec(pubrec.clone()),
+			Self::PubRel(pubrel) => Packet::PubRel(pubrel.clone()),
+			Self::PubComp(pubcomp) => Packet::PubComp(pubcomp.clone()),
+			Self::Subscribe(subscribe) => subscribe.as_subscribe().into(),
+			Self::SubAck(suback) => Packet::SubAck(suback.clone()),
+			Self::Unsubscribe(unsubscribe) => unsubscribe.as_unsubscribe().into(),
+			Self::UnsubAck(unsuback) => Packet::UnsubAck(unsuback.clone()),
+			Self::PingReq => Packet::PingReq,
+			Self::PingResp => Packet::PingResp,
+			Self::Disconnect => Packet::Disconnect,
+			#[cfg(feature = "v5")]
+			Self::Auth(auth) => auth.as_auth().into(),
+		}
+	}
+
+	#[inline]
+	pub fn packet_type(&self) -> PacketType {
+		match self {
+			Self::Connect(_) => PacketType::Connect,
+			Self::ConnAck(_) => PacketType::ConnAck,
+			Self::Publish(_) => PacketType::Publish,
+			Self::PubAck(_) => PacketType::PubAck,
+			Self::PubRec(_) => PacketType::PubRec,
+			Self::PubRel(_) => PacketType::PubRel,
+			Self::PubComp(_) => PacketType::PubComp,
+			Self::Subscribe(_) => PacketType::Subscribe,
+			Self::SubAck(_) => PacketType::SubAck,
+			Self::Unsubscribe(_) => PacketType::Unsubscribe,
+			Self::UnsubAck(_) => PacketType::UnsubAck,
+			Self::PingReq => PacketType::PingReq,
+			Self::PingResp => PacketType::PingResp,
+			Self::Disconnect => PacketType::Disconnect,
+			#[cfg(feature = "v5")]
+			Self::Auth(_) => PacketType::Auth,
+		}
+	}
+
+	pub fn serialize_to_bytes(&self, dst: &mut impl BufMut) -> Result<(), serde::WriteError> {
+		self.as_packet().serialize_to_bytes(dst)
+	}
+}
+
+impl<'a> From<Packet<'a>> for OwnedPacket {
+	#[inline]
+	fn from(value: Packet<'a>) -> Self {
+		value.into_owned()
+	}
+}
+
+impl<'a> Connect<'a> {
+	/// Converts the packet into an [`OwnedConnect`].
+	pub fn into_owned(self) -> OwnedConnect {
+		OwnedConnect {
+			protocol_name: self.protocol_name.into(),
+			protocol_level: self.protocol_level,
+			client_id: self.client_id.into(),
+			keep_alive: self.keep_alive,
+			clean_session: self.clean_session,
+			will: self.will.map(|will| OwnedWill {
+				topic: will.topic.into(),
+				payload: will.payload,
+				qos: will.qos,
+				retain: will.retain,
+				#[cfg(feature = "v5")]
+				properties: will.properties,
+			}),
+			credentials: self.credentials.map(|credentials| OwnedCredentials {
+				username: credentials.username.map(Into::into),
+				password: credentials.password.map(Into::into),
+			}),
+			#[cfg(feature = "v5")]
+			authentication_method: self.properties.authentication_method.map(Into::into),
+			#[cfg(feature = "v5")]
+			authentication_data: self
+				.properties
+				.authentication_data
+				.map(Bytes::copy_from_slice),
+			#[cfg(feature = "v5")]
+			receive_maximum: self.properties.receive_maximum,
+		}
+	}
+}
+
+impl OwnedConnect {
+	/// Borrows the packet as a [`Connect`].
+	pub fn as_connect(&self) -> Connect<'_> {
+		Connect {
+			protocol_name: &self.protocol_name,
+			protocol_level: self.protocol_level,
+			client_id: &self.client_id,
+			keep_alive: self.keep_alive,
+			clean_session: self.clean_session,
+			will: self.will.as_ref().map(|will| Will {
+				topic: &will.topic,
+				payload: will.payload.clone(),
+				qos: will.qos,
+				retain: will.retain,
+				#[cfg(feature = "v5")]
+				properties: will.properties.clone(),
+			}),
+			credentials: self.credentials.as_ref().map(|credentials| Credentials {
+				username: credentials.username.as_deref(),
+				password: credentials.password.as_deref(),
+			}),
+			#[cfg(feature = "v5")]
+			properties: ConnectProperties {
+				authentication_method: self.authentication_method.as_deref(),
+				authentication_data: self.authentication_data.as_deref(),
+				receive_maximum: self.receive_maximum,
+			},
+		}
+	}
+}
+
+impl<'a> Publish<'a> {
+	/// Converts the packet into an [`OwnedPublish`]. The payload is not
+	/// copied.
+	pub fn into_owned(self) -> OwnedPublish {
+		match self {
+			Self::AtMostOnce {
+				retain,
+				topic,
+				payload,
+			} => OwnedPublish::AtMostOnce {
+				retain,
+				topic: topic.into(),
+				payload,
+			},
+			Self::AtLeastOnce {
+				id,
+				retain,
+				duplicate,
+				topic,
+				payload,
+			} => OwnedPublish::AtLeastOnce {
+				id,
+				retain,
+				duplicate,
+				topic: topic.into(),
+				payload,
+			},
+			Self::ExactlyOnce {
+				id,
+				retain,
+				duplicate,
+				topic,
+				payload,
+			} => OwnedPublish::ExactlyOnce {
+				id,
+				retain,
+				duplicate,
+				topic: topic.into(),
+				payload,
+			},
+		}
+	}
+}
+
+impl OwnedPublish {
+	/// Borrows the packet as a [`Publish`].
+	pub fn as_publish(&self) -> Publish<'_> {
+		match self {
+			Self::AtMostOnce {
+				retain,
+				topic,
+				payload,
+			} => Publish::AtMostOnce {
+				retain: *retain,
+				topic,
+				payload: payload.clone(),
+			},
+			Self::AtLeastOnce {
+				id,
+				retain,
+				duplicate,
+				topic,
+				payload,
+			} => Publish::AtLeastOnce {
+				id: *id,
+				retain: *retain,
+				duplicate: *duplicate,
+				topic,
+				payload: payload.clone(),
+			},
+			Self::ExactlyOnce {
+				id,
+				retain,
+				duplicate,
+				topic,
+				payload,
+			} => Publish::ExactlyOnce {
+				id: *id,
+				retain: *retain,
+				duplicate: *duplicate,
+				topic,
+				payload: payload.clone(),
+			},
+		}
+	}
+}
+
+impl<'a> Subscribe<'a> {
+	/// Converts the packet into an [`OwnedSubscribe`].
+	pub fn into_owned(self) -> OwnedSubscribe {
+		OwnedSubscribe {
+			id: self.id,
+			filters: self
+				.filters
+				.into_iter()
+				.map(|(filter, qos)| (filter.into(), qos))
+				.collect(),
+		}
+	}
+}
+
+impl OwnedSubscribe {
+	/// Borrows the packet as a [`Subscribe`].
+	pub fn as_subscribe(&self) -> Subscribe<'_> {
+		Subscribe {
+			id: self.id,
+			filters: self
+				.filters
+				.iter()
+				.map(|(filter, qos)| (filter.as_ref(), *qos))
+				.collect(),
+		}
+	}
+}
+
+impl<'a> Unsubscribe<'a> {
+	/// Converts the packet into an [`OwnedUnsubscribe`].
+	pub fn into_owned(self) -> OwnedUnsubscribe {
+		OwnedUnsubscribe {
+			id: self.id,
+			filters: self.filters.into_iter().map(Into::into).collect(),
+		}
+	}
+}
+
+impl OwnedUnsubscribe {
+	/// Borrows the packet as an [`Unsubscribe`].
+	pub fn as_unsubscribe(&self) -> Unsubscribe<'_> {
+		Unsubscribe {
+			id: self.id,
+			filters: self.filters.iter().map(AsRef::as_ref).collect(),
+		}
+	}
+}
+
+#[cfg(feature = "v5")]
+impl<'a> Auth<'a> {
+	/// Converts the packet into an [`OwnedAuth`].
+	pub fn into_owned(self) -> OwnedAuth {
+		let AuthProperties {
+			authentication_method,
+			authentication_data,
+			reason_string,
+			user_properties,
+		} = self.properties;
+
+		OwnedAuth {
+			reason_code: self.reason_code,
+			authentication_method: authentication_method.map(Into::into),
+			authentication_data: authentication_data.map(Bytes::copy_from_slice),
+			reason_string: reason_string.map(Into::into),
+			user_properties: user_properties
+				.into_iter()
+				.map(|(name, value)| (name.into(), value.into()))
+				.collect(),
+		}
+	}
+}
+
+#[cfg(feature = "v5")]
+impl OwnedAuth {
+	/// Borrows the packet as an [`Auth`].
+	pub fn as_auth(&self) -> Auth<'_> {
+		Auth {
+			reason_code: self.reason_code,
+			properties: AuthProperties {
+				authentication_method: self.authentication_method.as_deref(),
+				authentication_data: self.authentication_data.as_deref(),
+				reason_string: self.reason_string.as_deref(),
+				user_properties: self
+					.user_properties
+					.iter()
+					.map(|(name, value)| (name.as_str(), value.as_str()))
+					.collect(),
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::OwnedPacket;
+	use crate::{
+		misc::{Credentials, Will},
+		packets::{Connect, Frame, Publish, SerializePacket, Subscribe, Unsubscribe},
+		Filter, Packet, PacketId, PacketType, QoS, Topic,
+	};
+	use bytes::{Bytes, BytesMut};
+
+	fn serialize(packet: &impl SerializePacket) -> Bytes {
+		let mut buffer = BytesMut::new();
+		packet.serialize_to_bytes(&mut buffer).unwrap();
+		buffer.freeze()
+	}
+
+	/// Parses `bytes` into an owned packet. The frame is dropped on return.
+	fn parse_owned(bytes: Bytes) -> OwnedPacket {
+		let frame = Frame::parse(bytes).unwrap();
+		Packet::parse(&frame).unwrap().into_owned()
+	}
+
+	#[test]
+	fn owned_packets_outlive_frame() {
+		let packets = [
+			serialize(&Connect {
+				client_id: "client",
+				will: Some(Will::new("a/b", "offline", QoS::AtLeastOnce, true).unwrap()),
+				credentials: Some(Credentials {
+					username: Some("user"),
+					password: Some("pass"),
+				}),
+				..Default::default()
+			}),
+			serialize(
+				&Publish::new(
+					Topic::from_static("a/b"),
+					"hello".into(),
+					QoS::ExactlyOnce,
+					true,
+					PacketId::new(7),
+				)
+				.unwrap(),
+			),
+			serialize(&Subscribe {
+				id: PacketId::new(8).unwrap(),
+				filters: vec![
+					(Filter::new("a/+").unwrap(), QoS::AtMostOnce),
+					(Filter::new("b/#").unwrap(), QoS::AtLeastOnce),
+				],
+			}),
+			serialize(&Unsubscribe {
+				id: PacketId::new(9).unwrap(),
+				filters: vec![Filter::new("a/+").unwrap()],
+			}),
+		];
+
+		let owned: Vec<_> = packets
+			.iter()
+			.map(|bytes| parse_owned(bytes.clone()))
+			.collect();
+
+		assert_eq!(
+			owned
+				.iter()
+				.map(OwnedPacket::packet_type)
+				.collect::<Vec<_>>(),
+			[
+				PacketType::Connect,
+				PacketType::Publish,
+				PacketType::Subscribe,
+				PacketType::Unsubscribe
+			]
+		);
+
+		for (owned, bytes) in owned.iter().zip(packets) {
+			let mut buffer = BytesMut::new();
+			owned.serialize_to_bytes(&mut buffer).unwrap();
+			assert_eq!(buffer, bytes);
+		}
+	}
+
+	#[test]
+	fn owned_publish_shares_payload() {
+		let publish = Publish::new(
+			Topic::from_static("a/b"),
+			"hello".into(),
+			QoS::AtMostOnce,
+			false,
+			None,
+		)
+		.unwrap();
+		let payload = publish.payload().as_ptr();
+
+		let owned = publish.into_owned();
+		let borrowed = owned.as_publish();
+		assert_eq!(borrowed.topic(), "a/b");
+		assert_eq!(borrowed.payload().as_ptr(), payload);
+	}
+}
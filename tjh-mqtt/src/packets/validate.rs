@@ -0,0 +1,350 @@
+use super::{DeserializeError, Frame, ProtocolVersion};
+use crate::packet::Packet;
+
+/// A violation of the MQTT protocol detected by [`validate`].
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolViolation {
+	#[error("reserved packet type {0:#04x}")]
+	ReservedPacketType(u8),
+	#[error("invalid reserved flags {flags:#06b} in fixed header of packet type {header:#04x}")]
+	ReservedHeaderFlags { header: u8, flags: u8 },
+	#[error("Publish packet has invalid QoS of 3")]
+	InvalidPublishQoS,
+	#[error("Publish packet with QoS of AtMostOnce has the duplicate flag set")]
+	DuplicateAtMostOnce,
+	#[error("reserved flag in Connect packet must be 0")]
+	ConnectReservedFlag,
+	#[error("Connect packet has will QoS or retain flags set without the will flag")]
+	WillFlagsWithoutWill,
+	#[error("Connect packet has invalid will QoS of 3")]
+	InvalidWillQoS,
+	#[error("Connect packet has the password flag set without the username flag")]
+	PasswordWithoutUsername,
+	#[error("reserved flags in ConnAck packet must be 0")]
+	ConnAckReservedFlags,
+	#[error("ConnAck packet has session present set with a non-zero return code")]
+	SessionPresentOnFailure,
+	#[error("invalid ConnAck return code {0}")]
+	InvalidReturnCode(u8),
+	#[error("Subscribe and Unsubscribe packets must contain at least one filter")]
+	NoFilters,
+	#[error("malformed packet: {0}")]
+	Malformed(#[source] DeserializeError),
+}
+
+const CONNECT: u8 = 0x10;
+const PUBLISH: u8 = 0x30;
+const PUBREL: u8 = 0x60;
+const SUBSCRIBE: u8 = 0x80;
+const UNSUBSCRIBE: u8 = 0xa0;
+
+const CONNECT_RESERVED_FLAG: u8 = 0x01;
+const CONNECT_WILL_FLAG: u8 = 0x04;
+const CONNECT_WILL_QOS_MASK: u8 = 0x18;
+const CONNECT_WILL_RETAIN_FLAG: u8 = 0x20;
+const CONNECT_PASSWORD_FLAG: u8 = 0x40;
+const CONNECT_USERNAME_FLAG: u8 = 0x80;
+
+/// Validates `frame` against the MQTT v3.1.1 specification.
+///
+/// In addition to the checks performed by [`Packet::parse`], this checks the
+/// reserved bits of the fixed header and the constraints on fields within the
+/// packet that the parser is lenient about.
+///
+/// With the `v5` feature, the MQTT v5 Auth packet type and ConnAck reason
+/// codes are also accepted.
+///
+/// # Example
+/// ```
+/// # use tjh_mqtt::packets::{validate, Frame, ProtocolViolation};
+/// // A Subscribe packet with reserved flags of 0b0000 rather than 0b0010.
+/// let frame = Frame {
+/// 	header: 0x80,
+/// 	payload: vec![0x00, 0x01, 0x00, 0x01, b'a', 0x00].into(),
+/// };
+/// assert!(matches!(
+/// 	validate(&frame),
+/// 	Err(ProtocolViolation::ReservedHeaderFlags { .. })
+/// ));
+/// ```
+pub fn validate(frame: &Frame) -> Result<(), ProtocolViolation> {
+	let header = frame.header;
+	let (packet_type, flags) = (header & 0xf0, header & 0x0f);
+
+	match packet_type {
+		0x00 => return Err(ProtocolViolation::ReservedPacketType(packet_type)),
+		#[cfg(not(feature = "v5"))]
+		0xf0 => return Err(ProtocolViolation::ReservedPacketType(packet_type)),
+		PUBLISH => {
+			if (flags & 0x06) >> 1 == 3 {
+				return Err(ProtocolViolation::InvalidPublishQoS);
+			}
+			if flags & 0x06 == 0 && flags & 0x08 != 0 {
+				return Err(ProtocolViolation::DuplicateAtMostOnce);
+			}
+		}
+		PUBREL | SUBSCRIBE | UNSUBSCRIBE => {
+			if flags != 0x02 {
+				return Err(ProtocolViolation::ReservedHeaderFlags { header, flags });
+			}
+		}
+		_ => {
+			if flags != 0x00 {
+				return Err(ProtocolViolation::ReservedHeaderFlags { header, flags });
+			}
+		}
+	}
+
+	// The parser rejects some invalid Connect flags without distinguishing them,
+	// so check the flags before parsing.
+	if packet_type == CONNECT {
+		validate_connect_flags(&frame.payload)?;
+	}
+
+	let packet = Packet::parse(frame).map_err(ProtocolViolation::Malformed)?;
+
+	match packet {
+		Packet::ConnAck(connack) => {
+			if frame.payload[0] & 0xfe != 0 {
+				return Err(ProtocolViolation::ConnAckReservedFlags);
+			}
+			if !is_connack_code(connack.code) {
+				return Err(ProtocolViolation::InvalidReturnCode(connack.code));
+			}
+			if connack.code != 0 && connack.session_present {
+				return Err(ProtocolViolation::SessionPresentOnFailure);
+			}
+			Ok(())
+		}
+		Packet::Subscribe(subscribe) => {
+			if subscribe.filters.is_empty() {
+				return Err(ProtocolViolation::NoFilters);
+			}
+			Ok(())
+		}
+		Packet::Unsubscribe(unsubscribe) => {
+			if unsubscribe.filters.is_empty() {
+				return Err(ProtocolViolation::NoFilters);
+			}
+			Ok(())
+		}
+		_ => Ok(()),
+	}
+}
+
+/// Returns `true` if `code` is a ConnAck return code from MQTT v3.1.1 or, with
+/// the `v5` feature, a ConnAck reason code from MQTT v5. The frame does not
+/// say which protocol version is in use, so either is accepted.
+fn is_connack_code(code: u8) -> bool {
+	match code {
+		0..=5 => true,
+		#[cfg(feature = "v5")]
+		0x80..=0x8a | 0x8c | 0x90 | 0x95 | 0x97 | 0x99..=0x9d | 0x9f => true,
+		_ => false,
+	}
+}
+
+/// Checks the flags byte of a Connect packet. If `payload` is too short to
+/// contain the flags, this is left for the parser to report.
+fn validate_connect_flags(payload: &[u8]) -> Result<(), ProtocolViolation> {
+	// The flags byte follows the length-prefixed protocol name and the protocol
+	// level.
+	let Some(name_len) = payload.get(..2) else {
+		return Ok(());
+	};
+	let name_len = u16::from_be_bytes([name_len[0], name_len[1]]) as usize;
+	let Some(&[level, flags]) = payload.get(2 + name_len..2 + name_len + 2) else {
+		return Ok(());
+	};
+
+	if flags & CONNECT_RESERVED_FLAG != 0 {
+		return Err(ProtocolViolation::ConnectReservedFlag);
+	}
+
+	if flags & CONNECT_WILL_FLAG == 0 {
+		if flags & (CONNECT_WILL_QOS_MASK | CONNECT_WILL_RETAIN_FLAG) != 0 {
+			return Err(ProtocolViolation::WillFlagsWithoutWill);
+		}
+	} else if (flags & CONNECT_WILL_QOS_MASK) >> 3 == 3 {
+		return Err(ProtocolViolation::InvalidWillQoS);
+	}
+
+	// MQTT v5 allows a password to be sent without a username.
+	if flags & CONNECT_PASSWORD_FLAG != 0
+		&& flags & CONNECT_USERNAME_FLAG == 0
+		&& level < ProtocolVersion::V5.level()
+	{
+		return Err(ProtocolViolation::PasswordWithoutUsername);
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{validate, ProtocolViolation};
+	use crate::packets::Frame;
+
+	fn frame(header: u8, payload: &[u8]) -> Frame {
+		Frame {
+			header,
+			payload: payload.to_vec().into(),
+		}
+	}
+
+	#[test]
+	fn reserved_packet_types() {
+		let reserved: &[u8] = if cfg!(feature = "v5") {
+			&[0x00]
+		} else {
+			&[0x00, 0xf0]
+		};
+		for &header in reserved {
+			assert!(matches!(
+				validate(&frame(header, &[])),
+				Err(ProtocolViolation::ReservedPacketType(_))
+			));
+		}
+	}
+
+	#[test]
+	fn reserved_header_flags() {
+		// Packet types whose reserved flags must be 0b0000.
+		for (header, payload) in [
+			(0x20, &[0x00, 0x00][..]),
+			(0x40, &[0x00, 0x01][..]),
+			(0x50, &[0x00, 0x01][..]),
+			(0x70, &[0x00, 0x01][..]),
+			(0x90, &[0x00, 0x01, 0x00][..]),
+			(0xb0, &[0x00, 0x01][..]),
+			(0xc0, &[][..]),
+			(0xd0, &[][..]),
+			(0xe0, &[][..]),
+		] {
+			validate(&frame(header, payload)).unwrap();
+			for flags in 0x01..=0x0f {
+				assert!(matches!(
+					validate(&frame(header | flags, payload)),
+					Err(ProtocolViolation::ReservedHeaderFlags { .. })
+				));
+			}
+		}
+
+		// Packet types whose reserved flags must be 0b0010.
+		for (header, payload) in [
+			(0x60, &[0x00, 0x01][..]),
+			(0x80, &[0x00, 0x01, 0x00, 0x01, b'a', 0x00][..]),
+			(0xa0, &[0x00, 0x01, 0x00, 0x01, b'a'][..]),
+		] {
+			validate(&frame(header | 0x02, payload)).unwrap();
+			for flags in (0x00..=0x0f).filter(|flags| *flags != 0x02) {
+				assert!(matches!(
+					validate(&frame(header | flags, payload)),
+					Err(ProtocolViolation::ReservedHeaderFlags { .. })
+				));
+			}
+		}
+	}
+
+	#[test]
+	fn publish_flags() {
+		let payload = [0x00, 0x01, b'a', 0x00, 0x01];
+		validate(&frame(0x32, &payload)).unwrap();
+		validate(&frame(0x3a, &payload)).unwrap();
+		assert!(matches!(
+			validate(&frame(0x36, &payload)),
+			Err(ProtocolViolation::InvalidPublishQoS)
+		));
+		assert!(matches!(
+			validate(&frame(0x38, &payload)),
+			Err(ProtocolViolation::DuplicateAtMostOnce)
+		));
+	}
+
+	#[test]
+	fn connect_flags() {
+		let connect_level = |level: u8, flags: u8| {
+			let mut payload = vec![0x00, 0x04, b'M', b'Q', b'T', b'T', level, flags, 0x00, 0x3c];
+			if cfg!(feature = "v5") && level == 0x05 {
+				// Empty Connect properties.
+				payload.push(0x00);
+			}
+			payload.extend_from_slice(&[0x00, 0x01, b'c']);
+			if flags & 0x04 != 0 {
+				payload.extend_from_slice(&[0x00, 0x01, b't', 0x00, 0x00]);
+			}
+			if flags & 0x80 != 0 {
+				payload.extend_from_slice(&[0x00, 0x01, b'u']);
+			}
+			if flags & 0x40 != 0 {
+				payload.extend_from_slice(&[0x00, 0x01, b'p']);
+			}
+			frame(0x10, &payload)
+		};
+		let connect = |flags: u8| connect_level(0x04, flags);
+
+		validate(&connect(0x02)).unwrap();
+		validate(&connect(0x2c)).unwrap();
+		assert!(matches!(
+			validate(&connect(0x03)),
+			Err(ProtocolViolation::ConnectReservedFlag)
+		));
+		assert!(matches!(
+			validate(&connect(0x20)),
+			Err(ProtocolViolation::WillFlagsWithoutWill)
+		));
+		assert!(matches!(
+			validate(&connect(0x1c)),
+			Err(ProtocolViolation::InvalidWillQoS)
+		));
+		assert!(matches!(
+			validate(&connect(0x42)),
+			Err(ProtocolViolation::PasswordWithoutUsername)
+		));
+		validate(&connect_level(0x05, 0x42)).unwrap();
+	}
+
+	#[test]
+	fn connack_fields() {
+		validate(&frame(0x20, &[0x01, 0x00])).unwrap();
+		assert!(matches!(
+			validate(&frame(0x20, &[0x02, 0x00])),
+			Err(ProtocolViolation::ConnAckReservedFlags)
+		));
+		assert!(matches!(
+			validate(&frame(0x20, &[0x00, 0x06])),
+			Err(ProtocolViolation::InvalidReturnCode(6))
+		));
+		assert!(matches!(
+			validate(&frame(0x20, &[0x01, 0x05])),
+			Err(ProtocolViolation::SessionPresentOnFailure)
+		));
+
+		// "Not authorized" in MQTT v5.
+		let result = validate(&frame(0x20, &[0x00, 0x87]));
+		if cfg!(feature = "v5") {
+			result.unwrap();
+		} else {
+			assert!(matches!(
+				result,
+				Err(ProtocolViolation::InvalidReturnCode(0x87))
+			));
+		}
+		assert!(matches!(
+			validate(&frame(0x20, &[0x00, 0x8b])),
+			Err(ProtocolViolation::InvalidReturnCode(0x8b))
+		));
+	}
+
+	#[test]
+	fn empty_subscribe() {
+		assert!(matches!(
+			validate(&frame(0x82, &[0x00, 0x01])),
+			Err(ProtocolViolation::NoFilters)
+		));
+		assert!(matches!(
+			validate(&frame(0xa2, &[0x00, 0x01])),
+			Err(ProtocolViolation::NoFilters)
+		));
+	}
+}
@@ -8,9 +8,33 @@ pub enum QoS {
 	ExactlyOnce,
 }
 
+impl QoS {
+	/// The highest Quality of Service level.
+	pub const MAX: Self = Self::ExactlyOnce;
+
+	/// Returns the value of the QoS level as encoded in MQTT packets.
+	///
+	/// ```
+	/// # use tjh_mqtt::QoS;
+	/// assert_eq!(QoS::AtLeastOnce.as_u8(), 1);
+	/// ```
+	#[inline]
+	pub const fn as_u8(self) -> u8 {
+		self as u8
+	}
+}
+
+/// The error returned when converting a value greater than 2 into a [`QoS`].
 #[derive(Debug)]
 pub struct InvalidQoS;
 
+/// Converts a QoS level as encoded in MQTT packets.
+///
+/// ```
+/// # use tjh_mqtt::QoS;
+/// assert_eq!(QoS::try_from(2).unwrap(), QoS::ExactlyOnce);
+/// assert!(QoS::try_from(3).is_err());
+/// ```
 impl TryFrom<u8> for QoS {
 	type Error = InvalidQoS;
 	#[inline]
@@ -23,3 +47,21 @@ impl TryFrom<u8> for QoS {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::QoS;
+
+	#[test]
+	fn qos_u8_round_trip() {
+		for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce] {
+			assert_eq!(QoS::try_from(qos.as_u8()).unwrap(), qos);
+			assert!(qos <= QoS::MAX);
+		}
+		assert_eq!(QoS::MAX.as_u8(), 2);
+
+		for value in 3..=u8::MAX {
+			assert!(QoS::try_from(value).is_err());
+		}
+	}
+}
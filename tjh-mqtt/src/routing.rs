@@ -0,0 +1,400 @@
+//! Routing of topics to values keyed by filters.
+use crate::{
+	filter::{MULTI_LEVEL_WILDCARD_STR, SINGLE_LEVEL_WILDCARD_STR},
+	Filter, FilterBuf, QoS, Topic,
+};
+use std::{collections::HashMap, fmt};
+
+/// A trie of filters, keyed by filter level.
+///
+/// Finding the values whose filters match a topic only visits the branches of
+/// the trie that can match, rather than checking every filter in turn. Topics
+/// are matched with the same rules as [`Filter::matches_topic`].
+///
+/// # Example
+/// ```
+/// # use tjh_mqtt::{routing::FilterTrie, Filter, Topic};
+/// let mut trie = FilterTrie::new();
+/// trie.insert(Filter::from_static("a/+"), 1);
+/// trie.insert(Filter::from_static("a/#"), 2);
+/// trie.insert(Filter::from_static("b/#"), 3);
+///
+/// let mut matches: Vec<_> = trie.matches(Topic::from_static("a/b")).collect();
+/// matches.sort();
+/// assert_eq!(matches, [&1, &2]);
+/// ```
+pub struct FilterTrie<T> {
+	root: Node<T>,
+	len: usize,
+}
+
+struct Node<T> {
+	exact: HashMap<String, Node<T>>,
+	single_level: Option<Box<Node<T>>>,
+	multi_level: Option<(FilterBuf, T)>,
+	value: Option<(FilterBuf, T)>,
+}
+
+impl<T> FilterTrie<T> {
+	/// Creates an empty `FilterTrie`.
+	#[inline]
+	pub fn new() -> Self {
+		Self {
+			root: Node::default(),
+			len: 0,
+		}
+	}
+
+	/// Inserts `value` for `filter`.
+	///
+	/// If the trie already contained a value for `filter` it is replaced, and the
+	/// old value is returned.
+	pub fn insert(&mut self, filter: &Filter, value: T) -> Option<T> {
+		let mut node = &mut self.root;
+		for level in filter.levels() {
+			node = match level {
+				// A multi-level wildcard can only be the final level.
+				MULTI_LEVEL_WILDCARD_STR => break,
+				SINGLE_LEVEL_WILDCARD_STR => node.single_level.get_or_insert_with(Default::default),
+				exact => node.exact.entry(exact.to_owned()).or_default(),
+			};
+		}
+
+		let previous = node.slot(filter).replace((filter.to_owned(), value));
+		if previous.is_none() {
+			self.len += 1;
+		}
+		previous.map(|(_, value)| value)
+	}
+
+	/// Removes the value for `filter` from the trie, returning it.
+	pub fn remove(&mut self, filter: &Filter) -> Option<T> {
+		let mut node = &mut self.root;
+		for level in filter.levels() {
+			node = match level {
+				MULTI_LEVEL_WILDCARD_STR => break,
+				SINGLE_LEVEL_WILDCARD_STR => node.single_level.as_deref_mut()?,
+				exact => node.exact.get_mut(exact)?,
+			};
+		}
+
+		let (_, value) = node.slot(filter).take()?;
+		self.len -= 1;
+		Some(value)
+	}
+
+	/// Returns a reference to the value for `filter`.
+	pub fn get(&self, filter: &Filter) -> Option<&T> {
+		let mut node = &self.root;
+		for level in filter.levels() {
+			node = match level {
+				MULTI_LEVEL_WILDCARD_STR => {
+					return node.multi_level.as_ref().map(|(_, value)| value);
+				}
+				SINGLE_LEVEL_WILDCARD_STR => node.single_level.as_deref()?,
+				exact => node.exact.get(exact)?,
+			};
+		}
+		node.value.as_ref().map(|(_, value)| value)
+	}
+
+	/// Returns an iterator over the values whose filters match `topic`.
+	///
+	/// The order of the values is unspecified.
+	#[inline]
+	pub fn matches(&self, topic: &Topic) -> impl Iterator<Item = &T> {
+		self.matching_filters(topic).map(|(_, value)| value)
+	}
+
+	/// Returns an iterator over the filters matching `topic`, along with their
+	/// values.
+	///
+	/// The order of the filters is unspecified.
+	pub fn matching_filters(&self, topic: &Topic) -> impl Iterator<Item = (&Filter, &T)> {
+		let mut matches = Vec::new();
+		self.for_each_match(topic, |filter, value| matches.push((filter, value)));
+		matches.into_iter()
+	}
+
+	/// Calls `f` with each filter matching `topic`, along with its value.
+	///
+	/// Unlike [`matching_filters`](Self::matching_filters), this does not
+	/// allocate. The order of the filters is unspecified.
+	#[inline]
+	pub fn for_each_match<'a>(&'a self, topic: &Topic, mut f: impl FnMut(&'a Filter, &'a T)) {
+		self.root.visit_matches(Some(topic.as_str()), &mut f);
+	}
+
+	/// Returns the number of filters in the trie.
+	#[inline]
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Returns `true` if the trie contains no filters.
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Removes all filters from the trie.
+	#[inline]
+	pub fn clear(&mut self) {
+		self.root = Node::default();
+		self.len = 0;
+	}
+}
+
+impl<T> Node<T> {
+	/// Returns the slot for the value of `filter`, where this node corresponds
+	/// to the final non-wildcard level of the filter.
+	#[inline]
+	fn slot(&mut self, filter: &Filter) -> &mut Option<(FilterBuf, T)> {
+		match filter.levels().last() {
+			Some(MULTI_LEVEL_WILDCARD_STR) => &mut self.multi_level,
+			_ => &mut self.value,
+		}
+	}
+
+	/// Visits the values matching `levels`, the remaining levels of the topic.
+	fn visit_matches<'a>(&'a self, levels: Option<&str>, f: &mut impl FnMut(&'a Filter, &'a T)) {
+		let Some(levels) = levels else {
+			if let Some((filter, value)) = &self.value {
+				f(filter, value);
+			}
+			return;
+		};
+
+		// A multi-level wildcard matches one or more remaining levels.
+		if let Some((filter, value)) = &self.multi_level {
+			f(filter, value);
+		}
+
+		let (level, remaining) = match levels.split_once('/') {
+			Some((level, remaining)) => (level, Some(remaining)),
+			None => (levels, None),
+		};
+
+		if let Some(node) = self.exact.get(level) {
+			node.visit_matches(remaining, f);
+		}
+
+		if let Some(node) = &self.single_level {
+			node.visit_matches(remaining, f);
+		}
+	}
+
+	fn collect_all<'a>(&'a self, filters: &mut Vec<(&'a FilterBuf, &'a T)>) {
+		for (filter, value) in self.value.iter().chain(&self.multi_level) {
+			filters.push((filter, value));
+		}
+		if let Some(node) = &self.single_level {
+			node.collect_all(filters);
+		}
+		for node in self.exact.values() {
+			node.collect_all(filters);
+		}
+	}
+}
+
+/// A set of filters with no redundant members.
+///
+/// A filter is redundant if another filter in the set [subsumes] it with at
+/// least the same QoS, as every message it would receive is already received
+/// through the other filter.
+///
+/// Note that a Server tracks each subscribed filter separately, and may deliver
+/// overlapping messages once per matching subscription with its own QoS, so
+/// compacting a set of filters can change what the Client receives.
+///
+/// # Example
+/// ```
+/// # use tjh_mqtt::{routing::FilterSet, Filter, QoS};
+/// let mut set = FilterSet::new();
+/// assert!(set.insert(Filter::from_static("a/b"), QoS::AtMostOnce));
+/// assert!(set.insert(Filter::from_static("a/#"), QoS::AtLeastOnce));
+/// assert!(!set.insert(Filter::from_static("a/b/c"), QoS::AtMostOnce));
+///
+/// // "a/b" was removed when "a/#" was inserted.
+/// assert_eq!(set.len(), 1);
+/// ```
+///
+/// [subsumes]: Filter::subsumes
+#[derive(Clone, Debug, Default)]
+pub struct FilterSet {
+	filters: Vec<(FilterBuf, QoS)>,
+}
+
+impl FilterSet {
+	/// Creates an empty `FilterSet`.
+	#[inline]
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Inserts `filter` into the set, removing any filters it makes redundant.
+	///
+	/// Returns `false` if `filter` is already covered by a filter in the set, in
+	/// which case the set is unchanged.
+	pub fn insert(&mut self, filter: &Filter, qos: QoS) -> bool {
+		let covers = |(a, a_qos): (&Filter, QoS), (b, b_qos): (&Filter, QoS)| {
+			a_qos >= b_qos && a.subsumes(b)
+		};
+
+		if self
+			.filters
+			.iter()
+			.any(|(existing, existing_qos)| covers((existing, *existing_qos), (filter, qos)))
+		{
+			return false;
+		}
+
+		self.filters
+			.retain(|(existing, existing_qos)| !covers((filter, qos), (existing, *existing_qos)));
+		self.filters.push((filter.to_owned(), qos));
+		true
+	}
+
+	/// Returns an iterator over the filters in the set, in insertion order.
+	#[inline]
+	pub fn iter(&self) -> impl Iterator<Item = (&Filter, QoS)> {
+		self.filters
+			.iter()
+			.map(|(filter, qos)| (filter.as_ref(), *qos))
+	}
+
+	/// Returns the number of filters in the set.
+	#[inline]
+	pub fn len(&self) -> usize {
+		self.filters.len()
+	}
+
+	/// Returns `true` if the set contains no filters.
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.filters.is_empty()
+	}
+
+	/// Consumes the set, returning the filters in insertion order.
+	#[inline]
+	pub fn into_vec(self) -> Vec<(FilterBuf, QoS)> {
+		self.filters
+	}
+}
+
+impl<T> Default for FilterTrie<T> {
+	#[inline]
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<T> Default for Node<T> {
+	#[inline]
+	fn default() -> Self {
+		Self {
+			exact: HashMap::new(),
+			single_level: None,
+			multi_level: None,
+			value: None,
+		}
+	}
+}
+
+impl<T: fmt::Debug> fmt::Debug for FilterTrie<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut filters = Vec::with_capacity(self.len);
+		self.root.collect_all(&mut filters);
+		f.debug_map().entries(filters).finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{FilterSet, FilterTrie};
+	use crate::{Filter, QoS, Topic};
+
+	const FILTERS: [&str; 16] = [
+		"#", "+", "/", "a", "a/", "/b", "a/b", "+/b", "a/+", "+/+", "+/#", "/#", "a/#", "a/b/#",
+		"a/b/c", "+/+/c/#",
+	];
+
+	const TOPICS: [&str; 12] = [
+		"a", "b", "/", "a/", "/b", "a/b", "a/c", "a/b/c", "a/b/c/d", "x/y/c/z", "//c//", "a//",
+	];
+
+	#[test]
+	fn agrees_with_matches_topic() {
+		let mut trie = FilterTrie::new();
+		for filter in FILTERS {
+			trie.insert(Filter::new(filter).unwrap(), filter);
+		}
+		assert_eq!(trie.len(), FILTERS.len());
+
+		for topic in TOPICS {
+			let topic = Topic::new(topic).unwrap();
+
+			let mut expected: Vec<_> = FILTERS
+				.into_iter()
+				.filter(|filter| Filter::from_static(filter).matches_topic(topic).is_some())
+				.collect();
+			expected.sort();
+
+			let mut matched: Vec<_> = trie.matches(topic).copied().collect();
+			matched.sort();
+
+			assert_eq!(matched, expected, "topic {topic}");
+		}
+	}
+
+	#[test]
+	fn insert_replaces_and_remove() {
+		let mut trie = FilterTrie::new();
+		let filter = Filter::from_static("a/+/#");
+
+		assert_eq!(trie.insert(filter, 1), None);
+		assert_eq!(trie.insert(filter, 2), Some(1));
+		assert_eq!(trie.len(), 1);
+		assert_eq!(trie.get(filter), Some(&2));
+
+		assert_eq!(trie.remove(Filter::from_static("a/+")), None);
+		assert_eq!(trie.remove(filter), Some(2));
+		assert_eq!(trie.remove(filter), None);
+		assert!(trie.is_empty());
+		assert_eq!(trie.matches(Topic::from_static("a/b/c")).count(), 0);
+	}
+
+	#[test]
+	fn filter_set_removes_subsumed_filters() {
+		let mut set = FilterSet::new();
+		for filter in ["a/b", "a/c", "b/+", "b/c/#"] {
+			assert!(set.insert(Filter::from_static(filter), QoS::AtMostOnce));
+		}
+		assert!(!set.insert(Filter::from_static("a/b"), QoS::AtMostOnce));
+		assert!(!set.insert(Filter::from_static("b/c"), QoS::AtMostOnce));
+
+		// "a/+" makes "a/b" and "a/c" redundant.
+		assert!(set.insert(Filter::from_static("a/+"), QoS::AtMostOnce));
+		let filters: Vec<_> = set.iter().map(|(filter, _)| filter.as_str()).collect();
+		assert_eq!(filters, ["b/+", "b/c/#", "a/+"]);
+
+		assert!(set.insert(Filter::from_static("#"), QoS::AtMostOnce));
+		assert_eq!(set.len(), 1);
+	}
+
+	#[test]
+	fn filter_set_respects_qos() {
+		let mut set = FilterSet::new();
+		assert!(set.insert(Filter::from_static("a/#"), QoS::AtMostOnce));
+
+		// A subsumed filter with a higher QoS is not redundant.
+		assert!(set.insert(Filter::from_static("a/b"), QoS::ExactlyOnce));
+		assert_eq!(set.len(), 2);
+
+		assert!(set.insert(Filter::from_static("a/#"), QoS::ExactlyOnce));
+		assert_eq!(
+			set.into_vec(),
+			[(Filter::from_static("a/#").to_owned(), QoS::ExactlyOnce)]
+		);
+	}
+}
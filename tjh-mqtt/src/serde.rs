@@ -1,13 +1,13 @@
-use crate::{packets::ParseError, PacketId};
+use crate::{packets::DeserializeError, PacketId};
 use bytes::{Buf, BufMut};
 use std::{io, mem, str::from_utf8};
 
 #[derive(Debug)]
 pub struct WriteError;
 
-pub fn require(src: &io::Cursor<&[u8]>, len: usize) -> Result<(), ParseError> {
+pub fn require(src: &io::Cursor<&[u8]>, len: usize) -> Result<(), DeserializeError> {
 	if src.remaining() < len {
-		Err(ParseError::Incomplete)
+		Err(DeserializeError::Incomplete)
 	} else {
 		Ok(())
 	}
@@ -21,7 +21,7 @@ pub fn require_mut(dst: &impl BufMut, len: usize) -> Result<(), WriteError> {
 	}
 }
 
-pub fn get_u8(src: &mut io::Cursor<&[u8]>) -> Result<u8, ParseError> {
+pub fn get_u8(src: &mut io::Cursor<&[u8]>) -> Result<u8, DeserializeError> {
 	require(src, mem::size_of::<u8>())?;
 	Ok(src.get_u8())
 }
@@ -32,7 +32,7 @@ pub fn put_u8(dst: &mut impl BufMut, val: u8) -> Result<(), WriteError> {
 	Ok(())
 }
 
-pub fn get_u16(src: &mut io::Cursor<&[u8]>) -> Result<u16, ParseError> {
+pub fn get_u16(src: &mut io::Cursor<&[u8]>) -> Result<u16, DeserializeError> {
 	require(src, mem::size_of::<u16>())?;
 	Ok(src.get_u16())
 }
@@ -43,13 +43,29 @@ pub fn put_u16(dst: &mut impl BufMut, val: u16) -> Result<(), WriteError> {
 	Ok(())
 }
 
-pub fn get_id(src: &mut io::Cursor<&[u8]>) -> Result<PacketId, ParseError> {
+#[cfg(feature = "v5")]
+pub fn get_u32(src: &mut io::Cursor<&[u8]>) -> Result<u32, DeserializeError> {
+	require(src, mem::size_of::<u32>())?;
+	Ok(src.get_u32())
+}
+
+#[cfg(feature = "v5")]
+pub fn put_u32(dst: &mut impl BufMut, val: u32) -> Result<(), WriteError> {
+	require_mut(dst, mem::size_of::<u32>())?;
+	dst.put_u32(val);
+	Ok(())
+}
+
+pub fn get_id(src: &mut io::Cursor<&[u8]>) -> Result<PacketId, DeserializeError> {
 	let id = get_u16(src)?;
-	let id = PacketId::new(id).ok_or(ParseError::ZeroPacketId)?;
+	let id = PacketId::new(id).ok_or(DeserializeError::ZeroPacketId)?;
 	Ok(id)
 }
 
-pub fn get_slice<'s>(src: &mut io::Cursor<&'s [u8]>, len: usize) -> Result<&'s [u8], ParseError> {
+pub fn get_slice<'s>(
+	src: &mut io::Cursor<&'s [u8]>,
+	len: usize,
+) -> Result<&'s [u8], DeserializeError> {
 	require(src, len)?;
 	let position = src.position() as usize;
 	src.advance(len);
@@ -62,7 +78,7 @@ pub fn put_slice(dst: &mut impl BufMut, slice: &[u8]) -> Result<(), WriteError>
 	Ok(())
 }
 
-pub fn get_str<'s>(src: &mut io::Cursor<&'s [u8]>) -> Result<&'s str, ParseError> {
+pub fn get_str<'s>(src: &mut io::Cursor<&'s [u8]>) -> Result<&'s str, DeserializeError> {
 	let len = get_u16(src)? as usize;
 	let slice = get_slice(src, len)?;
 	let s = from_utf8(slice)?;
@@ -77,24 +93,26 @@ pub fn put_str(dst: &mut impl BufMut, s: &str) -> Result<(), WriteError> {
 	put_slice(dst, s.as_bytes())
 }
 
-pub fn get_var(src: &mut io::Cursor<&[u8]>) -> Result<usize, ParseError> {
-	let mut value = 0;
-	for multiplier in [0x01, 0x80, 0x4000, 0x200000, usize::MAX] {
-		// Detect if we've read too many bytes.
-		if multiplier == usize::MAX {
-			return Err(ParseError::MalformedLength);
-		}
+pub fn get_var(src: &mut io::Cursor<&[u8]>) -> Result<usize, DeserializeError> {
+	get_var_with_len(src).map(|(value, _)| value)
+}
 
+/// Reads a variable length integer, returning its value and the number of
+/// bytes it was encoded with.
+pub fn get_var_with_len(src: &mut io::Cursor<&[u8]>) -> Result<(usize, usize), DeserializeError> {
+	let mut value = 0;
+	for (len, multiplier) in [0x01, 0x80, 0x4000, 0x200000].into_iter().enumerate() {
 		let encoded = get_u8(src)? as usize;
 		value += (encoded & 0x7f) * multiplier;
 
 		// exit early if we've reached the last byte.
 		if encoded & 0x80 == 0 {
-			break;
+			return Ok((value, len + 1));
 		}
 	}
 
-	Ok(value)
+	// The continuation bit was set on the fourth byte.
+	Err(DeserializeError::MalformedLength)
 }
 
 pub fn put_var(dst: &mut impl BufMut, mut value: usize) -> Result<(), WriteError> {
@@ -114,3 +132,102 @@ pub fn put_var(dst: &mut impl BufMut, mut value: usize) -> Result<(), WriteError
 		}
 	}
 }
+
+/// Returns the number of bytes [`put_var`] writes for `value`.
+#[cfg(feature = "v5")]
+pub fn var_len(value: usize) -> usize {
+	match value {
+		0..=0x7f => 1,
+		0x80..=0x3fff => 2,
+		0x4000..=0x1f_ffff => 3,
+		_ => 4,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{get_var, get_var_with_len, put_var, WriteError};
+	use crate::packets::DeserializeError;
+	use std::io;
+
+	#[test]
+	fn var_with_len() {
+		for (value, len) in [
+			(0, 1),
+			(127, 1),
+			(128, 2),
+			(16_383, 2),
+			(16_384, 3),
+			(2_097_151, 3),
+			(2_097_152, 4),
+			(268_435_455, 4),
+		] {
+			let mut buffer = Vec::new();
+			put_var(&mut buffer, value).unwrap();
+			buffer.push(0xff);
+
+			let mut cursor = io::Cursor::new(&buffer[..]);
+			assert_eq!(get_var_with_len(&mut cursor).unwrap(), (value, len));
+			assert_eq!(cursor.position() as usize, len);
+		}
+
+		// More than 4 bytes.
+		let mut cursor = io::Cursor::new(&[0xff, 0xff, 0xff, 0xff, 0x01][..]);
+		assert!(matches!(
+			get_var_with_len(&mut cursor),
+			Err(DeserializeError::MalformedLength)
+		));
+
+		// Truncated encoding.
+		let mut cursor = io::Cursor::new(&[0x80, 0x80][..]);
+		assert!(matches!(
+			get_var_with_len(&mut cursor),
+			Err(DeserializeError::Incomplete)
+		));
+	}
+
+	#[test]
+	fn var_boundaries() {
+		// The encodings at each boundary, from section 2.2.3 of the specification.
+		for (value, encoded) in [
+			(127, &[0x7f][..]),
+			(128, &[0x80, 0x01]),
+			(16_383, &[0xff, 0x7f]),
+			(16_384, &[0x80, 0x80, 0x01]),
+			(2_097_151, &[0xff, 0xff, 0x7f]),
+			(2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+			(268_435_455, &[0xff, 0xff, 0xff, 0x7f]),
+		] {
+			let mut buffer = Vec::new();
+			put_var(&mut buffer, value).unwrap();
+			assert_eq!(buffer, encoded, "encoding {value}");
+			assert_eq!(
+				get_var(&mut io::Cursor::new(encoded)).unwrap(),
+				value,
+				"decoding {encoded:02x?}"
+			);
+
+			#[cfg(feature = "v5")]
+			assert_eq!(super::var_len(value), encoded.len());
+		}
+
+		// The largest value is the last one which fits in 4 bytes.
+		assert!(matches!(
+			put_var(&mut Vec::new(), 268_435_456),
+			Err(WriteError)
+		));
+		assert!(matches!(
+			put_var(&mut Vec::new(), usize::MAX),
+			Err(WriteError)
+		));
+
+		// A continuation bit on the fourth byte is rejected without reading a
+		// fifth.
+		let mut cursor = io::Cursor::new(&[0x80, 0x80, 0x80, 0x80, 0x00][..]);
+		assert!(matches!(
+			get_var(&mut cursor),
+			Err(DeserializeError::MalformedLength)
+		));
+		assert_eq!(cursor.position(), 4);
+	}
+}
@@ -1,5 +1,5 @@
 use core::borrow;
-use std::{fmt, ops};
+use std::{borrow::Cow, fmt, ops};
 
 /// An MQTT topic.
 ///
@@ -13,8 +13,30 @@ pub enum InvalidTopic {
 	Empty,
 	#[error("topic cannot exceed maximum length for an MQTT string (65,535 bytes)")]
 	TooLong,
-	#[error("topic cannot contain a wildcard character ('{0}' at position {1})")]
+	#[error("topic cannot contain a wildcard character ('{1}' at position {0})")]
 	InvalidCharacter(usize, char),
+	#[error("topic cannot contain a null character (at position {0})")]
+	NullCharacter(usize),
+	#[error("topic cannot contain a control character or non-character ({1:?} at position {0})")]
+	DisallowedCharacter(usize, char),
+}
+
+/// The error returned when a string cannot be converted into a [`TopicBuf`].
+///
+/// Unlike [`InvalidTopic`], this keeps the rejected string.
+#[derive(Debug, thiserror::Error)]
+#[error("{topic:?}: {source}")]
+pub struct InvalidTopicString {
+	pub topic: String,
+	#[source]
+	pub source: InvalidTopic,
+}
+
+/// Returns `true` for the characters the MQTT specification recommends are
+/// not included in topics and filters: control characters and non-characters.
+pub(crate) fn is_disallowed(character: char) -> bool {
+	matches!(character, '\u{1}'..='\u{1f}' | '\u{7f}'..='\u{9f}' | '\u{fdd0}'..='\u{fdef}')
+		|| (character as u32) & 0xfffe == 0xfffe
 }
 
 impl Topic {
@@ -35,11 +57,36 @@ impl Topic {
 			if ['+', '#'].contains(&character) {
 				return Err(InvalidTopic::InvalidCharacter(position, character));
 			}
+			if character == '\0' {
+				return Err(InvalidTopic::NullCharacter(position));
+			}
 		}
 
 		Ok(unsafe { &*(topic as *const str as *const Topic) })
 	}
 
+	/// Creates a new Topic, also rejecting control characters and
+	/// non-characters.
+	///
+	/// The MQTT specification recommends these are not included in topics, but
+	/// does not require Servers to reject them, so [`Topic::new`] accepts them.
+	///
+	/// ```
+	/// # use tjh_mqtt::Topic;
+	/// assert!(Topic::new("a/\u{7}").is_ok());
+	/// assert!(Topic::new_strict("a/\u{7}").is_err());
+	/// ```
+	pub fn new_strict<S: AsRef<str> + ?Sized>(topic: &S) -> Result<&Topic, InvalidTopic> {
+		let topic = Self::new(topic)?;
+		if let Some((position, character)) =
+			topic.0.chars().enumerate().find(|(_, c)| is_disallowed(*c))
+		{
+			return Err(InvalidTopic::DisallowedCharacter(position, character));
+		}
+
+		Ok(topic)
+	}
+
 	/// Returns the length of the topic in bytes when encoded as UTF-8.
 	#[inline]
 	pub const fn len(&self) -> usize {
@@ -57,6 +104,25 @@ impl Topic {
 		&self.0
 	}
 
+	/// Creates a Topic from a borrowed or owned string, without allocating.
+	///
+	/// A borrowed `&str` produces a borrowed `&Topic`, as with [`Topic::new`]. An
+	/// owned `String` is moved into a [`TopicBuf`], as with [`TopicBuf::new`].
+	///
+	/// # Example
+	/// ```
+	/// # use std::borrow::Cow;
+	/// # use tjh_mqtt::Topic;
+	/// let topic = Topic::new_cow("a/b").unwrap();
+	/// assert!(matches!(topic, Cow::Borrowed(_)));
+	/// ```
+	pub fn new_cow<'a>(topic: impl Into<Cow<'a, str>>) -> Result<Cow<'a, Topic>, InvalidTopic> {
+		match topic.into() {
+			Cow::Borrowed(topic) => Topic::new(topic).map(Cow::Borrowed),
+			Cow::Owned(topic) => TopicBuf::new(topic).map(Cow::Owned),
+		}
+	}
+
 	/// Converts a `Topic` to an owned [`TopicBuf`]
 	#[inline]
 	pub fn to_topic_buf(&self) -> TopicBuf {
@@ -79,6 +145,28 @@ impl Topic {
 		self.0.split('/')
 	}
 
+	/// Returns the level at index `n`, or `None` if the topic has fewer levels.
+	///
+	/// # Example
+	/// ```
+	/// # use tjh_mqtt::Topic;
+	/// let topic = Topic::new("devices/1234/status").unwrap();
+	/// assert_eq!(topic.level(1), Some("1234"));
+	/// assert_eq!(topic.level(3), None);
+	/// ```
+	#[inline]
+	pub fn level(&self, n: usize) -> Option<&str> {
+		self.levels().nth(n)
+	}
+
+	/// Returns the number of levels in the topic, without allocating.
+	///
+	/// Empty levels are counted, so `"a//b"` has three levels.
+	#[inline]
+	pub fn level_count(&self) -> usize {
+		self.0.matches('/').count() + 1
+	}
+
 	/// Creates a Topic from an `&'static str`. The validity of the topic is
 	/// *not* checked.
 	///
@@ -209,6 +297,15 @@ impl borrow::Borrow<Topic> for TopicBuf {
 	}
 }
 
+// `Topic` and `TopicBuf` hash and compare as their string, so a map keyed by
+// `TopicBuf` can be queried with a `&str`.
+impl borrow::Borrow<str> for TopicBuf {
+	#[inline]
+	fn borrow(&self) -> &str {
+		self.as_str()
+	}
+}
+
 impl From<&Topic> for TopicBuf {
 	#[inline]
 	fn from(value: &Topic) -> Self {
@@ -225,17 +322,85 @@ impl AsRef<Topic> for TopicBuf {
 }
 
 impl TryFrom<&str> for TopicBuf {
-	type Error = InvalidTopic;
+	type Error = InvalidTopicString;
 	fn try_from(value: &str) -> Result<Self, Self::Error> {
-		TopicBuf::new(value)
+		match Topic::new(value) {
+			Ok(topic) => Ok(topic.into()),
+			Err(source) => Err(InvalidTopicString {
+				topic: value.into(),
+				source,
+			}),
+		}
 	}
 }
 
 impl TryFrom<String> for TopicBuf {
-	type Error = InvalidTopic;
+	type Error = InvalidTopicString;
 	#[inline]
 	fn try_from(value: String) -> Result<Self, Self::Error> {
-		Self::new(value)
+		match Topic::new(&value) {
+			Ok(_) => Ok(Self(value)),
+			Err(source) => Err(InvalidTopicString {
+				topic: value,
+				source,
+			}),
+		}
+	}
+}
+
+impl PartialEq<str> for Topic {
+	#[inline]
+	fn eq(&self, other: &str) -> bool {
+		self.as_str() == other
+	}
+}
+
+impl PartialEq<&str> for Topic {
+	#[inline]
+	fn eq(&self, other: &&str) -> bool {
+		self.as_str() == *other
+	}
+}
+
+impl PartialEq<TopicBuf> for Topic {
+	#[inline]
+	fn eq(&self, other: &TopicBuf) -> bool {
+		self.as_str() == other.as_str()
+	}
+}
+
+impl PartialEq<TopicBuf> for &Topic {
+	#[inline]
+	fn eq(&self, other: &TopicBuf) -> bool {
+		self.as_str() == other.as_str()
+	}
+}
+
+impl PartialEq<str> for TopicBuf {
+	#[inline]
+	fn eq(&self, other: &str) -> bool {
+		self.as_str() == other
+	}
+}
+
+impl PartialEq<&str> for TopicBuf {
+	#[inline]
+	fn eq(&self, other: &&str) -> bool {
+		self.as_str() == *other
+	}
+}
+
+impl PartialEq<Topic> for TopicBuf {
+	#[inline]
+	fn eq(&self, other: &Topic) -> bool {
+		self.as_str() == other.as_str()
+	}
+}
+
+impl PartialEq<&Topic> for TopicBuf {
+	#[inline]
+	fn eq(&self, other: &&Topic) -> bool {
+		self.as_str() == other.as_str()
 	}
 }
 
@@ -299,6 +464,53 @@ impl serde::Serialize for TopicBuf {
 
 #[cfg(test)]
 mod tests {
+	use super::{InvalidTopic, Topic, TopicBuf};
+
+	#[test]
+	fn rejects_disallowed_characters() {
+		assert!(matches!(
+			Topic::new("a/\0b"),
+			Err(InvalidTopic::NullCharacter(2))
+		));
+		assert!(matches!(
+			Topic::new_strict("\0"),
+			Err(InvalidTopic::NullCharacter(0))
+		));
+
+		for topic in [
+			"a/\u{1b}",
+			"a/\u{85}",
+			"a/\u{fdd0}",
+			"a/\u{fffe}",
+			"a/\u{10ffff}",
+		] {
+			assert!(Topic::new(topic).is_ok());
+			assert!(matches!(
+				Topic::new_strict(topic),
+				Err(InvalidTopic::DisallowedCharacter(2, _))
+			));
+		}
+
+		Topic::new_strict("a/b c/\u{e9}/\u{1f600}").unwrap();
+	}
+
+	#[test]
+	fn compares_with_str() {
+		let topic = Topic::from_static("a/b");
+		let topic_buf = TopicBuf::new("a/b").unwrap();
+
+		assert_eq!(*topic, *"a/b");
+		assert_eq!(*topic, "a/b");
+		assert_eq!(topic, topic_buf);
+		assert_eq!(topic_buf, *"a/b");
+		assert_eq!(topic_buf, "a/b");
+		assert_eq!(topic_buf, *topic);
+		assert_eq!(topic_buf, topic);
+
+		assert_ne!(*topic, "a/c");
+		assert_ne!(topic_buf, "a/c");
+		assert_ne!(topic, TopicBuf::new("a/c").unwrap());
+	}
 
 	#[test]
 	#[cfg(feature = "serde")]
@@ -337,4 +549,73 @@ mod tests {
 		let serialized = serde_json::to_string(&topic).unwrap();
 		assert_eq!(serialized, "\"gamma/beta/alpha\"");
 	}
+
+	#[test]
+	fn conversion_error_keeps_topic() {
+		let error = TopicBuf::try_from("a/+").unwrap_err();
+		assert_eq!(error.topic, "a/+");
+		assert!(matches!(
+			error.source,
+			InvalidTopic::InvalidCharacter(2, '+')
+		));
+
+		let error = TopicBuf::try_from(String::from("#")).unwrap_err();
+		assert_eq!(error.topic, "#");
+		assert!(TopicBuf::try_from("a/b").is_ok());
+	}
+
+	#[test]
+	fn level_access() {
+		let topic = Topic::new("a/b/c").unwrap();
+		assert_eq!(topic.level_count(), 3);
+		assert_eq!(topic.level(0), Some("a"));
+		assert_eq!(topic.level(2), Some("c"));
+		assert_eq!(topic.level(3), None);
+		assert_eq!(topic.level(usize::MAX), None);
+
+		let topic = Topic::new("a//b").unwrap();
+		assert_eq!(topic.level_count(), 3);
+		assert_eq!(topic.level(1), Some(""));
+		assert_eq!(topic.level(2), Some("b"));
+
+		let topic = Topic::new("/").unwrap();
+		assert_eq!(topic.level_count(), 2);
+		assert_eq!(topic.level(0), Some(""));
+		assert_eq!(topic.level(1), Some(""));
+		assert_eq!(topic.level(2), None);
+	}
+
+	#[test]
+	fn new_cow_borrows_str() {
+		use std::borrow::Cow;
+
+		const TOPIC: &str = "a/b/c";
+		let Cow::Borrowed(topic) = Topic::new_cow(TOPIC).unwrap() else {
+			panic!("expected a borrowed topic");
+		};
+		assert!(std::ptr::eq(topic.as_str(), TOPIC));
+
+		let Cow::Owned(topic) = Topic::new_cow(String::from("a/b/c")).unwrap() else {
+			panic!("expected an owned topic");
+		};
+		assert_eq!(topic, "a/b/c");
+
+		assert!(Topic::new_cow("a/+").is_err());
+	}
+
+	#[test]
+	fn map_lookup_by_str() {
+		use crate::TopicBuf;
+		use std::collections::HashMap;
+
+		let map: HashMap<TopicBuf, usize> = ["a", "a/b", "a/b/c"]
+			.into_iter()
+			.enumerate()
+			.map(|(n, s)| (TopicBuf::new(s).unwrap(), n))
+			.collect();
+
+		assert_eq!(map.get("a/b"), Some(&1));
+		assert_eq!(map.get(Topic::from_static("a/b/c")), Some(&2));
+		assert_eq!(map.get("a/b/"), None);
+	}
 }